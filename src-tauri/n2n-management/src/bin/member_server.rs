@@ -0,0 +1,556 @@
+//! 自建成员服务器，通过超级节点管理端口提供`/members/{group}`
+//!
+//! 成员描述默认取自edge的`-I`，可通过`PUT /members/{group}/{mac}`覆盖并保存在本地文件中
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::error::Error;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use flexi_logger::Logger;
+use http_body_util::{BodyExt, Full, Limited};
+use hyper::body::{Body, Bytes};
+use hyper::header::{AUTHORIZATION, CONTENT_TYPE, ETAG, IF_NONE_MATCH};
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{Method, Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use log::{error, info};
+use n2n_management::controller::{Controller, ControllerError};
+use n2n_management::members::{MemberEntry, MembersResponse};
+use serde::Deserialize;
+use tokio::net::TcpListener;
+
+const USAGE: &str = "用法: member_server [--listen 0.0.0.0:8080] [--management 127.0.0.1:5645] \
+[--key 管理端口密码] [--store members.json] [--token 访问令牌]";
+/// 令牌也可以由环境变量提供，避免出现在进程列表中
+const TOKEN_ENV: &str = "MEMBER_SERVER_TOKEN";
+/// 修改描述时请求体的最大长度
+const BODY_LIMIT: usize = 4096;
+
+#[derive(Debug, PartialEq)]
+struct Args {
+    listen: SocketAddr,
+    management: SocketAddr,
+    key: Option<String>,
+    store: PathBuf,
+    token: Option<String>,
+}
+
+impl Args {
+    fn parse(
+        mut args: impl Iterator<Item = String>,
+        env_token: Option<String>,
+    ) -> Result<Self, String> {
+        let mut parsed = Self {
+            listen: SocketAddr::from(([0, 0, 0, 0], 8080)),
+            management: SocketAddr::from(([127, 0, 0, 1], 5645)),
+            key: None,
+            store: PathBuf::from("members.json"),
+            token: env_token,
+        };
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or(format!("{}缺少参数", arg));
+            match arg.as_str() {
+                "--listen" => {
+                    parsed.listen = value()?.parse().map_err(|e| format!("{}:{}", arg, e))?
+                }
+                "--management" => {
+                    parsed.management = value()?.parse().map_err(|e| format!("{}:{}", arg, e))?
+                }
+                "--key" => parsed.key = Some(value()?),
+                "--store" => parsed.store = PathBuf::from(value()?),
+                "--token" => parsed.token = Some(value()?),
+                _ => return Err(format!("未知参数:{}", arg)),
+            }
+        }
+        parsed.token = parsed.token.filter(|t| !t.is_empty());
+        Ok(parsed)
+    }
+}
+
+/// 按组与MAC地址保存的成员描述
+struct DescriptionStore {
+    path: PathBuf,
+    groups: Mutex<HashMap<String, HashMap<String, String>>>,
+}
+
+impl DescriptionStore {
+    /// 文件不存在时为空
+    fn load(path: PathBuf) -> Result<Self, String> {
+        let groups = match fs::read_to_string(&path) {
+            Ok(s) => serde_json::from_str(&s).map_err(|e| format!("{}:{}", path.display(), e))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(format!("{}:{}", path.display(), e)),
+        };
+        Ok(Self {
+            path,
+            groups: Mutex::new(groups),
+        })
+    }
+
+    fn get(&self, group: &str, mac: &str) -> Option<String> {
+        self.groups.lock().ok()?.get(group)?.get(mac).cloned()
+    }
+
+    /// 修改后写入临时文件再替换，避免中途退出损坏
+    fn set(&self, group: &str, mac: &str, desc: String) -> Result<(), String> {
+        let mut groups = self.groups.lock().map_err(|e| e.to_string())?;
+        let members = groups.entry(group.to_string()).or_default();
+        if desc.is_empty() {
+            members.remove(mac);
+        } else {
+            members.insert(mac.to_string(), desc);
+        }
+        let data = serde_json::to_string_pretty(&*groups).map_err(|e| e.to_string())?;
+        let temp = self.path.with_extension("tmp");
+        fs::write(&temp, data).map_err(|e| e.to_string())?;
+        fs::rename(&temp, &self.path).map_err(|e| e.to_string())
+    }
+}
+
+#[derive(Deserialize)]
+struct Description {
+    desc: String,
+}
+
+struct Server {
+    controller: Controller,
+    store: DescriptionStore,
+    token: Option<String>,
+}
+
+fn json(status: StatusCode, body: String, etag: Option<String>) -> Response<Full<Bytes>> {
+    let mut builder = Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "application/json");
+    if let Some(etag) = etag {
+        builder = builder.header(ETAG, etag);
+    }
+    builder
+        .body(Full::new(Bytes::from(body)))
+        .unwrap_or_default()
+}
+
+/// 失败时返回`{"status":false}`，与成员服务器的应答格式一致
+fn failed(status: StatusCode) -> Response<Full<Bytes>> {
+    json(
+        status,
+        serde_json::to_string(&MembersResponse::default()).unwrap_or_default(),
+        None,
+    )
+}
+
+/// 逐字节比较全部内容，耗时与第几个字节不同无关
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// `xx:xx:xx:xx:xx:xx`格式的MAC地址
+fn valid_mac(mac: &str) -> bool {
+    let octets: Vec<&str> = mac.split(':').collect();
+    octets.len() == 6
+        && octets
+            .iter()
+            .all(|o| o.len() == 2 && o.chars().all(|c| c.is_ascii_hexdigit()))
+}
+
+fn etag(body: &str) -> String {
+    let mut hasher = DefaultHasher::new();
+    body.hash(&mut hasher);
+    format!("\"{:x}\"", hasher.finish())
+}
+
+impl Server {
+    /// 超级节点中属于该组的成员
+    async fn members(&self, group: &str) -> Result<MembersResponse, ControllerError> {
+        let members = self
+            .controller
+            .edges()
+            .await?
+            .into_iter()
+            .filter(|row| row.community == group && !row.ip4addr.is_empty())
+            .map(|row| MemberEntry {
+                desc: self
+                    .store
+                    .get(group, &row.macaddr)
+                    .or(Some(row.desc).filter(|d| !d.is_empty())),
+                ip4addr: row.ip4addr,
+            })
+            .collect();
+        Ok(MembersResponse {
+            status: true,
+            members,
+        })
+    }
+
+    /// 该组中当前在线的成员的MAC地址，按超级节点中的写法返回
+    async fn member_mac(&self, group: &str, mac: &str) -> Result<Option<String>, ControllerError> {
+        Ok(self
+            .controller
+            .edges()
+            .await?
+            .into_iter()
+            .find(|row| row.community == group && row.macaddr.eq_ignore_ascii_case(mac))
+            .map(|row| row.macaddr))
+    }
+
+    fn authorized<B>(&self, req: &Request<B>) -> bool {
+        match self.token {
+            None => true,
+            Some(ref token) => req
+                .headers()
+                .get(AUTHORIZATION)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.strip_prefix("Bearer "))
+                .is_some_and(|v| constant_time_eq(v.as_bytes(), token.as_bytes())),
+        }
+    }
+
+    async fn handle<B>(&self, req: Request<B>) -> Response<Full<Bytes>>
+    where
+        B: Body<Data = Bytes>,
+        B::Error: Into<Box<dyn Error + Send + Sync>>,
+    {
+        if !self.authorized(&req) {
+            return failed(StatusCode::UNAUTHORIZED);
+        }
+        let path: Vec<String> = req
+            .uri()
+            .path()
+            .trim_matches('/')
+            .split('/')
+            .map(String::from)
+            .collect();
+        match (req.method(), path.as_slice()) {
+            (&Method::GET, [members, group]) if members == "members" => {
+                let response = match self.members(group).await {
+                    Ok(response) => response,
+                    Err(e) => {
+                        error!("{}:{}", line!(), e);
+                        return failed(StatusCode::BAD_GATEWAY);
+                    }
+                };
+                let body = serde_json::to_string(&response).unwrap_or_default();
+                let etag = etag(&body);
+                let matched = req
+                    .headers()
+                    .get(IF_NONE_MATCH)
+                    .and_then(|v| v.to_str().ok())
+                    .is_some_and(|v| v == etag);
+                if matched {
+                    Response::builder()
+                        .status(StatusCode::NOT_MODIFIED)
+                        .header(ETAG, etag)
+                        .body(Full::default())
+                        .unwrap_or_default()
+                } else {
+                    json(StatusCode::OK, body, Some(etag))
+                }
+            }
+            (&Method::PUT, [members, group, mac]) if members == "members" => {
+                if !valid_mac(mac) {
+                    return failed(StatusCode::BAD_REQUEST);
+                }
+                // 只允许修改在线成员，避免任意写入组与MAC使文件无限增长
+                let mac = match self.member_mac(group, mac).await {
+                    Ok(Some(mac)) => mac,
+                    Ok(None) => return failed(StatusCode::NOT_FOUND),
+                    Err(e) => {
+                        error!("{}:{}", line!(), e);
+                        return failed(StatusCode::BAD_GATEWAY);
+                    }
+                };
+                let group = group.clone();
+                let body = match Limited::new(req.into_body(), BODY_LIMIT).collect().await {
+                    Ok(body) => body.to_bytes(),
+                    Err(_) => return failed(StatusCode::PAYLOAD_TOO_LARGE),
+                };
+                let description: Description = match serde_json::from_slice(&body) {
+                    Ok(d) => d,
+                    Err(_) => return failed(StatusCode::BAD_REQUEST),
+                };
+                match self.store.set(&group, &mac, description.desc) {
+                    Ok(_) => json(StatusCode::OK, r#"{"status":true}"#.to_string(), None),
+                    Err(e) => {
+                        error!("{}:{}", line!(), e);
+                        failed(StatusCode::INTERNAL_SERVER_ERROR)
+                    }
+                }
+            }
+            _ => failed(StatusCode::NOT_FOUND),
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    if let Err(e) = Logger::try_with_str("info").and_then(|l| l.start()) {
+        eprintln!("{}", e);
+    }
+    let args = match Args::parse(std::env::args().skip(1), std::env::var(TOKEN_ENV).ok()) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{}\n{}", e, USAGE);
+            std::process::exit(2);
+        }
+    };
+    let store = match DescriptionStore::load(args.store.clone()) {
+        Ok(store) => store,
+        Err(e) => {
+            error!("{}:{}", line!(), e);
+            std::process::exit(1);
+        }
+    };
+    let server = Arc::new(Server {
+        controller: Controller::new(args.management.port())
+            .with_address(&args.management.ip().to_string())
+            .with_key(args.key.clone()),
+        store,
+        token: args.token.clone(),
+    });
+    let listener = match TcpListener::bind(args.listen).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("{}:{}", line!(), e);
+            std::process::exit(1);
+        }
+    };
+    info!(
+        "成员服务器监听{}，超级节点管理端口{}",
+        args.listen, args.management
+    );
+    loop {
+        let (stream, _) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                error!("{}:{}", line!(), e);
+                continue;
+            }
+        };
+        let server = server.clone();
+        tokio::spawn(async move {
+            let service = service_fn(move |req| {
+                let server = server.clone();
+                async move { Ok::<_, Infallible>(server.handle(req).await) }
+            });
+            if let Err(e) = http1::Builder::new()
+                .serve_connection(TokioIo::new(stream), service)
+                .await
+            {
+                error!("{}:{}", line!(), e);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+    use tokio::net::UdpSocket;
+
+    use super::*;
+
+    /// 应答`edges`的超级节点管理端口
+    async fn supernode(rows: Vec<Value>) -> SocketAddr {
+        let sock = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let address = sock.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = vec![0; 1024];
+            while let Ok((n, peer)) = sock.recv_from(&mut buf).await {
+                let request = String::from_utf8_lossy(&buf[..n]).to_string();
+                let tag = request.split(' ').nth(1).unwrap_or_default().to_string();
+                let mut replies = vec![json!({"_tag": tag, "_type": "begin", "cmd": "edges"})];
+                for row in &rows {
+                    let mut row = row.clone();
+                    row["_tag"] = json!(tag);
+                    row["_type"] = json!("row");
+                    replies.push(row);
+                }
+                replies.push(json!({"_tag": tag, "_type": "end", "cmd": "edges"}));
+                for reply in replies {
+                    let _ = sock.send_to(reply.to_string().as_bytes(), peer).await;
+                }
+            }
+        });
+        address
+    }
+
+    async fn server(token: Option<&str>, store: PathBuf) -> Server {
+        let address = supernode(vec![
+            json!({"community": "g", "ip4addr": "10.0.0.2/24", "macaddr": "02:00:00:00:00:02", "desc": "a"}),
+            json!({"community": "g", "ip4addr": "10.0.0.3/24", "macaddr": "02:00:00:00:00:03", "desc": ""}),
+            json!({"community": "other", "ip4addr": "10.0.0.4/24", "macaddr": "02:00:00:00:00:04"}),
+        ])
+        .await;
+        Server {
+            controller: Controller::new(address.port()),
+            store: DescriptionStore::load(store).unwrap(),
+            token: token.map(String::from),
+        }
+    }
+
+    fn request(method: Method, uri: &str, token: Option<&str>, body: &str) -> Request<Full<Bytes>> {
+        let mut builder = Request::builder().method(method).uri(uri);
+        if let Some(token) = token {
+            builder = builder.header(AUTHORIZATION, format!("Bearer {}", token));
+        }
+        builder
+            .body(Full::new(Bytes::from(body.to_string())))
+            .unwrap()
+    }
+
+    async fn body(response: Response<Full<Bytes>>) -> Value {
+        let bytes = response.into_body().collect().await.unwrap().to_bytes();
+        serde_json::from_slice(&bytes).unwrap()
+    }
+
+    fn store_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "member_server_{}_{}.json",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[tokio::test]
+    async fn members_of_group() {
+        let server = server(None, store_path("members")).await;
+        let response = server
+            .handle(request(Method::GET, "/members/g", None, ""))
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let etag = response
+            .headers()
+            .get(ETAG)
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .to_string();
+        let members: MembersResponse = serde_json::from_value(body(response).await).unwrap();
+        assert!(members.status);
+        assert_eq!(
+            members.members,
+            vec![
+                MemberEntry {
+                    ip4addr: "10.0.0.2/24".to_string(),
+                    desc: Some("a".to_string())
+                },
+                MemberEntry {
+                    ip4addr: "10.0.0.3/24".to_string(),
+                    desc: None
+                },
+            ]
+        );
+
+        let mut cached = request(Method::GET, "/members/g", None, "");
+        cached
+            .headers_mut()
+            .insert(IF_NONE_MATCH, etag.parse().unwrap());
+        assert_eq!(
+            server.handle(cached).await.status(),
+            StatusCode::NOT_MODIFIED
+        );
+        let missing = server
+            .handle(request(Method::GET, "/groups", None, ""))
+            .await;
+        assert_eq!(missing.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn descriptions_persist() {
+        let path = store_path("store");
+        let server = server(None, path.clone()).await;
+        let response = server
+            .handle(request(
+                Method::PUT,
+                "/members/g/02:00:00:00:00:03",
+                None,
+                r#"{"desc":"b"}"#,
+            ))
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let members = body(
+            server
+                .handle(request(Method::GET, "/members/g", None, ""))
+                .await,
+        )
+        .await;
+        assert_eq!(members["members"][1]["desc"], "b");
+
+        let reloaded = DescriptionStore::load(path.clone()).unwrap();
+        assert_eq!(reloaded.get("g", "02:00:00:00:00:03").as_deref(), Some("b"));
+        let _ = fs::remove_file(path);
+    }
+
+    #[tokio::test]
+    async fn rejects_unknown_members() {
+        let path = store_path("unknown");
+        let server = server(None, path.clone()).await;
+        let put = |uri: &str| request(Method::PUT, uri, None, r#"{"desc":"b"}"#);
+        let invalid = server.handle(put("/members/g/not-a-mac")).await;
+        assert_eq!(invalid.status(), StatusCode::BAD_REQUEST);
+        let other_group = server.handle(put("/members/g/02:00:00:00:00:04")).await;
+        assert_eq!(other_group.status(), StatusCode::NOT_FOUND);
+        let unknown_group = server.handle(put("/members/x/02:00:00:00:00:03")).await;
+        assert_eq!(unknown_group.status(), StatusCode::NOT_FOUND);
+        assert!(!path.exists());
+        let response = server.handle(put("/members/g/02:00:00:00:00:03")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let _ = fs::remove_file(path);
+    }
+
+    #[test]
+    fn token_comparison() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secret2"));
+        assert!(valid_mac("02:aB:00:00:00:03"));
+        assert!(!valid_mac("02:00:00:00:00"));
+        assert!(!valid_mac("02:00:00:00:00:0g"));
+    }
+
+    #[tokio::test]
+    async fn token_required() {
+        let server = server(Some("secret"), store_path("token")).await;
+        let denied = server
+            .handle(request(Method::GET, "/members/g", None, ""))
+            .await;
+        assert_eq!(denied.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(body(denied).await, json!({"status": false, "members": []}));
+        let wrong = server
+            .handle(request(Method::GET, "/members/g", Some("x"), ""))
+            .await;
+        assert_eq!(wrong.status(), StatusCode::UNAUTHORIZED);
+        let allowed = server
+            .handle(request(Method::GET, "/members/g", Some("secret"), ""))
+            .await;
+        assert_eq!(allowed.status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn parse_args() {
+        let args = |s: &str| {
+            s.split_whitespace()
+                .map(String::from)
+                .collect::<Vec<_>>()
+                .into_iter()
+        };
+        let parsed = Args::parse(
+            args("--listen 127.0.0.1:9000 --key k"),
+            Some("t".to_string()),
+        )
+        .unwrap();
+        assert_eq!(parsed.listen, "127.0.0.1:9000".parse().unwrap());
+        assert_eq!(parsed.key.as_deref(), Some("k"));
+        assert_eq!(parsed.token.as_deref(), Some("t"));
+        assert!(Args::parse(args("--listen"), None).is_err());
+        assert!(Args::parse(args("--port 1"), None).is_err());
+    }
+}
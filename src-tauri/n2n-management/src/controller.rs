@@ -0,0 +1,627 @@
+use std::io::ErrorKind;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::Duration;
+
+use log::debug;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use thiserror::Error;
+use tokio::net::UdpSocket;
+use tokio::sync::{Mutex, OnceCell};
+use tokio::time::timeout;
+
+/// UDP报文最大长度
+const BUFFER_SIZE: usize = 65535;
+/// 等待应答的默认超时
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(3);
+
+#[derive(Serialize, Deserialize, Debug)]
+struct JsonResponse {
+    _tag: String,
+    _type: String,
+    #[serde(flatten)]
+    data: Map<String, Value>,
+}
+
+#[derive(Debug, Error)]
+pub enum ControllerError {
+    #[error("管理端口通信失败:{0}")]
+    IoError(String),
+    #[error("管理端口超时")]
+    Timeout,
+    #[error("管理端口数据解析失败:{0}")]
+    ParseError(String),
+    #[error("管理端口返回错误:{0}")]
+    RemoteError(String),
+    #[error("未知的消息类型:{0}")]
+    UnknownType(String),
+    #[error("未订阅事件")]
+    NotSubscribed,
+}
+
+impl From<std::io::Error> for ControllerError {
+    fn from(e: std::io::Error) -> Self {
+        match e.kind() {
+            ErrorKind::WouldBlock | ErrorKind::TimedOut => ControllerError::Timeout,
+            _ => ControllerError::IoError(e.to_string()),
+        }
+    }
+}
+
+/// `info`
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct InfoRow {
+    pub version: String,
+    pub builddate: String,
+    pub is_edge: i64,
+    pub is_supernode: i64,
+    pub macaddr: String,
+    pub ip4addr: String,
+    pub sockaddr: String,
+}
+
+/// `communities`
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct CommunityRow {
+    pub community: String,
+}
+
+/// `edges`
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct EdgeRow {
+    pub mode: String,
+    pub community: String,
+    pub ip4addr: String,
+    pub purgeable: i64,
+    pub local: i64,
+    pub macaddr: String,
+    pub sockaddr: String,
+    pub desc: String,
+    pub last_p2p: i64,
+    pub last_sent_query: i64,
+    pub last_seen: i64,
+}
+
+/// `supernodes`
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct SupernodeRow {
+    pub version: String,
+    pub purgeable: i64,
+    pub current: i64,
+    pub macaddr: String,
+    pub sockaddr: String,
+    pub selection: String,
+    pub last_seen: i64,
+    pub uptime: i64,
+}
+
+/// `timestamps`
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct TimestampsRow {
+    pub start_time: i64,
+    pub last_super: i64,
+    pub last_p2p: i64,
+}
+
+/// `packetstats`
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct PacketStatsRow {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub tx_pkt: u64,
+    pub rx_pkt: u64,
+}
+
+/// `verbose`
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct VerboseRow {
+    #[serde(rename = "traceLevel")]
+    pub trace_level: i64,
+}
+
+/// 订阅推送的事件
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct EventRow {
+    pub event: String,
+    pub action: String,
+    pub macaddr: String,
+    pub sockaddr: String,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+/// 可订阅的事件主题
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Topic {
+    Peer,
+    Debug,
+}
+
+impl Topic {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Topic::Peer => "peer",
+            Topic::Debug => "debug",
+        }
+    }
+}
+
+pub struct Controller {
+    address: String,
+    port: u16,
+    tag: AtomicU32,
+    key: Option<String>,
+    debug: bool,
+    timeout: Duration,
+    /// 首次通信时绑定
+    sock: OnceCell<UdpSocket>,
+    /// 同一时间只进行一次请求，避免应答交错
+    busy: Mutex<()>,
+    /// 当前订阅的tag
+    subscription: Mutex<Option<String>>,
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct Member {
+    pub address: String,
+    pub name: String,
+    pub mode: String,
+}
+
+impl From<EdgeRow> for Member {
+    fn from(row: EdgeRow) -> Self {
+        let or = |s: String, default: &str| {
+            if s.is_empty() {
+                default.to_string()
+            } else {
+                s
+            }
+        };
+        Member {
+            address: or(row.ip4addr, "None"),
+            name: or(row.desc, "None"),
+            mode: or(row.mode, "Unknown"),
+        }
+    }
+}
+
+impl Controller {
+    pub fn new(port: u16) -> Self {
+        Controller {
+            address: "127.0.0.1".to_string(),
+            port,
+            tag: AtomicU32::new(0),
+            key: None,
+            debug: false,
+            timeout: DEFAULT_TIMEOUT,
+            sock: OnceCell::new(),
+            busy: Mutex::new(()),
+            subscription: Mutex::new(None),
+        }
+    }
+
+    /// 设置管理端口所在地址，默认本机
+    pub fn with_address(mut self, address: &str) -> Self {
+        self.address = address.to_string();
+        self
+    }
+
+    /// 设置管理端口密码
+    pub fn with_key(mut self, key: Option<String>) -> Self {
+        self.key = key;
+        self
+    }
+
+    /// 设置等待应答的超时
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    fn next_tag(&self) -> String {
+        (self.tag.fetch_add(1, Ordering::SeqCst) % 1000).to_string()
+    }
+
+    fn cmdstr(&self, msgtype: &str, cmdline: &str) -> (String, String) {
+        let tagstr = self.next_tag();
+        let mut options = vec![tagstr.clone()];
+        if let Some(ref key) = self.key {
+            options.push("1".to_string());
+            options.push(key.clone());
+        }
+        let optionsstr = options.join(":");
+        (tagstr, format!("{} {} {}", msgtype, optionsstr, cmdline))
+    }
+
+    async fn sock(&self) -> Result<&UdpSocket, ControllerError> {
+        Ok(self
+            .sock
+            .get_or_try_init(|| UdpSocket::bind("0.0.0.0:0"))
+            .await?)
+    }
+
+    /// 接收一条消息
+    async fn recv(&self) -> Result<JsonResponse, ControllerError> {
+        let mut buffer = vec![0; BUFFER_SIZE];
+        let sock = self.sock().await?;
+        let (size, _) = timeout(self.timeout, sock.recv_from(&mut buffer))
+            .await
+            .map_err(|_| ControllerError::Timeout)??;
+        serde_json::from_slice(&buffer[..size]).map_err(|e| ControllerError::ParseError(e.to_string()))
+    }
+
+    fn remote_error(data: &Map<String, Value>) -> ControllerError {
+        ControllerError::RemoteError(
+            data.get("error")
+                .and_then(|e| e.as_str())
+                .unwrap_or("未知错误")
+                .to_string(),
+        )
+    }
+
+    async fn rx(&self, tagstr: &str) -> Result<Vec<Map<String, Value>>, ControllerError> {
+        let mut result = Vec::new();
+
+        loop {
+            let data = self.recv().await?;
+
+            if data._tag != tagstr {
+                continue;
+            }
+
+            match data._type.as_str() {
+                // 数据类型
+                "error" => {
+                    return Err(Self::remote_error(&data.data));
+                }
+                "end" => {
+                    return Ok(result);
+                }
+                "row" => {
+                    if self.debug {
+                        debug!("{:?}", data.data);
+                    }
+                    result.push(data.data);
+                }
+                "begin" => {}
+                "subscribed" => {
+                    if self.debug {
+                        debug!("Subscribed")
+                    }
+                    return Ok(result);
+                }
+                "unsubscribed" => {
+                    if self.debug {
+                        debug!("Unsubscribed")
+                    }
+                }
+                "event" => {
+                    result.push(data.data);
+                }
+                _ => {
+                    return Err(ControllerError::UnknownType(data._type));
+                }
+            }
+        }
+    }
+
+    async fn send(&self, msgtype: &str, cmdline: &str) -> Result<String, ControllerError> {
+        let (tagstr, msgstr) = self.cmdstr(msgtype, cmdline);
+        self.sock()
+            .await?
+            .send_to(msgstr.as_bytes(), format!("{}:{}", self.address, self.port))
+            .await?;
+        Ok(tagstr)
+    }
+
+    async fn call(
+        &self,
+        msgtype: &str,
+        cmdline: &str,
+    ) -> Result<Vec<Map<String, Value>>, ControllerError> {
+        let _busy = self.busy.lock().await;
+        let tagstr = self.send(msgtype, cmdline).await?;
+        self.rx(&tagstr).await
+    }
+
+    async fn read(&self, cmdline: &str) -> Result<Vec<Map<String, Value>>, ControllerError> {
+        self.call("r", cmdline).await
+    }
+
+    async fn write(&self, cmdline: &str) -> Result<Vec<Map<String, Value>>, ControllerError> {
+        self.call("w", cmdline).await
+    }
+
+    /// 将行数据转换为对应类型
+    fn rows<T: DeserializeOwned>(rows: Vec<Map<String, Value>>) -> Result<Vec<T>, ControllerError> {
+        rows.into_iter()
+            .map(|row| {
+                serde_json::from_value(Value::Object(row))
+                    .map_err(|e| ControllerError::ParseError(e.to_string()))
+            })
+            .collect()
+    }
+
+    /// 读取命令并转换
+    pub async fn read_rows<T: DeserializeOwned>(&self, cmdline: &str) -> Result<Vec<T>, ControllerError> {
+        let rows = self.read(cmdline).await?;
+        Self::rows(rows)
+    }
+
+    /// 客户端信息
+    pub async fn info(&self) -> Result<InfoRow, ControllerError> {
+        self.read_rows::<InfoRow>("info")
+            .await?
+            .into_iter()
+            .next()
+            .ok_or(ControllerError::ParseError("info".to_string()))
+    }
+
+    /// 获取虚拟ip
+    pub async fn get_vip(&self) -> Result<String, ControllerError> {
+        let info = self.info().await?;
+        if info.ip4addr.is_empty() {
+            Ok(String::from("0.0.0.0"))
+        } else {
+            Ok(info.ip4addr)
+        }
+    }
+
+    /// 通过管理端口关闭客户端
+    pub async fn close(&self) -> Result<(), ControllerError> {
+        self.write("stop").await.map(|_| ())
+    }
+
+    /// 发送stop但不等待应答，用于无法等待异步任务的停止流程
+    pub fn request_stop(&self) -> Result<(), ControllerError> {
+        let (_, msgstr) = self.cmdstr("w", "stop");
+        let sock = std::net::UdpSocket::bind("0.0.0.0:0")?;
+        sock.send_to(msgstr.as_bytes(), format!("{}:{}", self.address, self.port))?;
+        Ok(())
+    }
+
+    /// 检查客户端是否在线
+    pub async fn test(&self) -> bool {
+        self.read("help").await.is_ok()
+    }
+
+    /// 所在组
+    pub async fn communities(&self) -> Result<Vec<CommunityRow>, ControllerError> {
+        self.read_rows("communities").await
+    }
+
+    /// 查询当前所在组
+    pub async fn current_group(&self) -> Result<String, ControllerError> {
+        Ok(self
+            .communities()
+            .await?
+            .into_iter()
+            .next()
+            .map(|row| row.community)
+            .unwrap_or(String::from("None")))
+    }
+
+    /// 查询当前组所有成员
+    pub async fn edges(&self) -> Result<Vec<EdgeRow>, ControllerError> {
+        self.read_rows("edges").await
+    }
+
+    /// 查询超级节点
+    pub async fn supernodes(&self) -> Result<Vec<SupernodeRow>, ControllerError> {
+        self.read_rows("supernodes").await
+    }
+
+    /// 查询时间戳
+    pub async fn timestamps(&self) -> Result<TimestampsRow, ControllerError> {
+        self.read_rows::<TimestampsRow>("timestamps")
+            .await?
+            .into_iter()
+            .next()
+            .ok_or(ControllerError::ParseError("timestamps".to_string()))
+    }
+
+    /// 查询收发包统计
+    pub async fn packetstats(&self) -> Result<Vec<PacketStatsRow>, ControllerError> {
+        self.read_rows("packetstats").await
+    }
+
+    /// 查询日志等级
+    pub async fn verbose(&self) -> Result<i64, ControllerError> {
+        self.read_rows::<VerboseRow>("verbose")
+            .await?
+            .into_iter()
+            .next()
+            .map(|row| row.trace_level)
+            .ok_or(ControllerError::ParseError("verbose".to_string()))
+    }
+
+    /// 设置日志等级
+    pub async fn set_verbose(&self, level: u8) -> Result<i64, ControllerError> {
+        let rows = self.write(format!("verbose {}", level).as_str()).await?;
+        Self::rows::<VerboseRow>(rows)?
+            .into_iter()
+            .next()
+            .map(|row| row.trace_level)
+            .ok_or(ControllerError::ParseError("verbose".to_string()))
+    }
+
+    /// 重新加载组列表
+    pub async fn reload_communities(&self) -> Result<(), ControllerError> {
+        self.write("reload_communities").await.map(|_| ())
+    }
+
+    /// 订阅事件，同一时间管理端口只保留一个订阅者
+    pub async fn subscribe(&self, topic: Topic) -> Result<(), ControllerError> {
+        let _busy = self.busy.lock().await;
+        let tagstr = self.send("s", topic.as_str()).await?;
+        self.rx(&tagstr).await?;
+        *self.subscription.lock().await = Some(tagstr);
+        Ok(())
+    }
+
+    /// 等待下一个事件，超时返回None
+    pub async fn next_event(&self) -> Result<Option<EventRow>, ControllerError> {
+        let Some(tagstr) = self.subscription.lock().await.clone() else {
+            return Err(ControllerError::NotSubscribed);
+        };
+        let _busy = self.busy.lock().await;
+        loop {
+            let data = match self.recv().await {
+                Ok(data) => data,
+                Err(ControllerError::Timeout) => return Ok(None),
+                Err(e) => return Err(e),
+            };
+            if data._tag != tagstr {
+                continue;
+            }
+            match data._type.as_str() {
+                "event" => {
+                    return serde_json::from_value(Value::Object(data.data))
+                        .map(Some)
+                        .map_err(|e| ControllerError::ParseError(e.to_string()));
+                }
+                // 被其他订阅者顶替
+                "unsubscribed" => {
+                    *self.subscription.lock().await = None;
+                    return Err(ControllerError::NotSubscribed);
+                }
+                "error" => return Err(Self::remote_error(&data.data)),
+                _ => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::mock_edge::{MockEdge, Reply};
+
+    fn controller(edge: &MockEdge) -> Controller {
+        Controller::new(edge.port())
+    }
+
+    #[tokio::test]
+    async fn get_vip() {
+        let edge = MockEdge::start();
+        edge.rows("info", vec![json!({"version": "3.0", "ip4addr": "10.0.0.2"})]);
+        assert_eq!(controller(&edge).get_vip().await.unwrap(), "10.0.0.2");
+
+        edge.rows("info", vec![json!({"version": "3.0", "ip4addr": ""})]);
+        assert_eq!(controller(&edge).get_vip().await.unwrap(), "0.0.0.0");
+    }
+
+    #[tokio::test]
+    async fn edges() {
+        let edge = MockEdge::start();
+        edge.rows(
+            "edges",
+            vec![
+                json!({"mode": "pSp", "ip4addr": "10.0.0.3/24", "desc": "a", "macaddr": "02:00:00:00:00:03", "last_seen": 5}),
+                json!({"mode": "sn", "ip4addr": "", "desc": ""}),
+            ],
+        );
+        let rows = controller(&edge).edges().await.unwrap();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0].last_seen, 5);
+
+        let members: Vec<Member> = rows.into_iter().map(Member::from).collect();
+        assert_eq!(members[1].address, "None");
+        assert_eq!(members[1].name, "None");
+        assert_eq!(members[1].mode, "sn");
+    }
+
+    #[tokio::test]
+    async fn current_group() {
+        let edge = MockEdge::start();
+        edge.rows("communities", vec![json!({"community": "lers10"})]);
+        assert_eq!(controller(&edge).current_group().await.unwrap(), "lers10");
+
+        edge.rows("communities", vec![]);
+        assert_eq!(controller(&edge).current_group().await.unwrap(), "None");
+    }
+
+    #[tokio::test]
+    async fn test() {
+        let edge = MockEdge::start();
+        assert!(controller(&edge).test().await);
+
+        edge.reply("help", Reply::Error("badauth".to_string()));
+        assert!(!controller(&edge).test().await);
+    }
+
+    #[tokio::test]
+    async fn malformed_error() {
+        let edge = MockEdge::start();
+        edge.reply(
+            "edges",
+            Reply::Raw(vec![r#"{"_tag":"0","_type":"error"}"#.to_string()]),
+        );
+        match controller(&edge).edges().await {
+            Err(ControllerError::RemoteError(e)) => assert_eq!(e, "未知错误"),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn ignores_other_tags() {
+        let edge = MockEdge::start();
+        edge.reply(
+            "verbose",
+            Reply::Raw(vec![
+                r#"{"_tag":"99","_type":"row","traceLevel":1}"#.to_string(),
+                r#"{"_tag":"0","_type":"row","traceLevel":2}"#.to_string(),
+                r#"{"_tag":"0","_type":"end"}"#.to_string(),
+            ]),
+        );
+        assert_eq!(controller(&edge).verbose().await.unwrap(), 2);
+    }
+
+    #[tokio::test]
+    async fn write_commands() {
+        let edge = MockEdge::start();
+        edge.rows("verbose", vec![json!({"traceLevel": 3})]);
+        edge.rows("reload_communities", vec![]);
+        let c = controller(&edge);
+        assert_eq!(c.set_verbose(3).await.unwrap(), 3);
+        c.reload_communities().await.unwrap();
+        assert_eq!(edge.received(), vec!["w 0 verbose 3", "w 1 reload_communities"]);
+    }
+
+    #[tokio::test]
+    async fn peer_events() {
+        let edge = MockEdge::start();
+        let c = controller(&edge);
+        c.subscribe(Topic::Peer).await.unwrap();
+        assert!(edge.subscribed());
+
+        edge.event(json!({"event": "peer", "action": "add_p2p", "macaddr": "02:00:00:00:00:03"}));
+        let event = c.next_event().await.unwrap().unwrap();
+        assert_eq!(event.action, "add_p2p");
+        assert_eq!(event.macaddr, "02:00:00:00:00:03");
+    }
+
+    #[tokio::test]
+    async fn request_stop() {
+        let edge = MockEdge::start();
+        controller(&edge).request_stop().unwrap();
+        for _ in 0..100 {
+            if !edge.received().is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(edge.received(), vec!["w 0 stop"]);
+    }
+}
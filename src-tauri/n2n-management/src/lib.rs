@@ -0,0 +1,5 @@
+//! 不依赖Tauri的n2n管理端口客户端与成员服务器报文，供客户端与自建成员服务器共用
+pub mod controller;
+pub mod members;
+#[cfg(any(test, feature = "mock"))]
+pub mod mock_edge;
@@ -0,0 +1,39 @@
+use serde::{Deserialize, Serialize};
+
+/// 成员服务器中的一个成员
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct MemberEntry {
+    /// `ip/前缀长度`
+    pub ip4addr: String,
+    #[serde(default)]
+    pub desc: Option<String>,
+}
+
+impl MemberEntry {
+    /// 去掉前缀长度的地址
+    pub fn ip(&self) -> &str {
+        self.ip4addr.split('/').next().unwrap_or_default()
+    }
+}
+
+/// `GET {member_server}/members/{group}`的应答
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct MembersResponse {
+    pub status: bool,
+    #[serde(default)]
+    pub members: Vec<MemberEntry>,
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn rejects_failed_status() {
+        let res: MembersResponse = serde_json::from_value(json!({"status": false})).unwrap();
+        assert!(!res.status);
+        assert!(serde_json::from_value::<MembersResponse>(json!({"members": []})).is_err());
+    }
+}
@@ -0,0 +1,179 @@
+//! 测试用的edge管理端口，按脚本应答`_tag`/`_type`协议
+use std::collections::{HashMap, VecDeque};
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use serde_json::{json, Value};
+
+/// 对某条命令的应答
+#[derive(Clone)]
+pub enum Reply {
+    /// begin/row.../end
+    Rows(Vec<Value>),
+    /// error行
+    Error(String),
+    /// 原样发送的报文
+    Raw(Vec<String>),
+    /// 不应答
+    Silent,
+}
+
+#[derive(Default)]
+struct Script {
+    replies: HashMap<String, Reply>,
+    received: Vec<String>,
+    subscriber: Option<(SocketAddr, String)>,
+    events: VecDeque<Value>,
+}
+
+pub struct MockEdge {
+    port: u16,
+    script: Arc<Mutex<Script>>,
+    running: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Drop for MockEdge {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::SeqCst);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+impl MockEdge {
+    /// 在回环地址的随机端口上启动
+    pub fn start() -> Self {
+        let sock = UdpSocket::bind("127.0.0.1:0").expect("bind mock edge");
+        sock.set_read_timeout(Some(Duration::from_millis(20)))
+            .expect("set timeout");
+        let port = sock.local_addr().unwrap().port();
+        let script = Arc::new(Mutex::new(Script::default()));
+        let running = Arc::new(AtomicBool::new(true));
+
+        let handle = {
+            let script = script.clone();
+            let running = running.clone();
+            thread::spawn(move || Self::serve(sock, script, running))
+        };
+        let edge = Self {
+            port,
+            script,
+            running,
+            handle: Some(handle),
+        };
+        edge.reply("help", Reply::Rows(vec![json!({"cmd": "help"})]));
+        edge
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// 设置命令的应答，命令按首个单词匹配
+    pub fn reply(&self, command: &str, reply: Reply) {
+        self.script
+            .lock()
+            .unwrap()
+            .replies
+            .insert(command.to_string(), reply);
+    }
+
+    /// 以行数据应答
+    pub fn rows(&self, command: &str, rows: Vec<Value>) {
+        self.reply(command, Reply::Rows(rows));
+    }
+
+    /// 向订阅者推送事件
+    pub fn event(&self, event: Value) {
+        self.script.lock().unwrap().events.push_back(event);
+    }
+
+    /// 是否已有订阅者
+    pub fn subscribed(&self) -> bool {
+        self.script.lock().unwrap().subscriber.is_some()
+    }
+
+    /// 收到的全部请求
+    pub fn received(&self) -> Vec<String> {
+        self.script.lock().unwrap().received.clone()
+    }
+
+    fn with_tag(tag: &str, kind: &str, row: &Value) -> String {
+        let mut row = match row {
+            Value::Object(map) => map.clone(),
+            _ => Default::default(),
+        };
+        row.insert("_tag".to_string(), json!(tag));
+        row.insert("_type".to_string(), json!(kind));
+        Value::Object(row).to_string()
+    }
+
+    fn answer(script: &Mutex<Script>, request: &str, from: SocketAddr) -> Vec<String> {
+        let mut parts = request.splitn(3, ' ');
+        let msgtype = parts.next().unwrap_or_default();
+        let tag = parts
+            .next()
+            .unwrap_or_default()
+            .split(':')
+            .next()
+            .unwrap_or_default()
+            .to_string();
+        let cmdline = parts.next().unwrap_or_default().trim();
+        let command = cmdline.split(' ').next().unwrap_or_default();
+
+        let mut script = script.lock().unwrap();
+        script.received.push(request.to_string());
+
+        if msgtype == "s" {
+            let mut packets = Vec::new();
+            if let Some((old, old_tag)) = script.subscriber.take() {
+                if old != from {
+                    packets.push(Self::with_tag(&old_tag, "unsubscribed", &json!({})));
+                }
+            }
+            script.subscriber = Some((from, tag.clone()));
+            packets.push(Self::with_tag(&tag, "subscribed", &json!({})));
+            return packets;
+        }
+
+        match script.replies.get(command).cloned() {
+            None => vec![Self::with_tag(&tag, "error", &json!({"error": "unknowncmd"}))],
+            Some(Reply::Error(e)) => vec![Self::with_tag(&tag, "error", &json!({"error": e}))],
+            Some(Reply::Raw(packets)) => packets,
+            Some(Reply::Silent) => vec![],
+            Some(Reply::Rows(rows)) => {
+                let mut packets = vec![Self::with_tag(&tag, "begin", &json!({"cmd": command}))];
+                for row in rows.iter() {
+                    packets.push(Self::with_tag(&tag, "row", row));
+                }
+                packets.push(Self::with_tag(&tag, "end", &json!({"cmd": command})));
+                packets
+            }
+        }
+    }
+
+    fn serve(sock: UdpSocket, script: Arc<Mutex<Script>>, running: Arc<AtomicBool>) {
+        let mut buffer = [0; 2048];
+        while running.load(Ordering::SeqCst) {
+            if let Ok((size, from)) = sock.recv_from(&mut buffer) {
+                let request = String::from_utf8_lossy(&buffer[..size]).to_string();
+                for packet in Self::answer(&script, &request, from) {
+                    let _ = sock.send_to(packet.as_bytes(), from);
+                }
+            }
+            // 推送事件
+            let mut script = script.lock().unwrap();
+            if let Some((to, tag)) = script.subscriber.clone() {
+                while let Some(event) = script.events.pop_front() {
+                    let _ = sock.send_to(Self::with_tag(&tag, "event", &event).as_bytes(), to);
+                }
+            }
+        }
+    }
+}
@@ -1,110 +1,290 @@
 use std::env::current_dir;
-use std::fs::File;
-use std::io::Write;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
 
-use log::error;
+use lazy_static::lazy_static;
+use log::{error, info, warn};
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 use tauri::{AppHandle, Manager, Wry};
 use tauri_plugin_store::{StoreCollection, with_store};
 
-use crate::tools::{ExternalFilePosition, ProgramError};
+use crate::config::migration::CURRENT_VERSION;
+use crate::tools::{ExternalFilePosition, ProgramError, DEFAULT_STOP_TIMEOUT};
 use crate::tools::n2n_client::N2NClientConfig;
+use crate::tools::output::unix_time;
+use crate::tools::supernode::SupernodeConfig;
+use crate::tools::supervisor::RestartPolicy;
 
-pub(crate) const REMOTE_CONFIG: &str = "远程配置文件";
+pub mod migration;
+pub mod remote;
+
+lazy_static! {
+    /// 启动时配置文件无法解析的原因，此时以内存中的默认配置运行
+    static ref LOAD_ERROR: Mutex<Option<String>> = Mutex::new(None);
+}
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct LocalConfig {
-    pub n2n_config: N2NClientConfig,
-    pub nat_detect: Vec<String>,
+    /// 配置格式版本，旧版本读取时依次迁移
+    #[serde(default)]
+    pub version: u32,
+    /// 网络配置，可同时运行多个
+    #[serde(default)]
+    pub n2n_profiles: Vec<N2NClientConfig>,
+    /// STUN服务器池，检测NAT时从中选出可用的服务器
+    #[serde(default = "default_stun_servers")]
+    pub stun_servers: Vec<String>,
     pub miniserve_port: u32,
+    /// WinIPBroadcast异常退出后的重启策略
+    #[serde(default)]
+    pub win_ip_broadcast_restart: RestartPolicy,
+    /// 文件共享异常退出后的重启策略
+    #[serde(default)]
+    pub miniserve_restart: RestartPolicy,
+    /// 停止子进程时等待其自行退出的毫秒数，超时后强制结束
+    #[serde(default = "default_stop_timeout_ms")]
+    pub stop_timeout_ms: u64,
+    /// 本机主持网络时的超级节点
+    #[serde(default)]
+    pub supernode: SupernodeConfig,
+    /// 团队密钥，用于签名与验证邀请码
+    #[serde(default)]
+    pub invite_secret: Option<String>,
+}
+
+fn default_stop_timeout_ms() -> u64 {
+    DEFAULT_STOP_TIMEOUT.as_millis() as u64
+}
+
+fn default_stun_servers() -> Vec<String> {
+    vec![
+        "stun.nextcloud.com:3478".to_string(),
+        "stun.miwifi.com:3478".to_string(),
+        "stun.cloudflare.com:3478".to_string(),
+        "stun.l.google.com:19302".to_string(),
+    ]
 }
 
 impl Default for LocalConfig {
     fn default() -> Self {
         Self {
-            n2n_config: N2NClientConfig::default(),
-            nat_detect: vec![
-                "stun.nextcloud.com:3478".to_string(),
-                "stun.miwifi.com:3478".to_string(),
-            ],
+            version: CURRENT_VERSION,
+            n2n_profiles: vec![N2NClientConfig::default()],
+            stun_servers: default_stun_servers(),
             miniserve_port: 8090,
+            win_ip_broadcast_restart: RestartPolicy::default(),
+            miniserve_restart: RestartPolicy::default(),
+            stop_timeout_ms: default_stop_timeout_ms(),
+            supernode: SupernodeConfig::default(),
+            invite_secret: None,
         }
     }
 }
 
 impl LocalConfig {
-    /// 从store中取出config
-    pub fn get_config(app_handle: &AppHandle) -> Self {
-        let mut config = Self::default();
-        let position = match current_dir() {
-            Ok(position) => position.join(ExternalFilePosition::Config.to_string()),
-            Err(e) => {
-                error!(
-                    "{}:{}",
-                    line!(),
-                    ProgramError::GetCurrentDirError(e.to_string())
-                );
-                return config;
-            }
-        };
+    /// 配置文件位置
+    pub(crate) fn position() -> Result<PathBuf, ProgramError> {
+        current_dir()
+            .map(|position| position.join(ExternalFilePosition::Config.to_string()))
+            .map_err(|e| ProgramError::GetCurrentDirError(e.to_string()))
+    }
+
+    /// 从store中取出config，旧版本在内存中迁移。无法读取或解析时返回错误
+    pub fn get_config(app_handle: &AppHandle) -> Result<Self, ProgramError> {
+        let position = Self::position()?;
         let stores = app_handle.state::<StoreCollection<Wry>>();
-        if let Err(e) = with_store(app_handle.clone(), stores, position.clone(), |store| {
-            match store.get("config") {
-                None => {}
-                Some(v) => config = serde_json::from_value(v.clone()).unwrap(),
-            }
-            Ok(())
-        }) {
-            error!(
-                "{}:{}",
-                line!(),
-                ProgramError::ConfigGetError(e.to_string())
-            );
+        let value = with_store(app_handle.clone(), stores, position, |store| {
+            Ok(store.get("config").cloned())
+        })
+        .map_err(|e| ProgramError::ConfigGetError(e.to_string()))?;
+        match value {
+            None => Ok(Self::default()),
+            Some(v) => Self::parse(v).map(|(c, _)| c.normalize()).map_err(|e| {
+                error!("{}:{}", line!(), e);
+                e
+            }),
         }
-        config
-    }
-
-    /// 从网络下载默认配置覆盖本地配置
-    pub fn download_config() -> Result<(), ProgramError> {
-        match reqwest::blocking::get(REMOTE_CONFIG) {
-            Ok(res) => {
-                if res.status().is_success() {
-                    match File::create(ExternalFilePosition::Config.to_string())
-                        .map_err(|e| ProgramError::FileRWError(e.to_string()))
-                    {
-                        Ok(mut file) => {
-                            match res.bytes() {
-                                Ok(b) => {
-                                    match file.write_all(b.as_ref()) {
-                                        Ok(_) => {
-                                            // 写入本地
-                                            if let Err(e) = file.flush() {
-                                                Err(ProgramError::ConfigGetError(e.to_string()))
-                                            } else {
-                                                Ok(())
-                                            }
-                                        }
-                                        Err(e) => {
-                                            Err(ProgramError::ConfigGetError(e.to_string()))
-                                        }
-                                    }
-                                }
-                                Err(e) => {
-                                    Err(ProgramError::ConfigGetError(e.to_string()))
-                                }
-                            }
-                        }
-                        Err(e) => {
-                            Err(e)
-                        }
-                    }
-                } else {
-                    Err(ProgramError::ConfigGetError(String::from("下载远程配置文件失败")))
-                }
+    }
+
+    /// 启动时配置文件无法解析的原因
+    pub fn load_error() -> Option<String> {
+        LOAD_ERROR.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// 迁移到当前版本后解析，同时返回迁移前的版本
+    fn parse(mut value: Value) -> Result<(Self, u32), ProgramError> {
+        let from = migration::migrate(&mut value)?;
+        let config = serde_json::from_value::<Self>(value)
+            .map_err(|e| ProgramError::ConfigGetError(e.to_string()))?;
+        Ok((config, from))
+    }
+
+    /// 启动时加载配置文件。没有配置时使用默认值，旧版本迁移后写回。
+    /// 无法解析时保留原文件并另存一份备份，以内存中的默认值运行并返回错误。
+    /// 默认值取自缓存的远程配置或内置配置
+    pub fn prepare(app_handle: &AppHandle) -> Result<(), ProgramError> {
+        let position = Self::position()?;
+        let value = match position.exists() {
+            true => Self::read_value(app_handle)?,
+            false => None,
+        };
+        let Some(value) = value else {
+            info!("{}", ProgramError::ConfigGetError(String::from("本地没有配置文件，使用默认配置")));
+            return Self::write_value(app_handle, remote::defaults());
+        };
+        match Self::parse(value) {
+            Ok((config, from)) if from < CURRENT_VERSION => {
+                info!("配置从版本{}迁移到{}", from, CURRENT_VERSION);
+                backup(&position)?;
+                config.save(app_handle)
             }
+            Ok(_) => Ok(()),
             Err(e) => {
-                Err(ProgramError::ConfigGetError(e.to_string()))
+                warn!("{}:{}", line!(), e);
+                // 之后保存设置会覆盖原文件，先留一份副本
+                backup(&position)?;
+                *LOAD_ERROR.lock().unwrap_or_else(|e| e.into_inner()) = Some(e.to_string());
+                let stores = app_handle.state::<StoreCollection<Wry>>();
+                with_store(app_handle.clone(), stores, position, |store| {
+                    store.insert("config".to_string(), remote::defaults())
+                })
+                .map_err(|e| ProgramError::ConfigGetError(e.to_string()))?;
+                Err(e)
             }
         }
     }
+
+    /// 从文件重新读取store中的原始配置
+    pub(crate) fn read_value(app_handle: &AppHandle) -> Result<Option<Value>, ProgramError> {
+        let position = Self::position()?;
+        let stores = app_handle.state::<StoreCollection<Wry>>();
+        with_store(app_handle.clone(), stores, position, |store| {
+            store.load()?;
+            Ok(store.get("config").cloned())
+        })
+        .map_err(|e| ProgramError::ConfigGetError(e.to_string()))
+    }
+
+    /// 写回store并保存到文件
+    pub(crate) fn write_value(app_handle: &AppHandle, value: Value) -> Result<(), ProgramError> {
+        let position = Self::position()?;
+        let stores = app_handle.state::<StoreCollection<Wry>>();
+        with_store(app_handle.clone(), stores, position, |store| {
+            store.insert("config".to_string(), value)?;
+            store.save()
+        })
+        .map_err(|e| ProgramError::FileRWError(e.to_string()))
+    }
+
+    pub fn save(&self, app_handle: &AppHandle) -> Result<(), ProgramError> {
+        let value = serde_json::to_value(self).map_err(|e| ProgramError::FileRWError(e.to_string()))?;
+        Self::write_value(app_handle, value)
+    }
+
+    /// 团队密钥，空字符串视为未设置
+    pub fn invite_secret(&self) -> Option<&str> {
+        self.invite_secret.as_deref().filter(|s| !s.is_empty())
+    }
+
+    /// 保证至少有一个网络配置
+    fn normalize(mut self) -> Self {
+        if self.n2n_profiles.is_empty() {
+            self.n2n_profiles.push(N2NClientConfig::default());
+        }
+        self
+    }
+
+    /// 停止子进程的等待时间
+    pub fn stop_timeout(&self) -> Duration {
+        Duration::from_millis(self.stop_timeout_ms)
+    }
+
+    /// 按id查找网络配置，未指定时使用第一个
+    pub fn profile(&self, id: Option<&str>) -> Result<N2NClientConfig, ProgramError> {
+        match id {
+            None => self.n2n_profiles.first(),
+            Some(id) => self.n2n_profiles.iter().find(|p| p.id == id),
+        }
+        .cloned()
+        .ok_or(ProgramError::ProfileNotFound(id.unwrap_or_default().to_string()))
+    }
+}
+
+/// 启动时配置文件无法解析的原因，前端据此提示用户
+#[tauri::command]
+pub fn config_load_error() -> Option<String> {
+    LocalConfig::load_error()
+}
+
+/// 备份文件名，`config.json.v{版本}.{时间}.bak`
+fn backup_path(position: &Path, version: u32, time: u64) -> PathBuf {
+    let mut name = position.file_name().unwrap_or_default().to_os_string();
+    name.push(format!(".v{}.{}.bak", version, time));
+    position.with_file_name(name)
+}
+
+/// 覆盖配置文件前复制一份，文件不存在时不做任何事
+pub fn backup(position: &Path) -> Result<Option<PathBuf>, ProgramError> {
+    if !position.exists() {
+        return Ok(None);
+    }
+    let content = fs::read(position).map_err(|e| ProgramError::FileRWError(e.to_string()))?;
+    let version = serde_json::from_slice::<Value>(&content)
+        .ok()
+        .and_then(|v| v.get("config").map(migration::version))
+        .unwrap_or_default();
+    let target = backup_path(position, version, unix_time());
+    fs::write(&target, content).map_err(|e| ProgramError::FileRWError(e.to_string()))?;
+    info!("已备份配置文件到{}", target.display());
+    Ok(Some(target))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backup_keeps_old_file() {
+        let dir = std::env::temp_dir().join(format!("light_n2n_config_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let position = dir.join("config.json");
+        assert_eq!(backup(&position).unwrap(), None);
+
+        let content = r#"{"config":{"version":1,"nat_detect":[],"miniserve_port":8090}}"#;
+        fs::write(&position, content).unwrap();
+        let target = backup(&position).unwrap().unwrap();
+        assert!(target.file_name().unwrap().to_string_lossy().starts_with("config.json.v1."));
+        assert_eq!(fs::read_to_string(&target).unwrap(), content);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn parse_migrates_legacy() {
+        let legacy = serde_json::json!({
+            "n2n_config": {"group": "g", "server": "s", "port": 1, "member_server": "m", "control_port": 2},
+            "nat_detect": [],
+            "miniserve_port": 8090
+        });
+        let (config, from) = LocalConfig::parse(legacy).unwrap();
+        assert_eq!(from, 0);
+        assert_eq!(config.version, CURRENT_VERSION);
+        assert_eq!(config.n2n_profiles[0].id, "default");
+        assert_eq!(config.n2n_profiles[0].name, "默认");
+    }
+
+    #[test]
+    fn parse_without_stun_servers() {
+        let current = serde_json::json!({
+            "version": CURRENT_VERSION,
+            "n2n_profiles": [{"id": "home", "name": "家", "group": "g", "server": "s", "port": 1, "member_server": "m", "control_port": 2}],
+            "miniserve_port": 8090
+        });
+        let (config, from) = LocalConfig::parse(current).unwrap();
+        assert_eq!(from, CURRENT_VERSION);
+        assert_eq!(config.stun_servers, default_stun_servers());
+        assert_eq!(config.n2n_profiles[0].id, "home");
+    }
 }
@@ -0,0 +1,194 @@
+use std::collections::HashSet;
+
+use serde_json::{json, Map, Value};
+
+use crate::tools::ProgramError;
+
+/// 当前配置版本
+pub const CURRENT_VERSION: u32 = 3;
+
+type Migration = fn(&mut Map<String, Value>) -> Result<(), ProgramError>;
+
+/// 第n个函数将版本n迁移到n+1
+const MIGRATIONS: [Migration; CURRENT_VERSION as usize] = [v0_to_v1, v1_to_v2, v2_to_v3];
+
+/// 配置的版本，没有version字段的为0
+pub fn version(config: &Value) -> u32 {
+    config
+        .get("version")
+        .and_then(Value::as_u64)
+        .and_then(|v| u32::try_from(v).ok())
+        .unwrap_or(0)
+}
+
+/// 依次迁移到当前版本，返回迁移前的版本。高于当前版本的配置保持不变
+pub fn migrate(config: &mut Value) -> Result<u32, ProgramError> {
+    let from = version(config);
+    let object = config
+        .as_object_mut()
+        .ok_or(ProgramError::ConfigGetError("配置不是对象".to_string()))?;
+    for (version, migration) in MIGRATIONS.iter().enumerate().skip(from as usize) {
+        migration(object)?;
+        object.insert("version".to_string(), json!(version + 1));
+    }
+    Ok(from)
+}
+
+/// 单个`n2n_config`并入`n2n_profiles`
+fn v0_to_v1(config: &mut Map<String, Value>) -> Result<(), ProgramError> {
+    let legacy = config.remove("n2n_config");
+    let profiles = config
+        .entry("n2n_profiles")
+        .or_insert_with(|| json!([]))
+        .as_array_mut()
+        .ok_or(ProgramError::ConfigGetError("n2n_profiles不是数组".to_string()))?;
+    if let Some(Value::Object(mut legacy)) = legacy {
+        let id = legacy.entry("id").or_insert_with(|| json!("default")).clone();
+        legacy.entry("name").or_insert_with(|| json!("默认"));
+        if !profiles.iter().any(|p| p.get("id") == Some(&id)) {
+            profiles.insert(0, Value::Object(legacy));
+        }
+    }
+    Ok(())
+}
+
+/// 网络配置的id不能为空或重复，缺少名称时使用组名
+fn v1_to_v2(config: &mut Map<String, Value>) -> Result<(), ProgramError> {
+    let Some(profiles) = config.get_mut("n2n_profiles").and_then(Value::as_array_mut) else {
+        return Ok(());
+    };
+    let mut seen = HashSet::new();
+    for (index, profile) in profiles.iter_mut().enumerate() {
+        let Some(profile) = profile.as_object_mut() else {
+            continue;
+        };
+        // 缺少id时与之前的默认值一致，保留系统凭据中的密钥
+        let mut id = match profile.get("id").and_then(Value::as_str) {
+            None => "default".to_string(),
+            Some("") => format!("profile-{}", index + 1),
+            Some(id) => id.to_string(),
+        };
+        while !seen.insert(id.clone()) {
+            id = format!("{}-{}", id, index + 1);
+        }
+        profile.insert("id".to_string(), json!(id));
+        let named = profile
+            .get("name")
+            .and_then(Value::as_str)
+            .is_some_and(|n| !n.is_empty());
+        if !named {
+            let name = profile.get("group").cloned().unwrap_or(json!(id));
+            profile.insert("name".to_string(), name);
+        }
+    }
+    Ok(())
+}
+
+/// `nat_detect`改为STUN服务器池`stun_servers`，去掉空项与重复项
+fn v2_to_v3(config: &mut Map<String, Value>) -> Result<(), ProgramError> {
+    let Some(servers) = config.remove("nat_detect") else {
+        return Ok(());
+    };
+    if config.contains_key("stun_servers") {
+        return Ok(());
+    }
+    let servers = servers
+        .as_array()
+        .ok_or(ProgramError::ConfigGetError("nat_detect不是数组".to_string()))?;
+    let mut seen = HashSet::new();
+    let pool: Vec<Value> = servers
+        .iter()
+        .filter_map(Value::as_str)
+        .map(str::trim)
+        .filter(|s| !s.is_empty() && seen.insert(*s))
+        .map(|s| json!(s))
+        .collect();
+    config.insert("stun_servers".to_string(), Value::Array(pool));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::LocalConfig;
+
+    fn object(value: Value) -> Map<String, Value> {
+        value.as_object().unwrap().clone()
+    }
+
+    #[test]
+    fn legacy_single_profile() {
+        let mut config = object(json!({
+            "n2n_config": {"group": "lers10", "server": "a", "port": 1, "member_server": "m", "control_port": 2, "identification": "me"},
+            "nat_detect": ["s1", "s2"],
+            "miniserve_port": 8090
+        }));
+        v0_to_v1(&mut config).unwrap();
+        assert!(!config.contains_key("n2n_config"));
+        let profiles = config["n2n_profiles"].as_array().unwrap();
+        assert_eq!(profiles.len(), 1);
+        assert_eq!(profiles[0]["id"], "default");
+        assert_eq!(profiles[0]["identification"], "me");
+    }
+
+    #[test]
+    fn legacy_alongside_profiles() {
+        let mut config = object(json!({
+            "n2n_config": {"id": "default", "group": "old"},
+            "n2n_profiles": [{"id": "default", "group": "new"}, {"id": "b", "group": "b"}]
+        }));
+        v0_to_v1(&mut config).unwrap();
+        let profiles = config["n2n_profiles"].as_array().unwrap();
+        assert_eq!(profiles.len(), 2);
+        assert_eq!(profiles[0]["group"], "new");
+    }
+
+    #[test]
+    fn unique_profile_ids() {
+        let mut config = object(json!({
+            "n2n_profiles": [{"group": "a"}, {"id": "default", "group": "b"}, {"id": "", "name": "c"}]
+        }));
+        v1_to_v2(&mut config).unwrap();
+        let profiles = config["n2n_profiles"].as_array().unwrap();
+        let ids: Vec<_> = profiles.iter().map(|p| p["id"].as_str().unwrap()).collect();
+        assert_eq!(ids, vec!["default", "default-2", "profile-3"]);
+        assert_eq!(profiles[0]["name"], "a");
+        assert_eq!(profiles[2]["name"], "c");
+    }
+
+    #[test]
+    fn stun_server_pool() {
+        let mut config = object(json!({"nat_detect": ["a:3478", " ", "b:3478", "a:3478 "]}));
+        v2_to_v3(&mut config).unwrap();
+        assert!(!config.contains_key("nat_detect"));
+        assert_eq!(config["stun_servers"], json!(["a:3478", "b:3478"]));
+
+        let mut config = object(json!({"nat_detect": ["a:3478"], "stun_servers": ["c:3478"]}));
+        v2_to_v3(&mut config).unwrap();
+        assert_eq!(config["stun_servers"], json!(["c:3478"]));
+    }
+
+    #[test]
+    fn chain() {
+        let mut config = json!({
+            "n2n_config": {"group": "lers10", "server": "a", "port": 1, "member_server": "m", "control_port": 2, "identification": "me"},
+            "nat_detect": ["s1", "s2"],
+            "miniserve_port": 8090
+        });
+        assert_eq!(migrate(&mut config).unwrap(), 0);
+        assert_eq!(version(&config), CURRENT_VERSION);
+        let parsed: LocalConfig = serde_json::from_value(config.clone()).unwrap();
+        assert_eq!(parsed.n2n_profiles[0].group, "lers10");
+        assert_eq!(parsed.stun_servers, vec!["s1", "s2"]);
+        assert_eq!(parsed.version, CURRENT_VERSION);
+
+        // 已是当前版本时不变
+        let before = config.clone();
+        assert_eq!(migrate(&mut config).unwrap(), CURRENT_VERSION);
+        assert_eq!(config, before);
+        // 更高版本保持不变
+        let mut newer = json!({"version": CURRENT_VERSION + 1});
+        migrate(&mut newer).unwrap();
+        assert_eq!(newer, json!({"version": CURRENT_VERSION + 1}));
+    }
+}
@@ -0,0 +1,252 @@
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use log::{info, warn};
+use minisign_verify::{PublicKey, Signature};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tauri::{AppHandle, Emitter};
+
+use crate::config::{backup, migration, LocalConfig};
+use crate::tools::output::unix_time;
+use crate::tools::{ExternalFilePosition, ProgramError};
+
+pub(crate) const REMOTE_CONFIG: &str = "远程配置文件";
+/// 与更新包使用同一密钥，`tauri signer sign`生成的`.sig`放在配置文件旁
+const PUBLIC_KEY: &str = "dW50cnVzdGVkIGNvbW1lbnQ6IG1pbmlzaWduIHB1YmxpYyBrZXk6IEVDMDA0NDc4NTFENkQzNjEKUldSaDA5WlJlRVFBN04wVHFrTHFYZkRvVnBxQlhIaFpzcml1K0NRbnhUOGdvWEdNcnZsS1k4cnAK";
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// 远程配置更新并合并后推送到前端
+pub const CONFIG_EVENT: &str = "config-updated";
+
+/// 验证过的远程配置，保存在本地供离线时使用
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct RemoteConfig {
+    /// 下载的原始内容，与store文件格式相同
+    pub content: String,
+    /// base64编码的minisign签名
+    pub signature: String,
+    /// 下载时间，unix秒
+    pub fetched: u64,
+}
+
+impl RemoteConfig {
+    /// 验证签名后创建
+    pub fn new(content: String, signature: String, public_key: &str) -> Result<Self, ProgramError> {
+        verify(content.as_bytes(), &signature, public_key)?;
+        Ok(Self {
+            content,
+            signature,
+            fetched: unix_time(),
+        })
+    }
+
+    /// 迁移到当前版本后的默认配置
+    pub fn config(&self) -> Result<Value, ProgramError> {
+        let mut value = serde_json::from_str::<Value>(&self.content)
+            .map_err(|e| ProgramError::ConfigGetError(e.to_string()))?
+            .get_mut("config")
+            .map(Value::take)
+            .ok_or(ProgramError::ConfigGetError("远程配置中没有config".to_string()))?;
+        migration::migrate(&mut value)?;
+        serde_json::from_value::<LocalConfig>(value.clone())
+            .map_err(|e| ProgramError::ConfigGetError(e.to_string()))?;
+        Ok(value)
+    }
+
+    /// 读取本地缓存，重新验证签名，无效时返回None
+    pub fn load(position: &Path, public_key: &str) -> Option<Self> {
+        let content = fs::read_to_string(position).ok()?;
+        let cached = match serde_json::from_str::<Self>(&content) {
+            Ok(cached) => cached,
+            Err(e) => {
+                warn!("{}:{}", line!(), ProgramError::ConfigGetError(e.to_string()));
+                return None;
+            }
+        };
+        match verify(cached.content.as_bytes(), &cached.signature, public_key).and_then(|_| cached.config()) {
+            Ok(_) => Some(cached),
+            Err(e) => {
+                warn!("{}:远程配置缓存无效:{}", line!(), e);
+                None
+            }
+        }
+    }
+
+    pub fn save(&self, position: &Path) -> Result<(), ProgramError> {
+        let content = serde_json::to_vec_pretty(self).map_err(|e| ProgramError::FileRWError(e.to_string()))?;
+        fs::write(position, content).map_err(|e| ProgramError::FileRWError(e.to_string()))
+    }
+}
+
+/// 用base64编码的minisign公钥验证base64编码的签名
+pub fn verify(content: &[u8], signature: &str, public_key: &str) -> Result<(), ProgramError> {
+    let decode = |s: &str| {
+        STANDARD
+            .decode(s.trim())
+            .ok()
+            .and_then(|b| String::from_utf8(b).ok())
+            .ok_or(ProgramError::ConfigVerifyError("编码错误".to_string()))
+    };
+    let key = PublicKey::decode(&decode(public_key)?)
+        .map_err(|e| ProgramError::ConfigVerifyError(e.to_string()))?;
+    let signature = Signature::decode(&decode(signature)?)
+        .map_err(|e| ProgramError::ConfigVerifyError(e.to_string()))?;
+    key.verify(content, &signature, false)
+        .map_err(|e| ProgramError::ConfigVerifyError(e.to_string()))
+}
+
+/// 下载远程配置与`.sig`签名并验证
+pub async fn download(url: &str, public_key: &str) -> Result<RemoteConfig, ProgramError> {
+    let client = reqwest::Client::builder()
+        .timeout(REQUEST_TIMEOUT)
+        .build()
+        .map_err(|e| ProgramError::NetworkError(e.to_string()))?;
+    let get = |url: String| {
+        let client = client.clone();
+        async move {
+            let res = client
+                .get(url)
+                .send()
+                .await
+                .and_then(|res| res.error_for_status())
+                .map_err(|e| ProgramError::NetworkError(e.to_string()))?;
+            res.text().await.map_err(|e| ProgramError::NetworkError(e.to_string()))
+        }
+    };
+    let content = get(url.to_string()).await?;
+    let signature = get(format!("{}.sig", url)).await?;
+    RemoteConfig::new(content, signature, public_key)
+}
+
+/// 把新的远程默认值合并到用户配置下。
+/// 用户值与上次的默认值相同时视为未修改，使用新默认值；否则保留用户值。数组整体比较
+pub fn merge(base: Option<&Value>, remote: &Value, user: &Value) -> Value {
+    match (remote, user) {
+        (Value::Object(remote), Value::Object(user)) => {
+            let mut merged = user.clone();
+            for (key, value) in remote {
+                let base = base.and_then(|b| b.get(key));
+                let next = match user.get(key) {
+                    None => value.clone(),
+                    Some(user) => merge(base, value, user),
+                };
+                merged.insert(key.clone(), next);
+            }
+            Value::Object(merged)
+        }
+        _ if base == Some(user) => remote.clone(),
+        _ => user.clone(),
+    }
+}
+
+/// 本地缓存的远程配置，没有时使用内置默认值
+pub fn defaults() -> Value {
+    RemoteConfig::load(&ExternalFilePosition::RemoteConfig.path(), PUBLIC_KEY)
+        .and_then(|cached| cached.config().ok())
+        .unwrap_or_else(|| serde_json::to_value(LocalConfig::default()).unwrap_or_default())
+}
+
+/// 后台拉取远程配置，有变化时合并到用户配置并缓存。失败时继续使用本地配置
+pub async fn refresh(app_handle: AppHandle) {
+    match update(&app_handle).await {
+        Ok(true) => {
+            if let Err(e) = app_handle.emit(CONFIG_EVENT, ()) {
+                warn!("{}:{}", line!(), e);
+            }
+        }
+        Ok(false) => {}
+        Err(e) => warn!("{}:{}", line!(), e),
+    }
+}
+
+async fn update(app_handle: &AppHandle) -> Result<bool, ProgramError> {
+    let position = ExternalFilePosition::RemoteConfig.path();
+    let cached = RemoteConfig::load(&position, PUBLIC_KEY);
+    let remote = download(REMOTE_CONFIG, PUBLIC_KEY).await?;
+    if cached.as_ref().is_some_and(|c| c.content == remote.content) {
+        return Ok(false);
+    }
+    let defaults = remote.config()?;
+    let base = match cached {
+        Some(ref cached) => cached.config()?,
+        None => serde_json::to_value(LocalConfig::default())
+            .map_err(|e| ProgramError::ConfigGetError(e.to_string()))?,
+    };
+    let merged = match LocalConfig::read_value(app_handle)? {
+        Some(mut user) => {
+            migration::migrate(&mut user)?;
+            merge(Some(&base), &defaults, &user)
+        }
+        None => defaults,
+    };
+    serde_json::from_value::<LocalConfig>(merged.clone())
+        .map_err(|e| ProgramError::ConfigGetError(e.to_string()))?;
+    backup(&LocalConfig::position()?)?;
+    LocalConfig::write_value(app_handle, merged)?;
+    remote.save(&position)?;
+    info!("已合并远程配置");
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    /// 测试用密钥对生成的公钥与`CONTENT`的签名
+    const TEST_KEY: &str = "dW50cnVzdGVkIGNvbW1lbnQ6IG1pbmlzaWduIHB1YmxpYyBrZXk6IDA4MDcwNjA1MDQwMzAyMDEKUldRQkFnTUVCUVlIQ09wS2JHUGluRklLdnZWUWV4TXV4Zm1WUjNhdXZyNTdra0llNm1rVVJ0SXMK";
+    const SIGNATURE: &str = "dW50cnVzdGVkIGNvbW1lbnQ6IHNpZ25hdHVyZSBmcm9tIHRhdXJpIHNlY3JldCBrZXkKUlVRQkFnTUVCUVlIQ085TEVTL1VHYlQ5T0RXK1hBUEZYNnJhOVpFbG9XR2hsbTdhTVV0ekdxd1ZkUGUwTk8xK3VuUUJ3bThRZXhWWkV5OFBUMjBHMUpXM3pNRzdocmROMGd3PQp0cnVzdGVkIGNvbW1lbnQ6IHRpbWVzdGFtcDoxNzAwMDAwMDAwCWZpbGU6Y29uZmlnLmpzb24KWlNBeWlPY0dnOThhUkwrWlY1ZmsrTXllb2FlNHNRUnc2eGEvMG9WQUROakg0emVFTCsxQ2dnUnBNMmJocitlWXZMUWhOZEp2dEdGQWwwTkE1WHJoQ1E9PQo=";
+    const CONTENT: &str = r#"{"config":{"version":2,"nat_detect":["stun.example.com:3478"],"miniserve_port":8091}}"#;
+
+    #[test]
+    fn verifies_signature() {
+        let remote = RemoteConfig::new(CONTENT.to_string(), SIGNATURE.to_string(), TEST_KEY).unwrap();
+        assert_eq!(remote.config().unwrap()["miniserve_port"], 8091);
+
+        let tampered = CONTENT.replace("8091", "8092");
+        assert!(RemoteConfig::new(tampered, SIGNATURE.to_string(), TEST_KEY).is_err());
+        // 其他密钥签名的配置
+        assert!(RemoteConfig::new(CONTENT.to_string(), SIGNATURE.to_string(), PUBLIC_KEY).is_err());
+    }
+
+    #[test]
+    fn cache_round_trip() {
+        let position = std::env::temp_dir().join(format!("light_n2n_remote_{}.json", std::process::id()));
+        let remote = RemoteConfig::new(CONTENT.to_string(), SIGNATURE.to_string(), TEST_KEY).unwrap();
+        remote.save(&position).unwrap();
+        assert_eq!(RemoteConfig::load(&position, TEST_KEY), Some(remote.clone()));
+
+        // 被修改的缓存不再使用
+        let mut tampered = remote;
+        tampered.content = CONTENT.replace("8091", "8092");
+        tampered.save(&position).unwrap();
+        assert_eq!(RemoteConfig::load(&position, TEST_KEY), None);
+        fs::remove_file(&position).unwrap();
+    }
+
+    #[test]
+    fn merge_keeps_overrides() {
+        let base = json!({"miniserve_port": 8090, "stun_servers": ["a"], "supernode": {"port": 7654, "control_port": 5645}});
+        let remote = json!({"miniserve_port": 8091, "stun_servers": ["b"], "supernode": {"port": 7777, "control_port": 5646}, "stop_timeout_ms": 3000});
+        let user = json!({"miniserve_port": 9000, "stun_servers": ["a"], "supernode": {"port": 7654, "control_port": 6000}, "invite_secret": "s"});
+        let merged = merge(Some(&base), &remote, &user);
+        assert_eq!(
+            merged,
+            json!({
+                "miniserve_port": 9000,
+                "stun_servers": ["b"],
+                "supernode": {"port": 7777, "control_port": 6000},
+                "stop_timeout_ms": 3000,
+                "invite_secret": "s"
+            })
+        );
+        // 没有上次的默认值时只补充缺少的字段
+        let merged = merge(None, &remote, &user);
+        assert_eq!(merged["stun_servers"], json!(["a"]));
+        assert_eq!(merged["stop_timeout_ms"], 3000);
+    }
+}
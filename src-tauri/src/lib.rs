@@ -1,26 +1,32 @@
-use std::collections::HashMap;
-use std::env::current_dir;
-use std::sync::{Arc, Mutex, RwLock};
-
 use flexi_logger::{Cleanup, Criterion, DeferredNow, FileSpec, Logger, Naming};
 use flexi_logger::filter::{LogLineFilter, LogLineWriter};
-use lazy_static::lazy_static;
 use log::{error, Record, warn};
-use tauri::{AppHandle, Manager, Wry};
+use tauri::{AppHandle, Manager};
 use tauri::menu::{MenuBuilder, MenuItemBuilder};
 use tauri::tray::{MouseButton, MouseButtonState, TrayIconEvent};
-use tauri_plugin_store::{StoreCollection, with_store};
+use tauri_plugin_deep_link::DeepLinkExt;
 
-use crate::config::LocalConfig;
-use crate::tools::{child_drop, ChildProcess, ExternalFilePosition, ProgramError};
+use crate::config::remote;
+use crate::config::{config_load_error, LocalConfig};
 use crate::tools::adapter_check::n2n_check_adapter;
+use crate::tools::encryption::{n2n_key_clear, n2n_key_exists, n2n_key_set};
+use crate::tools::invite::{self, n2n_invite_create, n2n_invite_import, n2n_invite_pending};
 use crate::tools::miniserve::{miniserve_firewall_add, miniserve_firewall_check, miniserve_start, miniserve_stop};
 use crate::tools::n2n_client::{
     n2n_client_start, n2n_client_stop, n2n_firewall_add, n2n_firewall_check, n2n_members,
-    n2n_self_ip, n2n_status,
+    n2n_active_supernode, n2n_connection_state, n2n_peer_diagnose, n2n_port_mapping, n2n_profiles, n2n_self_ip,
+    n2n_status,
 };
-use crate::tools::nat_detect::nat_detect;
+use crate::tools::nat_detect::{nat_detect, stun_servers_probe};
+use crate::tools::output::process_output;
 use crate::tools::ping::ping_method;
+use crate::tools::supernode::{
+    supernode_available, supernode_firewall_add, supernode_firewall_check, supernode_host, supernode_status,
+    supernode_stop,
+};
+use crate::tools::supernodes::n2n_supernodes_probe;
+use crate::tools::supervisor::{self, restart_history};
+use crate::tools::process_manager::ProcessManager;
 use crate::tools::ping_detect::{
     ping_firewall_rule_add, ping_firewall_rule_check,
 };
@@ -29,26 +35,13 @@ use crate::tools::win_ip_broadcast::{
 };
 
 mod config;
+mod platform;
 mod tools;
 
-lazy_static! {
-    static ref CHILDS: Mutex<HashMap<&'static str, Arc<RwLock<dyn ChildProcess>>>> =
-        Mutex::new(HashMap::new());
-}
-
 // 无痛退出
 fn safe_exit(app_handle: AppHandle) {
-    let mut to_drop = Vec::new();
-    match CHILDS.lock() {
-        Ok(process) => {
-            for p in process.keys() {
-                to_drop.push(p.to_string())
-            }
-        }
-        Err(_) => {}
-    }
-    for p in to_drop {
-        let _ = child_drop(p.as_str());
+    if let Some(manager) = app_handle.try_state::<ProcessManager>() {
+        manager.stop_all();
     }
     app_handle.exit(0);
 }
@@ -88,71 +81,41 @@ pub fn run() {
         .start()
         .expect("Failed to log");
 
-    // 检查本地是否有配置文件
-    let position = match current_dir() {
-        Ok(position) => position.join(ExternalFilePosition::Config.to_string()),
-        Err(e) => {
-            error!("{}:{}",line!(),ProgramError::GetCurrentDirError(e.to_string()));
-            panic!();
-        }
-    };
-    if !position.exists() {
-        // 从服务器拉取配置
-        warn!("{}",ProgramError::ConfigGetError(String::from("本地没有配置文件")));
-        if let Err(e) = LocalConfig::download_config() {
-            error!("{}:{}",line!(),ProgramError::ConfigGetError(e.to_string()));
-            panic!();
-        }
-    }
-
     tauri::Builder::default()
         .plugin(tauri_plugin_dialog::init())
         .plugin(tauri_plugin_process::init())
-        .plugin(tauri_plugin_single_instance::init(|app, _, _| {
+        .plugin(tauri_plugin_single_instance::init(|app, argv, _| {
             if let Some(webview_window) = app.get_webview_window("main") {
                 let _ = webview_window.show();
                 let _ = webview_window.set_focus();
             }
+            // 点击邀请链接时由新实例转发
+            invite::handle_args(app, &argv);
         }))
+        .plugin(tauri_plugin_deep_link::init())
         .plugin(tauri_plugin_updater::Builder::new().build())
         .plugin(tauri_plugin_store::Builder::new().build())
         .plugin(tauri_plugin_shell::init())
+        .manage(ProcessManager::default())
         // 初始化
         .setup(move |app| {
             // 加载本地配置
             let app_handle = app.app_handle().clone();
-            let collection = app.state::<StoreCollection<Wry>>();
-
-            if let Err(e) = with_store(app_handle.clone(), collection, position.clone(), |store| {
-                match store.load() {
-                    Ok(_) => {
-                        match store.get("config") {
-                            None => {}
-                            Some(v) => {
-                                match serde_json::from_value::<LocalConfig>(v.clone()) {
-                                    Ok(_) => {}
-                                    Err(e) => {
-                                        warn!("{}:{}", line!(), ProgramError::ConfigGetError(e.to_string()));
-                                        // 配置不符合规则，重新拉取
-                                        if let Err(e) = LocalConfig::download_config() {
-                                            error!("{}:{}",line!(),ProgramError::ConfigGetError(e.to_string()));
-                                            panic!();
-                                        } else {
-                                            store.load()?
-                                        }
-                                    }
-                                }
-                            }
-                        }
-                    }
-                    Err(e) => {
-                        Err(e)?
-                    }
-                }
-                Ok(())
-            }) {
-                error!("{}:{}",line!(),ProgramError::ConfigGetError(e.to_string()));
+            if let Err(e) = LocalConfig::prepare(&app_handle) {
+                error!("{}:{}", line!(), e);
+            }
+            // 后台拉取远程配置，合并到本地配置下
+            tauri::async_runtime::spawn(remote::refresh(app_handle.clone()));
+            // 监管子进程，异常退出时按策略重启
+            supervisor::start(app_handle.clone());
+            // 注册邀请链接，安装包已注册时无影响
+            #[cfg(any(windows, target_os = "linux"))]
+            if let Err(e) = app.deep_link().register(invite::SCHEME) {
+                warn!("{}:{}", line!(), e);
             }
+            // 通过邀请链接启动
+            let args: Vec<String> = std::env::args().collect();
+            invite::handle_args(&app_handle, &args);
             // 托盘
             let exit = MenuItemBuilder::with_id("exit", "退出").build(app)?;
             let open = MenuItemBuilder::with_id("open", "显示主界面").build(app)?;
@@ -197,13 +160,25 @@ pub fn run() {
             n2n_client_stop,
             n2n_self_ip,
             n2n_status,
+            n2n_connection_state,
+            n2n_port_mapping,
+            n2n_peer_diagnose,
             n2n_members,
+            n2n_profiles,
+            n2n_key_set,
+            n2n_key_clear,
+            n2n_key_exists,
+            n2n_supernodes_probe,
+            n2n_active_supernode,
+            restart_history,
+            process_output,
             win_ip_broadcast_stop,
             win_ip_broadcast_start,
             win_ip_broadcast_status,
             n2n_check_adapter,
             ping_method,
             nat_detect,
+            stun_servers_probe,
             ping_firewall_rule_check,
             ping_firewall_rule_add,
             n2n_firewall_check,
@@ -211,7 +186,17 @@ pub fn run() {
             miniserve_start,
             miniserve_stop,
             miniserve_firewall_add,
-            miniserve_firewall_check
+            miniserve_firewall_check,
+            supernode_host,
+            supernode_stop,
+            supernode_status,
+            supernode_available,
+            supernode_firewall_check,
+            supernode_firewall_add,
+            n2n_invite_create,
+            n2n_invite_import,
+            n2n_invite_pending,
+            config_load_error
         ])
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
@@ -0,0 +1,27 @@
+use std::path::PathBuf;
+
+#[cfg(target_os = "linux")]
+mod linux;
+#[cfg(target_os = "windows")]
+mod windows;
+
+#[cfg(target_os = "linux")]
+pub use linux::*;
+#[cfg(target_os = "windows")]
+pub use windows::*;
+
+/// 防火墙放行的协议
+pub enum FirewallProtocol {
+    Tcp(u16),
+    Udp(u16),
+    IcmpEcho,
+}
+
+/// 防火墙规则
+pub struct FirewallRule {
+    pub name: &'static str,
+    /// Windows按程序放行
+    pub program: Option<PathBuf>,
+    /// Linux按协议与端口放行
+    pub protocol: FirewallProtocol,
+}
@@ -0,0 +1,263 @@
+use std::fs;
+use std::net::Ipv4Addr;
+use std::path::Path;
+use std::os::unix::process::ExitStatusExt;
+use std::process::{Child, Command, ExitStatus};
+
+use log::{debug, warn};
+
+use crate::platform::{FirewallProtocol, FirewallRule};
+use crate::tools::{command_success, execute_command, ProgramError};
+
+const TUN_DEVICE: &str = "/dev/net/tun";
+const ROUTE_TABLE: &str = "/proc/net/route";
+const NET_CLASS: &str = "/sys/class/net";
+/// edge未指定`-d`时创建的TAP网卡
+pub const DEFAULT_TAP_DEVICE: &str = "edge0";
+/// nftables中放行规则所在的表与链
+const NFT_FAMILY: &str = "inet";
+const NFT_TABLE: &str = "filter";
+const NFT_CHAIN: &str = "input";
+
+/// Linux下无窗口，无需处理
+pub fn hide_window(command: &mut Command) -> &mut Command {
+    command
+}
+
+/// 发送SIGTERM请求子进程退出
+pub fn terminate(child: &Child) -> Result<(), ProgramError> {
+    let pid = libc::pid_t::try_from(child.id()).map_err(|_| ProgramError::TransferError)?;
+    // SAFETY: 仅向自己创建且尚未回收的子进程发送信号
+    if unsafe { libc::kill(pid, libc::SIGTERM) } == 0 {
+        Ok(())
+    } else {
+        Err(ProgramError::ChildProcessError(
+            std::io::Error::last_os_error().to_string(),
+        ))
+    }
+}
+
+/// 结束子进程的信号
+pub fn exit_signal(status: &ExitStatus) -> Option<i32> {
+    status.signal()
+}
+
+/// 检测TUN/TAP支持，edge会通过/dev/net/tun自行创建网卡
+pub fn check_adapter() -> Result<bool, ProgramError> {
+    if Path::new(TUN_DEVICE).exists() {
+        return Ok(true);
+    }
+    warn!("{}不存在", TUN_DEVICE);
+    execute_command("ip", vec!["-d", "link", "show"]).map(|s| s.contains("tun type tap"))
+}
+
+/// 本机的TUN/TAP网卡，sysfs中带有tun_flags
+pub fn tap_devices() -> Vec<String> {
+    match fs::read_dir(NET_CLASS) {
+        Ok(entries) => entries
+            .filter_map(|e| e.ok())
+            .filter(|e| e.path().join("tun_flags").exists())
+            .filter_map(|e| e.file_name().into_string().ok())
+            .collect(),
+        Err(e) => {
+            warn!("{}:{}", line!(), e);
+            Vec::new()
+        }
+    }
+}
+
+/// 防火墙后端
+enum Backend {
+    Iptables,
+    Nftables,
+    None,
+}
+
+fn backend() -> Backend {
+    if command_success("iptables", vec!["--version"]).unwrap_or(false) {
+        Backend::Iptables
+    } else if command_success("nft", vec!["--version"]).unwrap_or(false) {
+        Backend::Nftables
+    } else {
+        Backend::None
+    }
+}
+
+fn iptables_args(action: &str, rule: &FirewallRule) -> Vec<String> {
+    let mut args = vec![action.to_string(), "INPUT".to_string()];
+    match rule.protocol {
+        FirewallProtocol::Tcp(port) => args.extend([
+            "-p".to_string(),
+            "tcp".to_string(),
+            "--dport".to_string(),
+            port.to_string(),
+        ]),
+        FirewallProtocol::Udp(port) => args.extend([
+            "-p".to_string(),
+            "udp".to_string(),
+            "--dport".to_string(),
+            port.to_string(),
+        ]),
+        FirewallProtocol::IcmpEcho => args.extend([
+            "-p".to_string(),
+            "icmp".to_string(),
+            "--icmp-type".to_string(),
+            "echo-request".to_string(),
+        ]),
+    }
+    args.extend([
+        "-m".to_string(),
+        "comment".to_string(),
+        "--comment".to_string(),
+        rule.name.to_string(),
+        "-j".to_string(),
+        "ACCEPT".to_string(),
+    ]);
+    args
+}
+
+/// nftables的匹配条件，与`nft list ruleset`中的写法相同
+fn nft_match(protocol: &FirewallProtocol) -> [String; 3] {
+    match protocol {
+        FirewallProtocol::Tcp(port) => ["tcp".to_string(), "dport".to_string(), port.to_string()],
+        FirewallProtocol::Udp(port) => ["udp".to_string(), "dport".to_string(), port.to_string()],
+        FirewallProtocol::IcmpEcho => [
+            "icmp".to_string(),
+            "type".to_string(),
+            "echo-request".to_string(),
+        ],
+    }
+}
+
+/// 规则集中是否有同名且条件相同的规则，端口改变后旧端口的规则不算
+fn nft_rule_listed(ruleset: &str, rule: &FirewallRule) -> bool {
+    let comment = format!("comment \"{}\"", rule.name);
+    let condition = format!("{} ", nft_match(&rule.protocol).join(" "));
+    ruleset
+        .lines()
+        .any(|line| line.contains(&comment) && line.contains(&condition))
+}
+
+fn nft_args(rule: &FirewallRule) -> Vec<String> {
+    let mut args = vec![
+        "add".to_string(),
+        "rule".to_string(),
+        NFT_FAMILY.to_string(),
+        NFT_TABLE.to_string(),
+        NFT_CHAIN.to_string(),
+    ];
+    args.extend(nft_match(&rule.protocol));
+    args.extend([
+        "accept".to_string(),
+        "comment".to_string(),
+        format!("\"{}\"", rule.name),
+    ]);
+    args
+}
+
+/// 查询防火墙规则是否存在
+pub fn firewall_rule_exists(rule: &FirewallRule) -> Result<bool, ProgramError> {
+    match backend() {
+        Backend::Iptables => {
+            let args = iptables_args("-C", rule);
+            command_success("iptables", args.iter().map(|s| s.as_str()).collect())
+        }
+        Backend::Nftables => {
+            let output_str = execute_command("nft", vec!["list", "ruleset"])?;
+            debug!("{}", output_str);
+            Ok(nft_rule_listed(&output_str, rule))
+        }
+        // 没有防火墙，不会拦截
+        Backend::None => Ok(true),
+    }
+}
+
+/// 默认的nftables配置中没有`inet filter`表，缺少时创建表与挂在input上的链
+fn nft_ensure_chain() -> Result<(), ProgramError> {
+    if command_success("nft", vec!["list", "chain", NFT_FAMILY, NFT_TABLE, NFT_CHAIN])? {
+        return Ok(());
+    }
+    debug!("创建nftables链{} {} {}", NFT_FAMILY, NFT_TABLE, NFT_CHAIN);
+    // add对已存在的表不报错
+    let table = command_success("nft", vec!["add", "table", NFT_FAMILY, NFT_TABLE])?;
+    // 策略为accept，只用于放行，不改变其他流量
+    let chain = command_success(
+        "nft",
+        vec![
+            "add",
+            "chain",
+            NFT_FAMILY,
+            NFT_TABLE,
+            NFT_CHAIN,
+            "{ type filter hook input priority 0 ; policy accept ; }",
+        ],
+    )?;
+    if table && chain {
+        Ok(())
+    } else {
+        Err(ProgramError::FireWallError)
+    }
+}
+
+/// 添加入站放行规则
+pub fn firewall_rule_add(rule: &FirewallRule) -> Result<(), ProgramError> {
+    let (command, args) = match backend() {
+        Backend::Iptables => ("iptables", iptables_args("-I", rule)),
+        Backend::Nftables => {
+            nft_ensure_chain()?;
+            ("nft", nft_args(rule))
+        }
+        Backend::None => return Ok(()),
+    };
+    if command_success(command, args.iter().map(|s| s.as_str()).collect())? {
+        Ok(())
+    } else {
+        Err(ProgramError::FireWallError)
+    }
+}
+
+/// 默认路由的网关，按路由表中的跃点数选择
+pub fn default_gateway() -> Result<Ipv4Addr, ProgramError> {
+    let table = fs::read_to_string(ROUTE_TABLE).map_err(|e| ProgramError::NetworkError(e.to_string()))?;
+    table
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            // Iface Destination Gateway Flags RefCnt Use Metric
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() < 7 || fields[1] != "00000000" {
+                return None;
+            }
+            // 以本机字节序输出的网络序地址
+            let gateway = u32::from_str_radix(fields[2], 16).ok().filter(|g| *g != 0)?;
+            let metric: u32 = fields[6].parse().ok()?;
+            Some((metric, Ipv4Addr::from(gateway.to_ne_bytes())))
+        })
+        .min_by_key(|(metric, _)| *metric)
+        .map(|(_, gateway)| gateway)
+        .ok_or(ProgramError::NetworkError("没有默认路由".to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nft_rule_port() {
+        let ruleset = "table inet filter {\n\tchain input {\n\t\tudp dport 7654 accept comment \"LightN2N_Allow_Supernode\"\n\t}\n}\n";
+        let rule = |port| FirewallRule {
+            name: "LightN2N_Allow_Supernode",
+            program: None,
+            protocol: FirewallProtocol::Udp(port),
+        };
+        assert!(nft_rule_listed(ruleset, &rule(7654)));
+        // 端口改变后需要重新添加
+        assert!(!nft_rule_listed(ruleset, &rule(7655)));
+        assert!(!nft_rule_listed(ruleset, &rule(765)));
+        let tcp = FirewallRule {
+            protocol: FirewallProtocol::Tcp(7654),
+            ..rule(7654)
+        };
+        assert!(!nft_rule_listed(ruleset, &tcp));
+    }
+}
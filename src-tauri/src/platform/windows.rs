@@ -0,0 +1,134 @@
+use std::net::Ipv4Addr;
+use std::os::windows::process::CommandExt;
+use std::process::{Child, Command, ExitStatus};
+
+use log::{debug, warn};
+
+use crate::platform::{FirewallProtocol, FirewallRule};
+use crate::tools::{command_success, execute_command, ProgramError};
+
+const CREATE_NO_WINDOW: u32 = 0x08000000;
+const ADAPTER_NAME: &str = "TAP-Windows Adapter";
+/// edge未指定`-d`时使用找到的第一个TAP网卡，没有固定名称，只用于比较
+pub const DEFAULT_TAP_DEVICE: &str = "";
+
+/// 隐藏子进程窗口
+pub fn hide_window(command: &mut Command) -> &mut Command {
+    command.creation_flags(CREATE_NO_WINDOW)
+}
+
+/// 不带/F调用taskkill，请求子进程退出
+pub fn terminate(child: &Child) -> Result<(), ProgramError> {
+    let pid = child.id().to_string();
+    match command_success("taskkill", vec!["/PID", pid.as_str()])? {
+        true => Ok(()),
+        false => Err(ProgramError::ChildProcessError(format!("taskkill {}失败", pid))),
+    }
+}
+
+/// Windows没有信号
+pub fn exit_signal(_status: &ExitStatus) -> Option<i32> {
+    None
+}
+
+/// 检测是否安装虚拟网卡
+pub fn check_adapter() -> Result<bool, ProgramError> {
+    match execute_command("wmic", vec!["nic", "list", "brief"]) {
+        Ok(s) => Ok(s.contains(ADAPTER_NAME)),
+        Err(e) => {
+            warn!("{}", e);
+            execute_command("ipconfig", vec!["/all"]).map(|s| s.contains(ADAPTER_NAME))
+        }
+    }
+}
+
+/// 本机的TAP网卡的连接名称
+pub fn tap_devices() -> Vec<String> {
+    let filter = format!("Description like '{}%'", ADAPTER_NAME);
+    match execute_command("wmic", vec!["nic", "where", filter.as_str(), "get", "NetConnectionID"]) {
+        Ok(s) => s
+            .lines()
+            .skip(1)
+            .map(str::trim)
+            .filter(|l| !l.is_empty())
+            .map(str::to_string)
+            .collect(),
+        Err(e) => {
+            warn!("{}", e);
+            Vec::new()
+        }
+    }
+}
+
+/// 查询防火墙规则是否存在
+pub fn firewall_rule_exists(rule: &FirewallRule) -> Result<bool, ProgramError> {
+    let output_str = execute_command(
+        "netsh",
+        vec![
+            "advfirewall",
+            "firewall",
+            "show",
+            "rule",
+            format!("name={}", rule.name).as_str(),
+        ],
+    )?;
+    debug!("{}", output_str);
+    Ok(!(output_str.contains("No rules match the specified criteria")
+        || output_str.contains("没有与指定标准相匹配的规则")))
+}
+
+/// 添加入站放行规则
+pub fn firewall_rule_add(rule: &FirewallRule) -> Result<(), ProgramError> {
+    let name = format!("name={}", rule.name);
+    let mut args = vec![
+        "advfirewall".to_string(),
+        "firewall".to_string(),
+        "add".to_string(),
+        "rule".to_string(),
+        name,
+    ];
+    match (&rule.program, &rule.protocol) {
+        (_, FirewallProtocol::IcmpEcho) => {
+            args.push("protocol=icmpv4:8,any".to_string());
+        }
+        (Some(program), _) => {
+            let path = program.to_str().ok_or(ProgramError::FireWallError)?;
+            args.push(format!("program={}", path));
+            args.push("enable=yes".to_string());
+        }
+        (None, FirewallProtocol::Tcp(port)) => {
+            args.push("protocol=TCP".to_string());
+            args.push(format!("localport={}", port));
+        }
+        (None, FirewallProtocol::Udp(port)) => {
+            args.push("protocol=UDP".to_string());
+            args.push(format!("localport={}", port));
+        }
+    }
+    args.push("dir=in".to_string());
+    args.push("action=allow".to_string());
+    let output_str = execute_command("netsh", args.iter().map(|s| s.as_str()).collect())?;
+    debug!("{}", output_str);
+    Ok(())
+}
+
+/// 默认路由的网关，按跃点数选择
+pub fn default_gateway() -> Result<Ipv4Addr, ProgramError> {
+    let output_str = execute_command("route", vec!["print", "-4", "0.0.0.0"])?;
+    debug!("{}", output_str);
+    output_str
+        .lines()
+        .filter_map(|line| {
+            // 网络目标 网络掩码 网关 接口 跃点数，直连路由的网关不是地址
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() < 5 || fields[0] != "0.0.0.0" || fields[1] != "0.0.0.0" {
+                return None;
+            }
+            let gateway: Ipv4Addr = fields[2].parse().ok()?;
+            let metric: u32 = fields[4].parse().ok()?;
+            Some((metric, gateway))
+        })
+        .min_by_key(|(metric, _)| *metric)
+        .map(|(_, gateway)| gateway)
+        .ok_or(ProgramError::NetworkError("没有默认路由".to_string()))
+}
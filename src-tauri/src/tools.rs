@@ -1,61 +1,105 @@
-use std::any::Any;
 use std::fmt::{Display, Formatter};
-use std::io::{BufRead, BufReader};
-use std::os::windows::process::CommandExt;
+use std::env::consts::EXE_SUFFIX;
 use std::path::PathBuf;
-use std::process::{Child, Command, Stdio};
-use std::thread;
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::Arc;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
 
 use chardet::detect;
 use encoding_rs::Encoding;
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
 use thiserror::Error;
 
-use crate::CHILDS;
+use crate::platform;
+use crate::tools::output::{ExitInfo, LineHook, OutputLog, Stream};
+use crate::tools::supervisor::RestartState;
 
 pub mod adapter_check;
+pub mod address;
+pub mod edge_log;
+pub mod encryption;
+pub mod invite;
+pub mod member_server;
 pub mod miniserve;
 pub mod n2n_client;
-pub mod n2n_controller;
+pub mod n2n_events;
 pub mod nat_detect;
+pub mod output;
+pub mod peer_diagnostics;
 pub mod ping;
 pub mod ping_detect;
+pub mod port_mapping;
+pub mod process_manager;
+pub mod supernode;
+pub mod supernodes;
+pub mod supervisor;
 pub mod win_ip_broadcast;
 
+#[cfg(test)]
+pub use n2n_management::mock_edge;
+pub use n2n_management::controller as n2n_controller;
+
+/// 默认的停止等待时间
+pub const DEFAULT_STOP_TIMEOUT: Duration = Duration::from_secs(3);
+
 /// 外部文件位置
 pub enum ExternalFilePosition {
     N2NClient,
     WinIPBroadcast,
     Config,
+    /// 缓存的远程配置
+    RemoteConfig,
+    /// 上次可用的STUN服务器
+    StunCache,
     MiniServe,
+    Supernode,
 }
 
-impl Display for ExternalFilePosition {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        let prefix: &str = "client";
+impl ExternalFilePosition {
+    /// 相对于程序目录的路径，可执行文件按平台添加后缀
+    pub fn path(&self) -> PathBuf {
+        let prefix = PathBuf::from("client");
         match self {
             ExternalFilePosition::N2NClient => {
-                write!(f, "{}\\x64\\edge.exe", prefix)
+                prefix.join("x64").join(format!("edge{}", EXE_SUFFIX))
             }
             ExternalFilePosition::WinIPBroadcast => {
-                write!(f, "{}\\x64\\WinIPBroadcast.exe", prefix)
-            }
-            ExternalFilePosition::Config => {
-                write!(f, "{}\\config.json", prefix)
+                prefix.join("x64").join(format!("WinIPBroadcast{}", EXE_SUFFIX))
             }
+            ExternalFilePosition::Config => prefix.join("config.json"),
+            ExternalFilePosition::RemoteConfig => prefix.join("remote_config.json"),
+            ExternalFilePosition::StunCache => prefix.join("stun_servers.json"),
             ExternalFilePosition::MiniServe => {
-                write!(f, "{}\\x64\\miniserve.exe", prefix)
+                prefix.join("x64").join(format!("miniserve{}", EXE_SUFFIX))
+            }
+            ExternalFilePosition::Supernode => {
+                prefix.join("x64").join(format!("supernode{}", EXE_SUFFIX))
             }
         }
     }
 }
 
+impl Display for ExternalFilePosition {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.path().display())
+    }
+}
+
 /// 外部二进制程序
 pub struct ExternalBinaryProgram {
     name: String,
     process: Option<Child>,
     path: PathBuf,
     args: Vec<String>,
+    envs: Vec<(String, String)>,
+    restart: RestartState,
+    /// 停止时等待子进程自行退出的时间
+    stop_timeout: Duration,
+    output: Arc<OutputLog>,
+    /// 本次运行的退出是否已记录
+    exit_recorded: bool,
+    line_hook: Option<LineHook>,
 }
 
 impl Drop for ExternalBinaryProgram {
@@ -71,22 +115,106 @@ impl ExternalBinaryProgram {
             process: None,
             path,
             args: command_args,
+            envs: Vec::new(),
+            restart: RestartState::default(),
+            stop_timeout: DEFAULT_STOP_TIMEOUT,
+            output: output::output_log(name),
+            exit_recorded: false,
+            line_hook: None,
         })
     }
 
+    /// 设置子进程的环境变量，用于传递不应出现在命令行中的参数
+    pub fn set_env(&mut self, key: &str, value: String) {
+        self.envs.push((key.to_string(), value));
+    }
+
+    /// 设置处理子进程每行输出的回调，需在start前设置
+    pub fn set_line_hook(&mut self, hook: LineHook) {
+        self.line_hook = Some(hook);
+    }
+
+    /// 最近一次退出情况
+    pub fn last_exit(&self) -> Option<ExitInfo> {
+        self.output.exit()
+    }
+
+    pub fn set_stop_timeout(&mut self, timeout: Duration) {
+        self.stop_timeout = timeout;
+    }
+
+    pub fn stop_timeout(&self) -> Duration {
+        self.stop_timeout
+    }
+
+    /// 等待子进程退出，超时返回false
+    pub fn wait_exit(&mut self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if !self.status() {
+                return true;
+            }
+            if Instant::now() >= deadline {
+                return false;
+            }
+            sleep(Duration::from_millis(50));
+        }
+    }
+
+    /// 请求子进程退出，超时后强制结束，并回收子进程
     pub fn stop(&mut self) -> Result<(), ProgramError> {
-        match self.process {
+        self.cancel_restart();
+        if self.status() {
+            if let Some(ref p) = self.process {
+                if let Err(e) = platform::terminate(p) {
+                    warn!("{}:{}", line!(), e);
+                }
+            }
+            if !self.wait_exit(self.stop_timeout) {
+                warn!("{}未在{:?}内退出，强制结束", self.name, self.stop_timeout);
+                if let Some(ref mut p) = self.process {
+                    p.kill()
+                        .map_err(|e| ProgramError::ChildProcessError(e.to_string()))?;
+                }
+            }
+        }
+        match self.process.take() {
             None => Ok(()),
-            Some(ref mut p) => p
-                .kill()
-                .map_err(|e| ProgramError::ChildProcessError(e.to_string())),
+            Some(mut p) => match p.wait() {
+                Ok(status) => {
+                    self.record_exit(&status);
+                    Ok(())
+                }
+                Err(e) => Err(ProgramError::ChildProcessError(e.to_string())),
+            },
         }
     }
 
+    /// 记录本次运行的退出情况，只记录一次
+    fn record_exit(&mut self, status: &ExitStatus) {
+        if !self.exit_recorded {
+            self.exit_recorded = true;
+            info!("{}退出:{}", self.name, status);
+            self.output.set_exit(ExitInfo::from(status));
+        }
+    }
+
+    /// 查询子进程是否已退出，未启动时返回None
+    fn poll_exit(&mut self) -> std::io::Result<Option<ExitStatus>> {
+        let status = match self.process {
+            None => return Ok(None),
+            Some(ref mut p) => p.try_wait()?,
+        };
+        if let Some(ref status) = status {
+            self.record_exit(status);
+        }
+        Ok(status)
+    }
+
     pub fn status(&mut self) -> bool {
         match self.process {
             None => false,
-            Some(ref mut p) => match p.try_wait() {
+            Some(_) => match self.poll_exit() {
                 Ok(o) => match o {
                     None => true,
                     Some(_) => false,
@@ -105,23 +233,36 @@ impl ExternalBinaryProgram {
             Err(ProgramError::CreateTwice(self.name.to_string()))
         } else {
             debug!("启动子进程：{:?}\n参数:{:?}", self.path, self.args);
-            match Command::new(self.path.clone())
+            let mut command = Command::new(self.path.clone());
+            command
                 .args(self.args.clone())
+                .envs(self.envs.clone())
                 .stdout(Stdio::piped())
-                .creation_flags(0x08000000)
-                .spawn()
-            {
+                .stderr(Stdio::piped());
+            match platform::hide_window(&mut command).spawn() {
                 Ok(mut child) => {
                     // 创建线程输出日志
-                    let stdout = child.stdout.take().unwrap();
-                    let name = self.name.clone();
-                    thread::spawn(move || {
-                        BufReader::new(stdout)
-                            .lines()
-                            .filter_map(|line| line.ok())
-                            .for_each(|line| info!("{}:{}", name, line))
-                    });
+                    if let Some(stdout) = child.stdout.take() {
+                        output::forward(
+                            self.name.clone(),
+                            stdout,
+                            Stream::Stdout,
+                            self.output.clone(),
+                            self.line_hook.clone(),
+                        );
+                    }
+                    if let Some(stderr) = child.stderr.take() {
+                        output::forward(
+                            self.name.clone(),
+                            stderr,
+                            Stream::Stderr,
+                            self.output.clone(),
+                            self.line_hook.clone(),
+                        );
+                    }
                     self.process = Some(child);
+                    self.exit_recorded = false;
+                    self.mark_started();
                     Ok(())
                 }
                 Err(e) => Err(ProgramError::ChildProcessError(e.to_string())),
@@ -132,11 +273,9 @@ impl ExternalBinaryProgram {
 
 /// 隐藏执行命令，获取输出
 pub fn execute_command(command: &str, args: Vec<&str>) -> Result<String, ProgramError> {
-    let output = match Command::new(command)
-        .args(args)
-        .creation_flags(0x08000000)
-        .output()
-    {
+    let mut command = Command::new(command);
+    command.args(args);
+    let output = match platform::hide_window(&mut command).output() {
         Ok(s) => s,
         Err(e) => {
             return Err(ProgramError::CommandRunningError(e.to_string()));
@@ -150,66 +289,23 @@ pub fn execute_command(command: &str, args: Vec<&str>) -> Result<String, Program
     Ok(decoded_str.into_owned())
 }
 
+/// 隐藏执行命令，仅获取是否执行成功
+pub fn command_success(command: &str, args: Vec<&str>) -> Result<bool, ProgramError> {
+    let mut command = Command::new(command);
+    command.args(args).stdout(Stdio::null()).stderr(Stdio::null());
+    match platform::hide_window(&mut command).status() {
+        Ok(s) => Ok(s.success()),
+        Err(e) => Err(ProgramError::CommandRunningError(e.to_string())),
+    }
+}
+
 /// 子进程
 pub trait ChildProcess: Sync + Send {
     fn get_process(&mut self) -> &mut ExternalBinaryProgram;
 
-    fn as_any(&mut self) -> &mut dyn Any;
-}
-
-/// 查询子进程运行状况
-pub fn child_status(name: &str) -> bool {
-    match CHILDS.lock() {
-        Ok(map) => {
-            match map.get(name) {
-                // 未启动
-                None => {
-                    false;
-                }
-                // 已启动
-                Some(s) => {
-                    // 是否退出
-                    match s.write() {
-                        Ok(mut s) => {
-                            return s.get_process().status();
-                        }
-                        Err(e) => {
-                            let error = ProgramError::ChildProcessError(e.to_string()).to_string();
-                            error!("{}:{}", line!(), error);
-                        }
-                    }
-                }
-            };
-        }
-        Err(e) => {
-            let error = ProgramError::ChildProcessError(e.to_string()).to_string();
-            error!("{}:{}", line!(), error);
-        }
-    }
-    false
-}
-
-pub fn child_drop(name: &str) -> Result<bool, String> {
-    match CHILDS.lock() {
-        Ok(mut map) => match map.remove(name) {
-            None => Ok(true),
-            Some(e) => match e.write() {
-                Ok(s) => {
-                    drop(s);
-                    Ok(true)
-                }
-                Err(e) => {
-                    let error = ProgramError::ChildProcessError(e.to_string()).to_string();
-                    error!("{}:{}", line!(), error);
-                    Err(error)
-                }
-            },
-        },
-        Err(e) => {
-            let error = ProgramError::ChildProcessError(e.to_string()).to_string();
-            error!("{}:{}", line!(), error);
-            Err(error)
-        }
+    /// 停止子进程，可先通过其他方式通知子进程退出
+    fn shutdown(&mut self) -> Result<(), ProgramError> {
+        self.get_process().stop()
     }
 }
 
@@ -223,8 +319,6 @@ pub enum ProgramError {
     CreateTwice(String),
     #[error("获取当前路径错误:{0}")]
     GetCurrentDirError(String),
-    #[error("Downcast错误")]
-    DowncastError,
     #[error("转换错误")]
     TransferError,
     #[error("未能获取到参数:{0}")]
@@ -235,10 +329,99 @@ pub enum ProgramError {
     CommandRunningError(String),
     #[error("获取配置文件失败:{0}")]
     ConfigGetError(String),
+    #[error("配置文件签名验证失败:{0}")]
+    ConfigVerifyError(String),
     #[error("文件读写失败:{0}")]
     FileRWError(String),
     #[error("网络错误:{0}")]
     NetworkError(String),
+    #[error("管理端口错误:{0}")]
+    ManagementError(String),
+    #[error("未找到网络配置:{0}")]
+    ProfileNotFound(String),
+    #[error("与正在运行的网络冲突:{0}")]
+    ProfileConflict(String),
+    #[error("地址设置错误:{0}")]
+    AddressError(String),
+    #[error("端口设置错误:{0}")]
+    PortError(String),
+    #[error("加密设置错误:{0}")]
+    EncryptionError(String),
+    #[error("邀请码错误:{0}")]
+    InviteError(String),
     #[error("防火墙错误")]
     FireWallError,
+    #[error("当前平台不支持")]
+    UnsupportedPlatform,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn program(name: &str, script: &str) -> ExternalBinaryProgram {
+        let mut program = ExternalBinaryProgram::new(
+            name,
+            PathBuf::from("sh"),
+            vec!["-c".to_string(), script.to_string()],
+        )
+        .unwrap();
+        program.set_stop_timeout(Duration::from_millis(300));
+        program
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn stop_terminates() {
+        let mut program = program("stop_terminates", "sleep 30");
+        program.start().unwrap();
+        let begin = Instant::now();
+        program.stop().unwrap();
+        assert!(begin.elapsed() < Duration::from_millis(300));
+        assert!(!program.status());
+        assert!(program.process.is_none());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn stop_kills_after_timeout() {
+        let mut program = program("stop_kills", "trap '' TERM; while :; do :; done");
+        program.start().unwrap();
+        // 等待trap生效
+        sleep(Duration::from_millis(100));
+        let begin = Instant::now();
+        program.stop().unwrap();
+        assert!(begin.elapsed() >= Duration::from_millis(300));
+        assert!(program.process.is_none());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn captures_output_and_exit() {
+        let mut program = program("captures_output", "echo out; echo err >&2; exit 3");
+        program.start().unwrap();
+        assert!(program.wait_exit(Duration::from_secs(3)));
+        sleep(Duration::from_millis(100));
+        let output = program.output.snapshot("captures_output", 10);
+        assert_eq!(output.exit.map(|e| e.code), Some(Some(3)));
+        let mut lines: Vec<_> = output.lines.into_iter().map(|l| (l.stream, l.line)).collect();
+        lines.sort_by_key(|(_, l)| l.clone());
+        assert_eq!(
+            lines,
+            vec![
+                (Stream::Stderr, "err".to_string()),
+                (Stream::Stdout, "out".to_string())
+            ]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn records_signal() {
+        let mut program = program("records_signal", "sleep 30");
+        program.start().unwrap();
+        program.stop().unwrap();
+        let output = program.output.snapshot("records_signal", 10);
+        assert_eq!(output.exit.and_then(|e| e.signal), Some(libc::SIGTERM));
+    }
 }
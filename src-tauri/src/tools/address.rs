@@ -0,0 +1,275 @@
+use std::fmt::{Display, Formatter};
+use std::net::Ipv4Addr;
+use std::str::FromStr;
+
+use if_addrs::IfAddr;
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::tools::n2n_client::N2NClientConfig;
+use crate::tools::ProgramError;
+
+/// 未写前缀时n2n使用的默认前缀
+const DEFAULT_PREFIX: u8 = 24;
+const MIN_MTU: u16 = 576;
+const MAX_MTU: u16 = 1500;
+
+/// 虚拟ip的分配方式
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(tag = "mode", content = "cidr", rename_all = "snake_case")]
+pub enum AddressMode {
+    /// 由超级节点分配
+    #[default]
+    Supernode,
+    /// 固定地址，如`10.0.0.5/24`
+    Static(String),
+    /// 由组内的DHCP服务分配
+    Dhcp,
+}
+
+/// IPv4网段
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Ipv4Cidr {
+    pub addr: Ipv4Addr,
+    pub prefix: u8,
+}
+
+impl FromStr for Ipv4Cidr {
+    type Err = ProgramError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || ProgramError::AddressError(format!("无效的地址:{}", s));
+        let (addr, prefix) = match s.trim().split_once('/') {
+            None => (s.trim(), DEFAULT_PREFIX),
+            Some((addr, prefix)) => (addr, prefix.parse::<u8>().map_err(|_| invalid())?),
+        };
+        if prefix > 32 {
+            return Err(invalid());
+        }
+        Ok(Self {
+            addr: addr.parse().map_err(|_| invalid())?,
+            prefix,
+        })
+    }
+}
+
+impl Display for Ipv4Cidr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+impl Ipv4Cidr {
+    fn mask(&self) -> u32 {
+        u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0)
+    }
+
+    fn network(&self) -> u32 {
+        u32::from(self.addr) & self.mask()
+    }
+
+    /// 地址是否位于网段内
+    pub fn contains(&self, addr: Ipv4Addr) -> bool {
+        u32::from(addr) & self.mask() == self.network()
+    }
+
+    /// 两个网段是否有重叠
+    pub fn overlaps(&self, other: &Ipv4Cidr) -> bool {
+        let prefix = self.prefix.min(other.prefix);
+        let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+        u32::from(self.addr) & mask == u32::from(other.addr) & mask
+    }
+
+    /// 是否为网络号或广播地址
+    fn is_reserved(&self) -> bool {
+        if self.prefix >= 31 {
+            return false;
+        }
+        let host = u32::from(self.addr) & !self.mask();
+        host == 0 || host == !self.mask()
+    }
+}
+
+/// 本机网卡的IPv4网段，不含回环网卡
+pub fn local_networks() -> Vec<(String, Ipv4Cidr)> {
+    match if_addrs::get_if_addrs() {
+        Ok(interfaces) => interfaces
+            .into_iter()
+            .filter(|i| !i.is_loopback())
+            .filter_map(|i| match i.addr {
+                IfAddr::V4(v4) => Some((
+                    i.name,
+                    Ipv4Cidr {
+                        addr: v4.ip,
+                        prefix: v4.prefixlen,
+                    },
+                )),
+                IfAddr::V6(_) => None,
+            })
+            .collect(),
+        Err(e) => {
+            warn!("{}:{}", line!(), e);
+            Vec::new()
+        }
+    }
+}
+
+/// 检查固定地址：位于组网段内，且不与本机其他网卡冲突。
+/// `taps`中的虚拟网卡不参与检查，它们可能是上次运行留下的，或是同一组网段中的其他配置
+pub fn check_static(
+    cidr: &Ipv4Cidr,
+    subnet: Option<&Ipv4Cidr>,
+    locals: &[(String, Ipv4Cidr)],
+    taps: &[String],
+) -> Result<(), ProgramError> {
+    if cidr.is_reserved() {
+        return Err(ProgramError::AddressError(format!("{}是网络号或广播地址", cidr)));
+    }
+    if let Some(subnet) = subnet {
+        if !subnet.contains(cidr.addr) || cidr.prefix < subnet.prefix {
+            return Err(ProgramError::AddressError(format!("{}不在组网段{}内", cidr, subnet)));
+        }
+    }
+    for (name, local) in locals {
+        if taps.contains(name) {
+            continue;
+        }
+        if local.overlaps(cidr) {
+            return Err(ProgramError::AddressError(format!(
+                "{}与本机网卡{}的{}冲突",
+                cidr, name, local
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// 检查MAC地址格式，必须为单播地址
+fn check_mac(mac: &str) -> Result<(), ProgramError> {
+    let invalid = || ProgramError::AddressError(format!("无效的MAC地址:{}", mac));
+    let octets = mac
+        .split(':')
+        .map(|o| {
+            if o.len() == 2 {
+                u8::from_str_radix(o, 16).map_err(|_| invalid())
+            } else {
+                Err(invalid())
+            }
+        })
+        .collect::<Result<Vec<u8>, ProgramError>>()?;
+    if octets.len() != 6 || octets[0] & 1 == 1 {
+        return Err(invalid());
+    }
+    Ok(())
+}
+
+/// 检查地址相关配置并生成edge参数，`taps`为本机的虚拟网卡
+pub fn edge_args(
+    config: &N2NClientConfig,
+    locals: &[(String, Ipv4Cidr)],
+    taps: &[String],
+) -> Result<Vec<String>, ProgramError> {
+    let mut args = Vec::new();
+    match config.address {
+        AddressMode::Supernode => {}
+        AddressMode::Static(ref cidr) => {
+            let cidr = cidr.parse::<Ipv4Cidr>()?;
+            let subnet = match config.subnet {
+                None => None,
+                Some(ref s) => Some(s.parse::<Ipv4Cidr>()?),
+            };
+            check_static(&cidr, subnet.as_ref(), locals, taps)?;
+            args.push("-a".to_string());
+            args.push(format!("static:{}", cidr));
+        }
+        AddressMode::Dhcp => {
+            args.push("-a".to_string());
+            args.push("dhcp:0.0.0.0".to_string());
+            args.push("-r".to_string());
+        }
+    }
+    if let Some(mtu) = config.mtu {
+        if !(MIN_MTU..=MAX_MTU).contains(&mtu) {
+            return Err(ProgramError::AddressError(format!(
+                "MTU需在{}到{}之间",
+                MIN_MTU, MAX_MTU
+            )));
+        }
+        args.push("-M".to_string());
+        args.push(mtu.to_string());
+    }
+    if let Some(ref device) = config.tap_device {
+        if device.is_empty() || device.chars().any(|c| c.is_whitespace()) {
+            return Err(ProgramError::AddressError(format!("无效的网卡名称:{}", device)));
+        }
+        args.push("-d".to_string());
+        args.push(device.clone());
+    }
+    if let Some(ref mac) = config.mac {
+        check_mac(mac)?;
+        args.push("-m".to_string());
+        args.push(mac.clone());
+    }
+    Ok(args)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cidr(s: &str) -> Ipv4Cidr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(cidr("10.0.0.5").prefix, 24);
+        assert_eq!(cidr("10.0.0.5/16").to_string(), "10.0.0.5/16");
+        assert!("10.0.0.5/33".parse::<Ipv4Cidr>().is_err());
+        assert!("10.0.0/24".parse::<Ipv4Cidr>().is_err());
+    }
+
+    #[test]
+    fn static_address() {
+        let subnet = cidr("10.0.0.0/16");
+        let locals = vec![
+            ("eth0".to_string(), cidr("192.168.1.10/24")),
+            ("edge0".to_string(), cidr("10.0.0.5/24")),
+        ];
+        let taps = vec!["edge0".to_string()];
+        let check = |s: &str, taps: &[String]| check_static(&cidr(s), Some(&subnet), &locals, taps);
+
+        assert!(check("10.0.0.5/24", &taps).is_ok());
+        // 同一组网段中其他配置的虚拟网卡
+        assert!(check("10.0.0.7/24", &taps).is_ok());
+        assert!(check("10.0.0.5/24", &[]).is_err());
+        assert!(check("10.1.0.5/24", &[]).is_err());
+        assert!(check("10.0.3.0/24", &taps).is_err());
+        assert!(check("10.0.3.4/8", &taps).is_err());
+        assert!(check_static(&cidr("192.168.1.20/24"), None, &locals, &taps).is_err());
+    }
+
+    #[test]
+    fn args() {
+        let mut config = N2NClientConfig::default();
+        assert!(edge_args(&config, &[], &[]).unwrap().is_empty());
+
+        config.address = AddressMode::Static("10.0.0.5".to_string());
+        config.mtu = Some(1290);
+        config.mac = Some("02:00:00:00:00:05".to_string());
+        assert_eq!(
+            edge_args(&config, &[], &[]).unwrap(),
+            vec!["-a", "static:10.0.0.5/24", "-M", "1290", "-m", "02:00:00:00:00:05"]
+        );
+
+        config.mac = Some("01:00:00:00:00:05".to_string());
+        assert!(edge_args(&config, &[], &[]).is_err());
+        config.mac = None;
+        config.mtu = Some(9000);
+        assert!(edge_args(&config, &[], &[]).is_err());
+
+        config.mtu = None;
+        config.address = AddressMode::Dhcp;
+        assert_eq!(edge_args(&config, &[], &[]).unwrap(), vec!["-a", "dhcp:0.0.0.0", "-r"]);
+    }
+}
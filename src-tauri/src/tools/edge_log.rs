@@ -0,0 +1,205 @@
+use serde::Serialize;
+
+/// 从edge日志中识别出的事件
+#[derive(Serialize, Clone, Debug, PartialEq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum EdgeEvent {
+    /// 已在超级节点注册
+    SupernodeRegistered,
+    /// 超级节点无响应，正在切换
+    SupernodeUnreachable,
+    /// 超级节点拒绝注册(REGISTER_SUPER_NAK)
+    AuthFailed { reason: String },
+    /// 无法打开TAP网卡
+    TapOpenFailed { reason: String },
+    /// 虚拟网卡已创建并设置地址
+    AddressAssigned { ip: String, mask: String, mac: String },
+    /// 与成员建立点对点连接
+    P2pEstablished { macaddr: String, sockaddr: String },
+}
+
+/// 去掉时间、源码位置与日志级别，如`17/Oct/2026 10:00:00 [edge.c:1] WARNING: msg`
+fn message(line: &str) -> &str {
+    let message = match line.find("] ") {
+        Some(i) if line[..i].contains(".c:") => &line[i + 2..],
+        _ => line,
+    };
+    ["ERROR: ", "WARNING: ", "NORMAL: ", "INFO: ", "DEBUG: "]
+        .iter()
+        .find_map(|level| message.strip_prefix(level))
+        .unwrap_or(message)
+        .trim()
+}
+
+/// 取出`key: value,`形式的值
+fn field<'a>(message: &'a str, key: &str) -> Option<&'a str> {
+    let start = message.find(key)? + key.len();
+    message[start..].split(',').next().map(|s| s.trim())
+}
+
+/// 解析一行edge输出
+pub fn parse(line: &str) -> Option<EdgeEvent> {
+    let message = message(line);
+    let lower = message.to_lowercase();
+    if message.contains("[OK] edge <<< ") {
+        return Some(EdgeEvent::SupernodeRegistered);
+    }
+    if lower.contains("supernode not responding") {
+        return Some(EdgeEvent::SupernodeUnreachable);
+    }
+    if lower.contains("register_super_nak") || lower.contains("authentication error") {
+        return Some(EdgeEvent::AuthFailed {
+            reason: message.to_string(),
+        });
+    }
+    const TAP_ERRORS: [&str; 6] = [
+        "tuntap open() error",
+        "tuntap ioctl",
+        "failed to setup tuntap",
+        "failed to open tap",
+        "unable to open tap",
+        "no windows tap device",
+    ];
+    if TAP_ERRORS.iter().any(|e| lower.contains(e)) {
+        return Some(EdgeEvent::TapOpenFailed {
+            reason: message.to_string(),
+        });
+    }
+    if message.starts_with("created local tap device IP:") {
+        return Some(EdgeEvent::AddressAssigned {
+            ip: field(message, "IP:")?.to_string(),
+            mask: field(message, "Mask:")?.to_string(),
+            mac: field(message, "MAC:")?.to_string(),
+        });
+    }
+    if let Some(rest) = message.strip_prefix("P2P connection established:") {
+        let (macaddr, sockaddr) = rest.trim().split_once(' ')?;
+        return Some(EdgeEvent::P2pEstablished {
+            macaddr: macaddr.to_string(),
+            sockaddr: sockaddr.trim_matches(|c| c == '[' || c == ']').to_string(),
+        });
+    }
+    None
+}
+
+/// 连接阶段
+#[derive(Serialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ConnectionPhase {
+    #[default]
+    Starting,
+    Connected,
+    /// 曾连接成功，正在重新连接超级节点
+    Reconnecting,
+    Failed,
+}
+
+/// 根据edge日志得出的连接状态
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct ConnectionState {
+    pub phase: ConnectionPhase,
+    /// 失败或重连的原因
+    pub reason: Option<String>,
+    pub ip: Option<String>,
+    pub mac: Option<String>,
+    /// 已建立点对点连接的次数
+    pub p2p_established: u32,
+}
+
+impl ConnectionState {
+    pub fn apply(&mut self, event: EdgeEvent) {
+        match event {
+            EdgeEvent::SupernodeRegistered => {
+                self.phase = ConnectionPhase::Connected;
+                self.reason = None;
+            }
+            EdgeEvent::SupernodeUnreachable => {
+                if self.phase == ConnectionPhase::Connected {
+                    self.phase = ConnectionPhase::Reconnecting;
+                }
+                if self.phase != ConnectionPhase::Failed {
+                    self.reason = Some("超级节点无响应".to_string());
+                }
+            }
+            EdgeEvent::AuthFailed { reason } => {
+                self.phase = ConnectionPhase::Failed;
+                self.reason = Some(format!("超级节点拒绝注册，组名、密钥或MAC地址冲突:{}", reason));
+            }
+            EdgeEvent::TapOpenFailed { reason } => {
+                self.phase = ConnectionPhase::Failed;
+                self.reason = Some(format!("无法打开虚拟网卡:{}", reason));
+            }
+            EdgeEvent::AddressAssigned { ip, mac, .. } => {
+                self.ip = Some(ip);
+                self.mac = Some(mac);
+            }
+            EdgeEvent::P2pEstablished { .. } => {
+                self.p2p_established += 1;
+            }
+        }
+    }
+
+    /// 连接失败的原因
+    pub fn failure(&self) -> Option<String> {
+        match self.phase {
+            ConnectionPhase::Failed => self.reason.clone(),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn patterns() {
+        assert_eq!(
+            parse("17/Oct/2026 10:00:00 [edge_utils.c:1505] [OK] edge <<< ================ >>> supernode"),
+            Some(EdgeEvent::SupernodeRegistered)
+        );
+        assert!(matches!(
+            parse("17/Oct/2026 10:00:00 [edge_utils.c:2280] WARNING: authentication error, MAC or IP address already in use or not released yet by supernode"),
+            Some(EdgeEvent::AuthFailed { .. })
+        ));
+        assert_eq!(
+            parse("17/Oct/2026 10:00:00 [tuntap_linux.c:90] ERROR: tuntap open() error: Permission denied[13]. Is the tun kernel module loaded?"),
+            Some(EdgeEvent::TapOpenFailed {
+                reason: "tuntap open() error: Permission denied[13]. Is the tun kernel module loaded?".to_string()
+            })
+        );
+        assert_eq!(
+            parse("17/Oct/2026 10:00:00 [edge.c:1163] created local tap device IP: 10.0.0.5, Mask: 255.255.255.0, MAC: 02:00:00:00:00:05"),
+            Some(EdgeEvent::AddressAssigned {
+                ip: "10.0.0.5".to_string(),
+                mask: "255.255.255.0".to_string(),
+                mac: "02:00:00:00:00:05".to_string()
+            })
+        );
+        assert_eq!(
+            parse("17/Oct/2026 10:00:00 [edge_utils.c:573] P2P connection established: 02:00:00:00:00:06 [1.2.3.4:5]"),
+            Some(EdgeEvent::P2pEstablished {
+                macaddr: "02:00:00:00:00:06".to_string(),
+                sockaddr: "1.2.3.4:5".to_string()
+            })
+        );
+        assert_eq!(parse("17/Oct/2026 10:00:00 [edge.c:1] Starting n2n edge"), None);
+    }
+
+    #[test]
+    fn state() {
+        let mut state = ConnectionState::default();
+        state.apply(EdgeEvent::SupernodeUnreachable);
+        assert_eq!(state.phase, ConnectionPhase::Starting);
+        state.apply(EdgeEvent::SupernodeRegistered);
+        assert_eq!(state.phase, ConnectionPhase::Connected);
+        assert_eq!(state.reason, None);
+        state.apply(EdgeEvent::SupernodeUnreachable);
+        assert_eq!(state.phase, ConnectionPhase::Reconnecting);
+        assert_eq!(state.failure(), None);
+        state.apply(EdgeEvent::AuthFailed {
+            reason: "nak".to_string(),
+        });
+        assert!(state.failure().unwrap().contains("nak"));
+    }
+}
@@ -0,0 +1,180 @@
+use keyring::Entry;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use tauri::AppHandle;
+
+use crate::config::LocalConfig;
+use crate::tools::ProgramError;
+
+/// 系统凭据管理器中的服务名
+const KEYRING_SERVICE: &str = "LightN2N";
+/// 最短密钥长度
+const MIN_KEY_LENGTH: usize = 8;
+
+/// edge支持的加密算法
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+pub enum Cipher {
+    Twofish,
+    #[default]
+    Aes,
+    ChaCha20,
+    Speck,
+}
+
+impl Cipher {
+    /// 对应edge的`-A`参数
+    fn edge_arg(&self) -> &'static str {
+        match self {
+            Cipher::Twofish => "-A2",
+            Cipher::Aes => "-A3",
+            Cipher::ChaCha20 => "-A4",
+            Cipher::Speck => "-A5",
+        }
+    }
+}
+
+/// 加密设置，密钥不写入配置文件而是保存在系统凭据管理器中
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(default)]
+pub struct EncryptionConfig {
+    /// 要求加密，缺少密钥或密钥过弱时拒绝启动
+    pub required: bool,
+    pub cipher: Cipher,
+    /// 加密包头(-H)
+    pub header_encryption: bool,
+}
+
+impl EncryptionConfig {
+    /// 生成edge的加密参数，密钥本身通过`N2N_KEY`环境变量传递。
+    /// 包头加密以组名为密钥，与负载密钥无关
+    pub fn edge_args(&self, key: Option<&str>, group: &str) -> Result<Vec<String>, ProgramError> {
+        let mut args = Vec::new();
+        match key {
+            None if self.required => {
+                return Err(ProgramError::EncryptionError("未设置密钥".to_string()))
+            }
+            None => {}
+            Some(key) => {
+                if let Err(e) = check_key(key, group) {
+                    if self.required {
+                        return Err(e);
+                    }
+                    warn!("{}", e);
+                }
+                args.push(self.cipher.edge_arg().to_string());
+            }
+        }
+        if self.header_encryption {
+            args.push("-H".to_string());
+        }
+        Ok(args)
+    }
+}
+
+/// 检查密钥强度
+pub fn check_key(key: &str, group: &str) -> Result<(), ProgramError> {
+    let weak = |reason: &str| Err(ProgramError::EncryptionError(reason.to_string()));
+    if key.chars().count() < MIN_KEY_LENGTH {
+        return weak("密钥长度不足8位");
+    }
+    if key.chars().all(|c| key.starts_with(c)) {
+        return weak("密钥由相同字符组成");
+    }
+    if key.eq_ignore_ascii_case(group) {
+        return weak("密钥与组名相同");
+    }
+    if key.chars().all(|c| c.is_ascii_digit()) && key.len() < 12 {
+        return weak("纯数字密钥长度不足12位");
+    }
+    Ok(())
+}
+
+fn entry(profile: &str) -> Result<Entry, ProgramError> {
+    Entry::new(KEYRING_SERVICE, profile).map_err(|e| ProgramError::EncryptionError(e.to_string()))
+}
+
+/// 读取配置对应的密钥
+pub fn get_key(profile: &str) -> Result<Option<String>, ProgramError> {
+    match entry(profile)?.get_password() {
+        Ok(key) => Ok(Some(key)),
+        Err(keyring::Error::NoEntry) => Ok(None),
+        Err(e) => Err(ProgramError::EncryptionError(e.to_string())),
+    }
+}
+
+/// 保存配置对应的密钥
+pub fn set_key(profile: &str, key: &str) -> Result<(), ProgramError> {
+    entry(profile)?
+        .set_password(key)
+        .map_err(|e| ProgramError::EncryptionError(e.to_string()))
+}
+
+/// 删除配置对应的密钥
+pub fn delete_key(profile: &str) -> Result<(), ProgramError> {
+    match entry(profile)?.delete_credential() {
+        Ok(_) | Err(keyring::Error::NoEntry) => Ok(()),
+        Err(e) => Err(ProgramError::EncryptionError(e.to_string())),
+    }
+}
+
+#[tauri::command]
+pub fn n2n_key_set(app_handle: AppHandle, profile: Option<String>, key: String) -> Result<(), String> {
+    LocalConfig::get_config(&app_handle)
+        .and_then(|config| config.profile(profile.as_deref()))
+        .and_then(|p| {
+            check_key(&key, &p.group)?;
+            set_key(&p.id, &key)
+        })
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub fn n2n_key_clear(app_handle: AppHandle, profile: Option<String>) -> Result<(), String> {
+    LocalConfig::get_config(&app_handle)
+        .and_then(|config| config.profile(profile.as_deref()))
+        .and_then(|p| delete_key(&p.id))
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub fn n2n_key_exists(app_handle: AppHandle, profile: Option<String>) -> Result<bool, String> {
+    LocalConfig::get_config(&app_handle)
+        .and_then(|config| config.profile(profile.as_deref()))
+        .and_then(|p| get_key(&p.id))
+        .map(|k| k.is_some())
+        .map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn weak_keys() {
+        assert!(check_key("short", "lers10").is_err());
+        assert!(check_key("aaaaaaaaaa", "lers10").is_err());
+        assert!(check_key("lers10lers10", "LERS10LERS10").is_err());
+        assert!(check_key("12345678901", "lers10").is_err());
+        assert!(check_key("123456789012", "lers10").is_ok());
+        assert!(check_key("correct horse", "lers10").is_ok());
+    }
+
+    #[test]
+    fn edge_args() {
+        let mut config = EncryptionConfig::default();
+        assert!(config.edge_args(None, "g").unwrap().is_empty());
+        assert_eq!(config.edge_args(Some("weak"), "g").unwrap(), vec!["-A3"]);
+        config.header_encryption = true;
+        assert_eq!(config.edge_args(None, "g").unwrap(), vec!["-H"]);
+
+        config.required = true;
+        config.cipher = Cipher::ChaCha20;
+        config.header_encryption = true;
+        assert!(config.edge_args(None, "g").is_err());
+        assert!(config.edge_args(Some("weak"), "g").is_err());
+        assert_eq!(
+            config.edge_args(Some("correct horse"), "g").unwrap(),
+            vec!["-A4", "-H"]
+        );
+    }
+}
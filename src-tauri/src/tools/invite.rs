@@ -0,0 +1,462 @@
+use std::sync::Mutex;
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use lazy_static::lazy_static;
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use tauri::{AppHandle, Emitter, Manager};
+
+use crate::config::LocalConfig;
+use crate::tools::encryption::{self, EncryptionConfig};
+use crate::tools::n2n_client::N2NClientConfig;
+use crate::tools::output::unix_time;
+use crate::tools::process_manager::ProcessManager;
+use crate::tools::supernode::Supernode;
+use crate::tools::ProgramError;
+
+/// 邀请链接的scheme，`lightn2n://join?code=...`
+pub const SCHEME: &str = "lightn2n";
+/// 收到邀请链接后通知前端取出待确认的邀请
+pub const INVITE_EVENT: &str = "invite-received";
+/// 当前邀请码格式版本
+const VERSION: u8 = 1;
+
+lazy_static! {
+    /// 通过链接收到、等待用户确认的邀请，前端加载前收到的也不会丢失
+    static ref PENDING: Mutex<Vec<InviteReceived>> = Mutex::new(Vec::new());
+}
+
+/// 邀请码中的网络配置
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Invite {
+    #[serde(rename = "v")]
+    pub version: u8,
+    pub name: String,
+    pub group: String,
+    pub supernodes: Vec<String>,
+    #[serde(default)]
+    pub member_server: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub member_server_token: Option<String>,
+    #[serde(default)]
+    pub encryption: EncryptionConfig,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
+    /// 过期时间，unix秒
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires: Option<u64>,
+}
+
+impl Invite {
+    pub fn new(
+        profile: &N2NClientConfig,
+        supernodes: Vec<String>,
+        key: Option<String>,
+        expires: Option<u64>,
+    ) -> Self {
+        Self {
+            version: VERSION,
+            name: profile.name.clone(),
+            group: profile.group.clone(),
+            supernodes,
+            member_server: profile.member_server.clone(),
+            member_server_token: profile.member_server_token.clone(),
+            encryption: profile.encryption.clone(),
+            key,
+            expires,
+        }
+    }
+
+    /// 序列化并签名，`payload[.signature]`
+    pub fn encode(&self, secret: Option<&str>) -> Result<String, ProgramError> {
+        let json =
+            serde_json::to_vec(self).map_err(|e| ProgramError::InviteError(e.to_string()))?;
+        let payload = URL_SAFE_NO_PAD.encode(json);
+        match secret {
+            None => Ok(payload),
+            Some(secret) => {
+                let signature =
+                    URL_SAFE_NO_PAD.encode(sign(secret, &payload)?.finalize().into_bytes());
+                Ok(format!("{}.{}", payload, signature))
+            }
+        }
+    }
+
+    /// 校验签名与有效期。设置了团队密钥时只接受以其签名的邀请码
+    pub fn decode(code: &str, secret: Option<&str>, now: u64) -> Result<Self, ProgramError> {
+        let code = code_from_link(code);
+        let (payload, signature) = match code.split_once('.') {
+            Some((payload, signature)) => (payload, Some(signature)),
+            None => (code, None),
+        };
+        match (secret, signature) {
+            (Some(secret), Some(signature)) => {
+                let signature = URL_SAFE_NO_PAD
+                    .decode(signature)
+                    .map_err(|_| ProgramError::InviteError("签名格式错误".to_string()))?;
+                sign(secret, payload)?
+                    .verify_slice(&signature)
+                    .map_err(|_| {
+                        ProgramError::InviteError("签名不匹配，邀请码可能被篡改".to_string())
+                    })?;
+            }
+            (Some(_), None) => return Err(ProgramError::InviteError("邀请码未签名".to_string())),
+            (None, Some(_)) => {
+                return Err(ProgramError::InviteError(
+                    "未设置团队密钥，无法验证签名".to_string(),
+                ))
+            }
+            (None, None) => {}
+        }
+        let json = URL_SAFE_NO_PAD
+            .decode(payload)
+            .map_err(|_| ProgramError::InviteError("格式错误".to_string()))?;
+        let invite: Self =
+            serde_json::from_slice(&json).map_err(|e| ProgramError::InviteError(e.to_string()))?;
+        if invite.version > VERSION {
+            return Err(ProgramError::InviteError(format!(
+                "不支持的版本{}",
+                invite.version
+            )));
+        }
+        if invite.expires.is_some_and(|expires| expires <= now) {
+            return Err(ProgramError::InviteError("已过期".to_string()));
+        }
+        if invite.group.is_empty() || invite.supernodes.is_empty() {
+            return Err(ProgramError::InviteError("缺少组名或超级节点".to_string()));
+        }
+        Ok(invite)
+    }
+
+    /// 只更新邀请码携带的字段，保留本机的标识、网卡、地址与端口等设置
+    pub fn apply(&self, profile: &mut N2NClientConfig) {
+        profile.name = self.name.clone();
+        profile.group = self.group.clone();
+        profile.supernodes = self.supernodes.clone();
+        profile.member_server = self.member_server.clone();
+        profile.member_server_token = self.member_server_token.clone();
+        profile.encryption = self.encryption.clone();
+    }
+
+    /// 生成网络配置，端口避开已有配置
+    pub fn to_profile(&self, id: String, profiles: &[N2NClientConfig]) -> N2NClientConfig {
+        let mut profile = N2NClientConfig {
+            id,
+            ..Default::default()
+        };
+        self.apply(&mut profile);
+        if let Some((server, port)) = self.supernodes[0].rsplit_once(':') {
+            profile.server = server.to_string();
+            profile.port = port.parse().unwrap_or(profile.port);
+        }
+        let others = profiles.iter().filter(|p| p.id != profile.id);
+        if let Some(port) = others.clone().map(|p| p.port).max() {
+            profile.port = profile.port.max(port.saturating_add(1));
+        }
+        if let Some(port) = others.map(|p| p.control_port).max() {
+            profile.control_port = profile.control_port.max(port.saturating_add(1));
+        }
+        profile
+    }
+}
+
+fn sign(secret: &str, payload: &str) -> Result<Hmac<Sha256>, ProgramError> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())
+        .map_err(|e| ProgramError::InviteError(e.to_string()))?;
+    mac.update(payload.as_bytes());
+    Ok(mac)
+}
+
+/// 从`lightn2n://join?code=...`中取出邀请码，其他内容原样返回
+fn code_from_link(text: &str) -> &str {
+    let text = text.trim();
+    match text.strip_prefix(&format!("{}://join?", SCHEME)) {
+        Some(query) => query
+            .split('&')
+            .find_map(|p| p.strip_prefix("code="))
+            .unwrap_or_default(),
+        None => text,
+    }
+}
+
+/// 邀请码与对应的链接
+#[derive(Serialize, Clone, Debug)]
+pub struct InviteCode {
+    pub code: String,
+    pub link: String,
+}
+
+/// 为网络配置生成邀请码，本机正在主持时使用本机超级节点地址
+#[tauri::command]
+pub fn n2n_invite_create(
+    app_handle: AppHandle,
+    profile: Option<String>,
+    expires_in: Option<u64>,
+    include_key: Option<bool>,
+) -> Result<InviteCode, String> {
+    let log = |e: ProgramError| {
+        let error = e.to_string();
+        error!("{}:{}", line!(), error);
+        error
+    };
+    let config = LocalConfig::get_config(&app_handle).map_err(log)?;
+    let profile = config.profile(profile.as_deref()).map_err(log)?;
+    let hosted = app_handle
+        .state::<ProcessManager>()
+        .supernode
+        .with(Supernode::NAME, |s| s.info().clone())
+        .ok()
+        .filter(|info| info.profile == profile.id && !info.supernodes.is_empty());
+    let supernodes = match hosted {
+        Some(info) => info.supernodes,
+        None => profile.supernode_list(),
+    };
+    let key = match include_key.unwrap_or(true) {
+        true => encryption::get_key(&profile.id).map_err(log)?,
+        false => None,
+    };
+    let expires = expires_in.map(|s| unix_time() + s);
+    let code = Invite::new(&profile, supernodes, key, expires)
+        .encode(config.invite_secret())
+        .map_err(log)?;
+    Ok(InviteCode {
+        link: format!("{}://join?code={}", SCHEME, code),
+        code,
+    })
+}
+
+/// 组名与超级节点相同的已有配置
+fn existing(profiles: &[N2NClientConfig], invite: &Invite) -> Option<usize> {
+    profiles
+        .iter()
+        .position(|p| p.group == invite.group && p.supernode_list() == invite.supernodes)
+}
+
+/// 邀请码的内容，导入前交由用户确认，不含密钥
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct InvitePreview {
+    pub name: String,
+    pub group: String,
+    pub supernodes: Vec<String>,
+    pub member_server: String,
+    pub encryption: EncryptionConfig,
+    pub has_key: bool,
+    pub expires: Option<u64>,
+    /// 是否经团队密钥验证
+    pub signed: bool,
+    /// 将被更新的已有配置名称
+    pub updates: Option<String>,
+}
+
+impl InvitePreview {
+    pub fn new(
+        code: &str,
+        secret: Option<&str>,
+        profiles: &[N2NClientConfig],
+        now: u64,
+    ) -> Result<Self, ProgramError> {
+        let invite = Invite::decode(code, secret, now)?;
+        Ok(Self {
+            updates: existing(profiles, &invite).map(|i| profiles[i].name.clone()),
+            name: invite.name,
+            group: invite.group,
+            supernodes: invite.supernodes,
+            member_server: invite.member_server,
+            encryption: invite.encryption,
+            has_key: invite.key.is_some(),
+            expires: invite.expires,
+            signed: secret.is_some(),
+        })
+    }
+}
+
+/// 导入邀请码，组名与超级节点相同的配置只更新邀请码中的字段，返回配置id
+pub fn import(app_handle: &AppHandle, code: &str) -> Result<String, ProgramError> {
+    let mut config = LocalConfig::get_config(app_handle)?;
+    let invite = Invite::decode(code, config.invite_secret(), unix_time())?;
+    if let Some(ref key) = invite.key {
+        encryption::check_key(key, &invite.group)?;
+    }
+    let id = match existing(&config.n2n_profiles, &invite) {
+        Some(index) => {
+            invite.apply(&mut config.n2n_profiles[index]);
+            config.n2n_profiles[index].id.clone()
+        }
+        None => {
+            let id = format!("{}-{}", invite.group, unix_time());
+            let profile = invite.to_profile(id.clone(), &config.n2n_profiles);
+            config.n2n_profiles.push(profile);
+            id
+        }
+    };
+    if let Some(ref key) = invite.key {
+        encryption::set_key(&id, key)?;
+    }
+    config.save(app_handle)?;
+    Ok(id)
+}
+
+#[tauri::command]
+pub fn n2n_invite_import(app_handle: AppHandle, code: String) -> Result<String, String> {
+    import(&app_handle, &code).map_err(|e| {
+        let error = e.to_string();
+        error!("{}:{}", line!(), error);
+        error
+    })
+}
+
+/// 通过链接收到的邀请，用户确认后以`code`调用`n2n_invite_import`
+#[derive(Serialize, Clone, Debug)]
+pub struct InviteReceived {
+    pub code: String,
+    pub preview: Option<InvitePreview>,
+    pub error: Option<String>,
+}
+
+/// 处理启动参数或其他实例转发的邀请链接，只解析不导入，等待用户确认
+pub fn handle_args(app_handle: &AppHandle, args: &[String]) {
+    let links: Vec<&String> = args
+        .iter()
+        .filter(|a| a.starts_with(&format!("{}://", SCHEME)))
+        .collect();
+    if links.is_empty() {
+        return;
+    }
+    let config = LocalConfig::get_config(app_handle).map_err(|e| e.to_string());
+    let received = links.into_iter().map(|link| {
+        let preview = config.as_ref().map_err(String::clone).and_then(|config| {
+            InvitePreview::new(
+                link,
+                config.invite_secret(),
+                &config.n2n_profiles,
+                unix_time(),
+            )
+            .map_err(|e| e.to_string())
+        });
+        match preview {
+            Ok(preview) => InviteReceived {
+                code: link.clone(),
+                preview: Some(preview),
+                error: None,
+            },
+            Err(e) => {
+                warn!("{}:{}", line!(), e);
+                InviteReceived {
+                    code: link.clone(),
+                    preview: None,
+                    error: Some(e),
+                }
+            }
+        }
+    });
+    PENDING
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .extend(received);
+    if let Err(e) = app_handle.emit(INVITE_EVENT, ()) {
+        error!("{}:{}", line!(), e);
+    }
+}
+
+/// 取出等待确认的邀请
+#[tauri::command]
+pub fn n2n_invite_pending() -> Vec<InviteReceived> {
+    std::mem::take(&mut *PENDING.lock().unwrap_or_else(|e| e.into_inner()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn invite() -> Invite {
+        let profile = N2NClientConfig {
+            name: "联机".to_string(),
+            group: "team".to_string(),
+            ..Default::default()
+        };
+        Invite::new(
+            &profile,
+            vec!["sn.example.com:7654".to_string()],
+            Some("secret-key".to_string()),
+            Some(1000),
+        )
+    }
+
+    #[test]
+    fn round_trip() {
+        let code = invite().encode(Some("team secret")).unwrap();
+        assert_eq!(
+            Invite::decode(&code, Some("team secret"), 999).unwrap(),
+            invite()
+        );
+        let link = format!("lightn2n://join?code={}", code);
+        assert_eq!(
+            Invite::decode(&link, Some("team secret"), 999).unwrap(),
+            invite()
+        );
+
+        let unsigned = invite().encode(None).unwrap();
+        assert!(!unsigned.contains('.'));
+        assert_eq!(Invite::decode(&unsigned, None, 999).unwrap(), invite());
+    }
+
+    #[test]
+    fn rejects_tampered() {
+        let code = invite().encode(Some("team secret")).unwrap();
+        let (_, signature) = code.split_once('.').unwrap();
+        let mut forged = invite();
+        forged.supernodes = vec!["evil.example.com:7654".to_string()];
+        let forged = format!("{}.{}", forged.encode(None).unwrap(), signature);
+        assert!(Invite::decode(&forged, Some("team secret"), 999).is_err());
+        assert!(Invite::decode(&code, Some("other"), 999).is_err());
+        // 设置了团队密钥时不接受未签名的邀请码
+        assert!(Invite::decode(&invite().encode(None).unwrap(), Some("team secret"), 999).is_err());
+        assert!(Invite::decode(&code, None, 999).is_err());
+    }
+
+    #[test]
+    fn rejects_expired() {
+        let code = invite().encode(None).unwrap();
+        let error = Invite::decode(&code, None, 1000).unwrap_err().to_string();
+        assert!(error.contains("过期"));
+    }
+
+    #[test]
+    fn reimport_keeps_local_settings() {
+        let mut profile = invite().to_profile("team-1".to_string(), &[]);
+        profile.identification = "desk".to_string();
+        profile.tap_device = Some("n2n1".to_string());
+        profile.port = 40000;
+        profile.port_mapping = true;
+        let mut rotated = invite();
+        rotated.name = "新名称".to_string();
+        rotated.encryption.header_encryption = true;
+        rotated.apply(&mut profile);
+        assert_eq!(profile.name, "新名称");
+        assert!(profile.encryption.header_encryption);
+        assert_eq!(profile.identification, "desk");
+        assert_eq!(profile.tap_device.as_deref(), Some("n2n1"));
+        assert_eq!(profile.port, 40000);
+        assert!(profile.port_mapping);
+
+        let code = rotated.encode(None).unwrap();
+        let preview = InvitePreview::new(&code, None, &[profile], 999).unwrap();
+        assert_eq!(preview.updates.as_deref(), Some("新名称"));
+        assert!(preview.has_key);
+        assert!(!preview.signed);
+    }
+
+    #[test]
+    fn profile_ports() {
+        let existing = vec![N2NClientConfig::default()];
+        let profile = invite().to_profile("team-1".to_string(), &existing);
+        assert_eq!(profile.server, "sn.example.com");
+        assert_eq!(profile.supernode_list(), vec!["sn.example.com:7654"]);
+        assert_eq!(profile.port, existing[0].port + 1);
+        assert_eq!(profile.control_port, existing[0].control_port + 1);
+        assert_eq!(profile.group, "team");
+    }
+}
@@ -0,0 +1,282 @@
+use std::time::{Duration, Instant};
+
+use log::{debug, warn};
+use reqwest::header::{ETAG, IF_NONE_MATCH};
+use reqwest::StatusCode;
+use tokio::sync::Mutex;
+
+pub use n2n_management::members::{MemberEntry, MembersResponse};
+
+use crate::tools::n2n_controller::{Controller, EdgeRow, Member};
+use crate::tools::ProgramError;
+
+/// 请求成员服务器的超时
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+/// 间隔内重复请求直接使用缓存
+const CACHE_INTERVAL: Duration = Duration::from_secs(10);
+
+struct Cached {
+    etag: Option<String>,
+    members: Vec<MemberEntry>,
+    fetched: Instant,
+}
+
+/// 一个组的成员服务器客户端，按间隔与ETag缓存应答
+pub struct MemberServer {
+    url: String,
+    client: reqwest::Client,
+    token: Option<String>,
+    interval: Duration,
+    cache: Mutex<Option<Cached>>,
+}
+
+impl MemberServer {
+    pub fn new(base: &str, group: &str) -> Result<Self, ProgramError> {
+        let client = reqwest::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .build()
+            .map_err(|e| ProgramError::NetworkError(e.to_string()))?;
+        Ok(Self {
+            url: format!("{}/members/{}", base.trim_end_matches('/'), group),
+            client,
+            token: None,
+            interval: CACHE_INTERVAL,
+            cache: Mutex::new(None),
+        })
+    }
+
+    /// 设置访问令牌，以`Authorization: Bearer`发送
+    pub fn with_token(mut self, token: Option<String>) -> Self {
+        self.token = token.filter(|t| !t.is_empty());
+        self
+    }
+
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// 获取组内成员，未修改时使用缓存
+    pub async fn fetch(&self) -> Result<Vec<MemberEntry>, ProgramError> {
+        let mut cache = self.cache.lock().await;
+        if let Some(cached) = cache.as_ref() {
+            if cached.fetched.elapsed() < self.interval {
+                return Ok(cached.members.clone());
+            }
+        }
+        let mut request = self.client.get(&self.url);
+        if let Some(ref token) = self.token {
+            request = request.bearer_auth(token);
+        }
+        if let Some(etag) = cache.as_ref().and_then(|c| c.etag.as_ref()) {
+            request = request.header(IF_NONE_MATCH, etag);
+        }
+        let response = request
+            .send()
+            .await
+            .map_err(|e| ProgramError::NetworkError(e.to_string()))?;
+        if response.status() == StatusCode::NOT_MODIFIED {
+            if let Some(cached) = cache.as_mut() {
+                debug!("成员未变化:{}", self.url);
+                cached.fetched = Instant::now();
+                return Ok(cached.members.clone());
+            }
+        }
+        if !response.status().is_success() {
+            return Err(ProgramError::NetworkError(response.status().to_string()));
+        }
+        let etag = response
+            .headers()
+            .get(ETAG)
+            .and_then(|v| v.to_str().ok())
+            .map(String::from);
+        let body = response
+            .text()
+            .await
+            .map_err(|e| ProgramError::NetworkError(e.to_string()))?;
+        let body: MembersResponse =
+            serde_json::from_str(&body).map_err(|_| ProgramError::TransferError)?;
+        if !body.status {
+            return Err(ProgramError::TransferError);
+        }
+        *cache = Some(Cached {
+            etag,
+            members: body.members.clone(),
+            fetched: Instant::now(),
+        });
+        Ok(body.members)
+    }
+
+    /// 成员列表，成员服务器不可达时只使用管理端口中的成员，管理端口出错时不合并连接方式，
+    /// 也无法排除自己，两者都失败时返回管理端口的错误
+    pub async fn members(&self, controller: &Controller) -> Result<Vec<Member>, ProgramError> {
+        let (me, edges, fetched) =
+            tokio::join!(controller.get_vip(), controller.edges(), self.fetch());
+        let me = me.unwrap_or_else(|e| {
+            warn!("{}:无法获取本机地址:{}", line!(), e);
+            String::new()
+        });
+        let edges = edges.map_err(|e| ProgramError::ManagementError(e.to_string()));
+        match (fetched, edges) {
+            (Ok(members), Ok(edges)) => Ok(merge(&me, members, edges)),
+            (Ok(members), Err(e)) => {
+                warn!("{}:管理端口成员不可用:{}", line!(), e);
+                Ok(merge(&me, members, Vec::new()))
+            }
+            (Err(e), Ok(edges)) => {
+                warn!("{}:成员服务器不可用，使用管理端口成员:{}", line!(), e);
+                Ok(from_edges(&me, edges))
+            }
+            (Err(e), Err(edges_error)) => {
+                warn!("{}:成员服务器不可用:{}", line!(), e);
+                Err(edges_error)
+            }
+        }
+    }
+}
+
+/// 合并成员服务器返回的成员与管理端口中的连接方式，排除自己
+pub fn merge(me: &str, members: Vec<MemberEntry>, edges: Vec<EdgeRow>) -> Vec<Member> {
+    let mut temp: Vec<Member> = members
+        .into_iter()
+        .filter(|m| m.ip() != me)
+        .map(|m| Member {
+            address: m.ip4addr,
+            name: m.desc.unwrap_or("Default".to_string()),
+            mode: "None".to_string(),
+        })
+        .collect();
+    for member in edges.into_iter().map(Member::from) {
+        if let Some(m) = temp.iter_mut().find(|x| x.address == member.address) {
+            m.mode = member.mode;
+        }
+    }
+    temp
+}
+
+/// 由管理端口中的成员组成列表，排除自己
+pub fn from_edges(me: &str, edges: Vec<EdgeRow>) -> Vec<Member> {
+    edges
+        .into_iter()
+        .filter(|e| !e.ip4addr.is_empty() && e.ip4addr.split('/').next() != Some(me))
+        .map(Member::from)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use serde_json::json;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::tools::mock_edge::{MockEdge, Reply};
+
+    const BODY: &str = r#"{"status":true,"members":[{"ip4addr":"10.0.0.2/24","desc":"me"},{"ip4addr":"10.0.0.3/24","desc":"b"},{"ip4addr":"10.0.0.4/24"}]}"#;
+
+    /// 返回固定成员与ETag的HTTP服务，记录请求次数
+    async fn serve() -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                counter.fetch_add(1, Ordering::SeqCst);
+                let mut buf = vec![0; 4096];
+                let n = stream.read(&mut buf).await.unwrap_or(0);
+                let request = String::from_utf8_lossy(&buf[..n]).to_lowercase();
+                let response = if request.contains("if-none-match: \"v1\"") {
+                    "HTTP/1.1 304 Not Modified\r\nETag: \"v1\"\r\nConnection: close\r\n\r\n".to_string()
+                } else {
+                    format!(
+                        "HTTP/1.1 200 OK\r\nETag: \"v1\"\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                        BODY.len(),
+                        BODY
+                    )
+                };
+                let _ = stream.write_all(response.as_bytes()).await;
+            }
+        });
+        (base, requests)
+    }
+
+    fn mock_edge() -> MockEdge {
+        let edge = MockEdge::start();
+        edge.rows("info", vec![json!({"ip4addr": "10.0.0.2"})]);
+        edge.rows(
+            "edges",
+            vec![
+                json!({"mode": "pSp", "ip4addr": "10.0.0.3/24", "desc": "b"}),
+                json!({"mode": "sn", "ip4addr": "10.0.0.9/24", "desc": "x"}),
+            ],
+        );
+        edge
+    }
+
+    #[tokio::test]
+    async fn members_merge() {
+        let (base, _) = serve().await;
+        let edge = mock_edge();
+        let server = MemberServer::new(&base, "g").unwrap();
+        let members = server.members(&Controller::new(edge.port())).await.unwrap();
+        assert_eq!(members.len(), 2);
+        assert_eq!(members[0].mode, "pSp");
+        assert_eq!(members[1].name, "Default");
+        assert_eq!(members[1].mode, "None");
+    }
+
+    #[tokio::test]
+    async fn caches_by_interval_and_etag() {
+        let (base, requests) = serve().await;
+        let server = MemberServer::new(&base, "g").unwrap();
+        let first = server.fetch().await.unwrap();
+        assert_eq!(server.fetch().await.unwrap(), first);
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+
+        let server = server.with_interval(Duration::ZERO);
+        assert_eq!(server.fetch().await.unwrap(), first);
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+        assert_eq!(first[1].desc.as_deref(), Some("b"));
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_edges() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        drop(listener);
+        let edge = mock_edge();
+        let server = MemberServer::new(&base, "g").unwrap();
+        let members = server.members(&Controller::new(edge.port())).await.unwrap();
+        let addresses: Vec<_> = members.iter().map(|m| m.address.as_str()).collect();
+        assert_eq!(addresses, vec!["10.0.0.3/24", "10.0.0.9/24"]);
+        assert_eq!(members[1].mode, "sn");
+    }
+
+    #[tokio::test]
+    async fn edges_error_keeps_members() {
+        let (base, _) = serve().await;
+        let edge = mock_edge();
+        edge.reply("edges", Reply::Error("badauth".to_string()));
+        let server = MemberServer::new(&base, "g").unwrap();
+        let members = server.members(&Controller::new(edge.port())).await.unwrap();
+        let addresses: Vec<_> = members.iter().map(|m| m.address.as_str()).collect();
+        assert_eq!(addresses, vec!["10.0.0.3/24", "10.0.0.4/24"]);
+        assert!(members.iter().all(|m| m.mode == "None"));
+    }
+
+    #[tokio::test]
+    async fn management_down_keeps_members() {
+        let (base, _) = serve().await;
+        let edge = MockEdge::start();
+        edge.reply("info", Reply::Error("badauth".to_string()));
+        edge.reply("edges", Reply::Error("badauth".to_string()));
+        let server = MemberServer::new(&base, "g").unwrap();
+        let members = server.members(&Controller::new(edge.port())).await.unwrap();
+        let addresses: Vec<_> = members.iter().map(|m| m.address.as_str()).collect();
+        assert_eq!(addresses, vec!["10.0.0.2/24", "10.0.0.3/24", "10.0.0.4/24"]);
+    }
+}
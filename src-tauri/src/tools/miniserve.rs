@@ -1,12 +1,10 @@
-use std::any::Any;
-use std::sync::{Arc, RwLock};
+use log::error;
+use tauri::{AppHandle, Manager, State};
 
-use log::{debug, error};
-use tauri::AppHandle;
-
-use crate::CHILDS;
 use crate::config::LocalConfig;
-use crate::tools::{child_drop, child_status, ChildProcess, execute_command, ExternalBinaryProgram, ExternalFilePosition, ProgramError};
+use crate::platform::{self, FirewallProtocol, FirewallRule};
+use crate::tools::{ChildProcess, ExternalBinaryProgram, ExternalFilePosition, ProgramError};
+use crate::tools::process_manager::ProcessManager;
 
 pub struct MiniServe {
     program: ExternalBinaryProgram,
@@ -47,119 +45,74 @@ impl ChildProcess for MiniServe {
     fn get_process(&mut self) -> &mut ExternalBinaryProgram {
         &mut self.program
     }
-
-    fn as_any(&mut self) -> &mut dyn Any {
-        self
-    }
 }
 
 #[tauri::command]
-pub fn miniserve_start(app_handle: AppHandle, path: String) -> Result<(), String> {
-    // 如果需要启动
-    if !child_status(MiniServe::NAME) {
-        let config = LocalConfig::get_config(&app_handle);
-        match MiniServe::new(
-            ExternalFilePosition::MiniServe.to_string(),
-            path,
-            config.miniserve_port,
-        ) {
-            Ok(mut client) => {
-                if let Err(e) = client.get_process().start() {
-                    let error = e.to_string();
-                    error!("{}:{}", line!(), error);
-                    Err(error)
-                } else {
-                    // 运行后保存
-                    match CHILDS.lock() {
-                        Ok(mut map) => {
-                            map.insert(MiniServe::NAME, Arc::new(RwLock::new(client)));
-                            Ok(())
-                        }
-                        Err(e) => {
-                            // 防止多开
-                            let error = ProgramError::ChildProcessError(e.to_string()).to_string();
-                            error!("{}:{}", line!(), error);
-                            drop(client);
-                            Err(error)
-                        }
-                    }
-                }
-            }
-            Err(e) => {
-                let error = e.to_string();
-                error!("{}:{}", line!(), error);
-                Err(error)
-            }
-        }
-    } else {
-        Ok(())
+pub fn miniserve_start(
+    app_handle: AppHandle,
+    manager: State<'_, ProcessManager>,
+    path: String,
+) -> Result<(), String> {
+    // 已在运行
+    if manager.file_share.running(MiniServe::NAME) {
+        return Ok(());
     }
+    let log = |e: ProgramError| {
+        let error = e.to_string();
+        error!("{}:{}", line!(), error);
+        error
+    };
+    let config = LocalConfig::get_config(&app_handle).map_err(log)?;
+    let mut client = MiniServe::new(
+        ExternalFilePosition::MiniServe.to_string(),
+        path,
+        config.miniserve_port,
+    )
+    .map_err(log)?;
+    client.get_process().set_stop_timeout(config.stop_timeout());
+    client.get_process().set_restart_policy(config.miniserve_restart);
+    client.get_process().start().map_err(log)?;
+    // 运行后保存
+    manager.file_share.insert(MiniServe::NAME, client).map_err(log)
 }
 
 #[tauri::command]
-pub fn miniserve_stop() -> Result<bool, String> {
-    child_drop(MiniServe::NAME)
+pub async fn miniserve_stop(app_handle: AppHandle) -> Result<bool, String> {
+    tauri::async_runtime::spawn_blocking(move || {
+        app_handle.state::<ProcessManager>().file_share.stop(MiniServe::NAME)
+    })
+    .await
+    .map_err(|e| e.to_string())?
+    .map(|_| true)
+    .map_err(|e| e.to_string())
 }
 
-#[tauri::command]
-pub fn miniserve_firewall_check() -> Result<bool, String> {
-    match execute_command(
-        "netsh",
-        vec![
-            "advfirewall",
-            "firewall",
-            "show",
-            "rule",
-            format!("name={}", MiniServe::FIRE_WALL_NAME).as_str(),
-        ],
-    ) {
-        Ok(output_str) => {
-            debug!("{}", output_str);
-            if output_str.contains("No rules match the specified criteria")
-                || output_str.contains("没有与指定标准相匹配的规则")
-            {
-                Ok(false)
-            } else {
-                Ok(true)
-            }
-        }
-        Err(e) => Err(e.to_string()),
+impl MiniServe {
+    /// 文件共享的防火墙规则
+    fn firewall_rule(port: u32) -> Result<FirewallRule, ProgramError> {
+        let current =
+            std::env::current_dir().map_err(|e| ProgramError::GetCurrentDirError(e.to_string()))?;
+        let port = u16::try_from(port).map_err(|_| ProgramError::TransferError)?;
+        Ok(FirewallRule {
+            name: Self::FIRE_WALL_NAME,
+            program: Some(current.join(ExternalFilePosition::MiniServe.path())),
+            protocol: FirewallProtocol::Tcp(port),
+        })
     }
 }
 
 #[tauri::command]
-pub fn miniserve_firewall_add() -> Result<(), String> {
-    match std::env::current_dir().map_err(|e| ProgramError::GetCurrentDirError(e.to_string())) {
-        Ok(current) => {
-            match current
-                .join(ExternalFilePosition::MiniServe.to_string())
-                .to_str()
-            {
-                None => Err(ProgramError::FireWallError.to_string()),
-                Some(path) => {
-                    match execute_command(
-                        "netsh",
-                        vec![
-                            "advfirewall",
-                            "firewall",
-                            "add",
-                            "rule",
-                            format!("name={}", MiniServe::FIRE_WALL_NAME).as_str(),
-                            "dir=in",
-                            "action=allow",
-                            format!("program={}", path).as_str(),
-                            "enable=yes",
-                        ],
-                    ) {
-                        Ok(output_str) => {
-                            debug!("{}", output_str);
-                            Ok(())
-                        }
-                        Err(e) => Err(e.to_string()),
-                    }
-                }
-            }
-        }
-        Err(e) => Err(e.to_string()),
-    }
-}
\ No newline at end of file
+pub fn miniserve_firewall_check(app_handle: AppHandle) -> Result<bool, String> {
+    let config = LocalConfig::get_config(&app_handle).map_err(|e| e.to_string())?;
+    MiniServe::firewall_rule(config.miniserve_port)
+        .and_then(|rule| platform::firewall_rule_exists(&rule))
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub fn miniserve_firewall_add(app_handle: AppHandle) -> Result<(), String> {
+    let config = LocalConfig::get_config(&app_handle).map_err(|e| e.to_string())?;
+    MiniServe::firewall_rule(config.miniserve_port)
+        .and_then(|rule| platform::firewall_rule_add(&rule))
+        .map_err(|e| e.to_string())
+}
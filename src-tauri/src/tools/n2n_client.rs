@@ -1,53 +1,127 @@
-use std::any::Any;
-use std::sync::{Arc, RwLock};
-use std::thread::sleep;
+use std::future::Future;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
-use log::{debug, error};
+use log::{debug, error, warn};
 use serde::{Deserialize, Serialize};
-use serde_json::Value;
-use tauri::AppHandle;
+use tauri::{AppHandle, Emitter, Manager, State};
+use tokio::time::sleep;
+use tokio_util::sync::CancellationToken;
 
-use crate::CHILDS;
 use crate::config::LocalConfig;
-use crate::tools::{
-    child_drop, child_status, ChildProcess, execute_command, ExternalBinaryProgram,
-    ExternalFilePosition, ProgramError,
-};
-use crate::tools::n2n_controller::{Controller, Member};
+use crate::platform::{self, FirewallProtocol, FirewallRule};
+use crate::tools::address::{self, AddressMode};
+use crate::tools::edge_log::{self, ConnectionState};
+use crate::tools::encryption::{self, EncryptionConfig};
+use crate::tools::member_server::MemberServer;
+use crate::tools::n2n_controller::{Controller, Member, SupernodeRow};
+use crate::tools::n2n_events::{PeerSubscriber, ProfilePeerEvent, PEER_EVENT};
+use crate::tools::nat_detect;
+use crate::tools::peer_diagnostics::{self, Evidence, PeerDiagnosis};
+use crate::tools::ping;
+use crate::tools::port_mapping::{Gateway, MappingStatus, PortMapper};
+use crate::tools::process_manager::{ProcessManager, ServiceMap};
+use crate::tools::supernodes;
+use crate::tools::supervisor::RestartPolicy;
+use crate::tools::{ChildProcess, ExternalBinaryProgram, ExternalFilePosition, ProgramError};
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
 pub struct N2NClientConfig {
+    /// 配置唯一标识
+    pub id: String,
+    /// 配置显示名称
+    pub name: String,
     pub identification: String,
     pub group: String,
     pub server: String,
     pub port: u16,
+    /// 通过PCP、NAT-PMP或UPnP在路由器上映射edge的UDP端口
+    pub port_mapping: bool,
     pub member_server: String,
+    /// 成员服务器要求的令牌
+    pub member_server_token: Option<String>,
     pub control_port: u16,
+    /// 超级节点列表，`host:port`，为空时使用server与port
+    pub supernodes: Vec<String>,
+    /// 启动前按延迟对超级节点排序
+    pub supernode_probe: bool,
+    /// 使用的TAP网卡名称，同时运行多个网络时需各不相同
+    pub tap_device: Option<String>,
+    pub encryption: EncryptionConfig,
+    /// 虚拟ip分配方式
+    pub address: AddressMode,
+    /// 组网段，固定地址必须位于其中
+    pub subnet: Option<String>,
+    pub mtu: Option<u16>,
+    /// 虚拟网卡的MAC地址
+    pub mac: Option<String>,
+    /// edge异常退出后的重启策略
+    pub restart: RestartPolicy,
 }
 
 impl Default for N2NClientConfig {
     fn default() -> Self {
         Self {
+            id: "default".to_string(),
+            name: "默认".to_string(),
             identification: "Default".to_string(),
             group: "lers10".to_string(),
             server: "服务器地址".to_string(),
             port: 49898,
+            port_mapping: false,
             member_server: "成员服务器地址".to_string(),
+            member_server_token: None,
             control_port: 5644,
+            supernodes: Vec::new(),
+            supernode_probe: true,
+            tap_device: None,
+            encryption: EncryptionConfig::default(),
+            address: AddressMode::default(),
+            subnet: None,
+            mtu: None,
+            mac: None,
+            restart: RestartPolicy::default(),
         }
     }
 }
 
+impl N2NClientConfig {
+    /// 配置的全部超级节点，未配置列表时使用server与port
+    pub fn supernode_list(&self) -> Vec<String> {
+        if self.supernodes.is_empty() {
+            vec![format!("{}:{}", self.server, self.port)]
+        } else {
+            self.supernodes.clone()
+        }
+    }
+
+    /// edge实际使用的TAP网卡，未指定时为平台默认的网卡
+    pub fn tap_name(&self) -> &str {
+        self.tap_device
+            .as_deref()
+            .unwrap_or(platform::DEFAULT_TAP_DEVICE)
+    }
+}
+
 pub struct N2NClient {
     program: ExternalBinaryProgram,
-    controller: Controller,
+    controller: Arc<Controller>,
+    member_server: Arc<MemberServer>,
+    config: N2NClientConfig,
+    subscriber: Option<PeerSubscriber>,
+    /// 运行期间保持的路由器端口映射
+    port_mapper: Option<PortMapper>,
+    /// 由edge日志得出的连接状态
+    state: Arc<Mutex<ConnectionState>>,
+    /// 停止时取消进行中的管理端口请求与事件订阅
+    cancel: CancellationToken,
 }
 
 impl Drop for N2NClient {
     fn drop(&mut self) {
-        let _ = self.controller.close();
-        let _ = self.program.stop();
+        let _ = self.shutdown();
     }
 }
 
@@ -55,31 +129,199 @@ impl N2NClient {
     pub const NAME: &'static str = "n2n_client";
     pub const FIRE_WALL_NAME: &'static str = "LightN2N_Allow_N2N";
 
+    /// 每个配置对应的子进程名
+    pub fn process_name(profile: &str) -> String {
+        format!("{}:{}", Self::NAME, profile)
+    }
+
     pub fn new(config: N2NClientConfig, program_path: String) -> Result<Self, ProgramError> {
         let current_dir =
             std::env::current_dir().map_err(|e| ProgramError::GetCurrentDirError(e.to_string()))?;
 
-        let program = ExternalBinaryProgram::new(
-            Self::NAME,
+        let mut args = vec![
+            "-c".to_string(),
+            config.group.clone(),
+            "-I".to_string(),
+            config.identification.clone(),
+            "-E".to_string(),
+            "-p".to_string(),
+            config.port.clone().to_string(),
+            "-t".to_string(),
+            config.control_port.to_string(),
+        ];
+        // 不转入后台，便于管理子进程
+        if cfg!(unix) {
+            args.push("-f".to_string());
+        }
+        let mut supernodes = config.supernode_list();
+        if config.supernode_probe && supernodes.len() > 1 {
+            supernodes = supernodes::ranked(&supernodes);
+        }
+        for supernode in supernodes {
+            args.push("-l".to_string());
+            args.push(supernode);
+        }
+        args.extend(address::edge_args(
+            &config,
+            &address::local_networks(),
+            &platform::tap_devices(),
+        )?);
+        let key = match encryption::get_key(&config.id) {
+            Ok(key) => key,
+            Err(e) if !config.encryption.required => {
+                error!("{}:{}", line!(), e);
+                None
+            }
+            Err(e) => return Err(e),
+        };
+        args.extend(config.encryption.edge_args(key.as_deref(), &config.group)?);
+        let mut program = ExternalBinaryProgram::new(
+            Self::process_name(&config.id).as_str(),
             current_dir.join(program_path),
-            vec![
-                "-c".to_string(),
-                config.group.clone(),
-                "-l".to_string(),
-                format!("{}:{}", config.server, config.port),
-                "-I".to_string(),
-                config.identification.clone(),
-                "-E".to_string(),
-                "-p".to_string(),
-                config.port.clone().to_string(),
-            ],
+            args,
         )?;
-        let controller = Controller::new(config.control_port);
+        if let Some(key) = key {
+            program.set_env("N2N_KEY", key);
+        }
+        program.set_restart_policy(config.restart.clone());
+        let state = Arc::new(Mutex::new(ConnectionState::default()));
+        let hook_state = state.clone();
+        program.set_line_hook(Arc::new(move |line| {
+            if let Some(event) = edge_log::parse(line) {
+                debug!("edge事件:{:?}", event);
+                match hook_state.lock() {
+                    Ok(mut s) => s.apply(event),
+                    Err(e) => error!("{}:{}", line!(), e),
+                }
+            }
+        }));
         Ok(Self {
             program,
-            controller,
+            controller: Arc::new(Controller::new(config.control_port)),
+            member_server: Arc::new(
+                MemberServer::new(&config.member_server, &config.group)?
+                    .with_token(config.member_server_token.clone()),
+            ),
+            config,
+            subscriber: None,
+            port_mapper: None,
+            state,
+            cancel: CancellationToken::new(),
         })
     }
+
+    pub fn connection_state(&self) -> ConnectionState {
+        match self.state.lock() {
+            Ok(s) => s.clone(),
+            Err(e) => {
+                error!("{}:{}", line!(), e);
+                ConnectionState::default()
+            }
+        }
+    }
+
+    /// 连接失败的原因，edge已退出时附带退出码
+    fn failure(&mut self) -> Option<String> {
+        if let Some(reason) = self.connection_state().failure() {
+            return Some(reason);
+        }
+        if self.program.status() {
+            return None;
+        }
+        match self.program.last_exit() {
+            Some(exit) if exit.code != Some(0) => Some(match exit.code {
+                Some(code) => format!("edge异常退出，退出码{}", code),
+                None => format!("edge被信号{}结束", exit.signal.unwrap_or_default()),
+            }),
+            _ => None,
+        }
+    }
+
+    /// 订阅管理端口的成员事件并推送到前端
+    pub fn subscribe_events(&mut self, app_handle: AppHandle) {
+        let profile = self.config.id.clone();
+        self.subscriber = Some(PeerSubscriber::start(
+            self.config.control_port,
+            self.cancel.child_token(),
+            move |event| {
+                let payload = ProfilePeerEvent {
+                    profile: profile.clone(),
+                    event,
+                };
+                if let Err(e) = app_handle.emit(PEER_EVENT, payload) {
+                    error!("{}:{}", line!(), e);
+                }
+            },
+        ));
+    }
+
+    /// 配置要求时在路由器上映射edge端口，停止时随cancel删除
+    pub fn start_port_mapping(&mut self) {
+        if self.config.port_mapping {
+            self.port_mapper = Some(PortMapper::start(
+                Gateway::detect(),
+                self.config.port,
+                self.cancel.child_token(),
+            ));
+        }
+    }
+
+    /// 在后台经UDP测量超级节点的往返时间，供下次启动时排序
+    pub fn probe_supernodes(&self) {
+        let supernodes = self.config.supernode_list();
+        if self.config.supernode_probe && supernodes.len() > 1 {
+            let community = self.config.group.clone();
+            tauri::async_runtime::spawn(async move {
+                supernodes::remember(&supernodes::probe(&supernodes, &community).await);
+            });
+        }
+    }
+
+    /// 端口映射状态，未启用时为None
+    pub fn port_mapping(&self) -> Option<MappingStatus> {
+        self.port_mapper.as_ref().map(PortMapper::status)
+    }
+}
+
+/// 配置及其运行状态
+#[derive(Serialize, Clone, Debug)]
+pub struct ProfileStatus {
+    pub id: String,
+    pub name: String,
+    pub group: String,
+    pub running: bool,
+}
+
+/// 与正在运行的其他配置冲突的端口或网卡
+fn check_conflict(
+    config: &N2NClientConfig,
+    profiles: &[N2NClientConfig],
+    edges: &ServiceMap<N2NClient>,
+) -> Result<(), ProgramError> {
+    for other in profiles {
+        if other.id == config.id || !edges.running(&N2NClient::process_name(&other.id)) {
+            continue;
+        }
+        if other.port == config.port {
+            return Err(ProgramError::ProfileConflict(format!(
+                "{}:端口{}",
+                other.name, other.port
+            )));
+        }
+        if other.control_port == config.control_port {
+            return Err(ProgramError::ProfileConflict(format!(
+                "{}:管理端口{}",
+                other.name, other.control_port
+            )));
+        }
+        if other.tap_name() == config.tap_name() {
+            return Err(ProgramError::ProfileConflict(format!(
+                "{}:虚拟网卡",
+                other.name
+            )));
+        }
+    }
+    Ok(())
 }
 
 impl ChildProcess for N2NClient {
@@ -87,273 +329,345 @@ impl ChildProcess for N2NClient {
         &mut self.program
     }
 
-    fn as_any(&mut self) -> &mut dyn Any {
-        self
+    /// 先通过管理端口通知edge退出，使其向超级节点注销
+    fn shutdown(&mut self) -> Result<(), ProgramError> {
+        self.cancel.cancel();
+        self.subscriber = None;
+        self.port_mapper = None;
+        if self.program.status() {
+            match self.controller.request_stop() {
+                Ok(_) => {
+                    let timeout = self.program.stop_timeout();
+                    if !self.program.wait_exit(timeout) {
+                        warn!("{}:edge未响应stop", line!());
+                    }
+                }
+                Err(e) => warn!("{}:{}", line!(), e),
+            }
+        }
+        self.program.stop()
     }
 }
 
-#[tauri::command]
-pub fn n2n_client_start(app_handle: AppHandle) -> Result<bool, String> {
-    // 如果需要启动
-    if !child_status(N2NClient::NAME) {
-        let config = LocalConfig::get_config(&app_handle);
-        match N2NClient::new(
-            config.n2n_config,
-            ExternalFilePosition::N2NClient.to_string(),
-        ) {
-            Ok(mut client) => {
-                if let Err(e) = client.get_process().start() {
-                    let error = e.to_string();
-                    error!("{}:{}", line!(), error);
-                    Err(error)
+/// 正在运行的edge，取出后不再占用ProcessManager
+struct RunningEdge {
+    controller: Arc<Controller>,
+    cancel: CancellationToken,
+    member_server: Arc<MemberServer>,
+}
+
+impl RunningEdge {
+    fn get(manager: &ProcessManager, name: &str) -> Result<Self, String> {
+        manager
+            .edges
+            .with(name, |c| {
+                if c.program.status() {
+                    Ok(Self {
+                        controller: c.controller.clone(),
+                        cancel: c.cancel.clone(),
+                        member_server: c.member_server.clone(),
+                    })
                 } else {
-                    // 运行后保存
-                    match CHILDS.lock() {
-                        Ok(mut map) => {
-                            map.insert(N2NClient::NAME, Arc::new(RwLock::new(client)));
-                            Ok(true)
-                        }
-                        Err(e) => {
-                            // 防止多开
-                            let error = ProgramError::ChildProcessError(e.to_string()).to_string();
-                            error!("{}:{}", line!(), error);
-                            drop(client);
-                            Err(error)
-                        }
-                    }
+                    Err(ProgramError::ChildProcessNotFound)
                 }
-            }
-            Err(e) => {
-                let error = e.to_string();
-                error!("{}:{}", line!(), error);
-                Err(error)
-            }
+            })
+            .and_then(|r| r)
+            .map_err(|e| e.to_string())
+    }
+
+    /// edge停止时放弃等待
+    async fn until_stopped<T>(
+        &self,
+        future: impl Future<Output = Result<T, String>>,
+    ) -> Result<T, String> {
+        tokio::select! {
+            _ = self.cancel.cancelled() => Err(ProgramError::ChildProcessNotFound.to_string()),
+            result = future => result,
         }
-    } else {
-        Ok(true)
     }
 }
 
 #[tauri::command]
-pub fn n2n_client_stop() -> Result<bool, String> {
-    child_drop(N2NClient::NAME)
+pub fn n2n_profiles(
+    app_handle: AppHandle,
+    manager: State<'_, ProcessManager>,
+) -> Result<Vec<ProfileStatus>, String> {
+    let config = LocalConfig::get_config(&app_handle).map_err(|e| e.to_string())?;
+    Ok(config
+        .n2n_profiles
+        .into_iter()
+        .map(|p| ProfileStatus {
+            running: manager.edges.running(&N2NClient::process_name(&p.id)),
+            id: p.id,
+            name: p.name,
+            group: p.group,
+        })
+        .collect())
 }
 
-#[tauri::command]
-pub fn n2n_status() -> Result<bool, String> {
-    if child_status(N2NClient::NAME) {
-        match CHILDS.lock() {
-            Ok(s) => match s.get(N2NClient::NAME) {
-                None => Err(ProgramError::ChildProcessNotFound.to_string()),
-                Some(p) => match p.write() {
-                    Ok(mut s) => match s.as_any().downcast_mut::<N2NClient>() {
-                        None => Err(ProgramError::DowncastError.to_string()),
-                        Some(c) => {
-                            for _ in 0..3 {
-                                if c.controller.test() {
-                                    return Ok(true);
-                                }
-                                sleep(Duration::from_millis(600));
-                            }
-                            Err(ProgramError::ChildProcessNotFound.to_string())
-                        }
-                    },
-                    Err(e) => Err(ProgramError::ChildProcessError(e.to_string()).to_string()),
-                },
-            },
-            Err(e) => Err(ProgramError::ChildProcessError(e.to_string()).to_string()),
-        }
-    } else {
-        Ok(false)
+/// 创建并启动edge，包含测速与读取密钥等阻塞操作
+fn start_client(app_handle: &AppHandle, profile: Option<String>) -> Result<bool, String> {
+    let config = LocalConfig::get_config(app_handle).map_err(|e| e.to_string())?;
+    let n2n_config = config
+        .profile(profile.as_deref())
+        .map_err(|e| e.to_string())?;
+    start_profile(app_handle, &config, n2n_config)
+}
+
+/// 按给定的网络配置启动edge，配置可以与保存的不同
+pub(crate) fn start_profile(
+    app_handle: &AppHandle,
+    config: &LocalConfig,
+    n2n_config: N2NClientConfig,
+) -> Result<bool, String> {
+    let manager = app_handle.state::<ProcessManager>();
+    let name = N2NClient::process_name(&n2n_config.id);
+    // 已在运行
+    if manager.edges.running(&name) {
+        return Ok(true);
     }
+    let log = |e: ProgramError| {
+        let error = e.to_string();
+        error!("{}:{}", line!(), error);
+        error
+    };
+    check_conflict(&n2n_config, &config.n2n_profiles, &manager.edges).map_err(log)?;
+    let mut client =
+        N2NClient::new(n2n_config, ExternalFilePosition::N2NClient.to_string()).map_err(log)?;
+    client.get_process().set_stop_timeout(config.stop_timeout());
+    client.get_process().start().map_err(log)?;
+    client.subscribe_events(app_handle.clone());
+    client.start_port_mapping();
+    client.probe_supernodes();
+    // 运行后保存
+    manager.edges.insert(&name, client).map_err(log)?;
+    Ok(true)
 }
 
 #[tauri::command]
-pub fn n2n_self_ip() -> Result<String, String> {
-    if child_status(N2NClient::NAME) {
-        match CHILDS.lock() {
-            Ok(s) => match s.get(N2NClient::NAME) {
-                None => Err(ProgramError::ChildProcessNotFound.to_string()),
-                Some(p) => match p.write() {
-                    Ok(mut s) => match s.as_any().downcast_mut::<N2NClient>() {
-                        None => Err(ProgramError::DowncastError.to_string()),
-                        Some(c) => match c.controller.get_vip() {
-                            Ok(s) => Ok(s),
-                            Err(e) => Err(e.to_string()),
-                        },
-                    },
-                    Err(e) => Err(ProgramError::ChildProcessError(e.to_string()).to_string()),
-                },
-            },
-            Err(e) => Err(ProgramError::ChildProcessError(e.to_string()).to_string()),
-        }
+pub async fn n2n_client_start(
+    app_handle: AppHandle,
+    profile: Option<String>,
+) -> Result<bool, String> {
+    tauri::async_runtime::spawn_blocking(move || start_client(&app_handle, profile))
+        .await
+        .map_err(|e| e.to_string())?
+}
+
+/// 查找配置对应的子进程名
+fn profile_process(app_handle: &AppHandle, profile: Option<String>) -> Result<String, String> {
+    LocalConfig::get_config(app_handle)
+        .and_then(|config| config.profile(profile.as_deref()))
+        .map(|p| N2NClient::process_name(&p.id))
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub async fn n2n_client_stop(
+    app_handle: AppHandle,
+    profile: Option<String>,
+) -> Result<bool, String> {
+    let name = profile_process(&app_handle, profile)?;
+    // 等待edge退出期间不阻塞其他命令
+    tauri::async_runtime::spawn_blocking(move || {
+        app_handle.state::<ProcessManager>().edges.stop(&name)
+    })
+    .await
+    .map_err(|e| e.to_string())?
+    .map(|_| true)
+    .map_err(|e| e.to_string())
+}
+
+/// 运行状态，连接失败时返回原因
+#[tauri::command]
+pub async fn n2n_status(
+    app_handle: AppHandle,
+    manager: State<'_, ProcessManager>,
+    profile: Option<String>,
+) -> Result<bool, String> {
+    let name = profile_process(&app_handle, profile)?;
+    let failure =
+        |manager: &ProcessManager| manager.edges.with(&name, |c| c.failure()).ok().flatten();
+    let edge = match manager.edges.with(&name, |c| c.program.status()) {
+        Ok(true) => RunningEdge::get(&manager, &name)?,
+        Ok(false) => return failure(&manager).map_or(Ok(false), Err),
+        Err(ProgramError::ChildProcessNotFound) => return Ok(false),
+        Err(e) => return Err(e.to_string()),
+    };
+    let online = edge
+        .until_stopped(async {
+            for _ in 0..3 {
+                if edge.controller.test().await {
+                    return Ok(true);
+                }
+                sleep(Duration::from_millis(600)).await;
+            }
+            Ok(false)
+        })
+        .await?;
+    if online {
+        Ok(true)
     } else {
-        Err(ProgramError::ChildProcessNotFound.to_string())
+        Err(failure(&manager).unwrap_or(ProgramError::ChildProcessNotFound.to_string()))
     }
 }
 
+/// 由edge日志得出的连接状态
 #[tauri::command]
-pub fn n2n_members(app_handle: AppHandle) -> Result<Vec<Member>, String> {
-    if child_status(N2NClient::NAME) {
-        match CHILDS.lock() {
-            Ok(s) => match s.get(N2NClient::NAME) {
-                None => Err(ProgramError::ChildProcessNotFound.to_string()),
-                Some(p) => match p.write() {
-                    Ok(mut s) => match s.as_any().downcast_mut::<N2NClient>() {
-                        None => Err(ProgramError::DowncastError.to_string()),
-                        Some(c) => {
-                            let config = LocalConfig::get_config(&app_handle);
-                            let member_server = format!(
-                                "{}/members/{}",
-                                config.n2n_config.member_server, config.n2n_config.group
-                            );
-                            if c.controller.test() {
-                                let mut temp = Vec::<Member>::new();
-                                match reqwest::blocking::get(member_server) {
-                                    Ok(response) => {
-                                        if response.status().is_success() {
-                                            match response.text() {
-                                                Ok(res) => {
-                                                    match serde_json::from_str::<Value>(&res) {
-                                                        Ok(res) => {
-                                                            match c.controller.get_vip() {
-                                                                Ok(me) => {
-                                                                    match res["status"].as_bool() {
-                                                                        None => {
-                                                                            Err(ProgramError::TransferError.to_string())
-                                                                        }
-                                                                        Some(s) => {
-                                                                            if s {
-                                                                                let Some(members) = res["members"].as_array() else { return Err(ProgramError::TransferError.to_string()); };
-                                                                                for member in members {
-                                                                                    let Some(ip) = member["ip4addr"].as_str() else { return Err(ProgramError::TransferError.to_string()); };
-                                                                                    if ip.split("/").collect::<Vec<_>>()[0] != me {
-                                                                                        temp.push(Member {
-                                                                                            address: ip.to_string(),
-                                                                                            name: member["desc"].as_str().unwrap_or("Default").to_string(),
-                                                                                            mode: "None".to_string(),
-                                                                                        })
-                                                                                    }
-                                                                                }
-                                                                                for member in c.controller.edges() {
-                                                                                    match temp.iter().position(|x| { x.address == member.address }) {
-                                                                                        None => {}
-                                                                                        Some(index) => {
-                                                                                            temp[index].mode = member.mode
-                                                                                        }
-                                                                                    }
-                                                                                }
-                                                                                Ok(temp)
-                                                                            } else {
-                                                                                Err(ProgramError::TransferError.to_string())
-                                                                            }
-                                                                        }
-                                                                    }
-                                                                }
-                                                                Err(e) => Err(e.to_string()),
-                                                            }
-                                                        }
-                                                        Err(e) => {
-                                                            Err(ProgramError::ChildProcessError(
-                                                                e.to_string(),
-                                                            )
-                                                                .to_string())
-                                                        }
-                                                    }
-                                                }
-                                                Err(e) => {
-                                                    Err(ProgramError::NetworkError(e.to_string())
-                                                        .to_string())
-                                                }
-                                            }
-                                        } else {
-                                            Err(ProgramError::NetworkError(
-                                                response.status().to_string(),
-                                            )
-                                                .to_string())
-                                        }
-                                    }
-                                    Err(e) => {
-                                        Err(ProgramError::NetworkError(e.to_string()).to_string())
-                                    }
-                                }
-                            } else {
-                                Err(ProgramError::ChildProcessError(String::from(
-                                    "Controller Test Failed",
-                                ))
-                                    .to_string())
-                            }
-                        }
-                    },
-                    Err(e) => Err(ProgramError::ChildProcessError(e.to_string()).to_string()),
-                },
-            },
-            Err(e) => Err(ProgramError::ChildProcessError(e.to_string()).to_string()),
-        }
-    } else {
-        Err(ProgramError::ChildProcessNotFound.to_string())
+pub fn n2n_connection_state(
+    app_handle: AppHandle,
+    manager: State<'_, ProcessManager>,
+    profile: Option<String>,
+) -> Result<ConnectionState, String> {
+    let name = profile_process(&app_handle, profile)?;
+    match manager.edges.with(&name, |c| c.connection_state()) {
+        Ok(state) => Ok(state),
+        Err(ProgramError::ChildProcessNotFound) => Ok(ConnectionState::default()),
+        Err(e) => Err(e.to_string()),
     }
 }
 
+/// 运行中的edge的端口映射状态，未启用或未运行时为None
 #[tauri::command]
-pub fn n2n_firewall_check() -> Result<bool, String> {
-    match execute_command(
-        "netsh",
-        vec![
-            "advfirewall",
-            "firewall",
-            "show",
-            "rule",
-            format!("name={}", N2NClient::FIRE_WALL_NAME).as_str(),
-        ],
-    ) {
-        Ok(output_str) => {
-            debug!("{}", output_str);
-            if output_str.contains("No rules match the specified criteria")
-                || output_str.contains("没有与指定标准相匹配的规则")
-            {
-                Ok(false)
-            } else {
-                Ok(true)
-            }
-        }
+pub fn n2n_port_mapping(
+    app_handle: AppHandle,
+    manager: State<'_, ProcessManager>,
+    profile: Option<String>,
+) -> Result<Option<MappingStatus>, String> {
+    let name = profile_process(&app_handle, profile)?;
+    match manager.edges.with(&name, |c| c.port_mapping()) {
+        Ok(status) => Ok(status),
+        Err(ProgramError::ChildProcessNotFound) => Ok(None),
         Err(e) => Err(e.to_string()),
     }
 }
 
 #[tauri::command]
-pub fn n2n_firewall_add() -> Result<(), String> {
-    match std::env::current_dir().map_err(|e| ProgramError::GetCurrentDirError(e.to_string())) {
-        Ok(current) => {
-            match current
-                .join(ExternalFilePosition::N2NClient.to_string())
-                .to_str()
-            {
-                None => Err(ProgramError::FireWallError.to_string()),
-                Some(path) => {
-                    match execute_command(
-                        "netsh",
-                        vec![
-                            "advfirewall",
-                            "firewall",
-                            "add",
-                            "rule",
-                            format!("name={}", N2NClient::FIRE_WALL_NAME).as_str(),
-                            "dir=in",
-                            "action=allow",
-                            format!("program={}", path).as_str(),
-                            "enable=yes",
-                        ],
-                    ) {
-                        Ok(output_str) => {
-                            debug!("{}", output_str);
-                            Ok(())
-                        }
-                        Err(e) => Err(e.to_string()),
-                    }
-                }
-            }
+pub async fn n2n_self_ip(
+    app_handle: AppHandle,
+    manager: State<'_, ProcessManager>,
+    profile: Option<String>,
+) -> Result<String, String> {
+    let edge = RunningEdge::get(&manager, &profile_process(&app_handle, profile)?)?;
+    edge.until_stopped(async { edge.controller.get_vip().await.map_err(|e| e.to_string()) })
+        .await
+}
+
+/// 当前正在使用的超级节点
+#[tauri::command]
+pub async fn n2n_active_supernode(
+    app_handle: AppHandle,
+    manager: State<'_, ProcessManager>,
+    profile: Option<String>,
+) -> Result<Option<SupernodeRow>, String> {
+    let edge = RunningEdge::get(&manager, &profile_process(&app_handle, profile)?)?;
+    edge.until_stopped(async {
+        match edge.controller.supernodes().await {
+            Ok(rows) => Ok(rows.into_iter().find(|r| r.current != 0)),
+            Err(e) => Err(e.to_string()),
         }
-        Err(e) => Err(e.to_string()),
+    })
+    .await
+}
+
+#[tauri::command]
+pub async fn n2n_members(
+    app_handle: AppHandle,
+    manager: State<'_, ProcessManager>,
+    profile: Option<String>,
+) -> Result<Vec<Member>, String> {
+    let edge = RunningEdge::get(&manager, &profile_process(&app_handle, profile)?)?;
+    edge.until_stopped(async {
+        edge.member_server
+            .members(&edge.controller)
+            .await
+            .map_err(|e| e.to_string())
+    })
+    .await
+}
+
+/// 结合NAT报告、管理端口中的对方地址与连接方式、经虚拟ip的往返时间，解释为何没有与成员直连
+#[tauri::command]
+pub async fn n2n_peer_diagnose(
+    app_handle: AppHandle,
+    manager: State<'_, ProcessManager>,
+    profile: Option<String>,
+    member: String,
+) -> Result<PeerDiagnosis, String> {
+    let n2n_config = LocalConfig::get_config(&app_handle)
+        .and_then(|config| config.profile(profile.as_deref()))
+        .map_err(|e| e.to_string())?;
+    let edge = RunningEdge::get(&manager, &N2NClient::process_name(&n2n_config.id))?;
+    let ip: IpAddr = member
+        .split('/')
+        .next()
+        .and_then(|ip| ip.parse().ok())
+        .ok_or_else(|| ProgramError::AddressError(member.clone()).to_string())?;
+    let port = n2n_config.port;
+    let firewall = tauri::async_runtime::spawn_blocking(move || {
+        N2NClient::firewall_rule(port).and_then(|rule| platform::firewall_rule_exists(&rule))
+    });
+    let (rows, rtt, nat) = edge
+        .until_stopped(async {
+            let (rows, rtt, nat) = tokio::join!(
+                edge.controller.edges(),
+                ping::ping_average(ip, Duration::from_secs(2), 3),
+                nat_detect::detect_report(&app_handle, false, profile.as_deref())
+            );
+            Ok((rows.map_err(|e| e.to_string())?, rtt.ok(), nat?))
+        })
+        .await?;
+    let firewall = match firewall.await {
+        Ok(Ok(exists)) => Some(exists),
+        Ok(Err(e)) => {
+            warn!("{}:{}", line!(), e);
+            None
+        }
+        Err(e) => {
+            error!("{}:{}", line!(), e);
+            None
+        }
+    };
+    Ok(peer_diagnostics::diagnose(
+        &member,
+        Evidence {
+            row: peer_diagnostics::find_row(&rows, &member),
+            rtt,
+            nat: &nat,
+            port,
+            port_mapping: n2n_config.port_mapping,
+            firewall,
+        },
+    ))
+}
+
+impl N2NClient {
+    /// edge的防火墙规则
+    fn firewall_rule(port: u16) -> Result<FirewallRule, ProgramError> {
+        let current =
+            std::env::current_dir().map_err(|e| ProgramError::GetCurrentDirError(e.to_string()))?;
+        Ok(FirewallRule {
+            name: Self::FIRE_WALL_NAME,
+            program: Some(current.join(ExternalFilePosition::N2NClient.path())),
+            protocol: FirewallProtocol::Udp(port),
+        })
     }
 }
+
+#[tauri::command]
+pub fn n2n_firewall_check(app_handle: AppHandle, profile: Option<String>) -> Result<bool, String> {
+    LocalConfig::get_config(&app_handle)
+        .and_then(|config| config.profile(profile.as_deref()))
+        .and_then(|p| N2NClient::firewall_rule(p.port))
+        .and_then(|rule| platform::firewall_rule_exists(&rule))
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub fn n2n_firewall_add(app_handle: AppHandle, profile: Option<String>) -> Result<(), String> {
+    LocalConfig::get_config(&app_handle)
+        .and_then(|config| config.profile(profile.as_deref()))
+        .and_then(|p| N2NClient::firewall_rule(p.port))
+        .and_then(|rule| platform::firewall_rule_add(&rule))
+        .map_err(|e| e.to_string())
+}
@@ -0,0 +1,197 @@
+use std::collections::HashSet;
+use std::time::Duration;
+
+use log::{debug, warn};
+use serde::Serialize;
+use tokio::time::sleep;
+use tokio_util::sync::CancellationToken;
+
+use crate::tools::n2n_controller::{Controller, EventRow, Topic};
+
+/// 推送给前端的事件名
+pub const PEER_EVENT: &str = "n2n-peer-event";
+
+/// 成员变化
+#[derive(Serialize, Clone, Debug, PartialEq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum PeerEvent {
+    /// 新成员出现
+    Joined { macaddr: String, sockaddr: String },
+    /// 成员离开
+    Left { macaddr: String, sockaddr: String },
+    /// 建立点对点连接
+    P2pEstablished { macaddr: String, sockaddr: String },
+    /// 回退到超级节点转发
+    Relayed { macaddr: String, sockaddr: String },
+}
+
+/// 根据edge的peer事件推断成员变化
+#[derive(Default)]
+pub struct PeerTracker {
+    known: HashSet<String>,
+}
+
+impl PeerTracker {
+    pub fn handle(&mut self, event: &EventRow) -> Vec<PeerEvent> {
+        let macaddr = event.macaddr.clone();
+        let sockaddr = event.sockaddr.clone();
+        let mut events = Vec::new();
+        match event.action.as_str() {
+            "add_p2p" | "del_p2p" => {
+                if self.known.insert(macaddr.clone()) {
+                    events.push(PeerEvent::Joined {
+                        macaddr: macaddr.clone(),
+                        sockaddr: sockaddr.clone(),
+                    });
+                }
+                if event.action == "add_p2p" {
+                    events.push(PeerEvent::P2pEstablished { macaddr, sockaddr });
+                } else {
+                    events.push(PeerEvent::Relayed { macaddr, sockaddr });
+                }
+            }
+            "purge" | "clear" => {
+                self.known.remove(&macaddr);
+                events.push(PeerEvent::Left { macaddr, sockaddr });
+            }
+            other => {
+                debug!("未处理的peer事件:{}", other);
+            }
+        }
+        events
+    }
+}
+
+/// 带有所属网络配置的成员变化
+#[derive(Serialize, Clone, Debug)]
+pub struct ProfilePeerEvent {
+    pub profile: String,
+    #[serde(flatten)]
+    pub event: PeerEvent,
+}
+
+/// 在管理端口上保持`sub peer`订阅的后台任务
+pub struct PeerSubscriber {
+    cancel: CancellationToken,
+}
+
+impl Drop for PeerSubscriber {
+    fn drop(&mut self) {
+        self.cancel.cancel();
+    }
+}
+
+impl PeerSubscriber {
+    /// 断开后每隔一秒重新订阅，直到被drop或cancel被取消
+    pub fn start<F>(control_port: u16, cancel: CancellationToken, emit: F) -> Self
+    where
+        F: Fn(PeerEvent) + Send + 'static,
+    {
+        let token = cancel.clone();
+        tauri::async_runtime::spawn(async move {
+            tokio::select! {
+                _ = token.cancelled() => {}
+                _ = Self::run(control_port, emit) => {}
+            }
+        });
+        Self { cancel }
+    }
+
+    async fn run<F: Fn(PeerEvent)>(control_port: u16, emit: F) {
+        let mut tracker = PeerTracker::default();
+        loop {
+            let controller = Controller::new(control_port);
+            if let Err(e) = controller.subscribe(Topic::Peer).await {
+                debug!("{}:{}", line!(), e);
+                sleep(Duration::from_secs(1)).await;
+                continue;
+            }
+            loop {
+                match controller.next_event().await {
+                    Ok(Some(event)) => {
+                        for e in tracker.handle(&event) {
+                            emit(e);
+                        }
+                    }
+                    Ok(None) => {}
+                    Err(e) => {
+                        warn!("{}:{}", line!(), e);
+                        break;
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use tokio::sync::mpsc;
+
+    use super::*;
+    use crate::tools::mock_edge::MockEdge;
+
+    fn row(action: &str, macaddr: &str) -> EventRow {
+        serde_json::from_value(json!({"event": "peer", "action": action, "macaddr": macaddr, "sockaddr": "1.2.3.4:5"}))
+            .unwrap()
+    }
+
+    #[test]
+    fn tracker() {
+        let mut tracker = PeerTracker::default();
+        let events = tracker.handle(&row("add_p2p", "a"));
+        assert!(matches!(events[0], PeerEvent::Joined { .. }));
+        assert!(matches!(events[1], PeerEvent::P2pEstablished { .. }));
+
+        let events = tracker.handle(&row("del_p2p", "a"));
+        assert_eq!(events.len(), 1);
+        assert!(matches!(events[0], PeerEvent::Relayed { .. }));
+
+        let events = tracker.handle(&row("purge", "a"));
+        assert!(matches!(events[0], PeerEvent::Left { .. }));
+        assert_eq!(tracker.handle(&row("add_p2p", "a")).len(), 2);
+    }
+
+    #[tokio::test]
+    async fn subscriber_emits() {
+        let edge = MockEdge::start();
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let cancel = CancellationToken::new();
+        let _subscriber = PeerSubscriber::start(edge.port(), cancel.clone(), move |e| {
+            let _ = tx.send(e);
+        });
+        while !edge.subscribed() {
+            sleep(Duration::from_millis(10)).await;
+        }
+        edge.event(json!({"event": "peer", "action": "add_p2p", "macaddr": "a", "sockaddr": "1.2.3.4:5"}));
+        let joined = tokio::time::timeout(Duration::from_secs(3), rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            joined,
+            PeerEvent::Joined {
+                macaddr: "a".to_string(),
+                sockaddr: "1.2.3.4:5".to_string()
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn subscriber_cancelled() {
+        let edge = MockEdge::start();
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let cancel = CancellationToken::new();
+        let _subscriber = PeerSubscriber::start(edge.port(), cancel.clone(), move |e| {
+            let _ = tx.send(e);
+        });
+        while !edge.subscribed() {
+            sleep(Duration::from_millis(10)).await;
+        }
+        cancel.cancel();
+        // 任务结束后发送端被释放
+        let closed = tokio::time::timeout(Duration::from_secs(3), rx.recv()).await.unwrap();
+        assert!(closed.is_none());
+    }
+}
@@ -1,34 +1,69 @@
 use std::fmt::Display;
-use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::net::SocketAddr;
 use std::time::Duration;
 
-use stunclient::StunClient;
-use tauri::AppHandle;
+use log::{error, info};
+use serde::Serialize;
+use tauri::{AppHandle, Manager};
 use thiserror::Error;
 
 use crate::config::LocalConfig;
-
-#[derive(Debug)]
+use crate::tools::nat_detect::behavior::Discovery;
+use crate::tools::nat_detect::report::NatReport;
+use crate::tools::nat_detect::servers::{KnownGood, ServerProbe};
+use crate::tools::n2n_client::N2NClient;
+use crate::tools::port_mapping::{self, Gateway, MappingStatus};
+use crate::tools::process_manager::ProcessManager;
+
+pub mod behavior;
+#[cfg(test)]
+pub mod fake_stun;
+pub mod report;
+pub mod servers;
+pub mod stun;
+
+/// 整个检测的超时，不包括测量映射保持时间
+const DETECT_TIMEOUT: Duration = Duration::from_secs(30);
+/// 测量映射保持时间时每次增加的等待，累计约5分钟
+const LIFETIME_STEPS: [Duration; 6] = [
+    Duration::from_secs(5),
+    Duration::from_secs(10),
+    Duration::from_secs(20),
+    Duration::from_secs(40),
+    Duration::from_secs(80),
+    Duration::from_secs(160),
+];
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
 pub enum NatType {
     OpenInternet,
     FullCone,
     RestrictedCone,
     PortRestrictedCone,
     Symmetric,
+    /// 没有NAT，但防火墙过滤了未发送过的地址
+    SymmetricUdpFirewall,
+    /// 所有服务器都没有应答
+    UdpBlocked,
+    Unknown,
 }
 
 #[derive(Debug, Error)]
 pub enum NatError {
     #[error("Failed to resolve STUN server address")]
     StunServerResolutionError,
+    #[error("No STUN server configured")]
+    NoServer,
     #[error("Failed to bind to local address")]
     LocalBindError,
     #[error("UDP socket is blocked")]
     UdpBlocked,
-    #[error("Symmetric UDP firewall detected")]
-    SymmetricUdpFirewall,
-    #[error("Unknown error occurred")]
-    Unknown,
+    #[error("NAT detection timed out")]
+    Timeout,
+    #[error("Malformed STUN message")]
+    Malformed,
+    #[error("STUN server error {0}: {1}")]
+    ServerError(u16, String),
 }
 
 impl Display for NatType {
@@ -37,90 +72,81 @@ impl Display for NatType {
     }
 }
 
-impl NatType {
-    fn perform_test_with_retries(
-        udp: &UdpSocket,
-        stun_addr: SocketAddr,
-        retries: usize,
-    ) -> Result<Option<SocketAddr>, NatError> {
-        let client = StunClient::new(stun_addr);
-        for _ in 0..retries {
-            match client.query_external_address(udp) {
-                Ok(addr) => return Ok(Some(addr)),
-                Err(_) => continue,
-            }
-        }
-        Ok(None)
-    }
-
-    pub fn detect(stun_server1: &str, stun_server2: &str) -> Result<Self, NatError> {
-        let local_addr: SocketAddr = "0.0.0.0:0".parse().unwrap();
-        let stun_addr = stun_server1
-            .to_socket_addrs()
-            .map_err(|_| NatError::StunServerResolutionError)?
-            .find(|x| x.is_ipv4())
-            .ok_or(NatError::StunServerResolutionError)?;
-
-        let udp = UdpSocket::bind(local_addr).map_err(|_| NatError::LocalBindError)?;
-
-        // Set a timeout for the UDP socket
-        udp.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
-
-        // Test I: Initial STUN request
-        let external_addr_1 = match Self::perform_test_with_retries(&udp, stun_addr, 3)? {
-            Some(addr) => addr,
-            None => return Err(NatError::UdpBlocked),
-        };
-
-        // Check if external address matches local address
-        if external_addr_1.ip() == udp.local_addr().unwrap().ip() {
-            return Ok(Self::OpenInternet);
-        }
-
-        // Test II: STUN request to a different STUN server
-        let another_stun_addr = stun_server2
-            .to_socket_addrs()
-            .map_err(|_| NatError::StunServerResolutionError)?
-            .find(|x| x.is_ipv4())
-            .ok_or(NatError::StunServerResolutionError)?;
-
-        let external_addr_2 = match Self::perform_test_with_retries(&udp, another_stun_addr, 3)? {
-            Some(addr) => addr,
-            None => return Err(NatError::SymmetricUdpFirewall),
-        };
-
-        if external_addr_1.ip() != external_addr_2.ip() {
-            return Ok(Self::Symmetric);
-        }
+/// 解析服务器地址，只使用IPv4
+pub async fn resolve(server: &str) -> Result<SocketAddr, NatError> {
+    tokio::net::lookup_host(server)
+        .await
+        .map_err(|_| NatError::StunServerResolutionError)?
+        .find(|x| x.is_ipv4())
+        .ok_or(NatError::StunServerResolutionError)
+}
 
-        // Test III: Rebind to another port and perform STUN request to the same server
-        let new_udp = UdpSocket::bind(local_addr).map_err(|_| NatError::LocalBindError)?;
-        new_udp
-            .set_read_timeout(Some(Duration::from_secs(2)))
-            .unwrap();
-        let external_addr_3 = match Self::perform_test_with_retries(&new_udp, stun_addr, 3)? {
-            Some(addr) => addr,
-            None => return Err(NatError::Unknown),
-        };
+/// 只在配置开启端口映射时检测。运行中的edge使用自己的映射状态，否则临时映射一次edge端口
+async fn port_mapping_status(
+    app_handle: &AppHandle,
+    config: &LocalConfig,
+    profile: Option<&str>,
+) -> Option<MappingStatus> {
+    let n2n_config = config.profile(profile).ok()?;
+    if !n2n_config.port_mapping {
+        return None;
+    }
+    let manager = app_handle.state::<ProcessManager>();
+    let name = N2NClient::process_name(&n2n_config.id);
+    if manager.edges.running(&name) {
+        return manager.edges.with(&name, |c| c.port_mapping()).ok().flatten();
+    }
+    // 读取路由表可能需要执行命令
+    let gateway = tauri::async_runtime::spawn_blocking(Gateway::detect).await.ok()?;
+    Some(port_mapping::probe(&gateway, n2n_config.port).await)
+}
 
-        if external_addr_1 == external_addr_3 {
-            return Ok(Self::FullCone);
+/// 检测NAT行为并生成报告，同时附上配置对应edge端口的映射状态
+pub(crate) async fn detect_report(
+    app_handle: &AppHandle,
+    lifetime: bool,
+    profile: Option<&str>,
+) -> Result<NatReport, String> {
+    let config = LocalConfig::get_config(app_handle).map_err(|e| e.to_string())?;
+    let position = KnownGood::position().map_err(|e| e.to_string())?;
+    let mut known = KnownGood::load(&position);
+    let lifetime = lifetime.then_some(&LIFETIME_STEPS[..]);
+    let discovery = Discovery::new(SocketAddr::from(([0, 0, 0, 0], 0)));
+    let (mut report, mapping) = tokio::join!(
+        report::generate(&config.stun_servers, &known, &discovery, lifetime, DETECT_TIMEOUT),
+        port_mapping_status(app_handle, &config, profile)
+    );
+    report.port_mapping = mapping;
+    report.p2p = report::verdict(&report);
+    info!("NAT检测报告:{}", report.to_json());
+    if known.update(&report.servers) {
+        if let Err(e) = known.save(&position) {
+            error!("{}:{}", line!(), e);
         }
-
-        // Check if port is the same
-        return if external_addr_1.port() == external_addr_3.port() {
-            Ok(Self::RestrictedCone)
-        } else {
-            Ok(Self::PortRestrictedCone)
-        };
     }
+    Ok(report)
+}
+
+/// 检测NAT行为并生成报告，`lifetime`为true时额外测量映射保持时间，约需5分钟
+#[tauri::command]
+pub async fn nat_detect(
+    app_handle: AppHandle,
+    lifetime: Option<bool>,
+    profile: Option<String>,
+) -> Result<NatReport, String> {
+    detect_report(&app_handle, lifetime.unwrap_or(false), profile.as_deref()).await
 }
 
+/// 询问服务器池中的每个STUN服务器并排序，同时更新上次可用的服务器
 #[tauri::command]
-pub async fn nat_detect(app_handle: AppHandle) -> Result<String, String> {
-    let config = LocalConfig::get_config(&app_handle);
-    match NatType::detect(config.nat_detect[0].as_str(), config.nat_detect[1].as_str()) {
-        Ok(s) => Ok(s.to_string()),
-        Err(e) => Err(e.to_string()),
+pub async fn stun_servers_probe(app_handle: AppHandle) -> Result<Vec<ServerProbe>, String> {
+    let config = LocalConfig::get_config(&app_handle).map_err(|e| e.to_string())?;
+    let discovery = Discovery::new(SocketAddr::from(([0, 0, 0, 0], 0)));
+    let probes = servers::order(servers::probe(&config.stun_servers, &discovery).await);
+    let position = KnownGood::position().map_err(|e| e.to_string())?;
+    let mut known = KnownGood::load(&position);
+    if known.update(&probes) {
+        known.save(&position).map_err(|e| e.to_string())?;
     }
+    Ok(probes)
 }
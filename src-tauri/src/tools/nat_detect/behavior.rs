@@ -0,0 +1,578 @@
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+use log::warn;
+use serde::Serialize;
+
+use crate::tools::address;
+use crate::tools::nat_detect::stun::{Attribute, BindingResponse, Message, StunClient};
+use crate::tools::nat_detect::{NatError, NatType};
+
+/// 每次请求等待应答的时间，与RFC 5389的初始RTO相同
+const REQUEST_TIMEOUT: Duration = Duration::from_millis(500);
+const RETRIES: usize = 3;
+
+/// RFC 5780 4.3 映射行为
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+pub enum MappingBehavior {
+    EndpointIndependent,
+    AddressDependent,
+    AddressAndPortDependent,
+    Unknown,
+}
+
+/// RFC 5780 4.4 过滤行为
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+pub enum FilteringBehavior {
+    EndpointIndependent,
+    AddressDependent,
+    AddressAndPortDependent,
+    Unknown,
+}
+
+/// RFC 5780 4.6 映射保持时间，`alive_ms`时仍可收到，`expired_ms`时已失效
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct BindingLifetime {
+    pub alive_ms: u64,
+    pub expired_ms: Option<u64>,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct NatBehavior {
+    /// 检测使用的本地地址
+    pub local: SocketAddr,
+    /// 主服务器看到的映射地址
+    pub mapped: SocketAddr,
+    /// 映射地址就是本机地址，没有经过NAT
+    pub direct: bool,
+    /// 支持RFC 5780的服务器，没有时只能比较两个服务器看到的映射
+    pub server: Option<SocketAddr>,
+    pub mapping: MappingBehavior,
+    pub filtering: FilteringBehavior,
+    pub binding_lifetime: Option<BindingLifetime>,
+}
+
+impl NatBehavior {
+    /// 换算为经典的NAT类型
+    pub fn nat_type(&self) -> NatType {
+        if self.direct {
+            return match self.filtering {
+                FilteringBehavior::EndpointIndependent | FilteringBehavior::Unknown => {
+                    NatType::OpenInternet
+                }
+                _ => NatType::SymmetricUdpFirewall,
+            };
+        }
+        match (self.mapping, self.filtering) {
+            (MappingBehavior::EndpointIndependent, FilteringBehavior::EndpointIndependent) => {
+                NatType::FullCone
+            }
+            (MappingBehavior::EndpointIndependent, FilteringBehavior::AddressDependent) => {
+                NatType::RestrictedCone
+            }
+            // 过滤行为未知时按最严格的情况
+            (MappingBehavior::EndpointIndependent, _) => NatType::PortRestrictedCone,
+            (MappingBehavior::Unknown, _) => NatType::Unknown,
+            _ => NatType::Symmetric,
+        }
+    }
+}
+
+/// 按RFC 5780检测NAT行为
+#[derive(Clone)]
+pub struct Discovery {
+    local: SocketAddr,
+    timeout: Duration,
+    retries: usize,
+}
+
+impl Discovery {
+    pub fn new(local: SocketAddr) -> Self {
+        Self {
+            local,
+            timeout: REQUEST_TIMEOUT,
+            retries: RETRIES,
+        }
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration, retries: usize) -> Self {
+        self.timeout = timeout;
+        self.retries = retries;
+        self
+    }
+
+    async fn client(&self) -> Result<StunClient, NatError> {
+        StunClient::bind(self.local, self.timeout, self.retries).await
+    }
+
+    /// 依次询问服务器，找到支持RFC 5780的服务器后检测映射与过滤行为。
+    /// 都不支持时比较前两个服务器看到的映射，过滤行为未知
+    pub async fn run(&self, servers: &[SocketAddr]) -> Result<NatBehavior, NatError> {
+        let client = self.client().await?;
+        let mut responses: Vec<(SocketAddr, BindingResponse)> = Vec::new();
+        for &server in servers {
+            match client.binding(server, vec![]).await {
+                Ok(Some(response)) => {
+                    let rfc5780 = response.other.is_some();
+                    responses.push((server, response));
+                    if rfc5780 {
+                        break;
+                    }
+                }
+                Ok(None) => {}
+                Err(e) => warn!("{}:{}:{}", line!(), server, e),
+            }
+        }
+        let primary = responses.iter().position(|(_, r)| r.other.is_some());
+        let (server, first) = responses
+            .get(primary.unwrap_or_default())
+            .cloned()
+            .ok_or(NatError::UdpBlocked)?;
+        let local = client.local_addr()?;
+        let direct = is_local(first.mapped, local);
+        let (mapping, filtering) = match first.other {
+            Some(other) => {
+                let mapping = match direct {
+                    true => MappingBehavior::EndpointIndependent,
+                    false => self.mapping(&client, &first, server, other).await?,
+                };
+                (mapping, self.filtering(server).await?)
+            }
+            None => {
+                let mapping = match (direct, responses.get(1)) {
+                    (true, _) => MappingBehavior::EndpointIndependent,
+                    (false, Some((_, second))) if second.mapped == first.mapped => {
+                        MappingBehavior::EndpointIndependent
+                    }
+                    // 无法区分是否与端口有关，按最严格的情况
+                    (false, Some(_)) => MappingBehavior::AddressAndPortDependent,
+                    (false, None) => MappingBehavior::Unknown,
+                };
+                (mapping, FilteringBehavior::Unknown)
+            }
+        };
+        Ok(NatBehavior {
+            local,
+            mapped: first.mapped,
+            direct,
+            server: first.other.map(|_| server),
+            mapping,
+            filtering,
+            binding_lifetime: None,
+        })
+    }
+
+    /// 4.3 向服务器的另一个ip、另一个ip与端口请求，比较映射地址。服务器返回错误时无法判断
+    async fn mapping(
+        &self,
+        client: &StunClient,
+        first: &BindingResponse,
+        server: SocketAddr,
+        other: SocketAddr,
+    ) -> Result<MappingBehavior, NatError> {
+        let second = client
+            .binding(SocketAddr::new(other.ip(), server.port()), vec![])
+            .await;
+        let Some(second) = rejected(second)?.flatten() else {
+            return Ok(MappingBehavior::Unknown);
+        };
+        if second.mapped == first.mapped {
+            return Ok(MappingBehavior::EndpointIndependent);
+        }
+        let Some(third) = rejected(client.binding(other, vec![]).await)?.flatten() else {
+            return Ok(MappingBehavior::Unknown);
+        };
+        match third.mapped == second.mapped {
+            true => Ok(MappingBehavior::AddressDependent),
+            false => Ok(MappingBehavior::AddressAndPortDependent),
+        }
+    }
+
+    /// 4.4 用新的本地端口，只与主地址通信后，请求服务器换ip和端口、只换端口回复。
+    /// 服务器拒绝CHANGE-REQUEST时无法判断
+    async fn filtering(&self, server: SocketAddr) -> Result<FilteringBehavior, NatError> {
+        let client = self.client().await?;
+        if client.binding(server, vec![]).await?.is_none() {
+            return Ok(FilteringBehavior::Unknown);
+        }
+        let changed =
+            |ip, port| client.binding(server, vec![Attribute::ChangeRequest { ip, port }]);
+        match rejected(changed(true, true).await)? {
+            None => return Ok(FilteringBehavior::Unknown),
+            // 服务器没有换地址回复，无法判断
+            Some(Some(response)) if response.source == server => {
+                return Ok(FilteringBehavior::Unknown)
+            }
+            Some(Some(_)) => return Ok(FilteringBehavior::EndpointIndependent),
+            Some(None) => {}
+        }
+        match rejected(changed(false, true).await)? {
+            None => Ok(FilteringBehavior::Unknown),
+            Some(Some(response)) if response.source == server => Ok(FilteringBehavior::Unknown),
+            Some(Some(_)) => Ok(FilteringBehavior::AddressDependent),
+            Some(None) => Ok(FilteringBehavior::AddressAndPortDependent),
+        }
+    }
+
+    /// 从同一个本地端口分别询问每个服务器
+    pub async fn probe(
+        &self,
+        servers: &[SocketAddr],
+    ) -> Result<Vec<Result<Option<BindingResponse>, NatError>>, NatError> {
+        let client = self.client().await?;
+        let mut responses = Vec::with_capacity(servers.len());
+        for &server in servers {
+            responses.push(client.binding(server, vec![]).await);
+        }
+        Ok(responses)
+    }
+
+    /// 4.5 从另一个本地端口向第一个端口的映射地址发送请求，能收到时NAT支持回环
+    pub async fn hairpinning(&self, server: SocketAddr) -> Result<bool, NatError> {
+        let target = self.client().await?;
+        let sender = self.client().await?;
+        let Some(first) = target.binding(server, vec![]).await? else {
+            return Err(NatError::UdpBlocked);
+        };
+        let request = Message::request(vec![]);
+        for _ in 0..self.retries {
+            sender.send(first.mapped, &request).await?;
+            if target
+                .receive(&request.transaction, self.timeout)
+                .await?
+                .is_some()
+            {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// 依次用新的本地端口询问同一服务器，观察映射端口的分配规律
+    pub async fn port_allocation(
+        &self,
+        server: SocketAddr,
+        samples: usize,
+    ) -> Result<PortAllocation, NatError> {
+        let mut ports = Vec::with_capacity(samples);
+        // 保持所有端口打开，避免本地端口被复用
+        let mut clients = Vec::with_capacity(samples);
+        for _ in 0..samples {
+            let client = self.client().await?;
+            if let Some(response) = client.binding(server, vec![]).await? {
+                ports.push((client.local_addr()?.port(), response.mapped.port()));
+            }
+            clients.push(client);
+        }
+        Ok(PortAllocation::classify(&ports))
+    }
+
+    /// 4.6 建立映射后按间隔累计等待，从另一个端口请求服务器把应答发到映射端口，
+    /// 收不到时映射已失效。服务器不支持RESPONSE-PORT时返回None
+    pub async fn binding_lifetime(
+        &self,
+        server: SocketAddr,
+        steps: &[Duration],
+    ) -> Result<Option<BindingLifetime>, NatError> {
+        let probe = self.client().await?;
+        let sender = self.client().await?;
+        let Some(first) = probe.binding(server, vec![]).await? else {
+            return Err(NatError::UdpBlocked);
+        };
+        let mut lifetime = BindingLifetime {
+            alive_ms: 0,
+            expired_ms: None,
+        };
+        let mut elapsed = Duration::ZERO;
+        for step in steps {
+            tokio::time::sleep(*step).await;
+            elapsed += *step;
+            let request = Message::request(vec![Attribute::ResponsePort(first.mapped.port())]);
+            let mut alive = false;
+            for _ in 0..self.retries {
+                sender.send(server, &request).await?;
+                tokio::select! {
+                    received = probe.receive(&request.transaction, self.timeout) => {
+                        if received?.is_some() {
+                            alive = true;
+                            break;
+                        }
+                    }
+                    // 应答或错误回到了发送端口
+                    received = sender.receive(&request.transaction, self.timeout) => {
+                        if received?.is_some() {
+                            return Ok(None);
+                        }
+                    }
+                }
+            }
+            let ms = elapsed.as_millis() as u64;
+            match alive {
+                true => lifetime.alive_ms = ms,
+                false => {
+                    lifetime.expired_ms = Some(ms);
+                    break;
+                }
+            }
+        }
+        Ok(Some(lifetime))
+    }
+}
+
+/// 新映射的端口分配方式
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+pub enum PortAllocation {
+    /// 映射端口与本地端口相同
+    Preserved,
+    /// 依次递增，间隔不超过`SEQUENTIAL_GAP`
+    Sequential,
+    Random,
+    Unknown,
+}
+
+/// 相邻两次映射的端口差不超过这个值时视为顺序分配，其他设备的连接也会占用端口
+const SEQUENTIAL_GAP: u16 = 16;
+
+impl PortAllocation {
+    /// 由(本地端口, 映射端口)判断
+    pub fn classify(ports: &[(u16, u16)]) -> Self {
+        if ports.len() < 2 {
+            return Self::Unknown;
+        }
+        if ports.iter().all(|(local, mapped)| local == mapped) {
+            return Self::Preserved;
+        }
+        let sequential = ports
+            .windows(2)
+            .all(|w| w[1].1 > w[0].1 && w[1].1 - w[0].1 <= SEQUENTIAL_GAP);
+        match sequential {
+            true => Self::Sequential,
+            false => Self::Random,
+        }
+    }
+}
+
+/// 服务器返回错误应答时记录并返回None，其他错误照常返回
+fn rejected<T>(result: Result<T, NatError>) -> Result<Option<T>, NatError> {
+    match result {
+        Ok(value) => Ok(Some(value)),
+        Err(NatError::ServerError(code, reason)) => {
+            warn!("{}:{} {}", line!(), code, reason);
+            Ok(None)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// 映射地址是否就是本机地址
+fn is_local(mapped: SocketAddr, local: SocketAddr) -> bool {
+    if mapped.port() != local.port() {
+        return false;
+    }
+    match local.ip().is_unspecified() {
+        false => mapped.ip() == local.ip(),
+        true => address::local_networks()
+            .iter()
+            .any(|(_, network)| IpAddr::V4(network.addr) == mapped.ip()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tools::nat_detect::fake_stun::{FakeNat, FakeStun};
+
+    fn discovery() -> Discovery {
+        Discovery::new("127.0.0.1:0".parse().unwrap()).with_timeout(Duration::from_millis(150), 2)
+    }
+
+    async fn detect(nat: FakeNat) -> NatBehavior {
+        let stun = FakeStun::start(nat, true).await;
+        discovery().run(&[stun.primary]).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn cone() {
+        let behavior = detect(FakeNat::behind(
+            MappingBehavior::EndpointIndependent,
+            FilteringBehavior::EndpointIndependent,
+        ))
+        .await;
+        assert!(!behavior.direct);
+        assert_eq!(behavior.mapping, MappingBehavior::EndpointIndependent);
+        assert_eq!(behavior.filtering, FilteringBehavior::EndpointIndependent);
+        assert_eq!(behavior.nat_type(), NatType::FullCone);
+
+        let behavior = detect(FakeNat::behind(
+            MappingBehavior::EndpointIndependent,
+            FilteringBehavior::AddressDependent,
+        ))
+        .await;
+        assert_eq!(behavior.filtering, FilteringBehavior::AddressDependent);
+        assert_eq!(behavior.nat_type(), NatType::RestrictedCone);
+
+        let behavior = detect(FakeNat::behind(
+            MappingBehavior::EndpointIndependent,
+            FilteringBehavior::AddressAndPortDependent,
+        ))
+        .await;
+        assert_eq!(
+            behavior.filtering,
+            FilteringBehavior::AddressAndPortDependent
+        );
+        assert_eq!(behavior.nat_type(), NatType::PortRestrictedCone);
+    }
+
+    #[tokio::test]
+    async fn symmetric() {
+        let behavior = detect(FakeNat::behind(
+            MappingBehavior::AddressDependent,
+            FilteringBehavior::AddressDependent,
+        ))
+        .await;
+        assert_eq!(behavior.mapping, MappingBehavior::AddressDependent);
+        assert_eq!(behavior.nat_type(), NatType::Symmetric);
+
+        let behavior = detect(FakeNat::behind(
+            MappingBehavior::AddressAndPortDependent,
+            FilteringBehavior::AddressAndPortDependent,
+        ))
+        .await;
+        assert_eq!(behavior.mapping, MappingBehavior::AddressAndPortDependent);
+        assert_eq!(
+            behavior.filtering,
+            FilteringBehavior::AddressAndPortDependent
+        );
+    }
+
+    #[tokio::test]
+    async fn direct() {
+        let behavior = detect(FakeNat::direct(FilteringBehavior::EndpointIndependent)).await;
+        assert!(behavior.direct);
+        assert_eq!(behavior.mapped, behavior.local);
+        assert_eq!(behavior.nat_type(), NatType::OpenInternet);
+
+        let behavior = detect(FakeNat::direct(FilteringBehavior::AddressAndPortDependent)).await;
+        assert_eq!(behavior.nat_type(), NatType::SymmetricUdpFirewall);
+    }
+
+    #[tokio::test]
+    async fn classic_servers() {
+        // 两个服务器都在127.0.0.1，只有端口不同
+        let first = FakeStun::start(
+            FakeNat::behind(
+                MappingBehavior::AddressAndPortDependent,
+                FilteringBehavior::EndpointIndependent,
+            ),
+            false,
+        )
+        .await;
+        let second = first.another(false).await;
+        let behavior = discovery()
+            .run(&[first.primary, second.primary])
+            .await
+            .unwrap();
+        assert_eq!(behavior.server, None);
+        assert_eq!(behavior.mapping, MappingBehavior::AddressAndPortDependent);
+        assert_eq!(behavior.filtering, FilteringBehavior::Unknown);
+
+        assert!(matches!(
+            discovery().run(&[]).await,
+            Err(NatError::UdpBlocked)
+        ));
+    }
+
+    #[tokio::test]
+    async fn hairpinning_and_allocation() {
+        let stun = FakeStun::start(
+            FakeNat::direct(FilteringBehavior::EndpointIndependent),
+            true,
+        )
+        .await;
+        assert!(discovery().hairpinning(stun.primary).await.unwrap());
+        assert_eq!(
+            discovery().port_allocation(stun.primary, 3).await.unwrap(),
+            PortAllocation::Preserved
+        );
+
+        // 模拟的公网地址不可达
+        let stun = FakeStun::start(
+            FakeNat::behind(
+                MappingBehavior::EndpointIndependent,
+                FilteringBehavior::EndpointIndependent,
+            ),
+            true,
+        )
+        .await;
+        assert!(!discovery().hairpinning(stun.primary).await.unwrap());
+        assert_eq!(
+            discovery().port_allocation(stun.primary, 3).await.unwrap(),
+            PortAllocation::Sequential
+        );
+    }
+
+    #[tokio::test]
+    async fn change_request_rejected() {
+        let stun = FakeStun::start(
+            FakeNat::behind(
+                MappingBehavior::EndpointIndependent,
+                FilteringBehavior::AddressDependent,
+            ),
+            true,
+        )
+        .await;
+        stun.reject_change_request();
+        let behavior = discovery().run(&[stun.primary]).await.unwrap();
+        assert_eq!(behavior.mapping, MappingBehavior::EndpointIndependent);
+        assert_eq!(behavior.filtering, FilteringBehavior::Unknown);
+        assert_eq!(behavior.nat_type(), NatType::PortRestrictedCone);
+    }
+
+    #[test]
+    fn classify_allocation() {
+        assert_eq!(
+            PortAllocation::classify(&[(1000, 1000)]),
+            PortAllocation::Unknown
+        );
+        assert_eq!(
+            PortAllocation::classify(&[(1000, 2000), (1001, 2001), (1002, 2004)]),
+            PortAllocation::Sequential
+        );
+        assert_eq!(
+            PortAllocation::classify(&[(1000, 2000), (1001, 31000), (1002, 9000)]),
+            PortAllocation::Random
+        );
+    }
+
+    #[tokio::test]
+    async fn lifetime() {
+        let mut nat = FakeNat::behind(
+            MappingBehavior::EndpointIndependent,
+            FilteringBehavior::AddressAndPortDependent,
+        );
+        nat.lifetime = Some(Duration::from_millis(250));
+        let stun = FakeStun::start(nat, true).await;
+        let steps = [100, 200, 200].map(Duration::from_millis);
+        let lifetime = discovery()
+            .binding_lifetime(stun.primary, &steps)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            lifetime,
+            BindingLifetime {
+                alive_ms: 100,
+                expired_ms: Some(300)
+            }
+        );
+
+        let classic = stun.another(false).await;
+        assert_eq!(
+            discovery()
+                .binding_lifetime(classic.primary, &steps)
+                .await
+                .unwrap(),
+            None
+        );
+    }
+}
@@ -0,0 +1,260 @@
+//! 测试用的STUN服务器，在127.0.0.1与127.0.0.2的两个端口上应答，并模拟客户端所在NAT的映射、过滤与超时
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use tokio::net::UdpSocket;
+use tokio::task::JoinHandle;
+
+use crate::tools::nat_detect::behavior::{FilteringBehavior, MappingBehavior};
+use crate::tools::nat_detect::stun::{
+    Attribute, Message, BINDING_ERROR, BINDING_REQUEST, BINDING_SUCCESS,
+};
+
+const PRIMARY_IP: Ipv4Addr = Ipv4Addr::new(127, 0, 0, 1);
+const ALTERNATE_IP: Ipv4Addr = Ipv4Addr::new(127, 0, 0, 2);
+/// 模拟的公网ip，没有人监听，发往它的报文会被丢弃，相当于不支持回环的NAT
+const PUBLIC_IP: Ipv4Addr = Ipv4Addr::new(127, 0, 0, 3);
+
+/// 模拟的NAT
+#[derive(Clone, Debug)]
+pub struct FakeNat {
+    pub mapping: MappingBehavior,
+    pub filtering: FilteringBehavior,
+    /// 没有向外发送时映射保持的时间
+    pub lifetime: Option<Duration>,
+    /// 公网ip，为None时不转换地址，只模拟防火墙
+    pub public: Option<IpAddr>,
+}
+
+impl FakeNat {
+    pub fn behind(mapping: MappingBehavior, filtering: FilteringBehavior) -> Self {
+        Self {
+            mapping,
+            filtering,
+            lifetime: None,
+            public: Some(IpAddr::V4(PUBLIC_IP)),
+        }
+    }
+
+    pub fn direct(filtering: FilteringBehavior) -> Self {
+        Self {
+            mapping: MappingBehavior::EndpointIndependent,
+            filtering,
+            lifetime: None,
+            public: None,
+        }
+    }
+}
+
+struct State {
+    nat: FakeNat,
+    /// (内部地址, 映射键) -> 公网端口
+    mappings: HashMap<(SocketAddr, Option<SocketAddr>), u16>,
+    /// 内部地址发送过的目的地址
+    sent: HashMap<SocketAddr, HashSet<SocketAddr>>,
+    /// 内部地址最后一次向外发送的时间
+    last_sent: HashMap<SocketAddr, Instant>,
+    next_port: u16,
+    /// 对CHANGE-REQUEST返回420错误
+    reject_change: bool,
+}
+
+impl State {
+    /// 内部地址发往目的地址时的映射
+    fn outbound(&mut self, from: SocketAddr, to: SocketAddr) -> SocketAddr {
+        self.sent.entry(from).or_default().insert(to);
+        self.last_sent.insert(from, Instant::now());
+        let Some(public) = self.nat.public else {
+            return from;
+        };
+        let key = match self.nat.mapping {
+            MappingBehavior::AddressDependent => Some(SocketAddr::new(to.ip(), 0)),
+            MappingBehavior::AddressAndPortDependent => Some(to),
+            _ => None,
+        };
+        let next = &mut self.next_port;
+        let port = *self.mappings.entry((from, key)).or_insert_with(|| {
+            *next += 1;
+            *next
+        });
+        SocketAddr::new(public, port)
+    }
+
+    /// 公网端口对应的内部地址
+    fn inbound(&self, port: u16) -> Option<SocketAddr> {
+        match self.nat.public {
+            None => Some(SocketAddr::new(IpAddr::V4(PRIMARY_IP), port)),
+            Some(_) => self
+                .mappings
+                .iter()
+                .find(|(_, p)| **p == port)
+                .map(|((addr, _), _)| *addr),
+        }
+    }
+
+    /// 按过滤行为与映射时间判断来自`from`的报文能否到达内部地址
+    fn permits(&self, to: SocketAddr, from: SocketAddr) -> bool {
+        let expired = self
+            .nat
+            .lifetime
+            .zip(self.last_sent.get(&to))
+            .is_some_and(|(lifetime, last)| last.elapsed() > lifetime);
+        let sent = self.sent.get(&to);
+        !expired
+            && match self.nat.filtering {
+                FilteringBehavior::AddressDependent => {
+                    sent.is_some_and(|s| s.iter().any(|a| a.ip() == from.ip()))
+                }
+                FilteringBehavior::AddressAndPortDependent => {
+                    sent.is_some_and(|s| s.contains(&from))
+                }
+                _ => true,
+            }
+    }
+}
+
+pub struct FakeStun {
+    /// 127.0.0.1上的主地址
+    pub primary: SocketAddr,
+    state: Arc<Mutex<State>>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl Drop for FakeStun {
+    fn drop(&mut self) {
+        self.tasks.iter().for_each(JoinHandle::abort);
+    }
+}
+
+impl FakeStun {
+    /// `rfc5780`为false时像经典服务器一样不返回OTHER-ADDRESS，不接受RESPONSE-PORT
+    pub async fn start(nat: FakeNat, rfc5780: bool) -> Self {
+        let state = State {
+            nat,
+            mappings: HashMap::new(),
+            sent: HashMap::new(),
+            last_sent: HashMap::new(),
+            next_port: 40000,
+            reject_change: false,
+        };
+        Self::serve(Arc::new(Mutex::new(state)), rfc5780).await
+    }
+
+    /// 之后像不支持CHANGE-REQUEST的服务器一样返回错误
+    pub fn reject_change_request(&self) {
+        self.state.lock().unwrap().reject_change = true;
+    }
+
+    /// 位于同一NAT之后的另一个服务器
+    pub async fn another(&self, rfc5780: bool) -> Self {
+        Self::serve(self.state.clone(), rfc5780).await
+    }
+
+    async fn serve(state: Arc<Mutex<State>>, rfc5780: bool) -> Self {
+        // [主ip主端口, 主ip副端口, 副ip主端口, 副ip副端口]
+        // 副ip上的同一端口可能已被占用，换端口重试
+        let mut sockets = Self::bind().await;
+        for _ in 0..10 {
+            if sockets.is_ok() {
+                break;
+            }
+            sockets = Self::bind().await;
+        }
+        let sockets = Arc::new(sockets.expect("bind 127.0.0.1/127.0.0.2"));
+        let primary = sockets[0].local_addr().unwrap();
+        let tasks = (0..sockets.len())
+            .map(|index| {
+                let sockets = sockets.clone();
+                let state = state.clone();
+                tokio::spawn(async move {
+                    let mut buf = [0; 1500];
+                    while let Ok((len, source)) = sockets[index].recv_from(&mut buf).await {
+                        let Ok(request) = Message::decode(&buf[..len]) else {
+                            continue;
+                        };
+                        if request.kind != BINDING_REQUEST {
+                            continue;
+                        }
+                        let reply = Self::reply(&state, &sockets, index, source, request, rfc5780);
+                        if let Some((from, to, response)) = reply {
+                            let _ = sockets[from].send_to(&response.encode(), to).await;
+                        }
+                    }
+                })
+            })
+            .collect();
+        Self {
+            primary,
+            state,
+            tasks,
+        }
+    }
+
+    async fn bind() -> std::io::Result<[UdpSocket; 4]> {
+        let primary = UdpSocket::bind((PRIMARY_IP, 0)).await?;
+        let port = primary.local_addr()?.port();
+        let secondary = UdpSocket::bind((PRIMARY_IP, 0)).await?;
+        let other_port = secondary.local_addr()?.port();
+        Ok([
+            primary,
+            secondary,
+            UdpSocket::bind((ALTERNATE_IP, port)).await?,
+            UdpSocket::bind((ALTERNATE_IP, other_port)).await?,
+        ])
+    }
+
+    /// 返回(应答使用的socket, 实际送达的内部地址, 应答)，被NAT丢弃时为None
+    fn reply(
+        state: &Mutex<State>,
+        sockets: &[UdpSocket; 4],
+        index: usize,
+        source: SocketAddr,
+        request: Message,
+        rfc5780: bool,
+    ) -> Option<(usize, SocketAddr, Message)> {
+        let address = |i: usize| sockets[i].local_addr().unwrap();
+        let mut state = state.lock().unwrap();
+        let mapped = state.outbound(source, address(index));
+        let mut from = index;
+        let mut to = source;
+        let unknown = Message {
+            kind: BINDING_ERROR,
+            transaction: request.transaction,
+            attributes: vec![Attribute::ErrorCode {
+                code: 420,
+                reason: "Unknown Attribute".to_string(),
+            }],
+        };
+        for attribute in &request.attributes {
+            match attribute {
+                Attribute::ChangeRequest { .. } if state.reject_change => {
+                    return Some((index, source, unknown));
+                }
+                Attribute::ChangeRequest { ip, port } if rfc5780 => {
+                    from ^= (*ip as usize) << 1 | *port as usize;
+                }
+                Attribute::ResponsePort(port) if rfc5780 => to = state.inbound(*port)?,
+                Attribute::ResponsePort(_) => return Some((index, source, unknown)),
+                _ => {}
+            }
+        }
+        if !state.permits(to, address(from)) {
+            return None;
+        }
+        let mut attributes = vec![
+            Attribute::XorMappedAddress(mapped),
+            Attribute::ResponseOrigin(address(from)),
+        ];
+        if rfc5780 {
+            attributes.push(Attribute::OtherAddress(address(3)));
+        }
+        let response = Message {
+            kind: BINDING_SUCCESS,
+            transaction: request.transaction,
+            attributes,
+        };
+        Some((from, to, response))
+    }
+}
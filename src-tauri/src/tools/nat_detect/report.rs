@@ -0,0 +1,311 @@
+use std::time::Duration;
+
+use serde::Serialize;
+
+use crate::tools::address;
+use crate::tools::nat_detect::behavior::{Discovery, FilteringBehavior, NatBehavior, PortAllocation};
+use crate::tools::nat_detect::servers::{self, KnownGood, ServerProbe};
+use crate::tools::nat_detect::{NatError, NatType};
+use crate::tools::output::unix_time;
+use crate::tools::port_mapping::MappingStatus;
+
+/// 观察端口分配规律时使用的本地端口数
+const ALLOCATION_SAMPLES: usize = 4;
+/// edge向超级节点重新注册的默认间隔(`-i`)，映射保持时间短于它时打好的洞会失效
+const N2N_REGISTER_INTERVAL_MS: u64 = 20_000;
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct LocalAddress {
+    pub interface: String,
+    /// `ip/前缀长度`
+    pub address: String,
+}
+
+/// 与其他成员直连的可能性
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+pub enum P2pLevel {
+    Likely,
+    Possible,
+    Unlikely,
+    Blocked,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct P2pVerdict {
+    pub level: P2pLevel,
+    pub reasons: Vec<String>,
+}
+
+/// 可以直接粘贴到求助帖中的检测报告
+#[derive(Serialize, Debug, Clone)]
+pub struct NatReport {
+    pub nat_type: NatType,
+    /// 检测中途失败的原因
+    pub error: Option<String>,
+    pub local_addresses: Vec<LocalAddress>,
+    /// 询问过的服务器，按排名排序
+    pub servers: Vec<ServerProbe>,
+    pub behavior: Option<NatBehavior>,
+    /// NAT是否把发往自己公网映射的报文转回内网，没有测试时为None
+    pub hairpinning: Option<bool>,
+    pub port_allocation: PortAllocation,
+    /// edge端口在路由器上的映射，没有尝试时为None
+    pub port_mapping: Option<MappingStatus>,
+    pub p2p: P2pVerdict,
+    /// 检测时间，unix秒
+    pub created: u64,
+}
+
+impl NatReport {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap_or_default()
+    }
+}
+
+/// 从服务器池中选出可用的服务器并检测NAT行为，中途出错或超时时报告已得到的部分。
+/// `timeout`不包括测量映射保持时间
+pub async fn generate(
+    pool: &[String],
+    known: &KnownGood,
+    discovery: &Discovery,
+    lifetime: Option<&[Duration]>,
+    timeout: Duration,
+) -> NatReport {
+    let mut report = NatReport {
+        nat_type: NatType::Unknown,
+        error: None,
+        local_addresses: address::local_networks()
+            .into_iter()
+            .map(|(interface, network)| LocalAddress {
+                interface,
+                address: network.to_string(),
+            })
+            .collect(),
+        servers: Vec::new(),
+        behavior: None,
+        hairpinning: None,
+        port_allocation: PortAllocation::Unknown,
+        port_mapping: None,
+        p2p: P2pVerdict {
+            level: P2pLevel::Possible,
+            reasons: Vec::new(),
+        },
+        created: unix_time(),
+    };
+    let timeout = timeout + lifetime.map(|s| s.iter().sum()).unwrap_or_default();
+    match tokio::time::timeout(timeout, detect(&mut report, pool, known, discovery, lifetime)).await {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => report.error = Some(e.to_string()),
+        Err(_) => report.error = Some(NatError::Timeout.to_string()),
+    }
+    report.p2p = verdict(&report);
+    report
+}
+
+async fn detect(
+    report: &mut NatReport,
+    pool: &[String],
+    known: &KnownGood,
+    discovery: &Discovery,
+    lifetime: Option<&[Duration]>,
+) -> Result<(), NatError> {
+    if pool.is_empty() {
+        return Err(NatError::NoServer);
+    }
+    report.servers = servers::choose(pool, known, discovery).await;
+    let selected = servers::select(&report.servers);
+    if selected.is_empty() {
+        report.nat_type = NatType::UdpBlocked;
+        return Ok(());
+    }
+
+    let behavior = discovery.run(&selected).await?;
+    report.nat_type = behavior.nat_type();
+    let server = behavior.server.unwrap_or(selected[0]);
+    let rfc5780 = behavior.server;
+    // 先记下行为，后面的步骤失败或超时时报告中仍有
+    report.behavior = Some(behavior);
+    report.hairpinning = discovery.hairpinning(server).await.ok();
+    report.port_allocation = discovery.port_allocation(server, ALLOCATION_SAMPLES).await?;
+    if let (Some(steps), Some(server)) = (lifetime, rfc5780) {
+        let binding_lifetime = discovery.binding_lifetime(server, steps).await?;
+        if let Some(ref mut behavior) = report.behavior {
+            behavior.binding_lifetime = binding_lifetime;
+        }
+    }
+    Ok(())
+}
+
+/// 按本机的NAT行为估计n2n能否与其他成员直连
+pub fn verdict(report: &NatReport) -> P2pVerdict {
+    let mut reasons = Vec::new();
+    let mut level = match report.nat_type {
+        NatType::UdpBlocked => {
+            reasons.push("STUN服务器都没有应答，UDP可能被阻断，edge也无法连接超级节点".to_string());
+            P2pLevel::Blocked
+        }
+        NatType::OpenInternet | NatType::FullCone | NatType::RestrictedCone => {
+            reasons.push("映射与目的地址无关，其他成员可以向本机打洞".to_string());
+            P2pLevel::Likely
+        }
+        NatType::PortRestrictedCone => {
+            reasons.push("端口受限，与对称型NAT后的成员无法直连，只能经超级节点转发".to_string());
+            P2pLevel::Possible
+        }
+        NatType::SymmetricUdpFirewall => {
+            reasons.push("没有NAT，但防火墙只放行本机发送过的地址".to_string());
+            P2pLevel::Possible
+        }
+        NatType::Symmetric => {
+            reasons.push("对称型NAT对每个对端分配不同端口，只能与公网或全锥型NAT后的成员直连".to_string());
+            if report.port_allocation == PortAllocation::Sequential {
+                reasons.push("端口顺序分配，对端支持端口预测时仍可能直连".to_string());
+            }
+            P2pLevel::Unlikely
+        }
+        NatType::Unknown => {
+            reasons.push("无法判断映射行为".to_string());
+            P2pLevel::Possible
+        }
+    };
+    if let Some(ref behavior) = report.behavior {
+        if behavior.filtering == FilteringBehavior::Unknown && !behavior.direct {
+            reasons.push("服务器不支持RFC 5780或拒绝了CHANGE-REQUEST，过滤行为按最严格的情况估计".to_string());
+        }
+        let expired = behavior.binding_lifetime.as_ref().and_then(|l| l.expired_ms);
+        if let Some(expired) = expired.filter(|ms| *ms <= N2N_REGISTER_INTERVAL_MS) {
+            reasons.push(format!(
+                "映射在{}秒内失效，短于edge的注册间隔{}秒，可用-i缩短间隔",
+                expired / 1000,
+                N2N_REGISTER_INTERVAL_MS / 1000
+            ));
+            if level == P2pLevel::Likely {
+                level = P2pLevel::Possible;
+            }
+        }
+    }
+    if let Some(ref status) = report.port_mapping {
+        match status.mapping {
+            Some(ref mapping) if level != P2pLevel::Blocked => {
+                reasons.push(format!(
+                    "路由器已通过{:?}为edge端口建立UDP映射，公网端口{}",
+                    mapping.protocol, mapping.external_port
+                ));
+                level = P2pLevel::Likely;
+            }
+            None if level == P2pLevel::Unlikely => {
+                reasons.push("路由器不支持端口映射，可在路由器上手动转发edge端口".to_string());
+            }
+            _ => {}
+        }
+    }
+    if report.hairpinning == Some(false) {
+        reasons.push("NAT不支持回环，同一局域网内的成员需通过局域网地址互连".to_string());
+    }
+    P2pVerdict { level, reasons }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tools::nat_detect::behavior::MappingBehavior;
+    use crate::tools::nat_detect::DETECT_TIMEOUT;
+    use crate::tools::nat_detect::fake_stun::{FakeNat, FakeStun};
+    use crate::tools::port_mapping::{MappingProtocol, PortMapping, Renewal};
+
+    fn discovery() -> Discovery {
+        Discovery::new("127.0.0.1:0".parse().unwrap()).with_timeout(Duration::from_millis(150), 2)
+    }
+
+    #[tokio::test]
+    async fn report() {
+        let stun = FakeStun::start(
+            FakeNat::behind(MappingBehavior::AddressAndPortDependent, FilteringBehavior::AddressAndPortDependent),
+            true,
+        )
+        .await;
+        let servers = vec![stun.primary.to_string(), "unresolvable.invalid:3478".to_string()];
+        let report = generate(&servers, &KnownGood::default(), &discovery(), None, DETECT_TIMEOUT).await;
+        assert_eq!(report.error, None);
+        assert_eq!(report.nat_type, NatType::Symmetric);
+        assert_eq!(report.servers[0].address, Some(stun.primary));
+        assert!(report.servers[0].rfc5780);
+        assert!(report.servers[0].mapped.is_some());
+        assert!(report.servers[1].error.is_some());
+        assert_eq!(report.hairpinning, Some(false));
+        assert_eq!(report.port_allocation, PortAllocation::Sequential);
+        assert_eq!(report.p2p.level, P2pLevel::Unlikely);
+
+        let json: serde_json::Value = serde_json::from_str(&report.to_json()).unwrap();
+        assert_eq!(json["nat_type"], "Symmetric");
+        assert_eq!(json["behavior"]["mapping"], "AddressAndPortDependent");
+    }
+
+    #[tokio::test]
+    async fn timeout_keeps_partial_report() {
+        let stun = FakeStun::start(
+            FakeNat::behind(MappingBehavior::EndpointIndependent, FilteringBehavior::EndpointIndependent),
+            true,
+        )
+        .await;
+        let steps = [Duration::from_millis(400)];
+        let report = generate(
+            &[stun.primary.to_string()],
+            &KnownGood::default(),
+            &discovery(),
+            Some(&steps),
+            Duration::ZERO,
+        )
+        .await;
+        assert_eq!(report.error, Some(NatError::Timeout.to_string()));
+        assert_eq!(report.nat_type, NatType::FullCone);
+        let behavior = report.behavior.unwrap();
+        assert_eq!(behavior.filtering, FilteringBehavior::EndpointIndependent);
+        assert_eq!(behavior.binding_lifetime, None);
+    }
+
+    #[tokio::test]
+    async fn blocked() {
+        // 没有服务器监听的端口
+        let socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server = socket.local_addr().unwrap().to_string();
+        drop(socket);
+        let report = generate(&[server], &KnownGood::default(), &discovery(), None, DETECT_TIMEOUT).await;
+        assert_eq!(report.nat_type, NatType::UdpBlocked);
+        assert_eq!(report.p2p.level, P2pLevel::Blocked);
+        assert!(report.servers[0].error.is_some());
+
+        let report = generate(&[], &KnownGood::default(), &discovery(), None, DETECT_TIMEOUT).await;
+        assert_eq!(report.nat_type, NatType::Unknown);
+        assert!(report.error.is_some());
+    }
+
+    #[tokio::test]
+    async fn port_mapping() {
+        let mut report = generate(&[], &KnownGood::default(), &discovery(), None, DETECT_TIMEOUT).await;
+        report.nat_type = NatType::Symmetric;
+        report.port_mapping = Some(MappingStatus {
+            mapping: None,
+            error: Some("网关没有应答".to_string()),
+            updated: 0,
+        });
+        assert_eq!(verdict(&report).level, P2pLevel::Unlikely);
+        report.port_mapping = Some(MappingStatus {
+            mapping: Some(PortMapping {
+                protocol: MappingProtocol::NatPmp,
+                gateway: "192.168.1.1:5351".to_string(),
+                internal: "192.168.1.2:49898".parse().unwrap(),
+                external_ip: None,
+                external_port: 49898,
+                lifetime: 3600,
+                updated: 0,
+                renewal: Renewal::None,
+            }),
+            error: None,
+            updated: 0,
+        });
+        let p2p = verdict(&report);
+        assert_eq!(p2p.level, P2pLevel::Likely);
+        assert!(p2p.reasons.iter().any(|r| r.contains("49898")));
+    }
+}
@@ -0,0 +1,252 @@
+use std::collections::HashSet;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::tools::nat_detect::behavior::Discovery;
+use crate::tools::nat_detect::{resolve, NatError};
+use crate::tools::output::unix_time;
+use crate::tools::{ExternalFilePosition, ProgramError};
+
+/// 检测时使用的服务器数，需要位于不同ip
+const SELECTED: usize = 2;
+
+/// 对一个STUN服务器的询问结果
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct ServerProbe {
+    pub server: String,
+    /// 解析后的地址
+    pub address: Option<SocketAddr>,
+    /// 服务器看到的映射地址与端口
+    pub mapped: Option<SocketAddr>,
+    pub rtt_ms: Option<u64>,
+    /// 是否返回了OTHER-ADDRESS
+    pub rfc5780: bool,
+    pub error: Option<String>,
+}
+
+impl ServerProbe {
+    pub fn healthy(&self) -> bool {
+        self.mapped.is_some()
+    }
+}
+
+/// 上次检测时可用的服务器，按排名保存，下次优先询问
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct KnownGood {
+    pub servers: Vec<String>,
+    /// 更新时间，unix秒
+    pub updated: u64,
+}
+
+impl KnownGood {
+    /// 缓存文件位置
+    pub fn position() -> Result<PathBuf, ProgramError> {
+        std::env::current_dir()
+            .map(|position| position.join(ExternalFilePosition::StunCache.to_string()))
+            .map_err(|e| ProgramError::GetCurrentDirError(e.to_string()))
+    }
+
+    /// 读取缓存，不存在或无法解析时为空
+    pub fn load(position: &Path) -> Self {
+        let Ok(content) = fs::read_to_string(position) else {
+            return Self::default();
+        };
+        serde_json::from_str(&content).unwrap_or_else(|e| {
+            warn!("{}:{}", line!(), ProgramError::FileRWError(e.to_string()));
+            Self::default()
+        })
+    }
+
+    /// 记录探测结果中可用的服务器，没有可用的服务器时保留原有缓存
+    pub fn update(&mut self, probes: &[ServerProbe]) -> bool {
+        let servers: Vec<String> = order(probes.to_vec())
+            .into_iter()
+            .filter(ServerProbe::healthy)
+            .map(|p| p.server)
+            .collect();
+        if servers.is_empty() {
+            return false;
+        }
+        self.servers = servers;
+        self.updated = unix_time();
+        true
+    }
+
+    pub fn save(&self, position: &Path) -> Result<(), ProgramError> {
+        let content = serde_json::to_vec_pretty(self).map_err(|e| ProgramError::FileRWError(e.to_string()))?;
+        fs::write(position, content).map_err(|e| ProgramError::FileRWError(e.to_string()))
+    }
+}
+
+/// 并行询问每个服务器，结果顺序与输入相同
+pub async fn probe(servers: &[String], discovery: &Discovery) -> Vec<ServerProbe> {
+    let handles: Vec<_> = servers
+        .iter()
+        .cloned()
+        .map(|server| {
+            let discovery = discovery.clone();
+            tokio::spawn(async move { probe_one(server, &discovery).await })
+        })
+        .collect();
+    let mut probes = Vec::with_capacity(servers.len());
+    for (handle, server) in handles.into_iter().zip(servers) {
+        probes.push(handle.await.unwrap_or_else(|e| ServerProbe {
+            server: server.clone(),
+            address: None,
+            mapped: None,
+            rtt_ms: None,
+            rfc5780: false,
+            error: Some(e.to_string()),
+        }));
+    }
+    probes
+}
+
+async fn probe_one(server: String, discovery: &Discovery) -> ServerProbe {
+    let mut probe = ServerProbe {
+        server,
+        address: None,
+        mapped: None,
+        rtt_ms: None,
+        rfc5780: false,
+        error: None,
+    };
+    let address = match resolve(&probe.server).await {
+        Ok(address) => address,
+        Err(e) => {
+            probe.error = Some(e.to_string());
+            return probe;
+        }
+    };
+    probe.address = Some(address);
+    let response = discovery
+        .probe(&[address])
+        .await
+        .and_then(|mut r| r.pop().unwrap_or(Ok(None)));
+    match response {
+        Ok(Some(response)) => {
+            probe.mapped = Some(response.mapped);
+            probe.rtt_ms = Some(response.rtt.as_millis() as u64);
+            probe.rfc5780 = response.other.is_some();
+        }
+        Ok(None) => probe.error = Some(NatError::Timeout.to_string()),
+        Err(e) => probe.error = Some(e.to_string()),
+    }
+    probe
+}
+
+/// 可用的排在前面，其中支持RFC 5780的优先，再按往返时间排序，相同时保持原有顺序
+pub fn order(mut probes: Vec<ServerProbe>) -> Vec<ServerProbe> {
+    probes.sort_by_key(|p| (!p.healthy(), !p.rfc5780, p.rtt_ms.unwrap_or(u64::MAX)));
+    probes
+}
+
+/// 从排好序的结果中选出位于不同ip的可用服务器
+pub fn select(probes: &[ServerProbe]) -> Vec<SocketAddr> {
+    let mut ips = HashSet::new();
+    probes
+        .iter()
+        .filter(|p| p.healthy())
+        .filter_map(|p| p.address)
+        .filter(|a| ips.insert(a.ip()))
+        .take(SELECTED)
+        .collect()
+}
+
+/// 先询问服务器池中上次可用的服务器，选不出足够的服务器时再询问其余的。返回排好序的结果
+pub async fn choose(pool: &[String], known: &KnownGood, discovery: &Discovery) -> Vec<ServerProbe> {
+    let (first, rest): (Vec<String>, Vec<String>) = pool.iter().cloned().partition(|s| known.servers.contains(s));
+    let mut probes = order(probe(&first, discovery).await);
+    if select(&probes).len() < SELECTED && !rest.is_empty() {
+        probes.extend(probe(&rest, discovery).await);
+        probes = order(probes);
+    }
+    probes
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::tools::nat_detect::behavior::{FilteringBehavior, MappingBehavior};
+    use crate::tools::nat_detect::fake_stun::{FakeNat, FakeStun};
+
+    fn p(server: &str, address: &str, rtt_ms: Option<u64>, rfc5780: bool) -> ServerProbe {
+        ServerProbe {
+            server: server.to_string(),
+            address: address.parse().ok(),
+            mapped: rtt_ms.map(|_| "198.51.100.7:40000".parse().unwrap()),
+            rtt_ms,
+            rfc5780,
+            error: None,
+        }
+    }
+
+    #[test]
+    fn ranking() {
+        let ordered = order(vec![
+            p("a", "192.0.2.1:3478", None, false),
+            p("b", "192.0.2.2:3478", Some(80), false),
+            p("c", "192.0.2.2:3479", Some(20), true),
+            p("d", "192.0.2.3:3478", Some(50), false),
+        ]);
+        let servers: Vec<_> = ordered.iter().map(|p| p.server.as_str()).collect();
+        assert_eq!(servers, vec!["c", "d", "b", "a"]);
+        // b与c位于同一ip
+        let selected: Vec<String> = select(&ordered).iter().map(ToString::to_string).collect();
+        assert_eq!(selected, vec!["192.0.2.2:3479", "192.0.2.3:3478"]);
+
+        let mut known = KnownGood::default();
+        assert!(known.update(&ordered));
+        assert_eq!(known.servers, vec!["c", "d", "b"]);
+        assert!(!known.update(&[p("a", "192.0.2.1:3478", None, false)]));
+        assert_eq!(known.servers.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn known_good_first() {
+        let stun = FakeStun::start(
+            FakeNat::behind(MappingBehavior::EndpointIndependent, FilteringBehavior::EndpointIndependent),
+            true,
+        )
+        .await;
+        // 没有服务器监听的端口
+        let socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let dead = socket.local_addr().unwrap().to_string();
+        drop(socket);
+        let discovery = Discovery::new("127.0.0.1:0".parse().unwrap()).with_timeout(Duration::from_millis(100), 2);
+        // 模拟服务器的副ip同样应答
+        let alternate = SocketAddr::new("127.0.0.2".parse().unwrap(), stun.primary.port());
+        let pool = vec![
+            dead,
+            "unresolvable.invalid:3478".to_string(),
+            stun.primary.to_string(),
+            alternate.to_string(),
+        ];
+
+        let probes = choose(&pool, &KnownGood::default(), &discovery).await;
+        assert_eq!(probes.len(), 4);
+        assert!(probes[..2].iter().all(|p| p.healthy() && p.rfc5780));
+        assert!(probes[2..].iter().all(|p| !p.healthy() && p.error.is_some()));
+        let mut selected = select(&probes);
+        selected.sort();
+        assert_eq!(selected, vec![stun.primary, alternate]);
+
+        let position = std::env::temp_dir().join(format!("light_n2n_stun_{}.json", std::process::id()));
+        let mut known = KnownGood::default();
+        assert!(known.update(&probes));
+        known.save(&position).unwrap();
+        let known = KnownGood::load(&position);
+        assert_eq!(known.servers.len(), 2);
+        fs::remove_file(&position).unwrap();
+
+        // 上次可用的服务器足够时不再询问其余的
+        let probes = choose(&pool, &known, &discovery).await;
+        assert_eq!(probes.len(), 2);
+    }
+}
@@ -0,0 +1,424 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use tokio::net::UdpSocket;
+
+use crate::tools::nat_detect::NatError;
+
+pub const MAGIC_COOKIE: u32 = 0x2112_A442;
+pub const BINDING_REQUEST: u16 = 0x0001;
+pub const BINDING_SUCCESS: u16 = 0x0101;
+pub const BINDING_ERROR: u16 = 0x0111;
+
+const HEADER_LEN: usize = 20;
+const MAPPED_ADDRESS: u16 = 0x0001;
+const CHANGE_REQUEST: u16 = 0x0003;
+/// RFC 3489中的CHANGED-ADDRESS，旧服务器用它代替OTHER-ADDRESS
+const CHANGED_ADDRESS: u16 = 0x0005;
+const ERROR_CODE: u16 = 0x0009;
+const XOR_MAPPED_ADDRESS: u16 = 0x0020;
+const RESPONSE_PORT: u16 = 0x0027;
+const SOFTWARE: u16 = 0x8022;
+const RESPONSE_ORIGIN: u16 = 0x802B;
+const OTHER_ADDRESS: u16 = 0x802C;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Attribute {
+    MappedAddress(SocketAddr),
+    XorMappedAddress(SocketAddr),
+    /// 要求服务器从另一个ip和/或端口回复
+    ChangeRequest {
+        ip: bool,
+        port: bool,
+    },
+    OtherAddress(SocketAddr),
+    ResponseOrigin(SocketAddr),
+    /// 要求服务器把应答发到请求来源ip的这个端口
+    ResponsePort(u16),
+    ErrorCode {
+        code: u16,
+        reason: String,
+    },
+    Software(String),
+    Unknown(u16, Vec<u8>),
+}
+
+/// STUN消息，只支持不带认证的Binding
+#[derive(Debug, Clone, PartialEq)]
+pub struct Message {
+    pub kind: u16,
+    pub transaction: [u8; 12],
+    pub attributes: Vec<Attribute>,
+}
+
+impl Message {
+    pub fn request(attributes: Vec<Attribute>) -> Self {
+        Self {
+            kind: BINDING_REQUEST,
+            transaction: transaction_id(),
+            attributes,
+        }
+    }
+
+    /// 映射地址，优先使用XOR-MAPPED-ADDRESS
+    pub fn mapped(&self) -> Option<SocketAddr> {
+        self.find(|a| match a {
+            Attribute::XorMappedAddress(addr) => Some(*addr),
+            _ => None,
+        })
+        .or(self.find(|a| match a {
+            Attribute::MappedAddress(addr) => Some(*addr),
+            _ => None,
+        }))
+    }
+
+    /// 服务器的另一组ip与端口
+    pub fn other(&self) -> Option<SocketAddr> {
+        self.find(|a| match a {
+            Attribute::OtherAddress(addr) => Some(*addr),
+            Attribute::Unknown(CHANGED_ADDRESS, value) => decode_address(value, None).ok(),
+            _ => None,
+        })
+    }
+
+    pub fn error(&self) -> Option<(u16, &str)> {
+        self.attributes.iter().find_map(|a| match a {
+            Attribute::ErrorCode { code, reason } => Some((*code, reason.as_str())),
+            _ => None,
+        })
+    }
+
+    fn find<T>(&self, f: impl FnMut(&Attribute) -> Option<T>) -> Option<T> {
+        self.attributes.iter().find_map(f)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut body = Vec::new();
+        for attribute in &self.attributes {
+            let (kind, value) = match attribute {
+                Attribute::MappedAddress(addr) => (MAPPED_ADDRESS, encode_address(*addr, None)),
+                Attribute::XorMappedAddress(addr) => (
+                    XOR_MAPPED_ADDRESS,
+                    encode_address(*addr, Some(&self.transaction)),
+                ),
+                Attribute::ChangeRequest { ip, port } => {
+                    let flags = (*ip as u32) << 2 | (*port as u32) << 1;
+                    (CHANGE_REQUEST, flags.to_be_bytes().to_vec())
+                }
+                Attribute::OtherAddress(addr) => (OTHER_ADDRESS, encode_address(*addr, None)),
+                Attribute::ResponseOrigin(addr) => (RESPONSE_ORIGIN, encode_address(*addr, None)),
+                Attribute::ResponsePort(port) => {
+                    let mut value = port.to_be_bytes().to_vec();
+                    value.extend_from_slice(&[0, 0]);
+                    (RESPONSE_PORT, value)
+                }
+                Attribute::ErrorCode { code, reason } => {
+                    let mut value = vec![0, 0, (code / 100) as u8, (code % 100) as u8];
+                    value.extend_from_slice(reason.as_bytes());
+                    (ERROR_CODE, value)
+                }
+                Attribute::Software(s) => (SOFTWARE, s.as_bytes().to_vec()),
+                Attribute::Unknown(kind, value) => (*kind, value.clone()),
+            };
+            body.extend_from_slice(&kind.to_be_bytes());
+            body.extend_from_slice(&(value.len() as u16).to_be_bytes());
+            body.extend_from_slice(&value);
+            // 属性按4字节对齐
+            body.resize(body.len().next_multiple_of(4), 0);
+        }
+        let mut message = Vec::with_capacity(HEADER_LEN + body.len());
+        message.extend_from_slice(&self.kind.to_be_bytes());
+        message.extend_from_slice(&(body.len() as u16).to_be_bytes());
+        message.extend_from_slice(&MAGIC_COOKIE.to_be_bytes());
+        message.extend_from_slice(&self.transaction);
+        message.extend_from_slice(&body);
+        message
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<Self, NatError> {
+        if buf.len() < HEADER_LEN || buf[0] & 0xC0 != 0 {
+            return Err(NatError::Malformed);
+        }
+        let kind = u16::from_be_bytes([buf[0], buf[1]]);
+        let length = u16::from_be_bytes([buf[2], buf[3]]) as usize;
+        if u32::from_be_bytes([buf[4], buf[5], buf[6], buf[7]]) != MAGIC_COOKIE
+            || buf.len() < HEADER_LEN + length
+        {
+            return Err(NatError::Malformed);
+        }
+        let mut transaction = [0; 12];
+        transaction.copy_from_slice(&buf[8..HEADER_LEN]);
+        let mut attributes = Vec::new();
+        let mut rest = &buf[HEADER_LEN..HEADER_LEN + length];
+        while !rest.is_empty() {
+            if rest.len() < 4 {
+                return Err(NatError::Malformed);
+            }
+            let attribute = u16::from_be_bytes([rest[0], rest[1]]);
+            let len = u16::from_be_bytes([rest[2], rest[3]]) as usize;
+            let value = rest.get(4..4 + len).ok_or(NatError::Malformed)?;
+            attributes.push(match attribute {
+                MAPPED_ADDRESS => Attribute::MappedAddress(decode_address(value, None)?),
+                XOR_MAPPED_ADDRESS => {
+                    Attribute::XorMappedAddress(decode_address(value, Some(&transaction))?)
+                }
+                CHANGE_REQUEST => {
+                    let flags = value.get(..4).ok_or(NatError::Malformed)?;
+                    Attribute::ChangeRequest {
+                        ip: flags[3] & 0x04 != 0,
+                        port: flags[3] & 0x02 != 0,
+                    }
+                }
+                OTHER_ADDRESS => Attribute::OtherAddress(decode_address(value, None)?),
+                RESPONSE_ORIGIN => Attribute::ResponseOrigin(decode_address(value, None)?),
+                RESPONSE_PORT => {
+                    let port = value.get(..2).ok_or(NatError::Malformed)?;
+                    Attribute::ResponsePort(u16::from_be_bytes([port[0], port[1]]))
+                }
+                ERROR_CODE => {
+                    let code = value.get(..4).ok_or(NatError::Malformed)?;
+                    Attribute::ErrorCode {
+                        code: (code[2] & 0x07) as u16 * 100 + code[3] as u16,
+                        reason: String::from_utf8_lossy(&value[4..]).to_string(),
+                    }
+                }
+                SOFTWARE => Attribute::Software(String::from_utf8_lossy(value).to_string()),
+                _ => Attribute::Unknown(attribute, value.to_vec()),
+            });
+            rest = rest
+                .get((4 + len).next_multiple_of(4)..)
+                .unwrap_or_default();
+        }
+        Ok(Self {
+            kind,
+            transaction,
+            attributes,
+        })
+    }
+}
+
+/// 事务id只用于匹配应答，时间加计数即可。PCP的nonce同样使用
+pub(crate) fn transaction_id() -> [u8; 12] {
+    static COUNTER: AtomicU32 = AtomicU32::new(0);
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or_default();
+    let mut id = [0; 12];
+    id[..8].copy_from_slice(&(nanos ^ std::process::id() as u64).to_be_bytes());
+    id[8..].copy_from_slice(&COUNTER.fetch_add(1, Ordering::Relaxed).to_be_bytes());
+    id
+}
+
+fn xor_key(transaction: &[u8; 12]) -> [u8; 16] {
+    let mut key = [0; 16];
+    key[..4].copy_from_slice(&MAGIC_COOKIE.to_be_bytes());
+    key[4..].copy_from_slice(transaction);
+    key
+}
+
+fn encode_address(addr: SocketAddr, transaction: Option<&[u8; 12]>) -> Vec<u8> {
+    let key = transaction.map(xor_key).unwrap_or_default();
+    let port = addr.port() ^ u16::from_be_bytes([key[0], key[1]]);
+    let (family, ip) = match addr.ip() {
+        IpAddr::V4(ip) => (1u8, ip.octets().to_vec()),
+        IpAddr::V6(ip) => (2u8, ip.octets().to_vec()),
+    };
+    let mut value = vec![0, family];
+    value.extend_from_slice(&port.to_be_bytes());
+    value.extend(ip.iter().zip(key.iter()).map(|(b, k)| b ^ k));
+    value
+}
+
+fn decode_address(value: &[u8], transaction: Option<&[u8; 12]>) -> Result<SocketAddr, NatError> {
+    let key = transaction.map(xor_key).unwrap_or_default();
+    if value.len() < 4 {
+        return Err(NatError::Malformed);
+    }
+    let port = u16::from_be_bytes([value[2], value[3]]) ^ u16::from_be_bytes([key[0], key[1]]);
+    let ip = &value[4..];
+    let ip = match (value[1], ip.len()) {
+        (1, 4) => {
+            let mut octets = [0; 4];
+            octets
+                .iter_mut()
+                .enumerate()
+                .for_each(|(i, b)| *b = ip[i] ^ key[i]);
+            IpAddr::V4(Ipv4Addr::from(octets))
+        }
+        (2, 16) => {
+            let mut octets = [0; 16];
+            octets
+                .iter_mut()
+                .enumerate()
+                .for_each(|(i, b)| *b = ip[i] ^ key[i]);
+            IpAddr::V6(Ipv6Addr::from(octets))
+        }
+        _ => return Err(NatError::Malformed),
+    };
+    Ok(SocketAddr::new(ip, port))
+}
+
+/// Binding请求的应答
+#[derive(Debug, Clone, PartialEq)]
+pub struct BindingResponse {
+    pub mapped: SocketAddr,
+    /// 服务器的另一组地址，不支持RFC 5780时为None
+    pub other: Option<SocketAddr>,
+    /// 应答的实际来源
+    pub source: SocketAddr,
+    pub rtt: Duration,
+}
+
+/// 在一个本地端口上发送Binding请求，按超时重传
+pub struct StunClient {
+    socket: UdpSocket,
+    timeout: Duration,
+    retries: usize,
+}
+
+impl StunClient {
+    pub async fn bind(
+        local: SocketAddr,
+        timeout: Duration,
+        retries: usize,
+    ) -> Result<Self, NatError> {
+        let socket = UdpSocket::bind(local)
+            .await
+            .map_err(|_| NatError::LocalBindError)?;
+        Ok(Self {
+            socket,
+            timeout,
+            retries: retries.max(1),
+        })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, NatError> {
+        self.socket
+            .local_addr()
+            .map_err(|_| NatError::LocalBindError)
+    }
+
+    /// 发送请求，未收到应答时返回None
+    pub async fn binding(
+        &self,
+        server: SocketAddr,
+        attributes: Vec<Attribute>,
+    ) -> Result<Option<BindingResponse>, NatError> {
+        let request = Message::request(attributes);
+        for _ in 0..self.retries {
+            let start = Instant::now();
+            self.send(server, &request).await?;
+            if let Some((response, source)) =
+                self.receive(&request.transaction, self.timeout).await?
+            {
+                if let Some((code, reason)) = response.error() {
+                    return Err(NatError::ServerError(code, reason.to_string()));
+                }
+                return Ok(Some(BindingResponse {
+                    mapped: response.mapped().ok_or(NatError::Malformed)?,
+                    other: response.other(),
+                    source,
+                    rtt: start.elapsed(),
+                }));
+            }
+        }
+        Ok(None)
+    }
+
+    pub async fn send(&self, server: SocketAddr, message: &Message) -> Result<(), NatError> {
+        self.socket
+            .send_to(&message.encode(), server)
+            .await
+            .map(|_| ())
+            .map_err(|_| NatError::UdpBlocked)
+    }
+
+    /// 等待指定事务的报文，忽略其他报文。检测回环时收到的是请求
+    pub async fn receive(
+        &self,
+        transaction: &[u8; 12],
+        timeout: Duration,
+    ) -> Result<Option<(Message, SocketAddr)>, NatError> {
+        let deadline = tokio::time::Instant::now() + timeout;
+        let mut buf = [0; 1500];
+        loop {
+            let received = tokio::time::timeout_at(deadline, self.socket.recv_from(&mut buf)).await;
+            let (len, source) = match received {
+                Err(_) => return Ok(None),
+                // Windows下对端不可达时recv会报错，视为没有应答
+                Ok(Err(_)) => continue,
+                Ok(Ok(r)) => r,
+            };
+            match Message::decode(&buf[..len]) {
+                Ok(message)
+                    if &message.transaction == transaction
+                        && matches!(
+                            message.kind,
+                            BINDING_REQUEST | BINDING_SUCCESS | BINDING_ERROR
+                        ) =>
+                {
+                    return Ok(Some((message, source)))
+                }
+                _ => continue,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// RFC 5769 2.2 IPv4应答示例
+    const RFC5769_RESPONSE: [u8; 80] = [
+        0x01, 0x01, 0x00, 0x3c, 0x21, 0x12, 0xa4, 0x42, 0xb7, 0xe7, 0xa7, 0x01, 0xbc, 0x34, 0xd6,
+        0x86, 0xfa, 0x87, 0xdf, 0xae, 0x80, 0x22, 0x00, 0x0b, 0x74, 0x65, 0x73, 0x74, 0x20, 0x76,
+        0x65, 0x63, 0x74, 0x6f, 0x72, 0x20, 0x00, 0x20, 0x00, 0x08, 0x00, 0x01, 0xa1, 0x47, 0xe1,
+        0x12, 0xa6, 0x43, 0x00, 0x08, 0x00, 0x14, 0x2b, 0x91, 0xf5, 0x99, 0xfd, 0x9e, 0x90, 0xc3,
+        0x8c, 0x74, 0x89, 0xf9, 0x2a, 0xf9, 0xba, 0x53, 0xf0, 0x6b, 0xe7, 0xd7, 0x80, 0x28, 0x00,
+        0x04, 0xc0, 0x7d, 0x4c, 0x96,
+    ];
+
+    #[test]
+    fn decode_rfc5769() {
+        let message = Message::decode(&RFC5769_RESPONSE).unwrap();
+        assert_eq!(message.kind, BINDING_SUCCESS);
+        assert_eq!(message.mapped(), Some("192.0.2.1:32853".parse().unwrap()));
+        assert_eq!(
+            message.attributes[0],
+            Attribute::Software("test vector".to_string())
+        );
+        // MESSAGE-INTEGRITY与FINGERPRINT不解析
+        assert!(matches!(
+            message.attributes[2],
+            Attribute::Unknown(0x0008, _)
+        ));
+    }
+
+    #[test]
+    fn round_trip() {
+        let message = Message {
+            kind: BINDING_SUCCESS,
+            transaction: transaction_id(),
+            attributes: vec![
+                Attribute::XorMappedAddress("203.0.113.9:40000".parse().unwrap()),
+                Attribute::XorMappedAddress("[2001:db8::1]:5000".parse().unwrap()),
+                Attribute::OtherAddress("127.0.0.2:3479".parse().unwrap()),
+                Attribute::ChangeRequest {
+                    ip: true,
+                    port: false,
+                },
+                Attribute::ResponsePort(4000),
+                Attribute::ErrorCode {
+                    code: 420,
+                    reason: "Unknown".to_string(),
+                },
+            ],
+        };
+        assert_eq!(Message::decode(&message.encode()).unwrap(), message);
+        assert!(Message::decode(&message.encode()[..30]).is_err());
+        assert_ne!(transaction_id(), transaction_id());
+    }
+}
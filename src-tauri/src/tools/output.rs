@@ -0,0 +1,211 @@
+use std::collections::{HashMap, VecDeque};
+use std::io::{BufRead, BufReader, Read};
+use std::process::ExitStatus;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use lazy_static::lazy_static;
+use log::{error, info};
+use serde::Serialize;
+
+use crate::platform;
+use crate::tools::ProgramError;
+
+/// 每个子进程保留的输出行数
+const CAPACITY: usize = 500;
+
+lazy_static! {
+    /// 按子进程名保存输出，子进程重启或停止后仍可查询
+    static ref OUTPUTS: Mutex<HashMap<String, Arc<OutputLog>>> = Mutex::new(HashMap::new());
+}
+
+/// unix时间戳，秒
+pub fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// 输出来源
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Stream {
+    Stdout,
+    Stderr,
+}
+
+/// 一行输出
+#[derive(Serialize, Clone, Debug)]
+pub struct OutputLine {
+    pub time: u64,
+    pub stream: Stream,
+    pub line: String,
+}
+
+/// 子进程的退出情况
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct ExitInfo {
+    pub time: u64,
+    /// 退出码，被信号结束时为None
+    pub code: Option<i32>,
+    /// 结束子进程的信号，仅unix
+    pub signal: Option<i32>,
+}
+
+impl From<&ExitStatus> for ExitInfo {
+    fn from(status: &ExitStatus) -> Self {
+        Self {
+            time: unix_time(),
+            code: status.code(),
+            signal: platform::exit_signal(status),
+        }
+    }
+}
+
+/// 子进程的输出环形缓冲区
+#[derive(Default)]
+pub struct OutputLog {
+    lines: Mutex<VecDeque<OutputLine>>,
+    exit: Mutex<Option<ExitInfo>>,
+}
+
+impl OutputLog {
+    pub fn push(&self, stream: Stream, line: String) {
+        match self.lines.lock() {
+            Ok(mut lines) => {
+                if lines.len() >= CAPACITY {
+                    lines.pop_front();
+                }
+                lines.push_back(OutputLine {
+                    time: unix_time(),
+                    stream,
+                    line,
+                });
+            }
+            Err(e) => error!("{}:{}", line!(), e),
+        }
+    }
+
+    pub fn set_exit(&self, exit: ExitInfo) {
+        match self.exit.lock() {
+            Ok(mut e) => *e = Some(exit),
+            Err(e) => error!("{}:{}", line!(), e),
+        }
+    }
+
+    pub fn exit(&self) -> Option<ExitInfo> {
+        self.exit.lock().ok().and_then(|e| e.clone())
+    }
+
+    /// 最近的limit行输出
+    pub fn snapshot(&self, name: &str, limit: usize) -> ProcessOutput {
+        let lines = match self.lines.lock() {
+            Ok(lines) => lines.iter().skip(lines.len().saturating_sub(limit)).cloned().collect(),
+            Err(_) => Vec::new(),
+        };
+        ProcessOutput {
+            name: name.to_string(),
+            exit: self.exit(),
+            lines,
+        }
+    }
+}
+
+/// 子进程最近的输出
+#[derive(Serialize, Clone, Debug)]
+pub struct ProcessOutput {
+    pub name: String,
+    /// 最近一次退出
+    pub exit: Option<ExitInfo>,
+    pub lines: Vec<OutputLine>,
+}
+
+/// 取得子进程对应的输出缓冲区，同名子进程共用
+pub fn output_log(name: &str) -> Arc<OutputLog> {
+    match OUTPUTS.lock() {
+        Ok(mut outputs) => outputs.entry(name.to_string()).or_default().clone(),
+        Err(e) => {
+            error!("{}:{}", line!(), e);
+            Arc::new(OutputLog::default())
+        }
+    }
+}
+
+/// 处理子进程每行输出的回调
+pub type LineHook = Arc<dyn Fn(&str) + Send + Sync>;
+
+/// 创建线程逐行读取子进程输出，写入日志与缓冲区
+pub fn forward<R: Read + Send + 'static>(
+    name: String,
+    reader: R,
+    stream: Stream,
+    log: Arc<OutputLog>,
+    hook: Option<LineHook>,
+) {
+    thread::spawn(move || {
+        for line in BufReader::new(reader).split(b'\n').map_while(Result::ok) {
+            // 输出不一定是UTF-8
+            let line = String::from_utf8_lossy(&line).trim_end_matches('\r').to_string();
+            info!("{}:{}", name, line);
+            if let Some(ref hook) = hook {
+                hook(&line);
+            }
+            log.push(stream, line);
+        }
+    });
+}
+
+/// 查询子进程最近的输出，未指定名称时返回全部
+#[tauri::command]
+pub fn process_output(name: Option<String>, limit: Option<usize>) -> Result<Vec<ProcessOutput>, String> {
+    let limit = limit.unwrap_or(CAPACITY);
+    match OUTPUTS.lock() {
+        Ok(outputs) => Ok(outputs
+            .iter()
+            .filter(|(n, _)| name.as_ref().is_none_or(|name| name == *n))
+            .map(|(n, log)| log.snapshot(n, limit))
+            .collect()),
+        Err(e) => {
+            let error = ProgramError::ChildProcessError(e.to_string()).to_string();
+            error!("{}:{}", line!(), error);
+            Err(error)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ring_buffer() {
+        let log = OutputLog::default();
+        for i in 0..CAPACITY + 10 {
+            log.push(Stream::Stdout, i.to_string());
+        }
+        let output = log.snapshot("edge", CAPACITY);
+        assert_eq!(output.lines.len(), CAPACITY);
+        assert_eq!(output.lines[0].line, "10");
+        assert_eq!(log.snapshot("edge", 2).lines[1].line, (CAPACITY + 9).to_string());
+    }
+
+    #[test]
+    fn forwards_lines() {
+        let log = Arc::new(OutputLog::default());
+        forward(
+            "edge".to_string(),
+            &b"first\r\nsecond \xff\nthird"[..],
+            Stream::Stderr,
+            log.clone(),
+            None,
+        );
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(3);
+        while log.snapshot("edge", CAPACITY).lines.len() < 3 && std::time::Instant::now() < deadline {
+            thread::yield_now();
+        }
+        let lines: Vec<_> = log.snapshot("edge", CAPACITY).lines.into_iter().map(|l| l.line).collect();
+        assert_eq!(lines, vec!["first", "second \u{fffd}", "third"]);
+    }
+}
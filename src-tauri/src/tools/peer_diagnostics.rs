@@ -0,0 +1,415 @@
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+use serde::Serialize;
+
+use crate::tools::address::Ipv4Cidr;
+use crate::tools::n2n_controller::EdgeRow;
+use crate::tools::nat_detect::report::NatReport;
+use crate::tools::nat_detect::NatType;
+use crate::tools::output::unix_time;
+
+/// UPnP ConflictInMappingEntry，端口已映射给局域网中其他设备
+const UPNP_CONFLICT: &str = "UPnP错误718";
+/// 经超级节点转发时认为过慢的往返时间
+const SLOW_RTT: Duration = Duration::from_millis(150);
+
+/// 与成员之间的数据通路，由管理端口`edges`行的mode得出
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+pub enum PeerPath {
+    /// `p2p`，已打洞直连
+    Direct,
+    /// `pSp`、`sn`，经超级节点转发
+    Relayed,
+    /// 管理端口中没有该成员
+    NotSeen,
+}
+
+impl PeerPath {
+    pub fn from_mode(mode: &str) -> Self {
+        match mode {
+            "p2p" => PeerPath::Direct,
+            "pSp" | "sn" => PeerPath::Relayed,
+            _ => PeerPath::NotSeen,
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+pub enum RemedyKind {
+    /// 在网络配置中启用端口映射
+    PortMapping,
+    /// 添加edge端口的防火墙放行规则
+    FirewallRule,
+    /// 更换edge端口
+    ChangePort,
+    /// 在路由器上手动转发edge端口
+    ForwardPort,
+    /// 请对方检查其网络
+    CheckPeer,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Remedy {
+    pub kind: RemedyKind,
+    pub detail: String,
+}
+
+/// 诊断所依据的信息
+pub struct Evidence<'a> {
+    /// 管理端口中该成员的行
+    pub row: Option<&'a EdgeRow>,
+    /// 经虚拟ip的往返时间，ping不通时为None
+    pub rtt: Option<Duration>,
+    pub nat: &'a NatReport,
+    /// edge端口
+    pub port: u16,
+    /// 配置是否启用了端口映射
+    pub port_mapping: bool,
+    /// 防火墙是否放行edge，无法查询时为None
+    pub firewall: Option<bool>,
+}
+
+/// 对一个成员的连接诊断
+#[derive(Serialize, Debug, Clone)]
+pub struct PeerDiagnosis {
+    pub member: String,
+    pub mode: String,
+    pub path: PeerPath,
+    /// 对方最后一次被看到的公网地址
+    pub sockaddr: Option<SocketAddr>,
+    /// 距最后一次看到对方的秒数
+    pub last_seen_secs: Option<u64>,
+    /// 距最后一次直连的秒数，从未直连时为None
+    pub last_p2p_secs: Option<u64>,
+    pub rtt_ms: Option<u64>,
+    pub nat: NatReport,
+    /// 原因说明
+    pub findings: Vec<String>,
+    pub remedies: Vec<Remedy>,
+}
+
+/// 管理端口中的时间戳距今的秒数
+fn elapsed(timestamp: i64) -> Option<u64> {
+    (timestamp > 0).then(|| unix_time().saturating_sub(timestamp as u64))
+}
+
+/// 对方上报的地址是否位于本机所在的局域网
+fn same_lan(nat: &NatReport, peer: IpAddr) -> bool {
+    let IpAddr::V4(peer) = peer else {
+        return false;
+    };
+    nat.local_addresses
+        .iter()
+        .filter_map(|a| a.address.parse::<Ipv4Cidr>().ok())
+        .any(|network| network.contains(peer))
+}
+
+/// 由连接方式、对方地址、往返时间与本机NAT行为解释为何没有直连，并给出建议
+pub fn diagnose(member: &str, evidence: Evidence) -> PeerDiagnosis {
+    let mode = evidence.row.map(|r| r.mode.clone()).unwrap_or_default();
+    let path = PeerPath::from_mode(&mode);
+    let sockaddr = evidence
+        .row
+        .and_then(|r| r.sockaddr.parse::<SocketAddr>().ok());
+    let mut findings = Vec::new();
+    let mut remedies = Vec::new();
+    let mut suggest = |kind: RemedyKind, detail: String| {
+        if !remedies.iter().any(|r: &Remedy| r.kind == kind) {
+            remedies.push(Remedy { kind, detail });
+        }
+    };
+    let nat = evidence.nat;
+
+    match path {
+        PeerPath::Direct => {
+            findings.push("已与该成员直连".to_string());
+            if evidence.rtt.is_none() {
+                findings.push("直连但ping不通，对方可能禁止了ICMP".to_string());
+                suggest(
+                    RemedyKind::CheckPeer,
+                    "请对方在防火墙中放行ICMP回显".to_string(),
+                );
+            }
+        }
+        PeerPath::NotSeen => {
+            findings.push("管理端口中没有该成员，它可能离线或尚未向超级节点注册".to_string());
+            suggest(
+                RemedyKind::CheckPeer,
+                "请对方确认edge已连接到同一超级节点".to_string(),
+            );
+        }
+        PeerPath::Relayed => {
+            let last_p2p = evidence.row.and_then(|r| elapsed(r.last_p2p));
+            findings.push(match last_p2p {
+                Some(secs) => format!("数据经超级节点转发，{}秒前曾经直连", secs),
+                None => "数据经超级节点转发，从未直连".to_string(),
+            });
+            if let Some(peer) = sockaddr {
+                if same_lan(nat, peer.ip()) {
+                    findings.push(format!(
+                        "对方地址{}与本机位于同一局域网，应能直连，检查双方防火墙",
+                        peer
+                    ));
+                } else if let IpAddr::V4(ip) = peer.ip() {
+                    // 私有地址或运营商级NAT的100.64.0.0/10
+                    let [a, b, ..] = ip.octets();
+                    if ip.is_private() || (a == 100 && b & 0xc0 == 64) {
+                        findings.push(format!(
+                            "超级节点看到的对方地址{}不是公网地址，对方位于多层NAT之后",
+                            peer
+                        ));
+                    }
+                }
+                let public = nat.behavior.as_ref().map(|b| b.mapped.ip());
+                if public == Some(peer.ip()) {
+                    findings.push("对方与本机共用同一公网出口".to_string());
+                    if nat.hairpinning == Some(false) {
+                        findings.push("NAT不支持回环，只能通过局域网地址互连".to_string());
+                    }
+                }
+            }
+
+            match nat.nat_type {
+                NatType::UdpBlocked => {
+                    findings.push("本机UDP可能被阻断".to_string());
+                    suggest(
+                        RemedyKind::ChangePort,
+                        "换用网络放行的UDP端口，如53或443".to_string(),
+                    );
+                }
+                NatType::Symmetric => {
+                    findings.push("本机为对称型NAT，只能与公网或全锥型NAT后的成员打洞".to_string());
+                }
+                NatType::PortRestrictedCone => {
+                    findings.push("本机为端口受限锥型NAT，对方为对称型NAT时无法打洞".to_string());
+                }
+                NatType::SymmetricUdpFirewall => {
+                    findings.push("本机没有NAT，但防火墙拦截了未主动联系过的地址".to_string());
+                    suggest(
+                        RemedyKind::FirewallRule,
+                        format!("放行edge端口{}的入站UDP", evidence.port),
+                    );
+                }
+                NatType::OpenInternet | NatType::FullCone | NatType::RestrictedCone => {
+                    findings.push("本机NAT允许打洞，问题多在对方一侧".to_string());
+                    suggest(RemedyKind::CheckPeer, "请对方也进行诊断".to_string());
+                }
+                NatType::Unknown => findings.push("无法判断本机NAT类型".to_string()),
+            }
+            let restricted = matches!(
+                nat.nat_type,
+                NatType::Symmetric | NatType::PortRestrictedCone
+            );
+            match nat.port_mapping.as_ref() {
+                Some(status) if status.mapping.is_some() => {
+                    let external = status
+                        .mapping
+                        .as_ref()
+                        .map(|m| m.external_port)
+                        .unwrap_or_default();
+                    findings.push(format!("路由器已映射edge端口，公网端口{}", external));
+                    if evidence.port_mapping {
+                        suggest(
+                            RemedyKind::CheckPeer,
+                            "本机端口已可从外部访问，请对方检查其NAT".to_string(),
+                        );
+                    } else if restricted {
+                        suggest(
+                            RemedyKind::PortMapping,
+                            "路由器支持端口映射，启用后对方可直接连入".to_string(),
+                        );
+                    }
+                }
+                Some(status) => {
+                    let error = status.error.clone().unwrap_or_default();
+                    if error.contains(UPNP_CONFLICT) {
+                        findings.push(format!("端口{}已被局域网中其他设备映射", evidence.port));
+                        suggest(
+                            RemedyKind::ChangePort,
+                            "换用其他edge端口后重新映射".to_string(),
+                        );
+                    } else {
+                        findings.push(format!("端口映射失败:{}", error));
+                    }
+                    if restricted {
+                        suggest(
+                            RemedyKind::ForwardPort,
+                            format!("在路由器上把UDP端口{}转发到本机", evidence.port),
+                        );
+                    }
+                }
+                None if restricted => {
+                    suggest(
+                        RemedyKind::PortMapping,
+                        "启用端口映射，让路由器为edge端口开放入站".to_string(),
+                    );
+                }
+                None => {}
+            }
+            if evidence.firewall == Some(false) {
+                findings.push(format!("防火墙没有放行edge端口{}", evidence.port));
+                suggest(
+                    RemedyKind::FirewallRule,
+                    format!("放行edge端口{}的入站UDP", evidence.port),
+                );
+            }
+            match evidence.rtt {
+                None => {
+                    findings.push("经超级节点也ping不通，对方可能禁止了ICMP或已离线".to_string())
+                }
+                Some(rtt) if rtt > SLOW_RTT => {
+                    findings.push(format!("经超级节点转发的往返时间{}ms", rtt.as_millis()))
+                }
+                Some(_) => {}
+            }
+        }
+    }
+
+    PeerDiagnosis {
+        member: member.to_string(),
+        mode,
+        path,
+        sockaddr,
+        last_seen_secs: evidence.row.and_then(|r| elapsed(r.last_seen)),
+        last_p2p_secs: evidence.row.and_then(|r| elapsed(r.last_p2p)),
+        rtt_ms: evidence.rtt.map(|d| d.as_millis() as u64),
+        nat: nat.clone(),
+        findings,
+        remedies,
+    }
+}
+
+/// 按虚拟ip找出成员的行，`member`可以带前缀长度
+pub fn find_row<'a>(rows: &'a [EdgeRow], member: &str) -> Option<&'a EdgeRow> {
+    let ip = member.split('/').next().unwrap_or_default();
+    rows.iter()
+        .find(|r| r.ip4addr.split('/').next() == Some(ip))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tools::nat_detect::behavior::Discovery;
+    use crate::tools::nat_detect::report;
+    use crate::tools::nat_detect::servers::KnownGood;
+    use crate::tools::port_mapping::MappingStatus;
+
+    async fn nat(nat_type: NatType) -> NatReport {
+        let discovery = Discovery::new("127.0.0.1:0".parse().unwrap());
+        let mut nat = report::generate(
+            &[],
+            &KnownGood::default(),
+            &discovery,
+            None,
+            Duration::from_secs(1),
+        )
+        .await;
+        nat.nat_type = nat_type;
+        nat
+    }
+
+    fn row(mode: &str, sockaddr: &str) -> EdgeRow {
+        EdgeRow {
+            mode: mode.to_string(),
+            ip4addr: "10.0.0.3/24".to_string(),
+            sockaddr: sockaddr.to_string(),
+            last_seen: unix_time() as i64 - 5,
+            ..Default::default()
+        }
+    }
+
+    fn kinds(diagnosis: &PeerDiagnosis) -> Vec<RemedyKind> {
+        diagnosis.remedies.iter().map(|r| r.kind).collect()
+    }
+
+    #[test]
+    fn path() {
+        assert_eq!(PeerPath::from_mode("p2p"), PeerPath::Direct);
+        assert_eq!(PeerPath::from_mode("pSp"), PeerPath::Relayed);
+        assert_eq!(PeerPath::from_mode("sn"), PeerPath::Relayed);
+        assert_eq!(PeerPath::from_mode("None"), PeerPath::NotSeen);
+        let rows = vec![row("p2p", "")];
+        assert!(find_row(&rows, "10.0.0.3").is_some());
+        assert!(find_row(&rows, "10.0.0.3/24").is_some());
+        assert!(find_row(&rows, "10.0.0.4").is_none());
+    }
+
+    #[tokio::test]
+    async fn relayed_behind_symmetric_nat() {
+        let nat = nat(NatType::Symmetric).await;
+        let row = row("pSp", "100.64.1.2:50000");
+        let diagnosis = diagnose(
+            "10.0.0.3",
+            Evidence {
+                row: Some(&row),
+                rtt: Some(Duration::from_millis(200)),
+                nat: &nat,
+                port: 49898,
+                port_mapping: false,
+                firewall: Some(false),
+            },
+        );
+        assert_eq!(diagnosis.path, PeerPath::Relayed);
+        assert_eq!(
+            diagnosis.sockaddr,
+            Some("100.64.1.2:50000".parse().unwrap())
+        );
+        assert_eq!(diagnosis.last_p2p_secs, None);
+        assert!(diagnosis.last_seen_secs.is_some_and(|s| s >= 5));
+        assert_eq!(diagnosis.rtt_ms, Some(200));
+        assert!(diagnosis.findings.iter().any(|f| f.contains("多层NAT")));
+        assert_eq!(
+            kinds(&diagnosis),
+            vec![RemedyKind::PortMapping, RemedyKind::FirewallRule]
+        );
+    }
+
+    #[tokio::test]
+    async fn mapping_conflict() {
+        let mut nat = nat(NatType::PortRestrictedCone).await;
+        nat.port_mapping = Some(MappingStatus {
+            mapping: None,
+            error: Some("端口映射失败:Upnp:UPnP错误718:ConflictInMappingEntry".to_string()),
+            updated: 0,
+        });
+        let row = row("pSp", "203.0.113.20:40000");
+        let diagnosis = diagnose(
+            "10.0.0.3/24",
+            Evidence {
+                row: Some(&row),
+                rtt: None,
+                nat: &nat,
+                port: 49898,
+                port_mapping: true,
+                firewall: Some(true),
+            },
+        );
+        assert_eq!(
+            kinds(&diagnosis),
+            vec![RemedyKind::ChangePort, RemedyKind::ForwardPort]
+        );
+        assert!(diagnosis.findings.iter().any(|f| f.contains("ping不通")));
+    }
+
+    #[tokio::test]
+    async fn direct_and_unseen() {
+        let nat = nat(NatType::FullCone).await;
+        let row = row("p2p", "203.0.113.20:40000");
+        let evidence = |row| Evidence {
+            row,
+            rtt: Some(Duration::from_millis(20)),
+            nat: &nat,
+            port: 49898,
+            port_mapping: false,
+            firewall: None,
+        };
+        let diagnosis = diagnose("10.0.0.3", evidence(Some(&row)));
+        assert_eq!(diagnosis.path, PeerPath::Direct);
+        assert!(diagnosis.remedies.is_empty());
+
+        let diagnosis = diagnose("10.0.0.3", evidence(None));
+        assert_eq!(diagnosis.path, PeerPath::NotSeen);
+        assert_eq!(kinds(&diagnosis), vec![RemedyKind::CheckPeer]);
+    }
+}
@@ -1,41 +1,30 @@
 use std::net::IpAddr;
 use std::str::FromStr;
-use std::sync::mpsc;
-use std::thread;
 use std::time::{Duration, Instant};
 
 use log::{debug, error};
 use ping::ping;
+use tokio::time::{sleep, timeout};
 
-pub fn ping_average(
+pub async fn ping_average(
     address: IpAddr,
     timeout_duration: Duration,
     count: usize,
-) -> Result<Duration, Box<dyn std::error::Error>> {
+) -> Result<Duration, Box<dyn std::error::Error + Send + Sync>> {
     let mut total_duration = Duration::new(0, 0);
     let mut success_count = 0;
 
     for i in 0..count {
-        let (tx, rx) = mpsc::channel();
-        let start = Instant::now();
-
-        thread::spawn(move || {
-            let result = ping(address, Some(timeout_duration), None, None, None, None);
-            let _ = tx.send(result);
-        });
-
-        match rx.recv_timeout(timeout_duration) {
-            Ok(Ok(())) => {
-                let duration = start.elapsed();
+        match ping_timeout(address, timeout_duration).await {
+            Some(duration) => {
                 total_duration += duration;
                 success_count += 1;
                 debug!("Ping {}: {:?}", i + 1, duration);
             }
-            Ok(Err(e)) => error!("Error receiving reply: {}", e),
-            Err(_) => error!("Ping {} timed out", i + 1),
+            None => error!("Ping {} timed out", i + 1),
         }
 
-        thread::sleep(Duration::from_secs(1)); // 每次ping之间等待1秒
+        sleep(Duration::from_secs(1)).await; // 每次ping之间等待1秒
     }
 
     if success_count > 0 {
@@ -45,11 +34,36 @@ pub fn ping_average(
     }
 }
 
+/// 在阻塞线程中ping，超时后不再等待结果
+pub async fn ping_timeout(address: IpAddr, timeout_duration: Duration) -> Option<Duration> {
+    let task = tauri::async_runtime::spawn_blocking(move || ping_once(address, timeout_duration));
+    match timeout(timeout_duration, task).await {
+        Ok(Ok(rtt)) => rtt,
+        Ok(Err(e)) => {
+            error!("{}:{}", line!(), e);
+            None
+        }
+        Err(_) => None,
+    }
+}
+
+/// 单次ping，返回往返时间
+pub fn ping_once(address: IpAddr, timeout_duration: Duration) -> Option<Duration> {
+    let start = Instant::now();
+    match ping(address, Some(timeout_duration), None, None, None, None) {
+        Ok(()) => Some(start.elapsed()),
+        Err(e) => {
+            debug!("Ping {}: {}", address, e);
+            None
+        }
+    }
+}
+
 #[tauri::command]
 pub async fn ping_method(host: String) -> Result<u128, String> {
-    let ip = IpAddr::from_str(host.as_str()).unwrap();
-    return match ping_average(ip, Duration::from_secs(3), 3) {
+    let ip = IpAddr::from_str(host.as_str()).map_err(|e| e.to_string())?;
+    match ping_average(ip, Duration::from_secs(3), 3).await {
         Ok(s) => Ok(s.as_millis()),
         Err(e) => Err(e.to_string()),
-    };
+    }
 }
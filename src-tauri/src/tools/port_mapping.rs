@@ -0,0 +1,357 @@
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use log::{error, info, warn};
+use serde::Serialize;
+use thiserror::Error;
+use tokio::net::UdpSocket;
+use tokio::time::sleep;
+use tokio_util::sync::CancellationToken;
+
+use crate::platform;
+use crate::tools::output::unix_time;
+
+pub mod natpmp;
+pub mod pcp;
+pub mod upnp;
+
+/// NAT-PMP与PCP的服务端口
+pub const GATEWAY_PORT: u16 = 5351;
+/// 请求的租期，秒
+pub const LEASE: u32 = 3600;
+/// 网关的首次应答超时，NAT-PMP与PCP每次重发加倍
+const REQUEST_TIMEOUT: Duration = Duration::from_millis(250);
+const RETRIES: u32 = 3;
+/// 永久映射的检查间隔，网关重启后映射会丢失
+const RECHECK_INTERVAL: Duration = Duration::from_secs(1800);
+/// 映射失败后的重试间隔
+const RETRY_INTERVAL: Duration = Duration::from_secs(60);
+/// 映射在路由器中的说明
+pub const DESCRIPTION: &str = "LightN2N";
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+pub enum MappingProtocol {
+    Pcp,
+    NatPmp,
+    Upnp,
+}
+
+/// 续期与删除映射时需要的协议数据
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum Renewal {
+    #[default]
+    None,
+    /// PCP续期必须使用同一nonce
+    Pcp([u8; 12]),
+    Upnp(upnp::Service),
+}
+
+/// 网关上的UDP端口映射
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct PortMapping {
+    pub protocol: MappingProtocol,
+    /// NAT-PMP、PCP服务地址或UPnP控制地址
+    pub gateway: String,
+    pub internal: SocketAddr,
+    /// 网关没有告知公网ip时为None
+    pub external_ip: Option<IpAddr>,
+    pub external_port: u16,
+    /// 租期，秒，0为永久
+    pub lifetime: u32,
+    /// 建立或续期的时间，unix秒
+    pub updated: u64,
+    #[serde(skip)]
+    pub renewal: Renewal,
+}
+
+#[derive(Debug, Error)]
+pub enum MappingError {
+    #[error("没有找到默认网关")]
+    NoGateway,
+    #[error("网关没有应答")]
+    Timeout,
+    #[error("网关不支持该协议")]
+    Unsupported,
+    #[error("网关拒绝映射:{0}")]
+    Rejected(String),
+    #[error("UPnP错误{0}:{1}")]
+    Upnp(u16, String),
+    #[error("网关应答格式错误")]
+    Malformed,
+    #[error("网络错误:{0}")]
+    Io(String),
+    #[error("端口映射失败:{0}")]
+    Failed(String),
+}
+
+impl From<std::io::Error> for MappingError {
+    fn from(e: std::io::Error) -> Self {
+        MappingError::Io(e.to_string())
+    }
+}
+
+/// 映射状态，失败时mapping为None
+#[derive(Serialize, Clone, Debug, Default, PartialEq)]
+pub struct MappingStatus {
+    pub mapping: Option<PortMapping>,
+    pub error: Option<String>,
+    /// 最后一次请求的时间，unix秒
+    pub updated: u64,
+}
+
+/// 请求映射时联系的地址
+#[derive(Clone, Debug)]
+pub struct Gateway {
+    /// NAT-PMP与PCP的服务地址，没有默认网关时为None
+    pub pmp: Option<SocketAddr>,
+    /// SSDP搜索地址
+    pub ssdp: SocketAddr,
+    pub timeout: Duration,
+}
+
+impl Gateway {
+    /// 使用系统的默认网关
+    pub fn detect() -> Self {
+        let pmp = match platform::default_gateway() {
+            Ok(ip) => Some(SocketAddr::new(IpAddr::V4(ip), GATEWAY_PORT)),
+            Err(e) => {
+                warn!("{}:{}", line!(), e);
+                None
+            }
+        };
+        Self {
+            pmp,
+            ssdp: upnp::SSDP_ADDR,
+            timeout: REQUEST_TIMEOUT,
+        }
+    }
+
+    /// 依次尝试PCP、NAT-PMP与UPnP。续期时先使用原来的协议
+    pub async fn map(&self, port: u16, lifetime: u32, previous: Option<&PortMapping>) -> Result<PortMapping, MappingError> {
+        let mut errors = Vec::new();
+        if let Some(previous) = previous {
+            match self.request(previous.protocol, port, lifetime, Some(previous)).await {
+                Ok(mapping) => return Ok(mapping),
+                Err(e) => errors.push(format!("{:?}:{}", previous.protocol, e)),
+            }
+        }
+        for protocol in [MappingProtocol::Pcp, MappingProtocol::NatPmp, MappingProtocol::Upnp] {
+            if previous.is_some_and(|p| p.protocol == protocol) {
+                continue;
+            }
+            match self.request(protocol, port, lifetime, None).await {
+                Ok(mapping) => return Ok(mapping),
+                Err(e) => errors.push(format!("{:?}:{}", protocol, e)),
+            }
+        }
+        Err(MappingError::Failed(errors.join("; ")))
+    }
+
+    async fn request(
+        &self,
+        protocol: MappingProtocol,
+        port: u16,
+        lifetime: u32,
+        previous: Option<&PortMapping>,
+    ) -> Result<PortMapping, MappingError> {
+        match protocol {
+            MappingProtocol::Pcp => {
+                let gateway = self.pmp.ok_or(MappingError::NoGateway)?;
+                let nonce = match previous.map(|p| &p.renewal) {
+                    Some(Renewal::Pcp(nonce)) => *nonce,
+                    _ => crate::tools::nat_detect::stun::transaction_id(),
+                };
+                pcp::map(gateway, port, lifetime, nonce, self.timeout).await
+            }
+            MappingProtocol::NatPmp => {
+                let gateway = self.pmp.ok_or(MappingError::NoGateway)?;
+                let external = previous.map(|p| p.external_port).unwrap_or(port);
+                natpmp::map(gateway, port, external, lifetime, self.timeout).await
+            }
+            MappingProtocol::Upnp => {
+                let service = match previous.map(|p| &p.renewal) {
+                    Some(Renewal::Upnp(service)) => service.clone(),
+                    _ => upnp::discover(self.ssdp, self.timeout * 8).await?,
+                };
+                upnp::map(&service, port, lifetime).await
+            }
+        }
+    }
+
+    /// 删除映射
+    pub async fn unmap(&self, mapping: &PortMapping) -> Result<(), MappingError> {
+        match (&mapping.renewal, self.pmp) {
+            (Renewal::Upnp(service), _) => upnp::unmap(service, mapping.external_port).await,
+            (Renewal::Pcp(nonce), Some(gateway)) => {
+                pcp::unmap(gateway, mapping.internal.port(), *nonce, self.timeout).await
+            }
+            (Renewal::None, Some(gateway)) => natpmp::unmap(gateway, mapping.internal.port(), self.timeout).await,
+            (_, None) => Err(MappingError::NoGateway),
+        }
+    }
+}
+
+/// 到达目的地址时使用的本机地址
+pub(crate) async fn local_ip(target: SocketAddr) -> Result<IpAddr, MappingError> {
+    let socket = UdpSocket::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0)).await?;
+    socket.connect(target).await?;
+    Ok(socket.local_addr()?.ip())
+}
+
+/// 向网关发送请求，超时后按RFC 6886加倍等待并重发，直到收到满足`accept`的应答
+pub(crate) async fn exchange(
+    socket: &UdpSocket,
+    request: &[u8],
+    timeout: Duration,
+    accept: impl Fn(&[u8]) -> bool,
+) -> Result<Vec<u8>, MappingError> {
+    let mut buf = [0; 1100];
+    let mut wait = timeout;
+    for _ in 0..RETRIES {
+        socket.send(request).await?;
+        let deadline = tokio::time::Instant::now() + wait;
+        loop {
+            match tokio::time::timeout_at(deadline, socket.recv(&mut buf)).await {
+                Ok(Ok(len)) if accept(&buf[..len]) => return Ok(buf[..len].to_vec()),
+                Ok(Ok(_)) => continue,
+                // 网关不监听时会收到ICMP端口不可达
+                Ok(Err(e)) if e.kind() == std::io::ErrorKind::ConnectionRefused => {
+                    return Err(MappingError::Unsupported)
+                }
+                Ok(Err(e)) => return Err(e.into()),
+                Err(_) => break,
+            }
+        }
+        wait *= 2;
+    }
+    Err(MappingError::Timeout)
+}
+
+/// 租期过半时续期
+fn renew_interval(lifetime: u32) -> Duration {
+    match lifetime {
+        0 => RECHECK_INTERVAL,
+        lifetime => Duration::from_millis(lifetime as u64 * 500),
+    }
+}
+
+/// edge运行期间保持端口映射，取消后删除映射
+pub struct PortMapper {
+    status: Arc<Mutex<MappingStatus>>,
+    cancel: CancellationToken,
+}
+
+impl Drop for PortMapper {
+    fn drop(&mut self) {
+        self.cancel.cancel();
+    }
+}
+
+impl PortMapper {
+    pub fn start(gateway: Gateway, port: u16, cancel: CancellationToken) -> Self {
+        let status = Arc::new(Mutex::new(MappingStatus::default()));
+        let shared = status.clone();
+        let token = cancel.clone();
+        tauri::async_runtime::spawn(async move {
+            let mut current: Option<PortMapping> = None;
+            loop {
+                let result = gateway.map(port, LEASE, current.as_ref()).await;
+                let wait = match result {
+                    Ok(ref mapping) => {
+                        info!("端口{}已通过{:?}映射到{}", port, mapping.protocol, mapping.external_port);
+                        current = Some(mapping.clone());
+                        renew_interval(mapping.lifetime)
+                    }
+                    Err(ref e) => {
+                        warn!("{}:{}", line!(), e);
+                        RETRY_INTERVAL
+                    }
+                };
+                match shared.lock() {
+                    Ok(mut s) => {
+                        *s = MappingStatus {
+                            mapping: result.as_ref().ok().cloned(),
+                            error: result.err().map(|e| e.to_string()),
+                            updated: unix_time(),
+                        }
+                    }
+                    Err(e) => error!("{}:{}", line!(), e),
+                }
+                tokio::select! {
+                    _ = token.cancelled() => break,
+                    _ = sleep(wait) => {}
+                }
+            }
+            if let Some(mapping) = current {
+                match gateway.unmap(&mapping).await {
+                    Ok(_) => info!("已删除端口{}的映射", port),
+                    Err(e) => warn!("{}:{}", line!(), e),
+                }
+            }
+        });
+        Self { status, cancel }
+    }
+
+    pub fn status(&self) -> MappingStatus {
+        match self.status.lock() {
+            Ok(s) => s.clone(),
+            Err(e) => {
+                error!("{}:{}", line!(), e);
+                MappingStatus::default()
+            }
+        }
+    }
+}
+
+/// 检查网关能否映射端口，成功后立即删除
+pub async fn probe(gateway: &Gateway, port: u16) -> MappingStatus {
+    let result = gateway.map(port, LEASE, None).await;
+    if let Ok(ref mapping) = result {
+        if let Err(e) = gateway.unmap(mapping).await {
+            warn!("{}:{}", line!(), e);
+        }
+    }
+    MappingStatus {
+        mapping: result.as_ref().ok().cloned(),
+        error: result.err().map(|e| e.to_string()),
+        updated: unix_time(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tools::port_mapping::natpmp::tests::FakeNatPmp;
+
+    #[tokio::test]
+    async fn falls_back_and_renews() {
+        let fake = FakeNatPmp::start(2).await;
+        let gateway = Gateway {
+            pmp: Some(fake.address),
+            // 没有SSDP应答
+            ssdp: "127.0.0.1:9".parse().unwrap(),
+            timeout: Duration::from_millis(50),
+        };
+        // PCP请求被只支持NAT-PMP的网关拒绝
+        let mapping = gateway.map(49898, LEASE, None).await.unwrap();
+        assert_eq!(mapping.protocol, MappingProtocol::NatPmp);
+        assert_eq!(mapping.external_port, 49898);
+
+        let cancel = CancellationToken::new();
+        let mapper = PortMapper::start(gateway, 49898, cancel.clone());
+        sleep(Duration::from_millis(1500)).await;
+        assert_eq!(mapper.status().mapping.map(|m| m.lifetime), Some(2));
+        // 首次映射与续期
+        assert!(fake.requests(49898) >= 2);
+        cancel.cancel();
+        sleep(Duration::from_millis(200)).await;
+        assert!(!fake.mapped(49898));
+    }
+
+    #[test]
+    fn renewal() {
+        assert_eq!(renew_interval(0), RECHECK_INTERVAL);
+        assert_eq!(renew_interval(3600), Duration::from_secs(1800));
+    }
+}
@@ -0,0 +1,196 @@
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::Duration;
+
+use tokio::net::UdpSocket;
+
+use crate::tools::output::unix_time;
+use crate::tools::port_mapping::{exchange, local_ip, MappingError, MappingProtocol, PortMapping, Renewal};
+
+const VERSION: u8 = 0;
+const OP_EXTERNAL_ADDRESS: u8 = 0;
+const OP_MAP_UDP: u8 = 1;
+/// 应答的操作码为请求加128
+const OP_RESPONSE: u8 = 128;
+
+/// RFC 6886 3.5的结果码
+fn result_error(code: u16) -> MappingError {
+    match code {
+        1 => MappingError::Unsupported,
+        2 => MappingError::Rejected("未授权".to_string()),
+        3 => MappingError::Rejected("网关没有连接外网".to_string()),
+        4 => MappingError::Rejected("资源不足".to_string()),
+        5 => MappingError::Unsupported,
+        code => MappingError::Rejected(format!("结果码{}", code)),
+    }
+}
+
+async fn connect(gateway: SocketAddr) -> Result<UdpSocket, MappingError> {
+    let socket = UdpSocket::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0)).await?;
+    socket.connect(gateway).await?;
+    Ok(socket)
+}
+
+/// 检查应答头，返回结果码之后的内容
+fn parse(response: &[u8], op: u8, len: usize) -> Result<&[u8], MappingError> {
+    if response.len() < 4 {
+        return Err(MappingError::Malformed);
+    }
+    // 不支持的版本以自己的版本号应答
+    if response[0] != VERSION {
+        return Err(MappingError::Unsupported);
+    }
+    let code = u16::from_be_bytes([response[2], response[3]]);
+    if code != 0 {
+        return Err(result_error(code));
+    }
+    if response[1] != OP_RESPONSE + op || response.len() < len {
+        return Err(MappingError::Malformed);
+    }
+    Ok(&response[4..len])
+}
+
+/// 网关的公网ip
+pub async fn external_address(gateway: SocketAddr, timeout: Duration) -> Result<Ipv4Addr, MappingError> {
+    let socket = connect(gateway).await?;
+    let request = [VERSION, OP_EXTERNAL_ADDRESS];
+    let response = exchange(&socket, &request, timeout, |r| r.len() >= 2 && r[1] >= OP_RESPONSE).await?;
+    let body = parse(&response, OP_EXTERNAL_ADDRESS, 12)?;
+    Ok(Ipv4Addr::new(body[4], body[5], body[6], body[7]))
+}
+
+/// 请求映射UDP端口，`external`只是建议值，网关可能分配其他端口
+pub async fn map(
+    gateway: SocketAddr,
+    port: u16,
+    external: u16,
+    lifetime: u32,
+    timeout: Duration,
+) -> Result<PortMapping, MappingError> {
+    let socket = connect(gateway).await?;
+    let mut request = vec![VERSION, OP_MAP_UDP, 0, 0];
+    request.extend_from_slice(&port.to_be_bytes());
+    request.extend_from_slice(&external.to_be_bytes());
+    request.extend_from_slice(&lifetime.to_be_bytes());
+    let accept = |r: &[u8]| r.len() >= 2 && r[1] >= OP_RESPONSE && (r.len() < 10 || r[8..10] == port.to_be_bytes());
+    let response = exchange(&socket, &request, timeout, accept).await?;
+    let body = parse(&response, OP_MAP_UDP, 16)?;
+    let external_port = u16::from_be_bytes([body[6], body[7]]);
+    let lifetime = u32::from_be_bytes([body[8], body[9], body[10], body[11]]);
+    // 公网ip需要另外询问，失败时不影响映射
+    let external_ip = external_address(gateway, timeout).await.ok().map(IpAddr::V4);
+    Ok(PortMapping {
+        protocol: MappingProtocol::NatPmp,
+        gateway: gateway.to_string(),
+        internal: SocketAddr::new(local_ip(gateway).await?, port),
+        external_ip,
+        external_port,
+        lifetime,
+        updated: unix_time(),
+        renewal: Renewal::None,
+    })
+}
+
+/// 删除映射，即租期为0的映射请求
+pub async fn unmap(gateway: SocketAddr, port: u16, timeout: Duration) -> Result<(), MappingError> {
+    map(gateway, port, 0, 0, timeout).await.map(|_| ())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+
+    pub const EXTERNAL_IP: Ipv4Addr = Ipv4Addr::new(203, 0, 113, 9);
+
+    #[derive(Default)]
+    struct State {
+        requests: HashMap<u16, usize>,
+        /// 内网端口 -> 租期
+        mappings: HashMap<u16, u32>,
+    }
+
+    /// 只支持NAT-PMP的网关，租期最多为`max_lifetime`秒
+    pub struct FakeNatPmp {
+        pub address: SocketAddr,
+        state: Arc<Mutex<State>>,
+    }
+
+    impl FakeNatPmp {
+        pub async fn start(max_lifetime: u32) -> Self {
+            let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let address = socket.local_addr().unwrap();
+            let state = Arc::new(Mutex::new(State::default()));
+            let shared = state.clone();
+            tokio::spawn(async move {
+                let mut buf = [0; 1100];
+                while let Ok((len, from)) = socket.recv_from(&mut buf).await {
+                    let request = &buf[..len];
+                    let response = match (request[0], request.get(1)) {
+                        (VERSION, Some(&OP_EXTERNAL_ADDRESS)) => {
+                            let mut r = vec![VERSION, OP_RESPONSE, 0, 0];
+                            r.extend_from_slice(&(unix_time() as u32).to_be_bytes());
+                            r.extend_from_slice(&EXTERNAL_IP.octets());
+                            r
+                        }
+                        (VERSION, Some(&OP_MAP_UDP)) if len >= 12 => {
+                            let port = u16::from_be_bytes([request[4], request[5]]);
+                            let external = u16::from_be_bytes([request[6], request[7]]);
+                            let lifetime = u32::from_be_bytes([request[8], request[9], request[10], request[11]]);
+                            let lifetime = lifetime.min(max_lifetime);
+                            let mut state = shared.lock().unwrap();
+                            *state.requests.entry(port).or_default() += 1;
+                            if lifetime == 0 {
+                                state.mappings.remove(&port);
+                            } else {
+                                state.mappings.insert(port, lifetime);
+                            }
+                            let mut r = vec![VERSION, OP_RESPONSE + OP_MAP_UDP, 0, 0];
+                            r.extend_from_slice(&(unix_time() as u32).to_be_bytes());
+                            r.extend_from_slice(&port.to_be_bytes());
+                            r.extend_from_slice(&if lifetime == 0 { 0 } else { external }.to_be_bytes());
+                            r.extend_from_slice(&lifetime.to_be_bytes());
+                            r
+                        }
+                        // 其他版本(PCP)以结果码1应答
+                        (_, op) => {
+                            let mut r = vec![VERSION, op.copied().unwrap_or(0) | OP_RESPONSE, 0, 1];
+                            r.extend_from_slice(&(unix_time() as u32).to_be_bytes());
+                            r
+                        }
+                    };
+                    let _ = socket.send_to(&response, from).await;
+                }
+            });
+            Self { address, state }
+        }
+
+        pub fn requests(&self, port: u16) -> usize {
+            self.state.lock().unwrap().requests.get(&port).copied().unwrap_or(0)
+        }
+
+        pub fn mapped(&self, port: u16) -> bool {
+            self.state.lock().unwrap().mappings.contains_key(&port)
+        }
+    }
+
+    #[tokio::test]
+    async fn map_and_unmap() {
+        let fake = FakeNatPmp::start(7200).await;
+        let timeout = Duration::from_millis(50);
+        let mapping = map(fake.address, 40001, 40001, 3600, timeout).await.unwrap();
+        assert_eq!(mapping.external_ip, Some(IpAddr::V4(EXTERNAL_IP)));
+        assert_eq!(mapping.external_port, 40001);
+        assert_eq!(mapping.lifetime, 3600);
+        assert_eq!(mapping.internal.port(), 40001);
+        assert!(fake.mapped(40001));
+        unmap(fake.address, 40001, timeout).await.unwrap();
+        assert!(!fake.mapped(40001));
+
+        // PCP请求得到NAT-PMP的版本错误
+        let socket = connect(fake.address).await.unwrap();
+        let response = exchange(&socket, &[2, 1, 0, 0], timeout, |_| true).await.unwrap();
+        assert!(matches!(parse(&response, OP_MAP_UDP, 16), Err(MappingError::Unsupported)));
+    }
+}
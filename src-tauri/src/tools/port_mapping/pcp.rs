@@ -0,0 +1,159 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+use tokio::net::UdpSocket;
+
+use crate::tools::output::unix_time;
+use crate::tools::port_mapping::{exchange, local_ip, MappingError, MappingProtocol, PortMapping, Renewal};
+
+const VERSION: u8 = 2;
+const OP_MAP: u8 = 1;
+/// 应答的R位
+const RESPONSE: u8 = 0x80;
+const PROTOCOL_UDP: u8 = 17;
+const REQUEST_LEN: usize = 60;
+
+/// RFC 6887 7.4的结果码
+fn result_error(code: u8) -> MappingError {
+    match code {
+        1 | 4 | 9 => MappingError::Unsupported,
+        2 => MappingError::Rejected("未授权".to_string()),
+        7 => MappingError::Rejected("网关没有连接外网".to_string()),
+        8 | 10 => MappingError::Rejected("资源不足".to_string()),
+        11 => MappingError::Rejected("无法分配建议的公网端口".to_string()),
+        12 => MappingError::Rejected("请求中的本机地址与来源不符".to_string()),
+        code => MappingError::Rejected(format!("结果码{}", code)),
+    }
+}
+
+/// PCP中的地址均为16字节，IPv4使用映射地址
+fn address_bytes(ip: IpAddr) -> [u8; 16] {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped().octets(),
+        IpAddr::V6(ip) => ip.octets(),
+    }
+}
+
+fn address_from(bytes: &[u8]) -> IpAddr {
+    let mut octets = [0; 16];
+    octets.copy_from_slice(&bytes[..16]);
+    let ip = Ipv6Addr::from(octets);
+    ip.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(IpAddr::V6(ip))
+}
+
+fn request(client: IpAddr, port: u16, lifetime: u32, nonce: [u8; 12]) -> Vec<u8> {
+    let mut request = Vec::with_capacity(REQUEST_LEN);
+    request.extend_from_slice(&[VERSION, OP_MAP, 0, 0]);
+    request.extend_from_slice(&lifetime.to_be_bytes());
+    request.extend_from_slice(&address_bytes(client));
+    request.extend_from_slice(&nonce);
+    request.extend_from_slice(&[PROTOCOL_UDP, 0, 0, 0]);
+    request.extend_from_slice(&port.to_be_bytes());
+    // 建议与内网相同的端口，删除时为0
+    request.extend_from_slice(&if lifetime == 0 { 0 } else { port }.to_be_bytes());
+    request.extend_from_slice(&address_bytes(IpAddr::V4(Ipv4Addr::UNSPECIFIED)));
+    request
+}
+
+/// 请求映射UDP端口，续期与删除时须使用同一nonce
+pub async fn map(
+    gateway: SocketAddr,
+    port: u16,
+    lifetime: u32,
+    nonce: [u8; 12],
+    timeout: Duration,
+) -> Result<PortMapping, MappingError> {
+    let socket = UdpSocket::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0)).await?;
+    socket.connect(gateway).await?;
+    let client = local_ip(gateway).await?;
+    // 只支持NAT-PMP的网关以版本0应答，长度不足MAP应答
+    let accept = |r: &[u8]| r.len() >= 4 && (r[0] != VERSION || (r.len() >= REQUEST_LEN && r[24..36] == nonce));
+    let response = exchange(&socket, &request(client, port, lifetime, nonce), timeout, accept).await?;
+    if response[0] != VERSION {
+        return Err(MappingError::Unsupported);
+    }
+    if response[3] != 0 {
+        return Err(result_error(response[3]));
+    }
+    if response[1] != RESPONSE | OP_MAP {
+        return Err(MappingError::Malformed);
+    }
+    let external_ip = address_from(&response[44..60]);
+    Ok(PortMapping {
+        protocol: MappingProtocol::Pcp,
+        gateway: gateway.to_string(),
+        internal: SocketAddr::new(client, port),
+        external_ip: Some(external_ip).filter(|ip| !ip.is_unspecified()),
+        external_port: u16::from_be_bytes([response[42], response[43]]),
+        lifetime: u32::from_be_bytes([response[4], response[5], response[6], response[7]]),
+        updated: unix_time(),
+        renewal: Renewal::Pcp(nonce),
+    })
+}
+
+/// 删除映射，即租期为0的映射请求
+pub async fn unmap(gateway: SocketAddr, port: u16, nonce: [u8; 12], timeout: Duration) -> Result<(), MappingError> {
+    map(gateway, port, 0, nonce, timeout).await.map(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    /// 分配内网端口加一的公网端口，只有原nonce可以续期与删除
+    async fn fake_pcp() -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let address = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut mappings: HashMap<u16, Vec<u8>> = HashMap::new();
+            let mut buf = [0; 1100];
+            while let Ok((len, from)) = socket.recv_from(&mut buf).await {
+                let request = &buf[..len];
+                let port = u16::from_be_bytes([request[40], request[41]]);
+                let lifetime = u32::from_be_bytes([request[4], request[5], request[6], request[7]]);
+                let nonce = request[24..36].to_vec();
+                let code = if address_from(&request[8..24]) != from.ip() {
+                    12
+                } else if mappings.get(&port).is_some_and(|n| *n != nonce) {
+                    2
+                } else if lifetime == 0 {
+                    mappings.remove(&port);
+                    0
+                } else {
+                    mappings.insert(port, nonce);
+                    0
+                };
+                let mut response = request.to_vec();
+                response[1] |= RESPONSE;
+                response[3] = code;
+                response[8..24].fill(0);
+                response[42..44].copy_from_slice(&if lifetime == 0 { 0 } else { port + 1 }.to_be_bytes());
+                response[44..60].copy_from_slice(&address_bytes("203.0.113.9".parse().unwrap()));
+                let _ = socket.send_to(&response, from).await;
+            }
+        });
+        address
+    }
+
+    #[tokio::test]
+    async fn map_and_unmap() {
+        let gateway = fake_pcp().await;
+        let timeout = Duration::from_millis(50);
+        let nonce = [7; 12];
+        let mapping = map(gateway, 40002, 3600, nonce, timeout).await.unwrap();
+        assert_eq!(mapping.protocol, MappingProtocol::Pcp);
+        assert_eq!(mapping.external_ip, Some("203.0.113.9".parse().unwrap()));
+        assert_eq!(mapping.external_port, 40003);
+        assert_eq!(mapping.lifetime, 3600);
+        assert_eq!(mapping.renewal, Renewal::Pcp(nonce));
+
+        assert!(matches!(
+            unmap(gateway, 40002, [8; 12], timeout).await,
+            Err(MappingError::Rejected(_))
+        ));
+        unmap(gateway, 40002, nonce, timeout).await.unwrap();
+        map(gateway, 40002, 3600, [8; 12], timeout).await.unwrap();
+    }
+}
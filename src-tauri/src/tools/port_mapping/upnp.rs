@@ -0,0 +1,360 @@
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::Duration;
+
+use log::debug;
+use reqwest::Url;
+use tokio::net::UdpSocket;
+
+use crate::tools::output::unix_time;
+use crate::tools::port_mapping::{
+    local_ip, MappingError, MappingProtocol, PortMapping, Renewal, DESCRIPTION,
+};
+
+/// SSDP组播地址
+pub const SSDP_ADDR: SocketAddr =
+    SocketAddr::new(IpAddr::V4(Ipv4Addr::new(239, 255, 255, 250)), 1900);
+const SEARCH_TARGET: &str = "urn:schemas-upnp-org:device:InternetGatewayDevice:1";
+/// 按优先顺序排列的WAN连接服务
+const SERVICE_TYPES: [&str; 3] = [
+    "urn:schemas-upnp-org:service:WANIPConnection:2",
+    "urn:schemas-upnp-org:service:WANIPConnection:1",
+    "urn:schemas-upnp-org:service:WANPPPConnection:1",
+];
+const HTTP_TIMEOUT: Duration = Duration::from_secs(3);
+/// OnlyPermanentLeasesSupported，部分路由器只接受租期0
+const ONLY_PERMANENT_LEASES: u16 = 725;
+
+/// 路由器的WAN连接服务
+#[derive(Clone, Debug, PartialEq)]
+pub struct Service {
+    pub control_url: String,
+    pub service_type: String,
+}
+
+fn client() -> Result<reqwest::Client, MappingError> {
+    reqwest::Client::builder()
+        .timeout(HTTP_TIMEOUT)
+        .build()
+        .map_err(|e| MappingError::Io(e.to_string()))
+}
+
+/// 取出第一个`<name>`元素的内容
+fn element<'a>(xml: &'a str, name: &str) -> Option<&'a str> {
+    let start = xml.find(&format!("<{}>", name))? + name.len() + 2;
+    let end = start + xml[start..].find(&format!("</{}>", name))?;
+    Some(xml[start..end].trim())
+}
+
+/// 从设备描述中找出WAN连接服务，相对的控制地址按URLBase或描述地址补全
+pub fn service_from(description: &str, location: &str) -> Result<Service, MappingError> {
+    let base = element(description, "URLBase").unwrap_or(location);
+    let base = Url::parse(base).map_err(|_| MappingError::Malformed)?;
+    let services: Vec<(&str, &str)> = description
+        .split("<service>")
+        .skip(1)
+        .filter_map(|s| Some((element(s, "serviceType")?, element(s, "controlURL")?)))
+        .collect();
+    let (service_type, control) = SERVICE_TYPES
+        .iter()
+        .find_map(|t| services.iter().find(|(s, _)| s == t))
+        .ok_or(MappingError::Unsupported)?;
+    let control_url = base.join(control).map_err(|_| MappingError::Malformed)?;
+    Ok(Service {
+        control_url: control_url.to_string(),
+        service_type: service_type.to_string(),
+    })
+}
+
+/// 取出HTTP头的值，不区分大小写
+fn header<'a>(response: &'a str, name: &str) -> Option<&'a str> {
+    response.lines().find_map(|line| {
+        let (key, value) = line.split_once(':')?;
+        key.trim().eq_ignore_ascii_case(name).then(|| value.trim())
+    })
+}
+
+/// 以SSDP搜索路由器，返回第一个提供WAN连接服务的设备
+pub async fn discover(ssdp: SocketAddr, timeout: Duration) -> Result<Service, MappingError> {
+    let socket = UdpSocket::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0)).await?;
+    let search = format!(
+        "M-SEARCH * HTTP/1.1\r\nHOST: {}\r\nST: {}\r\nMAN: \"ssdp:discover\"\r\nMX: 2\r\n\r\n",
+        SSDP_ADDR, SEARCH_TARGET
+    );
+    socket.send_to(search.as_bytes(), ssdp).await?;
+    let client = client()?;
+    let deadline = tokio::time::Instant::now() + timeout;
+    let mut buf = [0; 2048];
+    let mut last = MappingError::Timeout;
+    loop {
+        let len = match tokio::time::timeout_at(deadline, socket.recv(&mut buf)).await {
+            Ok(result) => result?,
+            Err(_) => return Err(last),
+        };
+        let response = String::from_utf8_lossy(&buf[..len]);
+        let Some(location) = header(&response, "location") else {
+            continue;
+        };
+        debug!("SSDP应答:{}", location);
+        let description = async {
+            client
+                .get(location)
+                .send()
+                .await?
+                .error_for_status()?
+                .text()
+                .await
+        };
+        match description.await {
+            Ok(description) => match service_from(&description, location) {
+                Ok(service) => return Ok(service),
+                Err(e) => last = e,
+            },
+            Err(e) => last = MappingError::Io(e.to_string()),
+        }
+    }
+}
+
+/// 调用服务的SOAP操作，返回应答正文
+async fn soap(
+    service: &Service,
+    action: &str,
+    arguments: &[(&str, String)],
+) -> Result<String, MappingError> {
+    let arguments: String = arguments
+        .iter()
+        .map(|(name, value)| format!("<{0}>{1}</{0}>", name, value))
+        .collect();
+    let body = format!(
+        "<?xml version=\"1.0\"?>\r\n<s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\"><s:Body><u:{0} xmlns:u=\"{1}\">{2}</u:{0}></s:Body></s:Envelope>",
+        action, service.service_type, arguments
+    );
+    let response = client()?
+        .post(&service.control_url)
+        .header("Content-Type", "text/xml; charset=\"utf-8\"")
+        .header(
+            "SOAPAction",
+            format!("\"{}#{}\"", service.service_type, action),
+        )
+        .body(body)
+        .send()
+        .await
+        .map_err(|e| MappingError::Io(e.to_string()))?;
+    let status = response.status();
+    let text = response
+        .text()
+        .await
+        .map_err(|e| MappingError::Io(e.to_string()))?;
+    if status.is_success() {
+        return Ok(text);
+    }
+    // 失败时以500返回UPnPError
+    match element(&text, "errorCode").and_then(|c| c.parse().ok()) {
+        Some(code) => Err(MappingError::Upnp(
+            code,
+            element(&text, "errorDescription")
+                .unwrap_or_default()
+                .to_string(),
+        )),
+        None => Err(MappingError::Io(status.to_string())),
+    }
+}
+
+async fn add(
+    service: &Service,
+    internal: IpAddr,
+    port: u16,
+    lifetime: u32,
+) -> Result<(), MappingError> {
+    let arguments = [
+        ("NewRemoteHost", String::new()),
+        ("NewExternalPort", port.to_string()),
+        ("NewProtocol", "UDP".to_string()),
+        ("NewInternalPort", port.to_string()),
+        ("NewInternalClient", internal.to_string()),
+        ("NewEnabled", "1".to_string()),
+        ("NewPortMappingDescription", DESCRIPTION.to_string()),
+        ("NewLeaseDuration", lifetime.to_string()),
+    ];
+    soap(service, "AddPortMapping", &arguments)
+        .await
+        .map(|_| ())
+}
+
+/// 把路由器的同号UDP端口映射到本机
+pub async fn map(service: &Service, port: u16, lifetime: u32) -> Result<PortMapping, MappingError> {
+    let url = Url::parse(&service.control_url).map_err(|_| MappingError::Malformed)?;
+    let host = url
+        .socket_addrs(|| Some(80))
+        .ok()
+        .and_then(|a| a.into_iter().next())
+        .ok_or(MappingError::Malformed)?;
+    let internal = local_ip(host).await?;
+    let lifetime = match add(service, internal, port, lifetime).await {
+        Err(MappingError::Upnp(ONLY_PERMANENT_LEASES, _)) if lifetime != 0 => {
+            add(service, internal, port, 0).await?;
+            0
+        }
+        result => result.map(|_| lifetime)?,
+    };
+    let external_ip = soap(service, "GetExternalIPAddress", &[])
+        .await
+        .ok()
+        .and_then(|r| element(&r, "NewExternalIPAddress")?.parse().ok());
+    Ok(PortMapping {
+        protocol: MappingProtocol::Upnp,
+        gateway: service.control_url.clone(),
+        internal: SocketAddr::new(internal, port),
+        external_ip,
+        external_port: port,
+        lifetime,
+        updated: unix_time(),
+        renewal: Renewal::Upnp(service.clone()),
+    })
+}
+
+pub async fn unmap(service: &Service, port: u16) -> Result<(), MappingError> {
+    let arguments = [
+        ("NewRemoteHost", String::new()),
+        ("NewExternalPort", port.to_string()),
+        ("NewProtocol", "UDP".to_string()),
+    ];
+    soap(service, "DeletePortMapping", &arguments)
+        .await
+        .map(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    const DESCRIPTION_XML: &str = "<?xml version=\"1.0\"?><root><device><deviceList><device><serviceList>\
+        <service><serviceType>urn:schemas-upnp-org:service:WANCommonInterfaceConfig:1</serviceType><controlURL>/cfg</controlURL></service>\
+        <service><serviceType>urn:schemas-upnp-org:service:WANIPConnection:1</serviceType><controlURL>/ctl/IPConn</controlURL></service>\
+        </serviceList></device></deviceList></device></root>";
+
+    fn respond(status: &str, body: &str) -> String {
+        format!(
+            "HTTP/1.1 {}\r\nContent-Type: text/xml\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status,
+            body.len(),
+            body
+        )
+    }
+
+    /// 只接受永久映射的IGD，返回SSDP地址与现有映射
+    async fn fake_igd() -> (SocketAddr, Arc<Mutex<HashMap<u16, String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let http = listener.local_addr().unwrap();
+        let mappings = Arc::new(Mutex::new(HashMap::new()));
+        let shared = mappings.clone();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut request = Vec::new();
+                let mut buf = [0; 4096];
+                // 读到正文结束
+                while let Ok(n) = stream.read(&mut buf).await {
+                    request.extend_from_slice(&buf[..n]);
+                    let text = String::from_utf8_lossy(&request);
+                    if n == 0
+                        || (text.starts_with("GET") && text.contains("\r\n\r\n"))
+                        || text.contains("</s:Envelope>")
+                    {
+                        break;
+                    }
+                }
+                let request = String::from_utf8_lossy(&request).to_string();
+                let port = element(&request, "NewExternalPort").and_then(|p| p.parse::<u16>().ok());
+                let response = if request.starts_with("GET /desc.xml") {
+                    respond("200 OK", DESCRIPTION_XML)
+                } else if !request.starts_with("POST /ctl/IPConn") {
+                    respond("404 Not Found", "")
+                } else if request.contains("#AddPortMapping") {
+                    if element(&request, "NewLeaseDuration") != Some("0") {
+                        respond(
+                            "500 Internal Server Error",
+                            "<s:Envelope><s:Body><s:Fault><detail><UPnPError><errorCode>725</errorCode><errorDescription>OnlyPermanentLeasesSupported</errorDescription></UPnPError></detail></s:Fault></s:Body></s:Envelope>",
+                        )
+                    } else {
+                        let client = element(&request, "NewInternalClient").unwrap().to_string();
+                        shared.lock().unwrap().insert(port.unwrap(), client);
+                        respond(
+                            "200 OK",
+                            "<s:Envelope><s:Body><u:AddPortMappingResponse/></s:Body></s:Envelope>",
+                        )
+                    }
+                } else if request.contains("#DeletePortMapping") {
+                    shared.lock().unwrap().remove(&port.unwrap());
+                    respond(
+                        "200 OK",
+                        "<s:Envelope><s:Body><u:DeletePortMappingResponse/></s:Body></s:Envelope>",
+                    )
+                } else {
+                    respond(
+                        "200 OK",
+                        "<s:Envelope><s:Body><u:GetExternalIPAddressResponse><NewExternalIPAddress>203.0.113.9</NewExternalIPAddress></u:GetExternalIPAddressResponse></s:Body></s:Envelope>",
+                    )
+                };
+                let _ = stream.write_all(response.as_bytes()).await;
+            }
+        });
+
+        let ssdp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let address = ssdp.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0; 2048];
+            while let Ok((len, from)) = ssdp.recv_from(&mut buf).await {
+                if !String::from_utf8_lossy(&buf[..len]).starts_with("M-SEARCH") {
+                    continue;
+                }
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nCACHE-CONTROL: max-age=120\r\nST: {}\r\nLocation: http://{}/desc.xml\r\n\r\n",
+                    SEARCH_TARGET, http
+                );
+                let _ = ssdp.send_to(response.as_bytes(), from).await;
+            }
+        });
+        (address, mappings)
+    }
+
+    #[test]
+    fn description() {
+        let service =
+            service_from(DESCRIPTION_XML, "http://192.168.1.1:5000/rootDesc.xml").unwrap();
+        assert_eq!(service.control_url, "http://192.168.1.1:5000/ctl/IPConn");
+        assert_eq!(service.service_type, SERVICE_TYPES[1]);
+        let based = DESCRIPTION_XML.replace(
+            "<device>",
+            "<URLBase>http://192.168.1.1:49152/</URLBase><device>",
+        );
+        let service = service_from(&based, "http://192.168.1.1:5000/rootDesc.xml").unwrap();
+        assert_eq!(service.control_url, "http://192.168.1.1:49152/ctl/IPConn");
+        assert!(matches!(
+            service_from("<root></root>", "http://192.168.1.1/"),
+            Err(MappingError::Unsupported)
+        ));
+    }
+
+    #[tokio::test]
+    async fn permanent_lease_fallback() {
+        let (ssdp, mappings) = fake_igd().await;
+        let service = discover(ssdp, Duration::from_secs(2)).await.unwrap();
+        let mapping = map(&service, 40004, 3600).await.unwrap();
+        assert_eq!(mapping.protocol, MappingProtocol::Upnp);
+        assert_eq!(mapping.lifetime, 0);
+        assert_eq!(mapping.external_port, 40004);
+        assert_eq!(mapping.external_ip, Some("203.0.113.9".parse().unwrap()));
+        assert_eq!(
+            mappings.lock().unwrap().get(&40004).map(String::as_str),
+            Some("127.0.0.1")
+        );
+
+        unmap(&service, 40004).await.unwrap();
+        assert!(mappings.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,197 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
+
+use log::error;
+
+use crate::tools::miniserve::MiniServe;
+use crate::tools::n2n_client::N2NClient;
+use crate::tools::supernode::Supernode;
+use crate::tools::supervisor::RestartRecord;
+use crate::tools::win_ip_broadcast::WinIPBroadcast;
+use crate::tools::{ChildProcess, ProgramError};
+
+type Services<T> = HashMap<String, Arc<RwLock<T>>>;
+
+/// 同一类子进程，按名称索引
+pub struct ServiceMap<T: ChildProcess> {
+    services: Mutex<Services<T>>,
+}
+
+impl<T: ChildProcess> Default for ServiceMap<T> {
+    fn default() -> Self {
+        Self {
+            services: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl<T: ChildProcess> ServiceMap<T> {
+    fn lock(&self) -> Result<MutexGuard<'_, Services<T>>, ProgramError> {
+        self.services
+            .lock()
+            .map_err(|e| ProgramError::ChildProcessError(e.to_string()))
+    }
+
+    pub fn get(&self, name: &str) -> Option<Arc<RwLock<T>>> {
+        self.lock().ok()?.get(name).cloned()
+    }
+
+    /// 保存已启动的子进程，替换掉的旧子进程在锁外停止
+    pub fn insert(&self, name: &str, service: T) -> Result<(), ProgramError> {
+        let old = self.lock()?.insert(name.to_string(), Arc::new(RwLock::new(service)));
+        if let Some(old) = old {
+            Self::shutdown(&old)?;
+        }
+        Ok(())
+    }
+
+    /// 对子进程执行操作，不存在时返回ChildProcessNotFound
+    pub fn with<R>(&self, name: &str, f: impl FnOnce(&mut T) -> R) -> Result<R, ProgramError> {
+        let service = self.get(name).ok_or(ProgramError::ChildProcessNotFound)?;
+        let mut service = service
+            .write()
+            .map_err(|e| ProgramError::ChildProcessError(e.to_string()))?;
+        Ok(f(&mut service))
+    }
+
+    /// 子进程是否正在运行
+    pub fn running(&self, name: &str) -> bool {
+        self.with(name, |s| s.get_process().status()).unwrap_or(false)
+    }
+
+    fn shutdown(service: &Arc<RwLock<T>>) -> Result<(), ProgramError> {
+        service
+            .write()
+            .map_err(|e| ProgramError::ChildProcessError(e.to_string()))?
+            .shutdown()
+    }
+
+    /// 移除并停止子进程，停止期间不占用整个集合
+    pub fn stop(&self, name: &str) -> Result<(), ProgramError> {
+        let service = self.lock()?.remove(name);
+        match service {
+            None => Ok(()),
+            Some(service) => Self::shutdown(&service),
+        }
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        self.lock()
+            .map(|s| s.keys().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// 停止全部子进程
+    pub fn stop_all(&self) {
+        for name in self.names() {
+            if let Err(e) = self.stop(&name) {
+                error!("{}:{}", line!(), e);
+            }
+        }
+    }
+
+    /// 按重启策略检查全部子进程
+    pub fn supervise(&self) -> Vec<RestartRecord> {
+        let services: Vec<_> = match self.lock() {
+            Ok(s) => s.values().cloned().collect(),
+            Err(e) => {
+                error!("{}:{}", line!(), e);
+                return Vec::new();
+            }
+        };
+        let mut records = Vec::new();
+        for service in services {
+            match service.write() {
+                Ok(mut s) => records.extend(s.get_process().supervise()),
+                Err(e) => error!("{}:{}", line!(), ProgramError::ChildProcessError(e.to_string())),
+            }
+        }
+        records
+    }
+}
+
+/// 全部子进程，注册在Tauri状态中
+#[derive(Default)]
+pub struct ProcessManager {
+    /// 每个网络配置对应一个edge
+    pub edges: ServiceMap<N2NClient>,
+    pub broadcast: ServiceMap<WinIPBroadcast>,
+    pub file_share: ServiceMap<MiniServe>,
+    /// 本机主持网络时的超级节点
+    pub supernode: ServiceMap<Supernode>,
+}
+
+impl ProcessManager {
+    pub fn supervise(&self) -> Vec<RestartRecord> {
+        let mut records = self.edges.supervise();
+        records.extend(self.broadcast.supervise());
+        records.extend(self.file_share.supervise());
+        records.extend(self.supernode.supervise());
+        records
+    }
+
+    pub fn stop_all(&self) {
+        self.edges.stop_all();
+        self.broadcast.stop_all();
+        self.file_share.stop_all();
+        self.supernode.stop_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::tools::ExternalBinaryProgram;
+
+    struct Sleeper {
+        program: ExternalBinaryProgram,
+    }
+
+    impl ChildProcess for Sleeper {
+        fn get_process(&mut self) -> &mut ExternalBinaryProgram {
+            &mut self.program
+        }
+    }
+
+    fn sleeper(name: &str) -> Sleeper {
+        let mut program =
+            ExternalBinaryProgram::new(name, PathBuf::from("sleep"), vec!["30".to_string()]).unwrap();
+        program.start().unwrap();
+        Sleeper { program }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn lifecycle() {
+        let map = ServiceMap::<Sleeper>::default();
+        assert!(!map.running("a"));
+        assert!(matches!(map.with("a", |_| ()), Err(ProgramError::ChildProcessNotFound)));
+
+        map.insert("a", sleeper("manager_a")).unwrap();
+        map.insert("b", sleeper("manager_b")).unwrap();
+        assert!(map.running("a"));
+        assert!(map.supervise().is_empty());
+
+        let a = map.get("a").unwrap();
+        map.stop("a").unwrap();
+        assert!(!map.running("a"));
+        assert!(!a.write().unwrap().get_process().status());
+
+        map.stop_all();
+        assert!(map.names().is_empty());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn insert_replaces() {
+        let map = ServiceMap::<Sleeper>::default();
+        map.insert("a", sleeper("manager_old")).unwrap();
+        let old = map.get("a").unwrap();
+        map.insert("a", sleeper("manager_new")).unwrap();
+        assert!(!old.write().unwrap().get_process().status());
+        assert!(map.running("a"));
+        map.stop_all();
+    }
+}
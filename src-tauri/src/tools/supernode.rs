@@ -0,0 +1,373 @@
+use log::error;
+use serde::{Deserialize, Serialize};
+use tauri::{AppHandle, Manager, State};
+
+use crate::config::LocalConfig;
+use crate::platform::{self, FirewallProtocol, FirewallRule};
+use crate::tools::address::{self, Ipv4Cidr};
+use crate::tools::n2n_client::{self, N2NClient, N2NClientConfig};
+use crate::tools::process_manager::ProcessManager;
+use crate::tools::supervisor::RestartPolicy;
+use crate::tools::{ChildProcess, ExternalBinaryProgram, ExternalFilePosition, ProgramError};
+
+/// 本机主持网络时运行的超级节点
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct SupernodeConfig {
+    /// 监听的UDP端口
+    pub port: u16,
+    /// 管理端口，不能与edge的相同
+    pub control_port: u16,
+    /// 对外公布的地址，如公网ip或域名，为空时只公布本机局域网地址
+    pub public_address: Option<String>,
+    /// 超级节点异常退出后的重启策略
+    pub restart: RestartPolicy,
+}
+
+impl Default for SupernodeConfig {
+    fn default() -> Self {
+        Self {
+            port: 7654,
+            control_port: 5645,
+            public_address: None,
+            restart: RestartPolicy::default(),
+        }
+    }
+}
+
+/// 其他成员加入本机网络所需的信息
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct HostInfo {
+    /// 连接到本机超级节点的网络配置
+    pub profile: String,
+    pub group: String,
+    pub port: u16,
+    /// 可供其他成员使用的超级节点地址，`host:port`
+    pub supernodes: Vec<String>,
+    pub member_server: String,
+}
+
+impl HostInfo {
+    pub fn new(
+        profile: &N2NClientConfig,
+        config: &SupernodeConfig,
+        networks: &[(String, Ipv4Cidr)],
+    ) -> Self {
+        let mut supernodes: Vec<String> = config
+            .public_address
+            .iter()
+            .filter(|a| !a.is_empty())
+            .map(|a| format!("{}:{}", a, config.port))
+            .collect();
+        supernodes.extend(
+            networks
+                .iter()
+                .map(|(_, n)| format!("{}:{}", n.addr, config.port)),
+        );
+        supernodes.dedup();
+        Self {
+            profile: profile.id.clone(),
+            group: profile.group.clone(),
+            port: config.port,
+            supernodes,
+            member_server: profile.member_server.clone(),
+        }
+    }
+}
+
+/// 指向本机超级节点的网络配置
+pub fn hosted_profile(
+    profile: &N2NClientConfig,
+    config: &SupernodeConfig,
+) -> Result<N2NClientConfig, ProgramError> {
+    let mut hosted = profile.clone();
+    hosted.server = "127.0.0.1".to_string();
+    hosted.supernodes = vec![format!("127.0.0.1:{}", config.port)];
+    hosted.supernode_probe = false;
+    // edge的本地端口与管理端口不能被超级节点占用，换用相邻端口
+    if hosted.port == config.port {
+        hosted.port = config
+            .port
+            .checked_add(1)
+            .ok_or(ProgramError::PortError(format!(
+                "{}之后没有可用端口",
+                config.port
+            )))?;
+    }
+    if hosted.control_port == config.control_port {
+        hosted.control_port = config
+            .control_port
+            .checked_sub(1)
+            .filter(|port| *port != 0)
+            .ok_or(ProgramError::PortError(format!(
+                "{}之前没有可用端口",
+                config.control_port
+            )))?;
+    }
+    Ok(hosted)
+}
+
+pub struct Supernode {
+    program: ExternalBinaryProgram,
+    info: HostInfo,
+}
+
+impl Supernode {
+    pub const NAME: &'static str = "supernode";
+    pub const FIRE_WALL_NAME: &'static str = "LightN2N_Allow_Supernode";
+
+    pub fn new(
+        config: &SupernodeConfig,
+        info: HostInfo,
+        program_path: String,
+    ) -> Result<Self, ProgramError> {
+        let current_dir =
+            std::env::current_dir().map_err(|e| ProgramError::GetCurrentDirError(e.to_string()))?;
+        let mut args = vec![
+            "-p".to_string(),
+            config.port.to_string(),
+            "-t".to_string(),
+            config.control_port.to_string(),
+        ];
+        // 不转入后台，便于管理子进程
+        if cfg!(unix) {
+            args.push("-f".to_string());
+        }
+        let mut program =
+            ExternalBinaryProgram::new(Self::NAME, current_dir.join(program_path), args)?;
+        program.set_restart_policy(config.restart.clone());
+        Ok(Self { program, info })
+    }
+
+    pub fn info(&self) -> &HostInfo {
+        &self.info
+    }
+
+    /// 超级节点的防火墙规则
+    fn firewall_rule(port: u16) -> Result<FirewallRule, ProgramError> {
+        let current =
+            std::env::current_dir().map_err(|e| ProgramError::GetCurrentDirError(e.to_string()))?;
+        Ok(FirewallRule {
+            name: Self::FIRE_WALL_NAME,
+            program: Some(current.join(ExternalFilePosition::Supernode.path())),
+            protocol: FirewallProtocol::Udp(port),
+        })
+    }
+}
+
+impl ChildProcess for Supernode {
+    fn get_process(&mut self) -> &mut ExternalBinaryProgram {
+        &mut self.program
+    }
+}
+
+/// 启动超级节点，并让网络配置的edge连接到它
+fn host(
+    app_handle: &AppHandle,
+    profile: Option<String>,
+    port: Option<u16>,
+) -> Result<HostInfo, String> {
+    let manager = app_handle.state::<ProcessManager>();
+    let log = |e: ProgramError| {
+        let error = e.to_string();
+        error!("{}:{}", line!(), error);
+        error
+    };
+    let config = LocalConfig::get_config(app_handle).map_err(log)?;
+    let profile = config.profile(profile.as_deref()).map_err(log)?;
+    // 已在主持，只能是同一个网络配置
+    if manager.supernode.running(Supernode::NAME) {
+        if let Ok(info) = manager.supernode.with(Supernode::NAME, |s| s.info.clone()) {
+            if info.profile == profile.id {
+                return Ok(info);
+            }
+            let name = config
+                .n2n_profiles
+                .iter()
+                .find(|p| p.id == info.profile)
+                .map_or(info.profile.clone(), |p| p.name.clone());
+            return Err(log(ProgramError::ProfileConflict(format!(
+                "{}:主持网络",
+                name
+            ))));
+        }
+    }
+    let mut supernode_config = config.supernode.clone();
+    if let Some(port) = port {
+        supernode_config.port = port;
+    }
+    let hosted = hosted_profile(&profile, &supernode_config).map_err(log)?;
+    let info = HostInfo::new(&profile, &supernode_config, &address::local_networks());
+    let mut supernode = Supernode::new(
+        &supernode_config,
+        info.clone(),
+        ExternalFilePosition::Supernode.to_string(),
+    )
+    .map_err(log)?;
+    supernode
+        .get_process()
+        .set_stop_timeout(config.stop_timeout());
+    supernode.get_process().start().map_err(log)?;
+    manager
+        .supernode
+        .insert(Supernode::NAME, supernode)
+        .map_err(log)?;
+
+    // 原先的edge连接的是其他超级节点
+    let edge = N2NClient::process_name(&profile.id);
+    let was_running = manager.edges.running(&edge);
+    manager.edges.stop(&edge).map_err(log)?;
+    if let Err(e) = n2n_client::start_profile(app_handle, &config, hosted) {
+        if let Err(e) = manager.supernode.stop(Supernode::NAME) {
+            error!("{}:{}", line!(), e);
+        }
+        // 恢复原先的连接，避免主持失败后离线
+        if was_running {
+            if let Err(e) = n2n_client::start_profile(app_handle, &config, profile) {
+                error!("{}:{}", line!(), e);
+            }
+        }
+        return Err(e);
+    }
+    Ok(info)
+}
+
+#[tauri::command]
+pub async fn supernode_host(
+    app_handle: AppHandle,
+    profile: Option<String>,
+    port: Option<u16>,
+) -> Result<HostInfo, String> {
+    tauri::async_runtime::spawn_blocking(move || host(&app_handle, profile, port))
+        .await
+        .map_err(|e| e.to_string())?
+}
+
+/// 停止超级节点与连接到它的edge
+#[tauri::command]
+pub async fn supernode_stop(app_handle: AppHandle) -> Result<bool, String> {
+    tauri::async_runtime::spawn_blocking(move || {
+        let manager = app_handle.state::<ProcessManager>();
+        if let Ok(profile) = manager
+            .supernode
+            .with(Supernode::NAME, |s| s.info.profile.clone())
+        {
+            manager.edges.stop(&N2NClient::process_name(&profile))?;
+        }
+        manager.supernode.stop(Supernode::NAME)
+    })
+    .await
+    .map_err(|e| e.to_string())?
+    .map(|_| true)
+    .map_err(|e| e.to_string())
+}
+
+/// 安装包不包含超级节点程序，需要用户自行放入`client/x64`
+#[tauri::command]
+pub fn supernode_available() -> bool {
+    std::env::current_dir()
+        .map(|dir| dir.join(ExternalFilePosition::Supernode.path()).is_file())
+        .unwrap_or(false)
+}
+
+/// 正在主持时返回加入信息
+#[tauri::command]
+pub fn supernode_status(manager: State<'_, ProcessManager>) -> Result<Option<HostInfo>, String> {
+    if !manager.supernode.running(Supernode::NAME) {
+        return Ok(None);
+    }
+    match manager.supernode.with(Supernode::NAME, |s| s.info.clone()) {
+        Ok(info) => Ok(Some(info)),
+        Err(ProgramError::ChildProcessNotFound) => Ok(None),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+#[tauri::command]
+pub fn supernode_firewall_check(app_handle: AppHandle, port: Option<u16>) -> Result<bool, String> {
+    let config = LocalConfig::get_config(&app_handle).map_err(|e| e.to_string())?;
+    Supernode::firewall_rule(port.unwrap_or(config.supernode.port))
+        .and_then(|rule| platform::firewall_rule_exists(&rule))
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub fn supernode_firewall_add(app_handle: AppHandle, port: Option<u16>) -> Result<(), String> {
+    let config = LocalConfig::get_config(&app_handle).map_err(|e| e.to_string())?;
+    Supernode::firewall_rule(port.unwrap_or(config.supernode.port))
+        .and_then(|rule| platform::firewall_rule_add(&rule))
+        .map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    #[test]
+    fn hosted() {
+        let config = SupernodeConfig {
+            port: 7654,
+            control_port: 5644,
+            ..Default::default()
+        };
+        let profile = N2NClientConfig {
+            port: 7654,
+            supernodes: vec!["example.com:7777".to_string()],
+            ..Default::default()
+        };
+        let hosted = hosted_profile(&profile, &config).unwrap();
+        assert_eq!(hosted.supernode_list(), vec!["127.0.0.1:7654"]);
+        assert_eq!(hosted.port, 7655);
+        assert_eq!(hosted.control_port, 5643);
+        assert!(!hosted.supernode_probe);
+        assert_eq!(hosted.group, profile.group);
+    }
+
+    #[test]
+    fn hosted_ports_out_of_range() {
+        let profile = N2NClientConfig {
+            port: 65535,
+            control_port: 1,
+            ..Default::default()
+        };
+        let config = SupernodeConfig {
+            port: 65535,
+            ..Default::default()
+        };
+        assert!(matches!(
+            hosted_profile(&profile, &config),
+            Err(ProgramError::PortError(_))
+        ));
+        let config = SupernodeConfig {
+            control_port: 1,
+            ..Default::default()
+        };
+        assert!(matches!(
+            hosted_profile(&profile, &config),
+            Err(ProgramError::PortError(_))
+        ));
+    }
+
+    #[test]
+    fn join_addresses() {
+        let config = SupernodeConfig {
+            public_address: Some("n2n.example.com".to_string()),
+            ..Default::default()
+        };
+        let networks = vec![(
+            "eth0".to_string(),
+            Ipv4Cidr {
+                addr: Ipv4Addr::new(192, 168, 1, 5),
+                prefix: 24,
+            },
+        )];
+        let info = HostInfo::new(&N2NClientConfig::default(), &config, &networks);
+        assert_eq!(
+            info.supernodes,
+            vec!["n2n.example.com:7654", "192.168.1.5:7654"]
+        );
+        assert_eq!(info.profile, "default");
+    }
+}
@@ -0,0 +1,251 @@
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use lazy_static::lazy_static;
+use log::debug;
+use serde::Serialize;
+use tauri::AppHandle;
+use tokio::net::UdpSocket;
+
+use crate::config::LocalConfig;
+
+/// 单个超级节点的探测超时
+const PROBE_TIMEOUT: Duration = Duration::from_secs(1);
+/// n2n v3报文头
+const N2N_VERSION: u8 = 3;
+const N2N_DEFAULT_TTL: u8 = 2;
+const N2N_COMMUNITY_SIZE: usize = 20;
+const MSG_TYPE_QUERY_PEER: u16 = 11;
+
+lazy_static! {
+    /// 最近一次测得的往返时间，下次启动时据此排序
+    static ref RTTS: Mutex<HashMap<String, Option<u128>>> = Mutex::new(HashMap::new());
+}
+
+/// 超级节点探测结果
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct SupernodeProbe {
+    pub address: String,
+    /// 往返时间，毫秒，不可达为None
+    pub rtt: Option<u128>,
+}
+
+/// 目标MAC为空的QUERY_PEER，超级节点以PEER_INFO应答，即edge选择超级节点时使用的PING。
+/// 启用了报头加密(-H)的社区无法解读该报文，不会应答
+fn ping_packet(community: &str) -> Vec<u8> {
+    let mut packet = vec![N2N_VERSION, N2N_DEFAULT_TTL];
+    packet.extend_from_slice(&MSG_TYPE_QUERY_PEER.to_be_bytes());
+    let mut name = [0; N2N_COMMUNITY_SIZE];
+    let len = community.len().min(N2N_COMMUNITY_SIZE - 1);
+    name[..len].copy_from_slice(&community.as_bytes()[..len]);
+    packet.extend_from_slice(&name);
+    // aflags、srcMac、sock(IPv4)、targetMac
+    packet.extend_from_slice(&[0; 2 + 6 + 8 + 6]);
+    packet
+}
+
+/// 向超级节点端口发送PING，收到应答的时间即往返时间
+async fn rtt(address: &str, community: &str, timeout: Duration) -> Option<Duration> {
+    let target = tokio::net::lookup_host(address)
+        .await
+        .ok()?
+        .find(|a| a.is_ipv4())?;
+    let socket = UdpSocket::bind(SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)))
+        .await
+        .ok()?;
+    socket.connect(target).await.ok()?;
+    let start = Instant::now();
+    socket.send(&ping_packet(community)).await.ok()?;
+    let mut buf = [0; 1500];
+    match tokio::time::timeout(timeout, socket.recv(&mut buf)).await {
+        Ok(Ok(_)) => Some(start.elapsed()),
+        Ok(Err(e)) => {
+            debug!("{}:{}", address, e);
+            None
+        }
+        Err(_) => None,
+    }
+}
+
+async fn probe_with(
+    addresses: &[String],
+    community: &str,
+    timeout: Duration,
+) -> Vec<SupernodeProbe> {
+    let tasks: Vec<_> = addresses
+        .iter()
+        .cloned()
+        .map(|address| {
+            let community = community.to_string();
+            tokio::spawn(async move {
+                let rtt = rtt(&address, &community, timeout)
+                    .await
+                    .map(|d| d.as_millis());
+                SupernodeProbe { address, rtt }
+            })
+        })
+        .collect();
+    let mut probes = Vec::with_capacity(tasks.len());
+    for (task, address) in tasks.into_iter().zip(addresses) {
+        probes.push(task.await.unwrap_or(SupernodeProbe {
+            address: address.clone(),
+            rtt: None,
+        }));
+    }
+    probes
+}
+
+/// 经UDP并行测量每个超级节点的往返时间，不需要ICMP权限
+pub async fn probe(addresses: &[String], community: &str) -> Vec<SupernodeProbe> {
+    probe_with(addresses, community, PROBE_TIMEOUT).await
+}
+
+/// 按往返时间排序，不可达的排在最后并保持原有顺序
+pub fn order(mut probes: Vec<SupernodeProbe>) -> Vec<SupernodeProbe> {
+    probes.sort_by_key(|p| p.rtt.unwrap_or(u128::MAX));
+    probes
+}
+
+/// 记录探测结果
+pub fn remember(probes: &[SupernodeProbe]) {
+    let mut rtts = RTTS.lock().unwrap_or_else(|e| e.into_inner());
+    for p in probes {
+        rtts.insert(p.address.clone(), p.rtt);
+    }
+}
+
+/// 按最近一次探测结果排序，不阻塞启动，没有探测过的视为不可达
+pub fn ranked(addresses: &[String]) -> Vec<String> {
+    let rtts = RTTS.lock().unwrap_or_else(|e| e.into_inner());
+    let probes = addresses
+        .iter()
+        .map(|a| SupernodeProbe {
+            address: a.clone(),
+            rtt: rtts.get(a).copied().flatten(),
+        })
+        .collect();
+    order(probes).into_iter().map(|p| p.address).collect()
+}
+
+#[tauri::command]
+pub async fn n2n_supernodes_probe(
+    app_handle: AppHandle,
+    profile: Option<String>,
+) -> Result<Vec<SupernodeProbe>, String> {
+    let config = LocalConfig::get_config(&app_handle)
+        .and_then(|config| config.profile(profile.as_deref()))
+        .map_err(|e| e.to_string())?;
+    let probes = probe(&config.supernode_list(), &config.group).await;
+    remember(&probes);
+    Ok(order(probes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tools::n2n_client::N2NClientConfig;
+
+    fn p(address: &str, rtt: Option<u128>) -> SupernodeProbe {
+        SupernodeProbe {
+            address: address.to_string(),
+            rtt,
+        }
+    }
+
+    fn addresses(probes: &[SupernodeProbe]) -> Vec<&str> {
+        probes.iter().map(|p| p.address.as_str()).collect()
+    }
+
+    #[test]
+    fn ordering() {
+        let ordered = order(vec![
+            p("a", None),
+            p("b", Some(80)),
+            p("c", None),
+            p("d", Some(20)),
+        ]);
+        assert_eq!(addresses(&ordered), vec!["d", "b", "a", "c"]);
+
+        // 往返时间相同的保持原有顺序
+        let ordered = order(vec![
+            p("a", Some(30)),
+            p("b", None),
+            p("c", Some(10)),
+            p("d", Some(30)),
+            p("e", None),
+            p("f", Some(10)),
+        ]);
+        assert_eq!(addresses(&ordered), vec!["c", "f", "a", "d", "b", "e"]);
+
+        // 都不可达时不改变顺序
+        let ordered = order(vec![p("x", None), p("y", None), p("z", None)]);
+        assert_eq!(addresses(&ordered), vec!["x", "y", "z"]);
+        assert!(order(Vec::new()).is_empty());
+    }
+
+    #[test]
+    fn ranking() {
+        // 地址只在本测试中使用，避免与其他测试共用记录
+        remember(&[
+            p("rank-a:7654", Some(50)),
+            p("rank-b:7654", None),
+            p("rank-c:7654", Some(5)),
+            p("rank-d:7654", Some(50)),
+        ]);
+        let configured =
+            ["rank-a", "rank-b", "rank-c", "rank-new", "rank-d"].map(|a| format!("{}:7654", a));
+        let expected =
+            ["rank-c", "rank-a", "rank-d", "rank-b", "rank-new"].map(|a| format!("{}:7654", a));
+        assert_eq!(ranked(&configured), expected);
+    }
+
+    #[test]
+    fn packet() {
+        let packet = ping_packet("team");
+        assert_eq!(packet.len(), 4 + N2N_COMMUNITY_SIZE + 22);
+        assert_eq!(packet[..4], [3, 2, 0, 11]);
+        assert_eq!(&packet[4..8], b"team");
+        assert!(packet[8..].iter().all(|b| *b == 0));
+        // 社区名保留结尾的0
+        assert_eq!(ping_packet(&"x".repeat(30))[4 + N2N_COMMUNITY_SIZE - 1], 0);
+    }
+
+    #[tokio::test]
+    async fn probe_over_udp() {
+        let supernode = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let answering = supernode.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let mut buf = [0; 1500];
+            while let Ok((len, from)) = supernode.recv_from(&mut buf).await {
+                if buf[..len] == ping_packet("team")[..] {
+                    let _ = supernode.send_to(&[3, 2, 0, 10], from).await;
+                }
+            }
+        });
+        let silent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let silent_address = silent.local_addr().unwrap().to_string();
+
+        let addresses = vec![silent_address.clone(), answering.clone()];
+        let probes = probe_with(&addresses, "team", Duration::from_millis(200)).await;
+        assert_eq!(probes[0], p(&silent_address, None));
+        assert!(probes[1].rtt.is_some());
+
+        remember(&probes);
+        assert_eq!(ranked(&addresses), vec![answering, silent_address]);
+    }
+
+    #[test]
+    fn legacy_server() {
+        let mut config = N2NClientConfig {
+            server: "sn.example.com".to_string(),
+            port: 7777,
+            ..N2NClientConfig::default()
+        };
+        assert_eq!(config.supernode_list(), vec!["sn.example.com:7777"]);
+
+        config.supernodes = vec!["a:1".to_string(), "b:2".to_string()];
+        assert_eq!(config.supernode_list(), vec!["a:1", "b:2"]);
+    }
+}
@@ -0,0 +1,320 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::thread;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use lazy_static::lazy_static;
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
+use tauri::{AppHandle, Emitter, Manager};
+
+use crate::tools::output::unix_time;
+use crate::tools::process_manager::ProcessManager;
+use crate::tools::{ExternalBinaryProgram, ProgramError};
+
+/// 推送给前端的事件名
+pub const RESTART_EVENT: &str = "process-restart";
+/// 检查子进程的间隔
+const INTERVAL: Duration = Duration::from_millis(500);
+/// 连续运行超过该时间后重置重启次数
+const STABLE: Duration = Duration::from_secs(60);
+/// 保留的重启记录条数
+const HISTORY_LIMIT: usize = 200;
+
+lazy_static! {
+    static ref HISTORY: Mutex<VecDeque<RestartRecord>> = Mutex::new(VecDeque::new());
+}
+
+/// 子进程退出后是否重启
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum RestartMode {
+    Never,
+    /// 仅在非正常退出时重启
+    #[default]
+    OnFailure,
+    Always,
+}
+
+/// 重启策略
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct RestartPolicy {
+    pub mode: RestartMode,
+    /// 连续重启的最大次数
+    pub max_restarts: u32,
+    /// 首次重启前的等待时间，之后每次翻倍
+    pub backoff_ms: u64,
+    pub max_backoff_ms: u64,
+}
+
+impl Default for RestartPolicy {
+    fn default() -> Self {
+        Self {
+            mode: RestartMode::default(),
+            max_restarts: 5,
+            backoff_ms: 1000,
+            max_backoff_ms: 60000,
+        }
+    }
+}
+
+impl RestartPolicy {
+    fn should_restart(&self, success: bool) -> bool {
+        match self.mode {
+            RestartMode::Never => false,
+            RestartMode::OnFailure => !success,
+            RestartMode::Always => true,
+        }
+    }
+
+    /// 第attempt次重启前的等待时间
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let factor = 1u64.checked_shl(attempt).unwrap_or(u64::MAX);
+        Duration::from_millis(self.backoff_ms.saturating_mul(factor).min(self.max_backoff_ms))
+    }
+}
+
+/// 子进程的重启状态
+#[derive(Default)]
+pub(crate) struct RestartState {
+    policy: RestartPolicy,
+    /// 连续重启次数
+    attempts: u32,
+    /// 计划重启的时间
+    pending: Option<Instant>,
+    /// 最近一次启动的时间
+    started: Option<Instant>,
+    /// 最近一次退出码
+    exit_code: Option<i32>,
+}
+
+/// 监管动作
+#[derive(Serialize, Clone, Debug, PartialEq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum RestartAction {
+    /// 退出且按策略不重启
+    Exited,
+    /// 已计划重启
+    Scheduled { delay_ms: u64 },
+    Restarted,
+    /// 重启失败
+    Failed { error: String },
+    /// 达到最大重启次数，放弃重启
+    GaveUp,
+}
+
+/// 重启记录
+#[derive(Serialize, Clone, Debug)]
+pub struct RestartRecord {
+    pub name: String,
+    /// unix时间戳，秒
+    pub time: u64,
+    pub exit_code: Option<i32>,
+    /// 第几次连续重启
+    pub attempt: u32,
+    #[serde(flatten)]
+    pub action: RestartAction,
+}
+
+impl ExternalBinaryProgram {
+    pub fn set_restart_policy(&mut self, policy: RestartPolicy) {
+        self.restart.policy = policy;
+    }
+
+    fn restart_record(&self, action: RestartAction) -> RestartRecord {
+        RestartRecord {
+            name: self.name.clone(),
+            time: unix_time(),
+            exit_code: self.restart.exit_code,
+            attempt: self.restart.attempts,
+            action,
+        }
+    }
+
+    /// 计划下一次重启，超过最大次数时放弃
+    fn schedule_restart(&mut self) -> RestartRecord {
+        if self.restart.attempts >= self.restart.policy.max_restarts {
+            return self.restart_record(RestartAction::GaveUp);
+        }
+        let delay = self.restart.policy.backoff(self.restart.attempts);
+        self.restart.pending = Some(Instant::now() + delay);
+        self.restart_record(RestartAction::Scheduled {
+            delay_ms: delay.as_millis() as u64,
+        })
+    }
+
+    /// 检查子进程，按策略处理退出与到期的重启
+    pub fn supervise(&mut self) -> Vec<RestartRecord> {
+        let mut records = Vec::new();
+        if let Some(at) = self.restart.pending {
+            if Instant::now() < at {
+                return records;
+            }
+            self.restart.pending = None;
+            self.restart.attempts += 1;
+            match self.start() {
+                Ok(_) => records.push(self.restart_record(RestartAction::Restarted)),
+                Err(e) => {
+                    records.push(self.restart_record(RestartAction::Failed {
+                        error: e.to_string(),
+                    }));
+                    records.push(self.schedule_restart());
+                }
+            }
+            return records;
+        }
+        if self.process.is_none() {
+            return records;
+        }
+        match self.poll_exit() {
+            Ok(None) => {
+                if self.restart.started.is_some_and(|s| s.elapsed() >= STABLE) {
+                    self.restart.attempts = 0;
+                }
+            }
+            Ok(Some(status)) => {
+                self.process = None;
+                self.restart.exit_code = status.code();
+                if self.restart.policy.should_restart(status.success()) {
+                    records.push(self.schedule_restart());
+                } else {
+                    records.push(self.restart_record(RestartAction::Exited));
+                }
+            }
+            Err(e) => {
+                error!("{}:{}", line!(), ProgramError::ChildProcessError(e.to_string()));
+            }
+        }
+        records
+    }
+
+    /// 启动时由start调用
+    pub(crate) fn mark_started(&mut self) {
+        self.restart.started = Some(Instant::now());
+    }
+
+    /// 主动停止时取消计划中的重启
+    pub(crate) fn cancel_restart(&mut self) {
+        self.restart.pending = None;
+    }
+}
+
+fn push_history(record: RestartRecord) {
+    match HISTORY.lock() {
+        Ok(mut history) => {
+            if history.len() >= HISTORY_LIMIT {
+                history.pop_front();
+            }
+            history.push_back(record);
+        }
+        Err(e) => error!("{}:{}", line!(), e),
+    }
+}
+
+/// 检查全部子进程
+fn supervise_all(app_handle: &AppHandle) {
+    let Some(manager) = app_handle.try_state::<ProcessManager>() else {
+        return;
+    };
+    for record in manager.supervise() {
+        warn!("{}:{:?}", record.name, record.action);
+        if let Err(e) = app_handle.emit(RESTART_EVENT, record.clone()) {
+            error!("{}:{}", line!(), e);
+        }
+        push_history(record);
+    }
+}
+
+/// 启动监管线程
+pub fn start(app_handle: AppHandle) {
+    thread::spawn(move || loop {
+        sleep(INTERVAL);
+        supervise_all(&app_handle);
+    });
+}
+
+/// 查询重启记录，可按子进程名过滤
+#[tauri::command]
+pub fn restart_history(name: Option<String>) -> Result<Vec<RestartRecord>, String> {
+    match HISTORY.lock() {
+        Ok(history) => Ok(history
+            .iter()
+            .filter(|r| name.as_ref().is_none_or(|n| &r.name == n))
+            .cloned()
+            .collect()),
+        Err(e) => {
+            let error = ProgramError::ChildProcessError(e.to_string()).to_string();
+            error!("{}:{}", line!(), error);
+            Err(error)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    #[test]
+    fn backoff() {
+        let policy = RestartPolicy::default();
+        assert_eq!(policy.backoff(0), Duration::from_secs(1));
+        assert_eq!(policy.backoff(3), Duration::from_secs(8));
+        assert_eq!(policy.backoff(10), Duration::from_secs(60));
+        assert_eq!(policy.backoff(100), Duration::from_secs(60));
+    }
+
+    fn wait_records(program: &mut ExternalBinaryProgram) -> Vec<RestartRecord> {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            let records = program.supervise();
+            if !records.is_empty() || Instant::now() > deadline {
+                return records;
+            }
+            sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn restarts_until_limit() {
+        let mut program =
+            ExternalBinaryProgram::new("false", PathBuf::from("false"), Vec::new()).unwrap();
+        program.set_restart_policy(RestartPolicy {
+            max_restarts: 2,
+            backoff_ms: 0,
+            ..RestartPolicy::default()
+        });
+        program.start().unwrap();
+
+        let mut actions = Vec::new();
+        while !actions.contains(&RestartAction::GaveUp) && actions.len() < 10 {
+            actions.extend(wait_records(&mut program).into_iter().map(|r| r.action));
+        }
+        assert_eq!(
+            actions,
+            vec![
+                RestartAction::Scheduled { delay_ms: 0 },
+                RestartAction::Restarted,
+                RestartAction::Scheduled { delay_ms: 0 },
+                RestartAction::Restarted,
+                RestartAction::GaveUp,
+            ]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn on_failure_ignores_success() {
+        let mut program =
+            ExternalBinaryProgram::new("true", PathBuf::from("true"), Vec::new()).unwrap();
+        program.start().unwrap();
+        let records = wait_records(&mut program);
+        assert_eq!(records[0].action, RestartAction::Exited);
+        assert_eq!(records[0].exit_code, Some(0));
+        assert!(program.supervise().is_empty());
+    }
+}
@@ -1,13 +1,9 @@
-use std::any::Any;
-use std::sync::{Arc, RwLock};
-
 use log::error;
+use tauri::{AppHandle, Manager, State};
 
-use crate::tools::{
-    child_drop, child_status, ChildProcess, ExternalBinaryProgram, ExternalFilePosition,
-    ProgramError,
-};
-use crate::CHILDS;
+use crate::config::LocalConfig;
+use crate::tools::process_manager::ProcessManager;
+use crate::tools::{ChildProcess, ExternalBinaryProgram, ExternalFilePosition, ProgramError};
 
 pub struct WinIPBroadcast {
     process: ExternalBinaryProgram,
@@ -32,59 +28,48 @@ impl ChildProcess for WinIPBroadcast {
     fn get_process(&mut self) -> &mut ExternalBinaryProgram {
         &mut self.process
     }
-
-    fn as_any(&mut self) -> &mut dyn Any {
-        self
-    }
 }
 
 #[tauri::command]
-pub fn win_ip_broadcast_start() -> Result<bool, String> {
-    // 如果子进程未正常运行
-    if !child_status(WinIPBroadcast::NAME) {
-        return match WinIPBroadcast::new(ExternalFilePosition::WinIPBroadcast.to_string()) {
-            Ok(mut p) => {
-                match p.get_process().start() {
-                    Ok(_) => {
-                        // 运行后保存
-                        match CHILDS.lock() {
-                            Ok(mut map) => {
-                                map.insert(WinIPBroadcast::NAME, Arc::new(RwLock::new(p)));
-                                Ok(true)
-                            }
-                            Err(e) => {
-                                // 防止多开
-                                let error =
-                                    ProgramError::ChildProcessError(e.to_string()).to_string();
-                                error!("{}", error);
-                                drop(p);
-                                Err(error)
-                            }
-                        }
-                    }
-                    Err(e) => {
-                        let error = e.to_string();
-                        error!("{}", error);
-                        Err(error)
-                    }
-                }
-            }
-            Err(e) => {
-                let error = e.to_string();
-                error!("{}", error);
-                Err(error)
-            }
-        };
+pub fn win_ip_broadcast_start(
+    app_handle: AppHandle,
+    manager: State<'_, ProcessManager>,
+) -> Result<bool, String> {
+    // Linux内核会直接在TAP网卡上转发广播
+    if cfg!(not(target_os = "windows")) {
+        return Err(ProgramError::UnsupportedPlatform.to_string());
+    }
+    // 已在运行
+    if manager.broadcast.running(WinIPBroadcast::NAME) {
+        return Ok(true);
     }
+    let log = |e: ProgramError| {
+        let error = e.to_string();
+        error!("{}", error);
+        error
+    };
+    let config = LocalConfig::get_config(&app_handle).map_err(log)?;
+    let mut p = WinIPBroadcast::new(ExternalFilePosition::WinIPBroadcast.to_string()).map_err(log)?;
+    p.get_process().set_stop_timeout(config.stop_timeout());
+    p.get_process().set_restart_policy(config.win_ip_broadcast_restart);
+    p.get_process().start().map_err(log)?;
+    // 运行后保存
+    manager.broadcast.insert(WinIPBroadcast::NAME, p).map_err(log)?;
     Ok(true)
 }
 
 #[tauri::command]
-pub fn win_ip_broadcast_stop() -> Result<bool, String> {
-    child_drop(WinIPBroadcast::NAME)
+pub async fn win_ip_broadcast_stop(app_handle: AppHandle) -> Result<bool, String> {
+    tauri::async_runtime::spawn_blocking(move || {
+        app_handle.state::<ProcessManager>().broadcast.stop(WinIPBroadcast::NAME)
+    })
+    .await
+    .map_err(|e| e.to_string())?
+    .map(|_| true)
+    .map_err(|e| e.to_string())
 }
 
 #[tauri::command]
-pub fn win_ip_broadcast_status() -> Result<bool, String> {
-    Ok(child_status(WinIPBroadcast::NAME))
+pub fn win_ip_broadcast_status(manager: State<'_, ProcessManager>) -> Result<bool, String> {
+    Ok(manager.broadcast.running(WinIPBroadcast::NAME))
 }
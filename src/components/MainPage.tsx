@@ -9,7 +9,9 @@ import {
     InputNumber,
     List,
     message,
+    Modal,
     Row,
+    Select,
     Space,
     Spin,
     theme,
@@ -18,10 +20,20 @@ import {
 import Global from "../config/Global.ts";
 import {PoweroffOutlined} from "@ant-design/icons";
 import {useEffect, useReducer, useState} from "react";
-import {async_run, Data, useInterval} from "../tool/ReactTool.ts";
+import {
+    async_run,
+    currentProfile,
+    Data,
+    InviteReceived,
+    PeerDiagnosis,
+    selectedProfileId,
+    selectProfile,
+    useInterval
+} from "../tool/ReactTool.ts";
 import multiavatar from "@multiavatar/multiavatar";
 import {invoke} from "@tauri-apps/api/core";
 import {Window} from "@tauri-apps/api/window";
+import {listen} from "@tauri-apps/api/event";
 import {resolve} from "@tauri-apps/api/path";
 
 function MainPage() {
@@ -32,6 +44,36 @@ function MainPage() {
     // n2n运行状态
     const [status, setStatus] = useState(false)
 
+    // 选中的网络配置
+    const [profileId, setProfileId] = useState(selectedProfileId())
+
+    // 传给n2n_*命令的网络配置参数
+    function profileArgs() {
+        return {profile: data ? currentProfile(data)?.id : profileId}
+    }
+
+    // 选中的配置已被删除时改回第一个
+    useEffect(() => {
+        if (data && profileId && !data.n2n_profiles?.some((p) => p.id == profileId)) {
+            selectProfile(undefined)
+            setProfileId(undefined)
+        }
+    }, [data, profileId])
+
+    // 切换网络配置后读取它的运行状态
+    useEffect(() => {
+        if (!data) {
+            return
+        }
+        invoke("n2n_status", profileArgs())
+            .then((s) => {
+                setStatus(s as boolean)
+            })
+            .catch((e) => {
+                message.error(e as string)
+            })
+    }, [data, profileId])
+
     // 异步获取外部配置
     useEffect(() => {
         async_run(async () => {
@@ -47,6 +89,78 @@ function MainPage() {
         })
     }, [])
 
+    // 通过邀请链接收到的邀请，经用户确认后才导入
+    useEffect(() => {
+        function confirmInvite(invite: InviteReceived) {
+            const preview = invite.preview
+            if (!preview) {
+                message.error(invite.error ?? "邀请码无效")
+                return
+            }
+            Modal.confirm({
+                title: "导入邀请",
+                content: <Space direction="vertical">
+                    <Typography.Text>网络：{preview.name}（{preview.group}）</Typography.Text>
+                    <Typography.Text>超级节点：{preview.supernodes.join("、")}</Typography.Text>
+                    {preview.member_server ?
+                        <Typography.Text>成员服务：{preview.member_server}</Typography.Text> : null}
+                    {preview.has_key ? <Typography.Text>包含加密密钥，将保存到系统密钥库</Typography.Text> : null}
+                    {preview.expires ?
+                        <Typography.Text>有效期至：{new Date(preview.expires * 1000).toLocaleString()}</Typography.Text> : null}
+                    {preview.updates ?
+                        <Alert type="info" showIcon message={`将更新已有配置“${preview.updates}”，本机端口等设置保持不变`}/> : null}
+                    {preview.signed ? null :
+                        <Alert type="warning" showIcon message="未设置团队密钥，无法确认邀请来源"/>}
+                </Space>,
+                onOk: async () => {
+                    let id: string
+                    try {
+                        id = await invoke<string>("n2n_invite_import", {code: invite.code})
+                    } catch (e) {
+                        message.error(String(e))
+                        return
+                    }
+                    const store = new Store(await resolve("client/config.json"));
+                    await store.load()
+                    const d = await store.get<Data>("config")
+                    if (d) {
+                        setData(d)
+                    }
+                    // 切换到导入的网络
+                    selectProfile(id)
+                    setProfileId(id)
+                    message.success(`已导入邀请，当前网络为“${preview.name}”`)
+                }
+            })
+        }
+
+        async function takePending() {
+            const invites = await invoke<InviteReceived[]>("n2n_invite_pending")
+            invites.forEach(confirmInvite)
+        }
+
+        async_run(takePending)
+        const unlisten = listen("invite-received", () => async_run(takePending))
+        return () => {
+            unlisten.then((f) => f())
+        }
+    }, [])
+
+    // 远程配置合并到本地配置后重新读取
+    useEffect(() => {
+        const unlisten = listen("config-updated", async () => {
+            const store = new Store(await resolve("client/config.json"));
+            await store.load()
+            const d = await store.get<Data>("config")
+            if (d) {
+                setData(d)
+            }
+        })
+        return () => {
+            unlisten.then((f) => f())
+        }
+    }, [])
+
     async function SetStore(key: string, value: any) {
         // 存储
         const store = new Store(await resolve("client/config.json"));
@@ -131,6 +245,47 @@ function MainPage() {
             return 0
         }
 
+        /**
+         * 诊断与成员没有直连的原因，缺少防火墙规则时可直接添加
+         */
+        async function Diagnose(member: string) {
+            await invoke<PeerDiagnosis>("n2n_peer_diagnose", {member: member, ...profileArgs()})
+                .then((d) => {
+                    Modal.info({
+                        title: d.path == "Direct" ? "已直连" : d.path == "Relayed" ? "经超级节点转发" : "未发现该成员",
+                        content: (
+                            <>
+                                {d.findings.map((f, i) => (
+                                    <Typography.Paragraph key={"f" + i} style={{marginBottom: 4}}>{f}</Typography.Paragraph>
+                                ))}
+                                {d.remedies.map((r, i) => (
+                                    <Typography.Paragraph key={"r" + i} type={"warning"} style={{marginBottom: 4}}>
+                                        {r.detail}
+                                        {r.kind == "FirewallRule" ?
+                                            <Typography.Link
+                                                style={{marginLeft: 10}}
+                                                onClick={() => {
+                                                    invoke("n2n_firewall_add", profileArgs())
+                                                        .then(() => {
+                                                            message.success("已添加防火墙规则")
+                                                        })
+                                                        .catch((e) => {
+                                                            message.error(e as string)
+                                                        })
+                                                }}>
+                                                添加规则
+                                            </Typography.Link> : null}
+                                    </Typography.Paragraph>
+                                ))}
+                            </>
+                        )
+                    })
+                })
+                .catch((e) => {
+                    message.error(e as string)
+                })
+        }
+
         // 监听状态
         useEffect(() => {
             // 关闭时清空成员
@@ -142,32 +297,52 @@ function MainPage() {
             }
         }, [status])
 
-        // 轮询 成员变化
+        /**
+         * 刷新成员列表
+         */
+        function RefreshMembers() {
+            // 仅窗口在前台时刷新成员
+            Window.getCurrent().isVisible()
+                .then(async (flag) => {
+                    if (flag) {
+                        await invoke("n2n_members", profileArgs())
+                            .then((m) => {
+                                if (typeof m == "object") {
+                                    let mem = (m as Array<Member_info>)
+                                    // 刷新成员
+                                    setMembers({
+                                        type: "members_change",
+                                        payload: mem
+                                    })
+                                }
+                            })
+                            .catch((e) => {
+                                message.error(e as string)
+                            })
+                    }
+                })
+        }
+
+        // 成员加入、离开或连接方式变化时立即刷新
+        useEffect(() => {
+            if (!status) {
+                return
+            }
+            const unlisten = listen("n2n-peer-event", () => {
+                RefreshMembers()
+            })
+            return () => {
+                unlisten.then((f) => f())
+            }
+        }, [status])
+
+        // 轮询 成员变化，作为事件推送的兜底
         useInterval(() => {
             // 未运行不执行
             if (status) {
-                // 仅窗口在前台时刷新成员
-                Window.getCurrent().isVisible()
-                    .then(async (flag) => {
-                        if (flag) {
-                            await invoke("n2n_members")
-                                .then((m) => {
-                                    if (typeof m == "object") {
-                                        let mem = (m as Array<Member_info>)
-                                        // 刷新成员
-                                        setMembers({
-                                            type: "members_change",
-                                            payload: mem
-                                        })
-                                    }
-                                })
-                                .catch((e) => {
-                                    message.error(e as string)
-                                })
-                        }
-                    })
+                RefreshMembers()
             }
-        }, 5000)
+        }, 30000)
 
         return (
             <div
@@ -235,6 +410,19 @@ function MainPage() {
                                         >
                                             Ping
                                         </Typography.Link>
+                                        <Typography.Link
+                                            style={{marginLeft: 10}}
+                                            disabled={item.info.address == "None" || loading}
+                                            onClick={() => {
+                                                setLoading(true)
+                                                Diagnose(item.info.address)
+                                                    .finally(() => {
+                                                        setLoading(false)
+                                                    })
+                                            }}
+                                        >
+                                            诊断
+                                        </Typography.Link>
                                         <Spin size={"small"} spinning={loading}/>
                                     </Typography>
                                 }
@@ -246,16 +434,46 @@ function MainPage() {
         )
     }
 
+    // 网络配置选择，只有一个配置时不显示
+    function ProfileSelect() {
+        const profiles = data?.n2n_profiles ?? []
+        if (profiles.length < 2) {
+            return null
+        }
+        return (
+            <Row align={"middle"} style={{marginLeft: 10, marginRight: 10, userSelect: "none"}}>
+                <Col span={5}>
+                    <Typography.Text>
+                        网络:
+                    </Typography.Text>
+                </Col>
+                <Col span={19}>
+                    <Select
+                        size={"small"}
+                        style={{width: "100%"}}
+                        value={data && currentProfile(data)?.id}
+                        options={profiles.map((p) => ({value: p.id, label: p.name}))}
+                        onChange={(id: string) => {
+                            selectProfile(id)
+                            setProfileId(id)
+                        }}
+                    />
+                </Col>
+            </Row>
+        )
+    }
+
     // 组选择
     function GroupSelect() {
         const [group_num, setGroup_num] = useReducer(group_change, 10)
 
         // 异步获取外部配置
         useEffect(() => {
-            if (data) {
+            const profile = data && currentProfile(data)
+            if (profile) {
                 setGroup_num({
                     type: "init",
-                    payload: data.n2n_config.group.slice(4) as unknown as number
+                    payload: profile.group.slice(4) as unknown as number
                 })
             }
         }, [])
@@ -265,8 +483,9 @@ function MainPage() {
                 case "change":
                     if (typeof action.payload == "number") {
                         async_run(async () => {
-                            if (data) {
-                                data.n2n_config.group = "lers" + action.payload
+                            const profile = data && currentProfile(data)
+                            if (data && profile) {
+                                profile.group = "lers" + action.payload
                                 await SetStore("config", data)
                             }
                         })
@@ -342,8 +561,9 @@ function MainPage() {
 
         // 异步获取外部配置
         useEffect(() => {
-            if (data) {
-                setName(data.n2n_config.identification)
+            const profile = data && currentProfile(data)
+            if (profile) {
+                setName(profile.identification)
             }
         }, [])
 
@@ -353,7 +573,7 @@ function MainPage() {
                 // 更新ip
                 async_run(async () => {
                     try {
-                        const ip = await invoke("n2n_self_ip")
+                        const ip = await invoke("n2n_self_ip", profileArgs())
                         if (typeof ip == "string") {
                             setIP(ip)
                         }
@@ -372,10 +592,10 @@ function MainPage() {
          */
         async function start_client() {
             if (await check_adapter()) {
-                await invoke("n2n_client_start")
+                await invoke("n2n_client_start", profileArgs())
                     .then(async (f) => {
                         if (f as boolean) {
-                            await invoke("n2n_status")
+                            await invoke("n2n_status", profileArgs())
                                 .then((s) => {
                                     setStatus(s as boolean);
                                 })
@@ -394,10 +614,10 @@ function MainPage() {
          * 停止n2n客户端
          */
         async function stop_client() {
-            await invoke("n2n_client_stop")
+            await invoke("n2n_client_stop", profileArgs())
                 .then(async (f) => {
                     if (f as boolean) {
-                        await invoke("n2n_status")
+                        await invoke("n2n_status", profileArgs())
                             .then((s) => {
                                 setStatus(s as boolean);
                             })
@@ -431,16 +651,16 @@ function MainPage() {
          */
         async function check_firewall() {
             let status = false
-            await invoke("n2n_firewall_check")
+            await invoke("n2n_firewall_check", profileArgs())
                 .then(async (s) => {
                     // 如果不存在防火墙
                     if (!s) {
                         // 添加防火墙
-                        await invoke("n2n_firewall_add")
+                        await invoke("n2n_firewall_add", profileArgs())
                             .catch((e) => {
                                 message.error(e as string)
                             })
-                        await invoke("n2n_firewall_check")
+                        await invoke("n2n_firewall_check", profileArgs())
                             .then((s) => {
                                 status = s as boolean
                             })
@@ -478,8 +698,9 @@ function MainPage() {
                                     let s = await check_firewall()
                                     if (s) {
                                         // 保存配置
-                                        if (data) {
-                                            data.n2n_config.identification = name
+                                        const profile = data && currentProfile(data)
+                                        if (data && profile) {
+                                            profile.identification = name
                                             await SetStore("config", data)
                                         }
                                         await start_client()
@@ -606,10 +827,38 @@ function MainPage() {
         )
     }
 
+    // 配置文件无法解析时提示，此时以默认配置运行
+    function ConfigAlert() {
+        const [error, setError] = useState<string | null>(null)
+
+        useEffect(() => {
+            invoke<string | null>("config_load_error")
+                .then((e) => {
+                    setError(e)
+                })
+                .catch((e) => {
+                    message.error(e as string)
+                })
+        }, [])
+
+        return (
+            <>
+                {error && <Alert
+                    message={"配置文件无法解析，当前使用默认配置，原文件已备份"}
+                    description={error}
+                    type={"error"}
+                    closable
+                />}
+            </>
+        )
+    }
+
     return (
         <Space direction={"vertical"} style={{paddingLeft: 10, paddingRight: 10, paddingTop: 5}}>
             <ButtonTop/>
+            <ProfileSelect/>
             <GroupSelect/>
+            <ConfigAlert/>
             <ICMPAlert/>
             <MemberList/>
         </Space>
@@ -1,12 +1,12 @@
 import React, {JSX, useEffect, useState} from "react";
-import {Button, Card, Col, Flex, List, message, Row, Select, Space, Typography} from "antd";
+import {Alert, Button, Card, Col, Flex, Input, List, message, Row, Select, Space, Typography} from "antd";
 import Global from "../config/Global.ts";
 import {BaseType} from "antd/es/typography/Base";
 import {CheckOutlined, QuestionCircleOutlined, StopOutlined} from "@ant-design/icons";
 import {invoke} from "@tauri-apps/api/core";
 import {open} from '@tauri-apps/plugin-shell';
 import {open as opend} from '@tauri-apps/plugin-dialog';
-import {async_run, Data} from "../tool/ReactTool.ts";
+import {async_run, Data, HostInfo, NatReport, selectedProfileId, selectProfile, ServerProbe} from "../tool/ReactTool.ts";
 import {Store} from "@tauri-apps/plugin-store";
 import {resolve} from "@tauri-apps/api/path";
 
@@ -14,6 +14,8 @@ function ToolPage() {
 
     function NatDetector() {
         const [nat_type, setNatType] = React.useState("Unknown")
+        const [report, setReport] = React.useState<NatReport>()
+        const [servers, setServers] = React.useState<Array<ServerProbe>>()
         const [loading, setLoading] = React.useState(false)
         const [other_type, setOtherType] = React.useState("Unknown")
         const [dig, setDig] = React.useState(-1)
@@ -23,7 +25,9 @@ function ToolPage() {
             ["FullCone", "success"],
             ["RestrictedCone", "warning"],
             ["PortRestrictedCone", "warning"],
+            ["SymmetricUdpFirewall", "warning"],
             ["Symmetric", "danger"],
+            ["UdpBlocked", "danger"],
             ["Unknown", "secondary"]
         ])
 
@@ -32,10 +36,19 @@ function ToolPage() {
             ["FullCone", 2],
             ["RestrictedCone", 1],
             ["PortRestrictedCone", 0.5],
+            ["SymmetricUdpFirewall", 0.5],
             ["Symmetric", -1],
+            ["UdpBlocked", -3],
             ["Unknown", -2]
         ])
 
+        const p2pColor = new Map([
+            ["Likely", "success"],
+            ["Possible", "warning"],
+            ["Unlikely", "danger"],
+            ["Blocked", "danger"]
+        ])
+
         function IfDig(one: string, another: string) {
             setDig((value.get(one) as number) + (value.get(another) as number))
         }
@@ -60,9 +73,15 @@ function ToolPage() {
         async function NatDetect() {
             try {
                 setLoading(true)
-                await invoke("nat_detect")
+                await invoke<NatReport>("nat_detect", {profile: selectedProfileId()})
                     .then((t) => {
-                        setNatType(t as string)
+                        setNatType(t.nat_type)
+                        setReport(t)
+                        if (t.error) {
+                            message.warning({
+                                content: t.error
+                            })
+                        }
                     })
             } catch (e) {
                 message.error({
@@ -73,6 +92,19 @@ function ToolPage() {
             }
         }
 
+        async function ProbeServers() {
+            try {
+                setLoading(true)
+                setServers(await invoke<Array<ServerProbe>>("stun_servers_probe"))
+            } catch (e) {
+                message.error({
+                    content: e as string
+                })
+            } finally {
+                setLoading(false)
+            }
+        }
+
         return (
             <Card
                 style={{width: Global.ThemeCss.cardWidth, userSelect: "none"}}
@@ -85,7 +117,16 @@ function ToolPage() {
                 title={<p style={{fontSize: 14}}>NAT类型判断工具</p>}
                 actions={[<Button onClick={async () => {
                     await NatDetect()
-                }} loading={loading} disabled={loading}>开始检测</Button>]}
+                }} loading={loading} disabled={loading}>开始检测</Button>,
+                    <Button onClick={async () => {
+                        await navigator.clipboard.writeText(JSON.stringify(report, null, 2))
+                        message.success({
+                            content: "已复制检测报告"
+                        })
+                    }} disabled={!report}>复制报告</Button>,
+                    <Button onClick={async () => {
+                        await ProbeServers()
+                    }} loading={loading} disabled={loading}>测试服务器</Button>]}
             >
                 <Row align={"middle"}>
                     <Col span={7}>
@@ -99,6 +140,52 @@ function ToolPage() {
                         </Typography.Text>
                     </Col>
                 </Row>
+                {report?.behavior && (
+                    <Row style={{marginTop: 5}}>
+                        <Col span={7}>
+                            <Typography.Text type={"secondary"}>
+                                映射/过滤:
+                            </Typography.Text>
+                        </Col>
+                        <Col span={15} offset={2}>
+                            <Typography.Text style={{fontSize: 12}}>
+                                {report.behavior.mapping} / {report.behavior.filtering}
+                            </Typography.Text>
+                        </Col>
+                    </Row>
+                )}
+                {report?.port_mapping && (
+                    <Row style={{marginTop: 5}}>
+                        <Col span={7}>
+                            <Typography.Text type={"secondary"}>
+                                端口映射:
+                            </Typography.Text>
+                        </Col>
+                        <Col span={15} offset={2}>
+                            <Typography.Text type={report.port_mapping.mapping ? "success" : "secondary"}
+                                             style={{fontSize: 12}}>
+                                {report.port_mapping.mapping
+                                    ? `${report.port_mapping.mapping.protocol} ${report.port_mapping.mapping.external_port}`
+                                    : report.port_mapping.error}
+                            </Typography.Text>
+                        </Col>
+                    </Row>
+                )}
+                {report && (
+                    <Row style={{marginTop: 5}}>
+                        <Col span={7}>
+                            <Typography.Text type={"secondary"}>
+                                直连:
+                            </Typography.Text>
+                        </Col>
+                        <Col span={15} offset={2}>
+                            <Typography.Text type={p2pColor.get(report.p2p.level) as BaseType}
+                                             style={{fontSize: 12}}>
+                                {report.p2p.reasons.join("；")}
+                            </Typography.Text>
+                        </Col>
+                    </Row>
+                )}
                 <Row style={{marginTop: 5}}>
                     <Col span={7}>
                         <Typography.Text type={"secondary"}>
@@ -126,6 +213,22 @@ function ToolPage() {
                         </Flex>
                     </Col>
                 </Row>
+                {servers && (
+                    <List
+                        size={"small"}
+                        dataSource={servers}
+                        renderItem={(s) => (
+                            <List.Item>
+                                <Typography.Text type={s.mapped ? undefined : "danger"} style={{fontSize: 12}}>
+                                    {s.server}
+                                </Typography.Text>
+                                <Typography.Text type={"secondary"} style={{fontSize: 12}}>
+                                    {s.mapped ? `${s.rtt_ms}ms${s.rfc5780 ? " RFC5780" : ""}` : s.error}
+                                </Typography.Text>
+                            </List.Item>
+                        )}
+                    />
+                )}
             </Card>
         )
     }
@@ -433,6 +536,230 @@ function ToolPage() {
         )
     }
 
+    function HostNetwork() {
+        const [info, setInfo] = useState<HostInfo | null>(null)
+        const [loading, setLoading] = useState(false)
+        // 超级节点程序是否存在
+        const [available, setAvailable] = useState(true)
+
+        useEffect(() => {
+            invoke("supernode_available")
+                .then((a) => {
+                    setAvailable(a as boolean)
+                })
+                .catch((e) => {
+                    message.error(e as string)
+                })
+            invoke("supernode_status")
+                .then((i) => {
+                    setInfo(i as HostInfo | null)
+                })
+                .catch((e) => {
+                    message.error(e as string)
+                })
+        }, [])
+
+        async function CheckFirewall() {
+            let status = false
+            await invoke("supernode_firewall_check")
+                .then(async (s) => {
+                    if (!s) {
+                        await invoke("supernode_firewall_add")
+                            .catch((e) => {
+                                message.error(e as string)
+                            })
+                        await invoke("supernode_firewall_check")
+                            .then((s) => {
+                                status = s as boolean
+                            })
+                            .catch((e) => {
+                                message.error(e as string)
+                            })
+                    } else {
+                        status = s as boolean
+                    }
+                })
+                .catch((e) => {
+                    message.error(e as string)
+                })
+            return status
+        }
+
+        async function StartHost() {
+            try {
+                setLoading(true)
+                if (await CheckFirewall()) {
+                    await invoke("supernode_host", {profile: selectedProfileId()})
+                        .then((i) => {
+                            setInfo(i as HostInfo)
+                        })
+                }
+            } catch (e) {
+                message.error(e as string)
+            } finally {
+                setLoading(false)
+            }
+        }
+
+        async function StopHost() {
+            try {
+                setLoading(true)
+                await invoke("supernode_stop")
+                    .then(() => {
+                        setInfo(null)
+                    })
+            } catch (e) {
+                message.error(e as string)
+            } finally {
+                setLoading(false)
+            }
+        }
+
+        return (
+            <Card
+                style={{width: Global.ThemeCss.cardWidth, userSelect: "none"}}
+                styles={{
+                    body: {
+                        paddingTop: 15,
+                        paddingBottom: 5
+                    }
+                }}
+                title={<>
+                    <p style={{fontSize: 14}}>主持网络</p>
+                </>}
+                actions={[<Button onClick={async () => {
+                    if (info) {
+                        await StopHost()
+                    } else {
+                        await StartHost()
+                    }
+                }} loading={loading}
+                                  disabled={loading || (!info && !available)}>{info ? "停止主持" : "开始主持"}</Button>]}
+            >
+                <Row align={"middle"}>
+                    <Col>
+                        <Typography.Text type={"secondary"}>
+                            说明:
+                        </Typography.Text>
+                    </Col>
+                    <Col>
+                        <Typography.Text>
+                            在本机运行超级节点，并让当前网络连接到它，其他成员填写下方地址与组名即可加入。
+                        </Typography.Text>
+                    </Col>
+                </Row>
+                {!available && <Alert
+                    message={"没有找到超级节点程序，请将n2n的supernode放入client/x64目录后重新打开"}
+                    type={"warning"}
+                />}
+                {info && <>
+                    <Row style={{marginTop: 5}}>
+                        <Col span={7}>
+                            <Typography.Text type={"secondary"}>
+                                组名:
+                            </Typography.Text>
+                        </Col>
+                        <Col span={15} offset={2}>
+                            <Typography.Text copyable>{info.group}</Typography.Text>
+                        </Col>
+                    </Row>
+                    {info.supernodes.map((s) => (
+                        <Row key={s}>
+                            <Col span={7}>
+                                <Typography.Text type={"secondary"}>
+                                    地址:
+                                </Typography.Text>
+                            </Col>
+                            <Col span={15} offset={2}>
+                                <Typography.Text copyable>{s}</Typography.Text>
+                            </Col>
+                        </Row>
+                    ))}
+                </>}
+                <Row>
+                    <Col span={24}>
+                        <Flex justify={"center"}>
+                            {info ?
+                                <Typography.Text type={"success"}>
+                                    Running
+                                    <CheckOutlined/>
+                                </Typography.Text> :
+                                <Typography.Text type={"danger"}>
+                                    Stopping
+                                    <StopOutlined/>
+                                </Typography.Text>
+                            }
+                        </Flex>
+                    </Col>
+                </Row>
+            </Card>
+        )
+    }
+
+    function Invite() {
+        const [link, setLink] = useState("")
+        const [code, setCode] = useState("")
+
+        async function CreateInvite() {
+            // 邀请一天内有效
+            await invoke("n2n_invite_create", {expiresIn: 86400, profile: selectedProfileId()})
+                .then((i) => {
+                    setLink((i as { link: string }).link)
+                })
+                .catch((e) => {
+                    message.error(e as string)
+                })
+        }
+
+        async function ImportInvite() {
+            await invoke<string>("n2n_invite_import", {code: code})
+                .then((id) => {
+                    setCode("")
+                    // 回到主页时使用导入的网络
+                    selectProfile(id)
+                    message.success("已导入并切换到该网络")
+                })
+                .catch((e) => {
+                    message.error(e as string)
+                })
+        }
+
+        return (
+            <Card
+                style={{width: Global.ThemeCss.cardWidth, userSelect: "none"}}
+                styles={{
+                    body: {
+                        paddingTop: 15,
+                        paddingBottom: 5
+                    }
+                }}
+                title={<>
+                    <p style={{fontSize: 14}}>邀请码</p>
+                </>}
+                actions={[
+                    <Button onClick={CreateInvite}>生成邀请</Button>,
+                    <Button disabled={code == ""} onClick={ImportInvite}>导入邀请</Button>
+                ]}
+            >
+                <Row align={"middle"}>
+                    <Col span={24}>
+                        <Input
+                            size={"small"}
+                            placeholder={"粘贴邀请码或lightn2n://链接"}
+                            value={code}
+                            onChange={(e) => setCode(e.target.value)}
+                        />
+                    </Col>
+                </Row>
+                {link != "" && <Row style={{marginTop: 5}}>
+                    <Col span={24}>
+                        <Typography.Text copyable ellipsis>{link}</Typography.Text>
+                    </Col>
+                </Row>}
+            </Card>
+        )
+    }
+
     type Tool = {
         name: string,
         element: JSX.Element
@@ -443,6 +770,14 @@ function ToolPage() {
             name: "NatDetect",
             element: <NatDetector/>
         },
+        {
+            name: "HostNetwork",
+            element: <HostNetwork/>
+        },
+        {
+            name: "Invite",
+            element: <Invite/>
+        },
         {
             name: "MiniServe",
             element: <MiniServe/>
@@ -32,16 +32,164 @@ export const Sleep = (ms: number) => {
     return new Promise(resolve => setTimeout(resolve, ms))
 }
 
+// 网络配置
+export type Profile = {
+    "id": string,
+    "name": string,
+    "control_port": number,
+    "group": string,
+    "identification": string,
+    "member_server": string,
+    "member_server_token"?: string | null,
+    "port": number,
+    // 在路由器上映射edge端口
+    "port_mapping"?: boolean,
+    "server": string,
+    "supernodes"?: Array<string>,
+    "supernode_probe"?: boolean,
+    "tap_device"?: string | null,
+    "encryption"?: {
+        "required": boolean,
+        "cipher": "Twofish" | "Aes" | "ChaCha20" | "Speck",
+        "header_encryption": boolean
+    },
+    "address"?: { "mode": "supernode" | "dhcp" } | { "mode": "static", "cidr": string },
+    "subnet"?: string | null,
+    "mtu"?: number | null,
+    "mac"?: string | null
+}
+
 // 配置
 export type Data = {
-    "n2n_config": {
-        "control_port": number,
-        "group": string,
-        "identification": string,
-        "member_server": string,
-        "port": number,
-        "server": string
-    },
+    // 配置格式版本，启动时由后端迁移到最新
+    "version"?: number,
+    "n2n_profiles"?: Array<Profile>,
     "miniserve_port": number,
-    "nat_detect": Array<string>
+    // STUN服务器池
+    "stun_servers": Array<string>,
+    "invite_secret"?: string | null,
+    "supernode"?: {
+        "port": number,
+        "control_port": number,
+        "public_address"?: string | null
+    }
+}
+
+// RFC 5780 NAT行为检测结果
+export type NatBehavior = {
+    "local": string,
+    "mapped": string,
+    "direct": boolean,
+    "server": string | null,
+    "mapping": "EndpointIndependent" | "AddressDependent" | "AddressAndPortDependent" | "Unknown",
+    "filtering": "EndpointIndependent" | "AddressDependent" | "AddressAndPortDependent" | "Unknown",
+    "binding_lifetime": { "alive_ms": number, "expired_ms": number | null } | null
+}
+
+// STUN服务器探测结果
+export type ServerProbe = {
+    "server": string,
+    "address": string | null,
+    "mapped": string | null,
+    "rtt_ms": number | null,
+    "rfc5780": boolean,
+    "error": string | null
+}
+
+// 路由器端口映射状态
+export type MappingStatus = {
+    "mapping": {
+        "protocol": "Pcp" | "NatPmp" | "Upnp",
+        "gateway": string,
+        "internal": string,
+        "external_ip": string | null,
+        "external_port": number,
+        "lifetime": number,
+        "updated": number
+    } | null,
+    "error": string | null,
+    "updated": number
+}
+
+// NAT检测报告
+export type NatReport = {
+    "nat_type": string,
+    "error": string | null,
+    "local_addresses": Array<{ "interface": string, "address": string }>,
+    "servers": Array<ServerProbe>,
+    "behavior": NatBehavior | null,
+    "hairpinning": boolean | null,
+    "port_allocation": "Preserved" | "Sequential" | "Random" | "Unknown",
+    "port_mapping": MappingStatus | null,
+    "p2p": { "level": "Likely" | "Possible" | "Unlikely" | "Blocked", "reasons": Array<string> },
+    "created": number
+}
+
+// 成员连接诊断
+export type PeerDiagnosis = {
+    "member": string,
+    "mode": string,
+    "path": "Direct" | "Relayed" | "NotSeen",
+    "sockaddr": string | null,
+    "last_seen_secs": number | null,
+    "last_p2p_secs": number | null,
+    "rtt_ms": number | null,
+    "nat": NatReport,
+    "findings": Array<string>,
+    "remedies": Array<{
+        "kind": "PortMapping" | "FirewallRule" | "ChangePort" | "ForwardPort" | "CheckPeer",
+        "detail": string
+    }>
+}
+
+// 本机主持网络时的加入信息
+export type HostInfo = {
+    "profile": string,
+    "group": string,
+    "port": number,
+    "supernodes": Array<string>,
+    "member_server": string
+}
+
+// 邀请链接的内容，导入前由用户确认
+export type InvitePreview = {
+    "name": string,
+    "group": string,
+    "supernodes": Array<string>,
+    "member_server": string,
+    "encryption": Profile["encryption"],
+    "has_key": boolean,
+    "expires"?: number | null,
+    // 是否经团队密钥验证
+    "signed": boolean,
+    // 将被更新的已有配置名称
+    "updates"?: string | null
+}
+
+// 通过链接收到、等待确认的邀请
+export type InviteReceived = {
+    "code": string,
+    "preview"?: InvitePreview | null,
+    "error"?: string | null
+}
+
+// 选中的网络配置id，保存在本地，主页与工具页共用
+const SELECTED_PROFILE = "selected_profile"
+
+export function selectedProfileId(): string | undefined {
+    return localStorage.getItem(SELECTED_PROFILE) ?? undefined
+}
+
+export function selectProfile(id: string | undefined) {
+    if (id) {
+        localStorage.setItem(SELECTED_PROFILE, id)
+    } else {
+        localStorage.removeItem(SELECTED_PROFILE)
+    }
+}
+
+// 当前使用的网络配置，未选择或已被删除时使用第一个
+export function currentProfile(data: Data): Profile | undefined {
+    const id = selectedProfileId()
+    return data.n2n_profiles?.find((p) => p.id == id) ?? data.n2n_profiles?.[0]
 }
\ No newline at end of file